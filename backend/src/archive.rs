@@ -0,0 +1,609 @@
+//! Downloads a whole vault or one of its directories as a single archive:
+//! GET /archive/:vault_id?format=zip - the whole vault
+//! GET /archive/:vault_id/*path?format=tar - a directory of the vault
+//!
+//! The archive is built while it is sent, each file is read, decrypted and written to the
+//! response a chunk at a time so nothing is staged on disk or held in memory. Files are stored without
+//! compression, ZIP archives past 4 GiB or with more than 65535 files use the ZIP64 records
+
+use crate::auth::{CurrentUser, Role};
+use crate::file_browser::{modified_secs, not_found, relative_path};
+use crate::html_creation::civil_time;
+use crate::storage_crypto::{self, StorageCipher};
+use crate::vault_api::{self, internal_error, ApiError};
+use crate::ApiState;
+use axum::body::{boxed, Body, Bytes};
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use common::config_utils::VaultInfo;
+use common::file_utils;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const TAR_BLOCK: usize = 512;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+/// Query of GET /archive, the format defaults to zip
+#[derive(Deserialize, Debug, Default)]
+pub struct ArchiveOptions {
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+pub async fn archive_vault_root(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    Query(options): Query<ArchiveOptions>,
+) -> Result<impl IntoResponse, ApiError> {
+    archive(state, &user, vault_id, "", options.format).await
+}
+
+pub async fn archive_path(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, path)): UrlPath<(i32, String)>,
+    Query(options): Query<ArchiveOptions>,
+) -> Result<impl IntoResponse, ApiError> {
+    archive(state, &user, vault_id, &path, options.format).await
+}
+
+async fn archive(
+    state: Arc<Mutex<ApiState>>,
+    user: &CurrentUser,
+    vault_id: i32,
+    path: &str,
+    format: ArchiveFormat,
+) -> Result<Response, ApiError> {
+    let (pool, cipher) = {
+        let state = state.lock().await;
+        (state.pool.clone(), state.storage_cipher.clone())
+    };
+    user.require(&pool, vault_id, Role::ReadOnly).await?;
+    let vault = vault_api::find_vault(&pool, vault_id).await?;
+    let relative = relative_path(path)?;
+    stream_archive(&vault, &relative, cipher, format)
+}
+
+/// Sends every file under a directory of the vault as an archive, the archive is built while it is sent
+pub fn stream_archive(
+    vault: &VaultInfo,
+    relative: &Path,
+    cipher: StorageCipher,
+    format: ArchiveFormat,
+) -> Result<Response, ApiError> {
+    let dir = vault.abs_path.join(relative);
+    if !dir.is_dir() {
+        return Err(not_found(relative));
+    }
+
+    let mut files = file_utils::get_all_files_from_path(&dir).map_err(internal_error)?;
+    files.sort();
+    // the files are put in a directory named after the one archived so they don't spill out when extracted
+    let name = match relative.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => vault.root_dir.clone(),
+    };
+    println!("sending {} files from {:?} as a {} archive", files.len(), dir, format.extension());
+
+    let (mut sender, body) = Body::channel();
+    let prefix = name.clone();
+    tokio::spawn(async move {
+        let mut builder = ArchiveBuilder::new(format);
+        let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+            for file in files {
+                let name = entry_name(&prefix, file.strip_prefix(&dir)?);
+                let modified_time = modified_secs(&tokio::fs::metadata(&file).await?);
+                let size = storage_crypto::plaintext_len(&file)?;
+                let mut reader = cipher.open_file(&file).await?;
+
+                sender.send_data(Bytes::from(builder.start_file(&name, size, modified_time)?)).await?;
+                while let Some(chunk) = reader.next_chunk().await? {
+                    builder.add_data(&chunk)?;
+                    sender.send_data(Bytes::from(chunk)).await?;
+                }
+                sender.send_data(Bytes::from(builder.end_file()?)).await?;
+            }
+            sender.send_data(Bytes::from(builder.finish()?)).await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            println!("Error sending archive of {:?}: {e}", dir);
+            // the client sees a failed download instead of an archive that is silently missing files
+            sender.abort();
+        }
+    });
+
+    let file_name = format!("{name}.{}", format.extension());
+    let disposition = format!("attachment; filename*=UTF-8''{}", utf8_percent_encode(&file_name, NON_ALPHANUMERIC));
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        boxed(body),
+    )
+        .into_response())
+}
+
+/// Archives always use / between directories
+fn entry_name(prefix: &str, relative: &Path) -> String {
+    let mut name = prefix.to_string();
+    for component in relative.iter() {
+        name.push('/');
+        name.push_str(&component.to_string_lossy());
+    }
+    name
+}
+
+/// Writes the bytes of an archive one file at a time, each file's contents are passed in as they are read
+/// so a file is never held in memory
+pub struct ArchiveBuilder {
+    format: ArchiveFormat,
+    /// bytes written so far, zip headers refer back to where each file starts
+    offset: u64,
+    central_directory: Vec<u8>,
+    entries: u64,
+    /// the file being written, between start_file and end_file
+    current: Option<ArchiveEntry>,
+}
+
+struct ArchiveEntry {
+    name: String,
+    modified_time: i64,
+    size: u64,
+    written: u64,
+    crc: crc32fast::Hasher,
+    header_offset: u64,
+}
+
+impl ArchiveBuilder {
+    pub fn new(format: ArchiveFormat) -> Self {
+        ArchiveBuilder {
+            format,
+            offset: 0,
+            central_directory: vec![],
+            entries: 0,
+            current: None,
+        }
+    }
+
+    /// Returns the headers of a file of size bytes, its contents are then passed to add_data
+    pub fn start_file(&mut self, name: &str, size: u64, modified_time: i64) -> io::Result<Vec<u8>> {
+        let header = match self.format {
+            ArchiveFormat::Zip => zip_local_header(name, size, modified_time)?,
+            ArchiveFormat::Tar => tar_headers(name, size, modified_time)?,
+        };
+        self.current = Some(ArchiveEntry {
+            name: name.to_string(),
+            modified_time,
+            size,
+            written: 0,
+            crc: crc32fast::Hasher::new(),
+            header_offset: self.offset,
+        });
+        self.offset += header.len() as u64;
+        Ok(header)
+    }
+
+    /// Records the next chunk of the current file's contents, the chunk itself is sent as is
+    pub fn add_data(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let entry = self.current.as_mut().ok_or_else(no_file_started)?;
+        entry.written += chunk.len() as u64;
+        if entry.written > entry.size {
+            return Err(changed_while_archived(&entry.name));
+        }
+        entry.crc.update(chunk);
+        self.offset += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Returns what follows the current file's contents, the zip data descriptor or the tar padding
+    pub fn end_file(&mut self) -> io::Result<Vec<u8>> {
+        let entry = self.current.take().ok_or_else(no_file_started)?;
+        if entry.written != entry.size {
+            return Err(changed_while_archived(&entry.name));
+        }
+        let trailer = match self.format {
+            ArchiveFormat::Zip => self.zip_data_descriptor(entry)?,
+            ArchiveFormat::Tar => vec![0; (entry.size.next_multiple_of(TAR_BLOCK as u64) - entry.size) as usize],
+        };
+        self.offset += trailer.len() as u64;
+        self.entries += 1;
+        Ok(trailer)
+    }
+
+    /// The end of the archive, the zip central directory or the two empty blocks that end a tar
+    /// Zip archives of more than 65535 files or past 4 GiB end with the ZIP64 records as well
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        if self.current.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the last file of the archive was not ended"));
+        }
+        if self.format == ArchiveFormat::Tar {
+            return Ok(vec![0; TAR_BLOCK * 2]);
+        }
+
+        let directory_size = self.central_directory.len() as u64;
+        let directory_offset = self.offset;
+        let mut end = self.central_directory;
+        if self.entries >= u16::MAX as u64 || directory_size >= ZIP64_MARKER || directory_offset >= ZIP64_MARKER {
+            let zip64_end_offset = directory_offset + directory_size;
+            end.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes()); // size of the rest of the record
+            end.extend_from_slice(&((3u16 << 8) | ZIP64_VERSION).to_le_bytes());
+            end.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+            end.extend_from_slice(&[0; 8]); // this disk and the disk the directory starts on
+            end.extend_from_slice(&self.entries.to_le_bytes());
+            end.extend_from_slice(&self.entries.to_le_bytes());
+            end.extend_from_slice(&directory_size.to_le_bytes());
+            end.extend_from_slice(&directory_offset.to_le_bytes());
+
+            end.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes()); // disk of the zip64 end record
+            end.extend_from_slice(&zip64_end_offset.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes()); // number of disks
+        }
+
+        // fields too large for the end record are all ones, readers then use the zip64 end record
+        let entries = self.entries.min(u16::MAX as u64) as u16;
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // this disk and the disk the directory starts on
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&zip_u32(directory_size).to_le_bytes());
+        end.extend_from_slice(&zip_u32(directory_offset).to_le_bytes());
+        end.extend_from_slice(&[0; 2]); // comment length
+        Ok(end)
+    }
+
+    /// The crc and sizes of the file, which aren't known when its local header is sent, and its
+    /// entry in the central directory
+    fn zip_data_descriptor(&mut self, entry: ArchiveEntry) -> io::Result<Vec<u8>> {
+        let crc = entry.crc.finalize();
+        let large = entry.size >= ZIP64_MARKER;
+        let mut descriptor = 0x0807_4b50u32.to_le_bytes().to_vec();
+        descriptor.extend_from_slice(&crc.to_le_bytes());
+        if large {
+            descriptor.extend_from_slice(&entry.size.to_le_bytes());
+            descriptor.extend_from_slice(&entry.size.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(entry.size as u32).to_le_bytes());
+        }
+
+        // sizes and the offset that don't fit are all ones and given in the zip64 extra field instead
+        let mut zip64_extra = vec![];
+        if large {
+            zip64_extra.extend_from_slice(&entry.size.to_le_bytes());
+            zip64_extra.extend_from_slice(&entry.size.to_le_bytes());
+        }
+        if entry.header_offset >= ZIP64_MARKER {
+            zip64_extra.extend_from_slice(&entry.header_offset.to_le_bytes());
+        }
+        let mut extra = vec![];
+        if !zip64_extra.is_empty() {
+            extra.extend_from_slice(&1u16.to_le_bytes());
+            extra.extend_from_slice(&(zip64_extra.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&zip64_extra);
+        }
+        let version = if extra.is_empty() { ZIP_VERSION } else { ZIP64_VERSION };
+
+        // made by unix so the permissions in the external attributes are used
+        let directory = &mut self.central_directory;
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&((3u16 << 8) | version).to_le_bytes());
+        directory.extend_from_slice(&zip_fields(&entry.name, version, entry.modified_time, crc, entry.size)?);
+        directory.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        directory.extend_from_slice(&[0; 6]); // comment length, disk, internal attributes
+        directory.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+        directory.extend_from_slice(&zip_u32(entry.header_offset).to_le_bytes());
+        directory.extend_from_slice(entry.name.as_bytes());
+        directory.extend_from_slice(&extra);
+        Ok(descriptor)
+    }
+}
+
+/// Zip sizes and offsets of 4 GiB or more are stored as all ones with the real value in a ZIP64 field
+const ZIP64_MARKER: u64 = u32::MAX as u64;
+/// Version 2.0 of the zip format, or 4.5 for entries that need ZIP64
+const ZIP_VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+
+/// The local header of a zip entry. The crc and sizes come after the contents in a data descriptor, so the
+/// file can be sent as it is read, a file of 4 GiB or more is marked as ZIP64 so the descriptor has 8 byte sizes
+fn zip_local_header(name: &str, size: u64, modified_time: i64) -> io::Result<Vec<u8>> {
+    let large = size >= ZIP64_MARKER;
+    let version = if large { ZIP64_VERSION } else { ZIP_VERSION };
+    let mut fields = zip_fields(name, version, modified_time, 0, 0)?;
+    let mut extra = vec![];
+    if large {
+        // the sizes are all ones with a zip64 field left empty, as the real sizes are in the descriptor
+        fields[14..22].copy_from_slice(&[0xFF; 8]);
+        extra.extend_from_slice(&1u16.to_le_bytes());
+        extra.extend_from_slice(&16u16.to_le_bytes());
+        extra.extend_from_slice(&[0; 16]);
+    }
+
+    let mut header = 0x0403_4b50u32.to_le_bytes().to_vec();
+    header.extend_from_slice(&fields);
+    header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&extra);
+    Ok(header)
+}
+
+/// The fields shared by the local header and the central directory, from the version needed to the name length
+/// Names are utf-8 and files are stored without compression, their crc and sizes follow them in a data descriptor
+fn zip_fields(name: &str, version: u16, modified_time: i64, crc: u32, size: u64) -> io::Result<Vec<u8>> {
+    let (time, date) = dos_time(modified_time);
+    let name_len = u16::try_from(name.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{name} is too long for a zip archive")))?;
+
+    let mut fields = vec![];
+    fields.extend_from_slice(&version.to_le_bytes());
+    fields.extend_from_slice(&0x0808u16.to_le_bytes());
+    fields.extend_from_slice(&0u16.to_le_bytes());
+    fields.extend_from_slice(&time.to_le_bytes());
+    fields.extend_from_slice(&date.to_le_bytes());
+    fields.extend_from_slice(&crc.to_le_bytes());
+    fields.extend_from_slice(&zip_u32(size).to_le_bytes());
+    fields.extend_from_slice(&zip_u32(size).to_le_bytes());
+    fields.extend_from_slice(&name_len.to_le_bytes());
+    Ok(fields)
+}
+
+/// The ustar header of a file, its contents and padding follow. Names longer than the header allows are sent in a
+/// GNU long name entry first, which every common tar reader understands
+fn tar_headers(name: &str, size: u64, modified_time: i64) -> io::Result<Vec<u8>> {
+    let mut headers = vec![];
+    if name.len() >= 100 {
+        let mut long_name = name.as_bytes().to_vec();
+        long_name.push(0);
+        headers.extend(tar_header("././@LongLink", long_name.len() as u64, 0, b'L'));
+        long_name.resize(long_name.len().next_multiple_of(TAR_BLOCK), 0);
+        headers.extend(long_name);
+    }
+    headers.extend(tar_header(name, size, modified_time.max(0) as u64, b'0'));
+    Ok(headers)
+}
+
+fn tar_header(name: &str, size: u64, modified_time: u64, kind: u8) -> Vec<u8> {
+    let mut header = vec![0u8; TAR_BLOCK];
+    let name_bytes = &name.as_bytes()[..name.len().min(99)];
+    header[..name_bytes.len()].copy_from_slice(name_bytes);
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    // the size field holds 11 octal digits, larger sizes are stored in base-256 as GNU tar does
+    if size < 8 << 30 {
+        header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+    } else {
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+    header[136..148].copy_from_slice(format!("{:011o}\0", modified_time.min(0o777_7777_7777)).as_bytes());
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // the checksum is calculated with the checksum field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+/// Zip stores modified times as MS-DOS (time, date) in local time, UTC is used as the server's
+/// local time means nothing to the person downloading. Times before 1980 become 1980
+fn dos_time(secs: i64) -> (u16, u16) {
+    let (year, month, day, hour, minute, second) = civil_time(secs);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = (hour << 11) | (minute << 5) | (second / 2);
+    let date = ((year.min(2107) - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// Zip fields of 4 GiB or more are all ones, with the value given in a ZIP64 record instead
+fn zip_u32(value: u64) -> u32 {
+    value.min(ZIP64_MARKER) as u32
+}
+
+fn no_file_started() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "no file of the archive was started")
+}
+
+fn changed_while_archived(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{name} changed size while it was archived"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn build(format: ArchiveFormat, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = ArchiveBuilder::new(format);
+        let mut archive = vec![];
+        for (name, contents) in files {
+            archive.extend(builder.start_file(name, contents.len() as u64, 1_700_000_000).unwrap());
+            // contents are passed in a chunk at a time as they are read
+            let (first, rest) = contents.split_at(contents.len() / 2);
+            for chunk in [first, rest] {
+                builder.add_data(chunk).unwrap();
+                archive.extend_from_slice(chunk);
+            }
+            archive.extend(builder.end_file().unwrap());
+        }
+        archive.extend(builder.finish().unwrap());
+        archive
+    }
+
+    #[test]
+    fn test_zip_layout() {
+        let archive = build(ArchiveFormat::Zip, &[("papers/a.txt", b"hello"), ("papers/b/c.txt", b"")]);
+
+        // local header of the first file, its contents then the data descriptor with its crc and sizes
+        assert_eq!(read_u32(&archive, 0), 0x0403_4b50);
+        assert_eq!(read_u16(&archive, 6), 0x0808);
+        assert_eq!(&archive[30..42], b"papers/a.txt");
+        assert_eq!(&archive[42..47], b"hello");
+        assert_eq!(read_u32(&archive, 47), 0x0807_4b50);
+        assert_eq!(read_u32(&archive, 51), 0x3610_a686);
+        assert_eq!((read_u32(&archive, 55), read_u32(&archive, 59)), (5, 5));
+
+        // end of central directory points back at the directory and counts both files
+        let end = archive.len() - 22;
+        assert_eq!(read_u32(&archive, end), 0x0605_4b50);
+        assert_eq!(read_u16(&archive, end + 10), 2);
+        let directory = read_u32(&archive, end + 16) as usize;
+        assert_eq!(directory + read_u32(&archive, end + 12) as usize, end);
+        assert_eq!(read_u32(&archive, directory), 0x0201_4b50);
+        assert_eq!(read_u32(&archive, directory + 16), 0x3610_a686);
+        assert_eq!(read_u32(&archive, directory + 24), 5);
+        assert_eq!(read_u32(&archive, directory + 42), 0);
+        assert_eq!(&archive[directory + 46..directory + 58], b"papers/a.txt");
+    }
+
+    #[test]
+    fn test_zip64_past_4_gib() {
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Zip);
+        // a file of 5 GiB, the chunks of contents aren't needed to check the records around them
+        let local_header = builder.start_file("papers/big.bin", 5 << 30, 1_700_000_000).unwrap();
+        assert_eq!(read_u32(&local_header, 18), u32::MAX);
+        assert_eq!(read_u16(&local_header, 28), 20);
+        assert_eq!(read_u16(&local_header, 30 + 14), 1);
+        builder.current.as_mut().unwrap().written = 5 << 30;
+        builder.offset += 5 << 30;
+        let descriptor = builder.end_file().unwrap();
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(&descriptor[8..16], &(5u64 << 30).to_le_bytes());
+
+        // the next file starts past 4 GiB so its offset is in a zip64 field too
+        let small_offset = builder.offset;
+        builder.start_file("papers/small.txt", 0, 1_700_000_000).unwrap();
+        builder.end_file().unwrap();
+        let directory = builder.central_directory.clone();
+        let directory_offset = builder.offset;
+        let end = builder.finish().unwrap();
+
+        // sizes of the big file then the offset of the small one
+        assert_eq!(read_u16(&directory, 6), 45);
+        assert_eq!(read_u32(&directory, 24), u32::MAX);
+        let extra = 46 + "papers/big.bin".len();
+        assert_eq!(read_u16(&directory, extra + 2), 16);
+        let small = extra + 20;
+        assert_eq!(read_u32(&directory, small + 42), u32::MAX);
+        assert_eq!(&directory[small + 46 + "papers/small.txt".len() + 4..], &small_offset.to_le_bytes());
+
+        // zip64 end record, its locator, then the end record with the offset left to the zip64 one
+        let zip64_end = directory.len();
+        assert_eq!(read_u32(&end, zip64_end), 0x0606_4b50);
+        assert_eq!(&end[zip64_end + 48..zip64_end + 56], &directory_offset.to_le_bytes());
+        assert_eq!(read_u32(&end, zip64_end + 56), 0x0706_4b50);
+        assert_eq!(&end[zip64_end + 64..zip64_end + 72], &(directory_offset + directory.len() as u64).to_le_bytes());
+        assert_eq!(read_u32(&end, end.len() - 22), 0x0605_4b50);
+        assert_eq!(read_u32(&end, end.len() - 6), u32::MAX);
+    }
+
+    #[test]
+    fn test_zip64_past_65535_files() {
+        let names: Vec<String> = (0..70_000).map(|i| format!("papers/{i}.txt")).collect();
+        let files: Vec<(&str, &[u8])> = names.iter().map(|name| (name.as_str(), &b""[..])).collect();
+        let archive = build(ArchiveFormat::Zip, &files);
+
+        let end = archive.len() - 22;
+        assert_eq!(read_u16(&archive, end + 10), u16::MAX);
+        let zip64_end = end - 20 - 56;
+        assert_eq!(read_u32(&archive, zip64_end), 0x0606_4b50);
+        assert_eq!(&archive[zip64_end + 32..zip64_end + 40], &70_000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_files_that_change_size_fail_the_archive() {
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Tar);
+        builder.start_file("papers/a.txt", 5, 1_700_000_000).unwrap();
+        assert!(builder.add_data(b"hello, again").is_err());
+
+        let mut builder = ArchiveBuilder::new(ArchiveFormat::Zip);
+        builder.start_file("papers/a.txt", 5, 1_700_000_000).unwrap();
+        builder.add_data(b"hi").unwrap();
+        assert!(builder.end_file().is_err());
+    }
+
+    #[test]
+    fn test_tar_layout() {
+        let long_name = format!("papers/{}.txt", "x".repeat(120));
+        let archive = build(ArchiveFormat::Tar, &[("papers/a.txt", b"hello"), (&long_name, b"hi")]);
+
+        assert_eq!(archive.len() % TAR_BLOCK, 0);
+        assert_eq!(&archive[..12], b"papers/a.txt");
+        assert_eq!(&archive[124..136], b"00000000005\0");
+        assert_eq!(&archive[257..263], b"ustar\0");
+        let checksum: u32 = archive[..TAR_BLOCK]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| if (148..156).contains(&i) { b' ' as u32 } else { *byte as u32 })
+            .sum();
+        assert_eq!(&archive[148..156], format!("{checksum:06o}\0 ").as_bytes());
+        assert_eq!(&archive[TAR_BLOCK..TAR_BLOCK + 5], b"hello");
+
+        // the long name is sent in its own entry before the file
+        let long_header = TAR_BLOCK * 2;
+        assert_eq!(archive[long_header + 156], b'L');
+        assert_eq!(&archive[long_header + TAR_BLOCK..][..long_name.len()], long_name.as_bytes());
+        assert!(archive.ends_with(&[0; TAR_BLOCK * 2]));
+
+        // sizes past the 11 octal digits are stored in base-256
+        let header = tar_header("papers/big.bin", 10 << 30, 0, b'0');
+        assert_eq!(header[124], 0x80);
+        assert_eq!(&header[128..136], &(10u64 << 30).to_be_bytes());
+    }
+
+    #[test]
+    fn test_dos_time() {
+        // 2023-11-14 22:13:20 UTC
+        let (time, date) = dos_time(1_700_000_000);
+        assert_eq!(time, (22 << 11) | (13 << 5) | 10);
+        assert_eq!(date, (43 << 9) | (11 << 5) | 14);
+        assert_eq!(dos_time(0), (0, (1 << 5) | 1));
+    }
+}
@@ -0,0 +1,210 @@
+//! Every route but /share/:token needs a user's api token, sent as `Authorization: Bearer <token>`
+//! or as the password of http basic auth with the user's name so browsers and WebDAV clients can log in
+//!
+//! What a user can do in a vault comes from their role in vault_permissions, admins are owners of every vault
+//! Tokens are only stored as sha256 hashes
+
+use crate::vault_api::{internal_error, ApiError};
+use crate::ApiState;
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// What a user can do in a vault, each role allows everything the ones before it do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// browse, download and sync files from the server
+    ReadOnly,
+    /// also upload, change and delete files and create share links
+    ReadWrite,
+    /// also configure and delete the vault and decide who can access it
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read_only",
+            Role::ReadWrite => "read_write",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "read_only" => Some(Role::ReadOnly),
+            "read_write" => Some(Role::ReadWrite),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+/// The user making a request, taking it as an argument makes a route require a valid token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrentUser {
+    pub user_id: i32,
+    pub name: String,
+    pub is_admin: bool,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<Mutex<ApiState>>> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<Mutex<ApiState>>) -> Result<Self, Self::Rejection> {
+        let credentials = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_credentials);
+        let (name, token) = match credentials {
+            Some(credentials) => credentials,
+            None => return Err(unauthorized()),
+        };
+
+        let pool = state.lock().await.pool.clone();
+        match find_user_by_token(&pool, name.as_deref(), &token).await {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) => Err(unauthorized()),
+            Err(e) => Err(internal_error(e).into_response()),
+        }
+    }
+}
+
+impl CurrentUser {
+    /// The user's role in a vault, None if they can't access it
+    pub async fn role(&self, pool: &Pool<Sqlite>, vault_id: i32) -> Result<Option<Role>, sqlx::Error> {
+        Ok(self.roles(pool).await?.remove(&vault_id))
+    }
+
+    /// The user's role in every vault they can access
+    pub async fn roles(&self, pool: &Pool<Sqlite>) -> Result<HashMap<i32, Role>, sqlx::Error> {
+        if self.is_admin {
+            let rows = sqlx::query("select vault_id from vaults;").fetch_all(pool).await?;
+            return Ok(rows.iter().map(|row| (row.get::<i32, _>(0), Role::Owner)).collect());
+        }
+        let rows = sqlx::query(
+            "select vault_permissions.vault_id, role from vault_permissions \
+            join vaults on vaults.vault_id == vault_permissions.vault_id where user_id == ?;",
+        )
+        .bind(self.user_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| Some((row.get::<i32, _>(0), Role::parse(&row.get::<String, _>(1))?)))
+            .collect())
+    }
+
+    /// Checks the user has at least role in the vault, vaults they can't access are reported as not existing
+    pub async fn require(&self, pool: &Pool<Sqlite>, vault_id: i32, role: Role) -> Result<Role, ApiError> {
+        match self.role(pool, vault_id).await.map_err(internal_error)? {
+            Some(granted) if granted >= role => Ok(granted),
+            Some(_) => Err((
+                StatusCode::FORBIDDEN,
+                format!("{} needs {} access to vault {vault_id}", self.name, role.as_str()),
+            )),
+            None => Err((StatusCode::NOT_FOUND, format!("vault {vault_id} does not exist"))),
+        }
+    }
+
+    pub fn require_admin(&self) -> Result<(), ApiError> {
+        match self.is_admin {
+            true => Ok(()),
+            false => Err((StatusCode::FORBIDDEN, format!("{} is not an admin", self.name))),
+        }
+    }
+}
+
+/// (user name, token) from an Authorization header, the name is only sent with basic auth
+fn parse_credentials(authorization: &str) -> Option<(Option<String>, String)> {
+    let (scheme, value) = authorization.trim().split_once(' ')?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "bearer" => Some((None, value.trim().to_string())),
+        "basic" => {
+            let decoded = String::from_utf8(STANDARD.decode(value.trim()).ok()?).ok()?;
+            let (name, token) = decoded.split_once(':')?;
+            Some((Some(name.to_string()), token.to_string()))
+        }
+        _ => None,
+    }
+}
+
+async fn find_user_by_token(
+    pool: &Pool<Sqlite>,
+    name: Option<&str>,
+    token: &str,
+) -> Result<Option<CurrentUser>, sqlx::Error> {
+    let row = sqlx::query(
+        "select user_id, name, is_admin from users where token_hash == ? AND (? IS NULL OR name == ?);",
+    )
+    .bind(hash_token(token))
+    .bind(name)
+    .bind(name)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| CurrentUser {
+        user_id: row.get::<i32, _>(0),
+        name: row.get::<String, _>(1),
+        is_admin: row.get::<bool, _>(2),
+    }))
+}
+
+/// A new random api token
+pub fn new_token() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// Tokens are random so a plain hash is enough to keep them safe in the db
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"Datoxidize\"")],
+        "a valid api token is required",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_credentials() {
+        assert_eq!(parse_credentials("Bearer abc123"), Some((None, "abc123".to_string())));
+        // "sam:abc:123" in base64, only the first colon separates the name
+        assert_eq!(
+            parse_credentials("Basic c2FtOmFiYzoxMjM="),
+            Some((Some("sam".to_string()), "abc:123".to_string()))
+        );
+        assert_eq!(parse_credentials("Basic not base64"), None);
+        assert_eq!(parse_credentials("Digest abc"), None);
+        assert_eq!(parse_credentials("abc123"), None);
+    }
+
+    #[test]
+    fn test_roles_are_ordered() {
+        assert!(Role::Owner > Role::ReadWrite && Role::ReadWrite > Role::ReadOnly);
+        for role in [Role::ReadOnly, Role::ReadWrite, Role::Owner] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(hash_token("abc").len(), 64);
+    }
+}
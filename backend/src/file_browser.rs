@@ -0,0 +1,431 @@
+//! Web file browser for the vaults stored on the server:
+//! GET /browse - lists the vaults
+//! GET /browse/:vault_id/*path - lists a directory, or shows a file's size, modified time and history
+//! POST /browse/:vault_id/*path - saves the files of a multipart form into the directory
+//! GET /download/:vault_id/*path - the decrypted contents of a file
+//!
+//! Paths are relative to the vault root, the root itself is /browse/:vault_id
+
+use crate::auth::{CurrentUser, Role};
+use crate::html_creation::{format_time, HtmlTemplate};
+use crate::quota_api::{self, QuotaReport};
+use crate::server_db_api;
+use crate::storage_crypto::{self, StorageCipher};
+use crate::vault_api::{self, internal_error, ApiError};
+use crate::ApiState;
+use askama::Template;
+use axum::body::{boxed, Body, Bytes};
+use axum::extract::{Multipart, Path as UrlPath, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use common::config_utils::VaultInfo;
+use common::ignore_utils::{IgnoreRules, IGNORE_FILE_NAME};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sqlx::{Pool, Sqlite};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::Mutex;
+
+/// Largest upload accepted from the browser, the whole form is held in memory
+pub const UPLOAD_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// Characters left as is in a path segment of a link
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+#[derive(Template)]
+#[template(path = "vaults.html")]
+struct VaultsTemplate {
+    user_name: String,
+    account: QuotaReport,
+    vaults: Vec<(VaultInfo, QuotaReport)>,
+}
+
+struct DirEntryRow {
+    name: String,
+    link: String,
+    is_dir: bool,
+    size: u64,
+    modified: String,
+}
+
+#[derive(Template)]
+#[template(path = "directory.html")]
+struct DirectoryTemplate {
+    title: String,
+    breadcrumbs: Vec<(String, String)>,
+    entries: Vec<DirEntryRow>,
+    upload_link: String,
+    archive_link: String,
+    read_only: bool,
+    storage: QuotaReport,
+}
+
+struct HistoryRow {
+    time: String,
+    event: String,
+    modified: String,
+    file_size: i64,
+}
+
+#[derive(Template)]
+#[template(path = "file.html")]
+struct FileTemplate {
+    title: String,
+    breadcrumbs: Vec<(String, String)>,
+    size: u64,
+    modified: String,
+    download_link: String,
+    history: Vec<HistoryRow>,
+}
+
+pub async fn browse_vaults(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+) -> Result<impl IntoResponse, ApiError> {
+    let pool = state.lock().await.pool.clone();
+    let mut vaults = vec![];
+    for vault in vault_api::read_user_vaults(&pool, &user).await? {
+        let storage = quota_api::vault_report(&pool, vault.vault_id).await.map_err(internal_error)?;
+        vaults.push((vault, storage));
+    }
+    let account = quota_api::user_report(&pool, user.user_id).await.map_err(internal_error)?;
+    Ok(HtmlTemplate(VaultsTemplate { user_name: user.name, account, vaults }))
+}
+
+pub async fn browse_vault_root(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Response, ApiError> {
+    let pool = state.lock().await.pool.clone();
+    browse(&pool, &user, vault_id, "").await
+}
+
+pub async fn browse_path(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, path)): UrlPath<(i32, String)>,
+) -> Result<Response, ApiError> {
+    let pool = state.lock().await.pool.clone();
+    browse(&pool, &user, vault_id, &path).await
+}
+
+pub async fn upload_to_vault_root(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    multipart: Multipart,
+) -> Result<Redirect, ApiError> {
+    upload(state, &user, vault_id, "", multipart).await
+}
+
+pub async fn upload_to_path(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, path)): UrlPath<(i32, String)>,
+    multipart: Multipart,
+) -> Result<Redirect, ApiError> {
+    upload(state, &user, vault_id, &path, multipart).await
+}
+
+pub async fn download_file(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, path)): UrlPath<(i32, String)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (pool, cipher) = {
+        let state = state.lock().await;
+        (state.pool.clone(), state.storage_cipher.clone())
+    };
+    user.require(&pool, vault_id, Role::ReadOnly).await?;
+    let vault = vault_api::find_vault(&pool, vault_id).await?;
+    let relative = relative_path(&path)?;
+    send_file(&vault, &relative, &cipher).await
+}
+
+/// Sends the decrypted contents of a file in the vault as an attachment
+/// The file is decrypted a chunk at a time as it is sent so large files are never held in memory
+pub async fn send_file(vault: &VaultInfo, relative: &Path, cipher: &StorageCipher) -> Result<Response, ApiError> {
+    let full_path = vault.abs_path.join(relative);
+    if !full_path.is_file() {
+        return Err(not_found(relative));
+    }
+
+    let size = storage_crypto::plaintext_len(&full_path).map_err(internal_error)?;
+    let mut reader = cipher.open_file(&full_path).await.map_err(internal_error)?;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+            while let Some(chunk) = reader.next_chunk().await? {
+                sender.send_data(Bytes::from(chunk)).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            println!("Error sending {:?}: {e}", full_path);
+            // the client sees a failed download instead of a file that is silently cut short
+            sender.abort();
+        }
+    });
+
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+    let disposition = format!("attachment; filename*=UTF-8''{}", utf8_percent_encode(&name, PATH_SEGMENT));
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        boxed(body),
+    )
+        .into_response())
+}
+
+async fn browse(pool: &Pool<Sqlite>, user: &CurrentUser, vault_id: i32, path: &str) -> Result<Response, ApiError> {
+    let role = user.require(pool, vault_id, Role::ReadOnly).await?;
+    let vault = vault_api::find_vault(pool, vault_id).await?;
+    let relative = relative_path(path)?;
+    let full_path = vault.abs_path.join(&relative);
+    let metadata = fs::metadata(&full_path).map_err(|_| not_found(&relative))?;
+    let breadcrumbs = breadcrumbs(&vault, &relative);
+    let title = breadcrumbs.last().map(|(name, _)| name.clone()).unwrap_or_default();
+
+    if metadata.is_dir() {
+        let entries = list_directory(&vault, &relative).map_err(internal_error)?;
+        return Ok(HtmlTemplate(DirectoryTemplate {
+            title,
+            breadcrumbs,
+            entries,
+            upload_link: link("browse", vault_id, &relative),
+            archive_link: link("archive", vault_id, &relative),
+            read_only: vault.read_only || role < Role::ReadWrite,
+            storage: quota_api::vault_report(pool, vault_id).await.map_err(internal_error)?,
+        })
+        .into_response());
+    }
+
+    let history = server_db_api::get_file_history(pool, vault_id, &relative)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|entry| HistoryRow {
+            time: format_time(entry.time),
+            event: entry.event,
+            modified: format_time(entry.modified_time),
+            file_size: entry.file_size,
+        })
+        .collect();
+    Ok(HtmlTemplate(FileTemplate {
+        title,
+        breadcrumbs,
+        size: storage_crypto::plaintext_len(&full_path).map_err(internal_error)?,
+        modified: format_time(modified_secs(&metadata)),
+        download_link: link("download", vault_id, &relative),
+        history,
+    })
+    .into_response())
+}
+
+async fn upload(
+    state: Arc<Mutex<ApiState>>,
+    user: &CurrentUser,
+    vault_id: i32,
+    path: &str,
+    mut multipart: Multipart,
+) -> Result<Redirect, ApiError> {
+    let (pool, cipher, default_ignore, locks) = {
+        let state = state.lock().await;
+        (state.pool.clone(), state.storage_cipher.clone(), state.default_ignore.clone(), state.dav_locks.clone())
+    };
+    user.require(&pool, vault_id, Role::ReadWrite).await?;
+    let vault = vault_api::find_vault(&pool, vault_id).await?;
+    if vault.read_only {
+        return Err((StatusCode::FORBIDDEN, format!("vault {vault_id} is read-only")));
+    }
+    let relative = relative_path(path)?;
+    if !vault.abs_path.join(&relative).is_dir() {
+        return Err(not_found(&relative));
+    }
+    let rules = IgnoreRules::for_vault(&vault.abs_path, &default_ignore);
+
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        let name = match field.file_name() {
+            Some(name) if !name.is_empty() => name.to_string(),
+            // an empty file input or a field that isn't a file
+            _ => continue,
+        };
+        let file_relative = relative.join(relative_path(&name)?);
+        if file_relative.parent() != Some(relative.as_path()) {
+            return Err((StatusCode::BAD_REQUEST, format!("{:?} must be a file name", name)));
+        }
+        if rules.is_ignored_in_vault(&vault.abs_path.join(&file_relative), &vault.abs_path, false) {
+            return Err((StatusCode::BAD_REQUEST, format!("{:?} is ignored in this vault", name)));
+        }
+        locks.check_unlocked(vault_id, &file_relative, false, "")?;
+        let contents = field.bytes().await.map_err(bad_request)?;
+        save_stored_file(&pool, &cipher, &vault, &file_relative, &contents, "uploaded from browser").await?;
+        println!("uploaded {:?} to vault {vault_id} from the browser", file_relative);
+    }
+    Ok(Redirect::to(&link("browse", vault_id, &relative)))
+}
+
+/// Writes a file that wasn't synced from a client, adds it to file_metadata so clients download it and records
+/// the event in its history
+pub async fn save_stored_file(
+    pool: &Pool<Sqlite>,
+    cipher: &StorageCipher,
+    vault: &VaultInfo,
+    relative: &Path,
+    contents: &[u8],
+    event: &str,
+) -> Result<(), ApiError> {
+    let full_path = vault.abs_path.join(relative);
+    quota_api::check_writes(pool, &[(vault.vault_id, full_path.clone(), contents.len() as i64)]).await?;
+    // the server reads the ignore file in the vault root when scanning so it is left as plaintext
+    match relative == Path::new(IGNORE_FILE_NAME) {
+        true => fs::write(&full_path, contents),
+        false => cipher.write_file(&full_path, contents),
+    }
+    .map_err(internal_error)?;
+
+    let modified_time = modified_time_and_nanos(&fs::metadata(&full_path).map_err(internal_error)?);
+    let file_size = contents.len() as i64;
+    server_db_api::upsert_stored_file(pool, vault.vault_id, &full_path, &vault.root_dir, modified_time, file_size)
+        .await
+        .map_err(internal_error)?;
+    server_db_api::add_file_history(pool, vault.vault_id, relative, event, modified_time.0, file_size)
+        .await
+        .map_err(internal_error)
+}
+
+/// Entries of a directory, directories first then by name
+fn list_directory(vault: &VaultInfo, relative: &Path) -> io::Result<Vec<DirEntryRow>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(vault.abs_path.join(relative))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let entry_relative = relative.join(entry.file_name());
+        entries.push(DirEntryRow {
+            name: entry.file_name().to_string_lossy().to_string(),
+            link: link("browse", vault.vault_id, &entry_relative),
+            is_dir: metadata.is_dir(),
+            size: match metadata.is_dir() {
+                true => 0,
+                false => storage_crypto::plaintext_len(&entry.path())?,
+            },
+            modified: format_time(modified_secs(&metadata)),
+        });
+    }
+    entries.sort_by(|a, b| (!a.is_dir, &a.name).cmp(&(!b.is_dir, &b.name)));
+    Ok(entries)
+}
+
+/// (name, link) of the vault root and every directory down to relative
+fn breadcrumbs(vault: &VaultInfo, relative: &Path) -> Vec<(String, String)> {
+    let mut crumbs = vec![(vault.root_dir.clone(), link("browse", vault.vault_id, Path::new("")))];
+    let mut current = PathBuf::new();
+    for component in relative.iter() {
+        current.push(component);
+        crumbs.push((component.to_string_lossy().to_string(), link("browse", vault.vault_id, &current)));
+    }
+    crumbs
+}
+
+/// Builds /<prefix>/<vault_id>/<relative> with every path segment percent encoded
+pub fn link(prefix: &str, vault_id: i32, relative: &Path) -> String {
+    let mut link = format!("/{prefix}/{vault_id}");
+    for component in relative.iter() {
+        link.push('/');
+        link.extend(utf8_percent_encode(&component.to_string_lossy(), PATH_SEGMENT));
+    }
+    link
+}
+
+/// A path from a url, it must stay inside the vault so only plain directory and file names are allowed
+pub fn relative_path(path: &str) -> Result<PathBuf, ApiError> {
+    let path = Path::new(path.trim_matches('/'));
+    match path.components().all(|component| matches!(component, Component::Normal(_))) {
+        true => Ok(path.to_path_buf()),
+        false => Err((StatusCode::BAD_REQUEST, format!("{:?} is not a path inside the vault", path))),
+    }
+}
+
+pub fn modified_secs(metadata: &fs::Metadata) -> i64 {
+    modified_time_and_nanos(metadata).0
+}
+
+/// (seconds, nanoseconds) since unix epoch, as file_metadata keeps modified times
+pub fn modified_time_and_nanos(metadata: &fs::Metadata) -> (i64, u32) {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |duration| (duration.as_secs() as i64, duration.subsec_nanos()))
+}
+
+pub fn not_found(relative: &Path) -> ApiError {
+    (StatusCode::NOT_FOUND, format!("{:?} does not exist", relative))
+}
+
+fn bad_request(e: impl std::fmt::Display) -> ApiError {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relative_path_stays_in_vault() {
+        assert_eq!(relative_path("/docs/notes.txt/").unwrap(), PathBuf::from("docs/notes.txt"));
+        assert_eq!(relative_path("").unwrap(), PathBuf::new());
+        assert!(relative_path("docs/../../vault1").is_err());
+        assert!(relative_path("./docs").is_err());
+    }
+
+    #[test]
+    fn test_links_are_percent_encoded() {
+        assert_eq!(link("browse", 2, Path::new("my docs/a#1.txt")), "/browse/2/my%20docs/a%231.txt");
+        assert_eq!(link("download", 0, Path::new("")), "/download/0");
+    }
+
+    #[tokio::test]
+    async fn test_upload_is_listed_with_history() {
+        let pool = server_db_api::test_pool().await;
+        let dir = tempfile::tempdir().unwrap();
+        let vault = VaultInfo {
+            vault_id: 3,
+            root_dir: "papers".to_string(),
+            abs_path: dir.path().to_path_buf(),
+            sync_frequency: 5,
+            read_only: false,
+            retention_days: None,
+            ignore_patterns: vec![],
+        };
+        fs::create_dir(dir.path().join("drafts")).unwrap();
+        let cipher = StorageCipher::from_key(&[7; 32]);
+
+        let relative = Path::new("drafts/intro.txt");
+        save_stored_file(&pool, &cipher, &vault, relative, b"hello", "uploaded from browser").await.unwrap();
+        save_stored_file(&pool, &cipher, &vault, relative, b"hello again", "uploaded from browser").await.unwrap();
+
+        assert_eq!(cipher.read_file(&dir.path().join(relative)).unwrap(), b"hello again");
+        let entries = list_directory(&vault, Path::new("drafts")).unwrap();
+        assert_eq!(entries[0].name, "intro.txt");
+        assert_eq!(entries[0].size, 11);
+        assert_eq!(entries[0].link, "/browse/3/drafts/intro.txt");
+
+        let history = server_db_api::get_file_history(&pool, 3, relative).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].file_size, 11);
+        assert_eq!(history[0].event, "uploaded from browser");
+
+        let rows = sqlx::query("select file_id from file_metadata;").fetch_all(&pool).await.unwrap();
+        assert_eq!(rows.len(), 1);
+    }
+}
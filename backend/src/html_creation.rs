@@ -1,33 +1,9 @@
 use askama::Template;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use dotenvy::var;
 
-#[derive(Template, Debug)]
-#[template(path = "directory.html")]
-struct DirEntryHtmlTemplate {
-    paths: Vec<String>,
-}
-
-
-fn get_top_level_directories() -> Vec<String> {
-    let root_paths = std::fs::read_dir(var("TEST_STORAGE").unwrap())
-        .expect(&*format!("Could not find storage_vault at {}", var("TEST_STORAGE").unwrap()));
-
-    root_paths.into_iter().map(|path|
-        path.unwrap()
-            .path().to_str()
-            .unwrap().to_string())
-        .collect()
-}
-
-pub async fn test_render() -> impl IntoResponse {
-    let paths = get_top_level_directories();
-    let dir_template = DirEntryHtmlTemplate { paths };
-    HtmlTemplate(dir_template)
-}
-
-pub struct HtmlTemplate<T>(T);
+/// Renders an askama template as the html of a response
+pub struct HtmlTemplate<T>(pub T);
 
 impl<T> IntoResponse for HtmlTemplate<T>
 where
@@ -43,4 +19,47 @@ where
                 .into_response(),
         }
     }
-}
\ No newline at end of file
+}
+
+/// Formats seconds since unix epoch as a UTC date and time eg: "2023-11-14 22:13 UTC"
+pub fn format_time(secs: i64) -> String {
+    let (year, month, day, hour, minute, _) = civil_time(secs);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02} UTC")
+}
+
+/// Splits seconds since unix epoch into a UTC (year, month, day, hour, minute, second)
+pub fn civil_time(secs: i64) -> (i64, i64, i64, i64, i64, i64) {
+    // converts days since epoch to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let days = secs.div_euclid(60 * 60 * 24);
+    let secs_of_day = secs.rem_euclid(60 * 60 * 24);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / (60 * 60),
+        secs_of_day % (60 * 60) / 60,
+        secs_of_day % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13 UTC");
+        assert_eq!(format_time(951_782_400), "2000-02-29 00:00 UTC");
+    }
+}
@@ -1,24 +1,55 @@
+mod archive;
+mod auth;
+mod file_browser;
 mod html_creation;
+mod quota_api;
 mod server_db_api;
 mod server_sync_core;
+mod share_api;
+mod storage_crypto;
+mod users_api;
+mod vault_api;
+mod webdav;
 
+use crate::archive::{archive_path, archive_vault_root};
+use crate::file_browser::{
+    browse_path, browse_vault_root, browse_vaults, download_file, upload_to_path, upload_to_vault_root,
+};
+use crate::quota_api::{get_user_quota, get_vault_quota, set_user_quota, set_vault_quota};
 use crate::server_db_api::{
-    get_metadata_blob, get_metadata_differences, insert_new_metadata_into_db,
+    get_default_ignore_patterns, get_metadata_blob, get_metadata_differences,
+    insert_new_metadata_into_db,
+};
+use crate::server_sync_core::{
+    delete_files_from_client, get_remote_files_for_client, merge_file_from_client, receive_files_from_client,
+    save_user_required_files,
+};
+use crate::share_api::{create_share, list_shares, open_protected_share, open_share, revoke_share};
+use crate::storage_crypto::StorageCipher;
+use crate::users_api::{
+    create_user, current_user, delete_permission, delete_user, list_permissions, list_users, replace_token,
+    update_permission,
 };
-use crate::server_sync_core::{get_remote_files_for_client, receive_files_from_client, save_user_required_files};
+use crate::vault_api::{create_vault, delete_vault, get_vault, list_vaults, update_vault};
+use crate::webdav::{dav_path, dav_vault_root, DavLocks};
 use axum::{
-    response::IntoResponse,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    response::Redirect,
+    routing::{any, delete, get, post, put},
      Json, Router,
 };
 use common::file_utils::FileMetadata;
-use common::{common_db_utils};
+use common::{common_db_utils, ignore_utils};
 use dotenvy::{var};
 use serde_json::{json, Value};
 use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
 use std::error::Error;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 
 #[tokio::main]
@@ -26,11 +57,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
     //init environment variables
     dotenvy::from_path("./backend/.env").unwrap();
 
+    // Master key is loaded before the db is opened as the db is encrypted with a key derived from it
+    let storage_cipher = StorageCipher::load_from_env()?;
+    let db_path = storage_crypto::database_path_from_url(&var("DATABASE_URL").unwrap());
+    storage_cipher.encrypt_database(&db_path).await?;
+
     // Initial load of db - spawns two lots of pools, gives one to common_utils to read
     // local files and insert/update database accordingly
     // second pool is used for general communication between client and db
-    let pool = server_db_api::init_db(var("DATABASE_URL").unwrap()).await?;
-    let pool2 = server_db_api::init_db(var("DATABASE_URL").unwrap()).await?;
+    let pool = server_db_api::init_db(var("DATABASE_URL").unwrap(), &storage_cipher).await?;
+    let pool2 = server_db_api::init_db(var("DATABASE_URL").unwrap(), &storage_cipher).await?;
+    server_db_api::migrate_tables(&pool).await?;
+
+    // a new server has no users yet, the admin's token is only shown here
+    if let Some(admin) = users_api::create_admin_if_missing(&pool).await.map_err(|(_, e)| e)? {
+        println!("created user {} with api token {}", admin.user.name, admin.token);
+        println!("use it to create the other users with POST /users, it is not shown again");
+    }
 
     println!("database was: ");
     common_db_utils::select_all_from_file_metadata(&pool)
@@ -42,11 +85,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap();
     println!("recreated db");
 
+    let default_ignore = ignore_utils::read_default_patterns(Path::new(
+        &var("DEFAULT_IGNORE_FILE").unwrap_or_default(),
+    ));
+    let scan_ignore = default_ignore.clone();
+
     tokio::task::spawn_blocking(move || {
-        common_db_utils::init_metadata_into_db(&pool2, true);
+        // the server keeps links as links so no policies are needed
+        common_db_utils::init_metadata_into_db(&pool2, true, &scan_ignore, &HashMap::new())
     })
-    .await
-    .unwrap();
+    .await??;
+
+    storage_crypto::correct_encrypted_file_sizes(&pool).await?;
 
     println!("loaded metadata into db");
     //db_api::add_files_to_db(&pool).await?;
@@ -56,10 +106,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Stores stateful data
     let api_state = Arc::new(Mutex::new(ApiState {
-        client_requested: vec![],
-        pool,
+        client_requested: HashMap::new(),
+        pool: pool.clone(),
+        storage_cipher: storage_cipher.clone(),
+        default_ignore,
+        dav_locks: DavLocks::default(),
     }));
 
+    // history and old versions past their vault's retention_days are removed at startup and every hour after
+    let prune_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = server_db_api::prune_expired_history(&prune_pool).await {
+                println!("Error removing expired file history - {e}");
+            }
+        }
+    });
+
     // Building application routes
     let router = router(api_state);
 
@@ -69,18 +134,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     axum::Server::bind(&addr)
         .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await?;
+
+    pool.close().await;
     Ok(())
 }
 
+/// Waits for ctrl-c or SIGTERM (eg: docker stop or systemd), either way the database is closed afterwards
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error installing SIGTERM handler");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Error installing ctrl-c handler"),
+        _ = terminate.recv() => {}
+    }
+    println!("shutting down");
+}
+
 fn router(api_state: Arc<Mutex<ApiState>>) -> Router {
     Router::new()
-        // `GET /` goes to `root`
-        .route("/", get(common::router_utils::show_files))
+        // `GET /` goes to the file browser
+        .route("/", get(|| async { Redirect::to("/browse") }))
         // 'GET /show' will display the content posted in /test
         .route("/show", get(get_synced_file))
         // GET show_dirs will show the current list of directories being watched
-        .route("/show_dirs", get(get_directories))
+        .route("/show_dirs", get(|| async { Redirect::to("/browse") }))
         // POST /copy takes a JSON form of a file and copies it to the server
         //.route("/copy", post(copy_file))
         // GET /copy/metadata_blob_send gets the files as a metadata blob struct as json and sends to client
@@ -90,6 +168,8 @@ fn router(api_state: Arc<Mutex<ApiState>>) -> Router {
             "/copy/metadata_blob_receive",
             post(get_metadata_differences),
         )
+        // GET /copy/ignore_defaults sends the ignore patterns applied to every vault so clients ignore the same files
+        .route("/copy/ignore_defaults", get(get_default_ignore_patterns))
         // POST /copy/metadata_diff_receive receives the file metadata that is new for the server
         .route(
             "/copy/metadata_diff_receive",
@@ -107,14 +187,88 @@ fn router(api_state: Arc<Mutex<ApiState>>) -> Router {
             "/copy/receive_files_from_client",
             post(receive_files_from_client)
         )
+        // POST /copy/delete_files_from_client removes the files and directories a client deleted since its last sync
+        .route(
+            "/copy/delete_files_from_client",
+            post(delete_files_from_client)
+        )
+        // POST /copy/merge_from_client merges a client's copy of a text file that changed on both sides
+        .route("/copy/merge_from_client", post(merge_file_from_client))
+        // GET /vaults lists the vaults, POST /vaults creates one
+        .route("/vaults", get(list_vaults).post(create_vault))
+        // GET, PATCH and DELETE /vaults/:vault_id read, configure and remove a single vault
+        .route(
+            "/vaults/:vault_id",
+            get(get_vault).patch(update_vault).delete(delete_vault),
+        )
+        // GET /vaults/:vault_id/shares lists the vault's share links, POST creates one
+        .route("/vaults/:vault_id/shares", get(list_shares).post(create_share))
+        // DELETE /vaults/:vault_id/shares/:token revokes a share link
+        .route("/vaults/:vault_id/shares/:token", delete(revoke_share))
+        // GET /share/:token sends a shared file or directory to anyone with the link, POST takes its password
+        .route("/share/:token", get(open_share).post(open_protected_share))
+        // GET /vaults/:vault_id/permissions lists who can access the vault
+        .route("/vaults/:vault_id/permissions", get(list_permissions))
+        // PUT and DELETE /vaults/:vault_id/permissions/:user_id give a user a role in the vault or remove it
+        .route(
+            "/vaults/:vault_id/permissions/:user_id",
+            put(update_permission).delete(delete_permission),
+        )
+        // GET /users lists the users, POST /users creates one, GET /users/me is the calling user
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/me", get(current_user))
+        // DELETE /users/:user_id removes a user, POST /users/:user_id/token gives them a new api token
+        .route("/users/:user_id", delete(delete_user))
+        .route("/users/:user_id/token", post(replace_token))
+        // GET and PUT /vaults/:vault_id/quota and /users/:user_id/quota show the storage used and set its limits
+        .route("/vaults/:vault_id/quota", get(get_vault_quota).put(set_vault_quota))
+        .route("/users/:user_id/quota", get(get_user_quota).put(set_user_quota))
+        // GET /browse lists the vaults in the web file browser
+        .route("/browse", get(browse_vaults))
+        // GET /browse/:vault_id/*path shows a directory or a file, POST uploads files into a directory
+        .route(
+            "/browse/:vault_id",
+            get(browse_vault_root)
+                .post(upload_to_vault_root)
+                .layer(DefaultBodyLimit::max(file_browser::UPLOAD_LIMIT)),
+        )
+        .route(
+            "/browse/:vault_id/*path",
+            get(browse_path)
+                .post(upload_to_path)
+                .layer(DefaultBodyLimit::max(file_browser::UPLOAD_LIMIT)),
+        )
+        // GET /download/:vault_id/*path sends the decrypted contents of a file
+        .route("/download/:vault_id/*path", get(download_file))
+        // GET /archive/:vault_id/*path?format=zip|tar streams a vault or a directory as an archive
+        .route("/archive/:vault_id", get(archive_vault_root))
+        .route("/archive/:vault_id/*path", get(archive_path))
+        // /dav/:vault_id/*path serves a vault over WebDAV, the method decides what is done
+        .route(
+            "/dav/:vault_id",
+            any(dav_vault_root).layer(DefaultBodyLimit::max(file_browser::UPLOAD_LIMIT)),
+        )
+        // clients open the vault root as /dav/:vault_id/ which the wildcard route doesn't match
+        .route(
+            "/dav/:vault_id/",
+            any(dav_vault_root).layer(DefaultBodyLimit::max(file_browser::UPLOAD_LIMIT)),
+        )
+        .route(
+            "/dav/:vault_id/*path",
+            any(dav_path).layer(DefaultBodyLimit::max(file_browser::UPLOAD_LIMIT)),
+        )
         .with_state(api_state)
 }
 
 //todo - where i got up to - add a mutex to this
 #[derive(Clone)]
 pub struct ApiState {
-    pub client_requested: Vec<FileMetadata>,
+    /// files each user asked for in the current sync, by user_id
+    pub client_requested: HashMap<i32, Vec<FileMetadata>>,
     pub pool: Pool<Sqlite>,
+    pub storage_cipher: StorageCipher,
+    pub default_ignore: Vec<String>,
+    pub dav_locks: DavLocks,
 }
 
 /*
@@ -132,10 +286,6 @@ async fn copy_file(Json(payload): Json<RemoteFile>) -> impl IntoResponse {
 
  */
 
-async fn get_directories() -> impl IntoResponse {
-    html_creation::test_render().await
-}
-
 async fn get_synced_file() -> Json<Value> {
     let file = "";
     Json(json!(file))
@@ -154,8 +304,11 @@ mod tests {
     async fn copy_file_via_http() {
         let pool = test_db_init().await;
         let router = router(Arc::new(Mutex::new(ApiState {
-            client_requested: vec![],
+            client_requested: HashMap::new(),
             pool,
+            storage_cipher: StorageCipher::disabled(),
+            default_ignore: vec![],
+            dav_locks: DavLocks::default(),
         })));
         let client = TestClient::new(router);
         let path =
@@ -191,8 +344,11 @@ mod tests {
     async fn copy_nested_file_via_http() {
         let pool = test_db_init().await;
         let router = router(Arc::new(Mutex::new(ApiState {
-            client_requested: vec![],
+            client_requested: HashMap::new(),
             pool,
+            storage_cipher: StorageCipher::disabled(),
+            default_ignore: vec![],
+            dav_locks: DavLocks::default(),
         })));
         let client = TestClient::new(router);
         fs::create_dir_all("../client/example_dir/test_copy_nested_http/http_test/another")
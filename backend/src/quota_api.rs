@@ -0,0 +1,378 @@
+//! Routes for storage quotas:
+//! GET /vaults/:vault_id/quota - what the vault stores and its limits
+//! PUT /vaults/:vault_id/quota - sets the vault's limits from a Quota, admins only
+//! GET /users/:user_id/quota - what the vaults the user owns store and the user's limits, admins or the user themselves
+//! PUT /users/:user_id/quota - sets the user's limits from a Quota, admins only
+//!
+//! A vault's usage is the plaintext size and number of its files in file_metadata, a user's usage is the total
+//! of every vault they own, whoever wrote the files. Writes that would go over a limit are refused with
+//! INSUFFICIENT_STORAGE, writes that don't add to the usage are still allowed so an over quota vault can shrink
+
+use crate::auth::{CurrentUser, Role};
+use crate::vault_api::{self, internal_error, ApiError};
+use crate::{users_api, ApiState};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Limits on what a vault, or all the vaults a user owns, can store, None is unlimited
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<i64>,
+    pub max_files: Option<i64>,
+}
+
+/// What a vault or a user's vaults store
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: i64,
+    pub files: i64,
+}
+
+/// Body of the quota routes
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaReport {
+    #[serde(flatten)]
+    pub usage: Usage,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+    }
+}
+
+impl Quota {
+    fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_files.is_none()
+    }
+
+    /// Refuses usage over a limit, but only if the write adds to what is already stored
+    fn check(&self, usage: Usage, added: Usage, name: &str) -> Result<(), ApiError> {
+        if let Some(max) = self.max_bytes.filter(|max| added.bytes > 0 && usage.bytes > *max) {
+            return Err((
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("{name} is over quota, this would store {} of {max} bytes", usage.bytes),
+            ));
+        }
+        if let Some(max) = self.max_files.filter(|max| added.files > 0 && usage.files > *max) {
+            return Err((
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("{name} is over quota, this would store {} of {max} files", usage.files),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Shown in the file browser, eg: "1200 of 5000 bytes, 3 files"
+impl fmt::Display for QuotaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.usage.bytes)?;
+        if let Some(max) = self.quota.max_bytes {
+            write!(f, " of {max}")?;
+        }
+        write!(f, " bytes, {}", self.usage.files)?;
+        if let Some(max) = self.quota.max_files {
+            write!(f, " of {max}")?;
+        }
+        write!(f, " files")
+    }
+}
+
+pub async fn get_vault_quota(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Json<QuotaReport>, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::ReadOnly).await?;
+    Ok(Json(vault_report(pool, vault_id).await.map_err(internal_error)?))
+}
+
+pub async fn set_vault_quota(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    Json(quota): Json<Quota>,
+) -> Result<Json<QuotaReport>, ApiError> {
+    user.require_admin()?;
+    check_limits(&quota)?;
+    let pool = &state.lock().await.pool;
+    vault_api::find_vault(pool, vault_id).await?;
+    sqlx::query("UPDATE vaults SET quota_bytes = ?, quota_files = ? WHERE vault_id == ?;")
+        .bind(quota.max_bytes)
+        .bind(quota.max_files)
+        .bind(vault_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    println!("{} set the quota of vault {vault_id} to {:?}", user.name, quota);
+    Ok(Json(vault_report(pool, vault_id).await.map_err(internal_error)?))
+}
+
+pub async fn get_user_quota(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(user_id): UrlPath<i32>,
+) -> Result<Json<QuotaReport>, ApiError> {
+    if user.user_id != user_id {
+        user.require_admin()?;
+    }
+    let pool = &state.lock().await.pool;
+    users_api::find_user(pool, user_id).await?;
+    Ok(Json(user_report(pool, user_id).await.map_err(internal_error)?))
+}
+
+pub async fn set_user_quota(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(user_id): UrlPath<i32>,
+    Json(quota): Json<Quota>,
+) -> Result<Json<QuotaReport>, ApiError> {
+    user.require_admin()?;
+    check_limits(&quota)?;
+    let pool = &state.lock().await.pool;
+    let target = users_api::find_user(pool, user_id).await?;
+    sqlx::query("UPDATE users SET quota_bytes = ?, quota_files = ? WHERE user_id == ?;")
+        .bind(quota.max_bytes)
+        .bind(quota.max_files)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    println!("{} set the quota of {} to {:?}", user.name, target.name, quota);
+    Ok(Json(user_report(pool, user_id).await.map_err(internal_error)?))
+}
+
+/// Refuses writes that would take a vault, or the owner of a vault, over their quota
+/// writes are (vault_id, path on the server, plaintext size), a file replacing a stored one only adds the
+/// difference in size
+pub async fn check_writes(pool: &Pool<Sqlite>, writes: &[(i32, PathBuf, i64)]) -> Result<(), ApiError> {
+    let vault_ids = writes.iter().map(|(vault_id, _, _)| *vault_id).collect::<HashSet<i32>>();
+    let mut vault_quotas = HashMap::new();
+    let mut owners = HashMap::new();
+    let mut user_quotas = HashMap::new();
+    for vault_id in vault_ids {
+        vault_quotas.insert(vault_id, read_vault_quota(pool, vault_id).await.map_err(internal_error)?);
+        let vault_owners = read_vault_owners(pool, vault_id).await.map_err(internal_error)?;
+        for user_id in vault_owners.iter() {
+            let quota = read_user_quota(pool, *user_id).await.map_err(internal_error)?;
+            user_quotas.insert(*user_id, quota);
+        }
+        owners.insert(vault_id, vault_owners);
+    }
+    // most servers have no quotas, there is no need to look at every file then
+    if vault_quotas.values().chain(user_quotas.values()).all(Quota::is_unlimited) {
+        return Ok(());
+    }
+
+    let mut added: HashMap<i32, Usage> = HashMap::new();
+    let mut seen = HashSet::new();
+    for (vault_id, full_path, file_size) in writes {
+        if !seen.insert(full_path) {
+            continue;
+        }
+        let stored = stored_size(pool, full_path).await.map_err(internal_error)?;
+        added.entry(*vault_id).or_default().add(Usage {
+            bytes: file_size - stored.unwrap_or(0),
+            files: stored.is_none() as i64,
+        });
+    }
+
+    let mut added_for_users: HashMap<i32, Usage> = HashMap::new();
+    for (vault_id, vault_added) in added.iter() {
+        let quota = vault_quotas[vault_id];
+        if !quota.is_unlimited() {
+            let mut usage = vault_usage(pool, *vault_id).await.map_err(internal_error)?;
+            usage.add(*vault_added);
+            quota.check(usage, *vault_added, &format!("vault {vault_id}"))?;
+        }
+        for user_id in owners[vault_id].iter() {
+            added_for_users.entry(*user_id).or_default().add(*vault_added);
+        }
+    }
+    for (user_id, user_added) in added_for_users {
+        let quota = user_quotas[&user_id];
+        if !quota.is_unlimited() {
+            let mut usage = user_usage(pool, user_id).await.map_err(internal_error)?;
+            usage.add(user_added);
+            let owner = users_api::find_user(pool, user_id).await?;
+            quota.check(usage, user_added, &format!("user {}", owner.name))?;
+        }
+    }
+    Ok(())
+}
+
+fn check_limits(quota: &Quota) -> Result<(), ApiError> {
+    match [quota.max_bytes, quota.max_files].iter().flatten().any(|max| *max < 0) {
+        true => Err((StatusCode::BAD_REQUEST, "a quota can't be negative".to_string())),
+        false => Ok(()),
+    }
+}
+
+pub async fn vault_report(pool: &Pool<Sqlite>, vault_id: i32) -> Result<QuotaReport, sqlx::Error> {
+    Ok(QuotaReport {
+        usage: vault_usage(pool, vault_id).await?,
+        quota: read_vault_quota(pool, vault_id).await?,
+    })
+}
+
+pub async fn user_report(pool: &Pool<Sqlite>, user_id: i32) -> Result<QuotaReport, sqlx::Error> {
+    Ok(QuotaReport {
+        usage: user_usage(pool, user_id).await?,
+        quota: read_user_quota(pool, user_id).await?,
+    })
+}
+
+async fn vault_usage(pool: &Pool<Sqlite>, vault_id: i32) -> Result<Usage, sqlx::Error> {
+    let row = sqlx::query("select coalesce(sum(file_size), 0), count(*) from file_metadata where vault_id == ? AND is_dir == 0;")
+        .bind(vault_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(Usage {
+        bytes: row.get::<i64, _>(0),
+        files: row.get::<i64, _>(1),
+    })
+}
+
+async fn user_usage(pool: &Pool<Sqlite>, user_id: i32) -> Result<Usage, sqlx::Error> {
+    let row = sqlx::query(
+        "select coalesce(sum(file_size), 0), count(file_id) from vault_permissions \
+        left join file_metadata on file_metadata.vault_id == vault_permissions.vault_id AND is_dir == 0 \
+        where user_id == ? AND role == 'owner';",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(Usage {
+        bytes: row.get::<i64, _>(0),
+        files: row.get::<i64, _>(1),
+    })
+}
+
+async fn read_vault_quota(pool: &Pool<Sqlite>, vault_id: i32) -> Result<Quota, sqlx::Error> {
+    let row = sqlx::query("select quota_bytes, quota_files from vaults where vault_id == ?;")
+        .bind(vault_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row
+        .map(|row| Quota {
+            max_bytes: row.get::<Option<i64>, _>(0),
+            max_files: row.get::<Option<i64>, _>(1),
+        })
+        .unwrap_or_default())
+}
+
+async fn read_user_quota(pool: &Pool<Sqlite>, user_id: i32) -> Result<Quota, sqlx::Error> {
+    let row = sqlx::query("select quota_bytes, quota_files from users where user_id == ?;")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row
+        .map(|row| Quota {
+            max_bytes: row.get::<Option<i64>, _>(0),
+            max_files: row.get::<Option<i64>, _>(1),
+        })
+        .unwrap_or_default())
+}
+
+async fn read_vault_owners(pool: &Pool<Sqlite>, vault_id: i32) -> Result<Vec<i32>, sqlx::Error> {
+    let rows = sqlx::query("select user_id from vault_permissions where vault_id == ? AND role == 'owner';")
+        .bind(vault_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(|row| row.get::<i32, _>(0)).collect())
+}
+
+async fn stored_size(pool: &Pool<Sqlite>, full_path: &Path) -> Result<Option<i64>, sqlx::Error> {
+    let row = sqlx::query("select file_size from file_metadata where file_path == ?;")
+        .bind(full_path.to_str().unwrap())
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|row| row.get::<i64, _>(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_db_api;
+
+    async fn test_pool() -> Pool<Sqlite> {
+        let pool = server_db_api::test_pool().await;
+        for vault_id in [0, 1] {
+            sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (?, ?, 'papers', 5);")
+                .bind(vault_id)
+                .bind(format!("/storage/vault{vault_id}/papers"))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO users (user_id, name, token_hash) VALUES (2, 'sam', 'hash');")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool
+    }
+
+    fn write(vault_id: i32, name: &str, file_size: i64) -> (i32, PathBuf, i64) {
+        (vault_id, PathBuf::from(format!("/storage/vault{vault_id}/papers/{name}")), file_size)
+    }
+
+    async fn store(pool: &Pool<Sqlite>, writes: &[(i32, PathBuf, i64)]) {
+        check_writes(pool, writes).await.unwrap();
+        for (vault_id, full_path, file_size) in writes {
+            server_db_api::upsert_stored_file(pool, *vault_id, full_path, "papers", (1, 0), *file_size)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_vault_quota() {
+        let pool = test_pool().await;
+        store(&pool, &[write(0, "a.txt", 600), write(0, "b.txt", 300)]).await;
+        sqlx::query("UPDATE vaults SET quota_bytes = 1000, quota_files = 3 WHERE vault_id == 0;")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let over = check_writes(&pool, &[write(0, "c.txt", 200)]).await.unwrap_err();
+        assert_eq!(over.0, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(over.1, "vault 0 is over quota, this would store 1100 of 1000 bytes");
+        // replacing a file only counts the difference, other vaults aren't limited
+        store(&pool, &[write(0, "a.txt", 700), write(1, "c.txt", 5000)]).await;
+        store(&pool, &[write(0, "c.txt", 0)]).await;
+        let too_many = check_writes(&pool, &[write(0, "d.txt", 0)]).await.unwrap_err();
+        assert_eq!(too_many.1, "vault 0 is over quota, this would store 4 of 3 files");
+
+        sqlx::query("UPDATE vaults SET quota_bytes = 10 WHERE vault_id == 0;").execute(&pool).await.unwrap();
+        store(&pool, &[write(0, "a.txt", 1)]).await;
+        let report = vault_report(&pool, 0).await.unwrap();
+        assert_eq!(report.to_string(), "301 of 10 bytes, 3 of 3 files");
+    }
+
+    #[tokio::test]
+    async fn test_user_quota_covers_owned_vaults() {
+        let pool = test_pool().await;
+        users_api::set_role(&pool, 0, 2, Some(Role::Owner)).await.unwrap();
+        users_api::set_role(&pool, 1, 2, Some(Role::ReadWrite)).await.unwrap();
+        sqlx::query("UPDATE users SET quota_bytes = 1000 WHERE user_id == 2;").execute(&pool).await.unwrap();
+
+        store(&pool, &[write(0, "a.txt", 400), write(1, "b.txt", 5000)]).await;
+        assert_eq!(user_report(&pool, 2).await.unwrap().usage, Usage { bytes: 400, files: 1 });
+        let over = check_writes(&pool, &[write(0, "b.txt", 400), write(0, "c.txt", 400)]).await.unwrap_err();
+        assert_eq!(over.1, "user sam is over quota, this would store 1200 of 1000 bytes");
+        assert_eq!(user_report(&pool, 1).await.unwrap(), QuotaReport::default());
+    }
+}
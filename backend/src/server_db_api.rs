@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::fs;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use axum::extract::State;
@@ -10,15 +11,22 @@ use axum::response::IntoResponse;
 use axum::routing::get;
 use sqlx::{Pool, Row, Sqlite, SqlitePool};
 use sqlx::sqlite::{SqlitePoolOptions, SqliteRow};
+use serde::Serialize;
 use tokio::sync::Mutex;
 use common::file_utils::{MetadataBlob, FileMetadata, VaultMetadata, ServerPresent, convert_path_to_local};
-use common::{common_db_utils, file_utils, RemoteFile};
+use common::{common_db_utils, file_utils, router_utils, RemoteFile};
 use common::common_db_utils::convert_root_dirs_of_metadata;
-use crate::ApiState;
+use common::version_vector::{VersionVector, SERVER_DEVICE};
+use crate::auth::{CurrentUser, Role};
+use crate::storage_crypto::StorageCipher;
+use crate::{quota_api, ApiState};
 
 /// Main database tables on the server are:
 /// 1. file_metadata
 /// 2. vaults
+/// 3. file_history
+/// 4. file_ancestors
+/// 5. deleted_vaults
 ///
 /// file_metadata has the following columns:
 /// 1. file_id - a primary key for identifying every file. This should remain even if a file is deleted
@@ -32,12 +40,15 @@ use crate::ApiState;
 ///         would be read in data as i64.
 ///         An i64 should be obtained from the file by reading the metadata (provides SystemTime) then
 ///         `mod_time.duration_since(SystemTime::UNIX_EPOCH)` This provides a Duration struct
-///         which should be cast to seconds and stored as i64
+///         which should be cast to seconds and stored as i64, the rest goes in modified_nanos
+///         as `subsec_nanos()`: u32 for rust, INTEGER for sqlite
 /// 5. file_size - the size of the file in bytes
 ///         Rust type is i64, sqlite is BIGINT
 ///         NB - file metadata is stored as u64 so has a higher max size than i64
 ///         This should not be a problem as the maximum size file size that can be stored by i64
 ///         is approx 9223 PB
+/// 6. version - the file's version vector as JSON eg {"server":2,"a1b2c3":1}, NULL when unknown
+///         Rust type is VersionVector read with `VersionVector::from_db`, sqlite is TEXT
 ///
 /// vaults has the following columns:
 /// 1. root_dir - the root directory of the vault
@@ -54,35 +65,483 @@ use crate::ApiState;
 ///         under the same path on the server. eg `./storage` is the vault root
 ///         the `./storage` dir has folders like `vault0`, `vault1`, etc
 ///         these allow clean identification of vaults between computers
+/// 5. read_only - 1 if the server refuses writes to the vault from clients, 0 otherwise
+///         Rust type is bool, sqlite is INTEGER
+/// 6. retention_days - how long file_history and the versions in file_ancestors are kept, NULL keeps them forever
+///         the newest version of each file in file_ancestors is always kept so it can still be merged
+///         Rust type is Option<i32>, sqlite is INTEGER
+///
+/// file_history has a row for every time a file was written to the server, unlike file_metadata it
+/// is kept when the server restarts
+/// 1. vault_id and relative_path - the file, the path is relative to the vault root so it stays
+///         the same when a vault is renamed
+/// 2. time - when the file was written, in seconds since unix epoch
+/// 3. event - what wrote the file eg: "synced from client" or "uploaded from browser"
+/// 4. modified_time and file_size - the file's metadata after it was written
+///
+/// file_ancestors keeps the contents of text files at the versions they were synced at, so when a
+/// client and the server both change a file the changes can be merged against the version they started from
+/// Only the newest ANCESTORS_KEPT versions of a file are kept
+/// 1. vault_id and relative_path - the file, as in file_history
+/// 2. version - the file's version vector as JSON, as in file_metadata
+/// 3. contents - the file's contents at that version, encrypted the same way as stored files
+/// 4. saved_at - when the version was kept, in seconds since unix epoch, 0 for versions kept by older servers
+///
+/// deleted_vaults keeps the id of every vault that was deleted so new vaults never reuse it, a client
+/// still linked to a deleted vault would otherwise sync against a new, empty vault and delete its files
+/// 1. vault_id - the deleted vault's id
+/// 2. deleted_at - when it was deleted, in seconds since unix epoch
 
-pub async fn init_db(db_url: String) -> Result<Pool<Sqlite>, Box<dyn Error>> {
+pub async fn init_db(db_url: String, cipher: &StorageCipher) -> Result<Pool<Sqlite>, Box<dyn Error>> {
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(db_url.as_str())
+        .connect_with(cipher.database_options(&db_url)?)
         .await?;
 
 
     Ok(pool)
 }
 
+/// Creates the vaults table on a new server and brings tables created by older versions up to date
+pub async fn migrate_tables(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS vaults
+    (
+    vault_id       INTEGER PRIMARY KEY NOT NULL,
+    abs_path       TEXT                NOT NULL,
+    root_dir       TEXT                NOT NULL,
+    sync_frequency INTEGER             NOT NULL
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    common_db_utils::add_column_if_missing(pool, "vaults", "read_only", "INTEGER NOT NULL DEFAULT 0")
+        .await?;
+    common_db_utils::add_column_if_missing(pool, "vaults", "retention_days", "INTEGER").await?;
+    common_db_utils::add_column_if_missing(pool, "vaults", "quota_bytes", "BIGINT").await?;
+    common_db_utils::add_column_if_missing(pool, "vaults", "quota_files", "BIGINT").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS file_history
+    (
+    history_id     INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    vault_id       INTEGER                           NOT NULL,
+    relative_path  TEXT                              NOT NULL,
+    time           BIGINT                            NOT NULL,
+    event          TEXT                              NOT NULL,
+    modified_time  BIGINT                            NOT NULL,
+    file_size      BIGINT                            NOT NULL
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS share_links
+    (
+    token          TEXT PRIMARY KEY    NOT NULL,
+    vault_id       INTEGER             NOT NULL,
+    relative_path  TEXT                NOT NULL,
+    created_at     BIGINT              NOT NULL,
+    expires_at     BIGINT,
+    max_downloads  BIGINT,
+    download_count BIGINT              NOT NULL DEFAULT 0,
+    password_hash  TEXT
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS users
+    (
+    user_id        INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name           TEXT UNIQUE                       NOT NULL,
+    token_hash     TEXT UNIQUE                       NOT NULL,
+    is_admin       INTEGER                           NOT NULL DEFAULT 0
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS vault_permissions
+    (
+    vault_id       INTEGER             NOT NULL,
+    user_id        INTEGER             NOT NULL,
+    role           TEXT                NOT NULL,
+    PRIMARY KEY (vault_id, user_id)
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS file_ancestors
+    (
+    ancestor_id    INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    vault_id       INTEGER                           NOT NULL,
+    relative_path  TEXT                              NOT NULL,
+    version        TEXT                              NOT NULL,
+    contents       BLOB                              NOT NULL,
+    UNIQUE (vault_id, relative_path, version)
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    common_db_utils::add_column_if_missing(pool, "file_ancestors", "saved_at", "BIGINT NOT NULL DEFAULT 0").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS deleted_vaults
+    (
+    vault_id       INTEGER PRIMARY KEY NOT NULL,
+    deleted_at     BIGINT              NOT NULL
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    common_db_utils::add_column_if_missing(pool, "users", "quota_bytes", "BIGINT").await?;
+    common_db_utils::add_column_if_missing(pool, "users", "quota_files", "BIGINT").await?;
+    Ok(())
+}
+
+/// An in memory database with every server table, for tests
+#[cfg(test)]
+pub async fn test_pool() -> Pool<Sqlite> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    migrate_tables(&pool).await.unwrap();
+    common_db_utils::create_server_file_metadata(&pool).await.unwrap();
+    pool
+}
+
+/// A row of file_history
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileHistoryEntry {
+    pub time: i64,
+    pub event: String,
+    pub modified_time: i64,
+    pub file_size: i64,
+}
+
+/// Records that a file was written to the server, relative_path is relative to the vault root
+pub async fn add_file_history(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    relative_path: &Path,
+    event: &str,
+    modified_time: i64,
+    file_size: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO file_history (vault_id, relative_path, time, event, modified_time, file_size) \
+        VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(vault_id)
+    .bind(relative_path.to_str().unwrap())
+    .bind(now_secs())
+    .bind(event)
+    .bind(modified_time)
+    .bind(file_size)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gets every write of a file, newest first
+pub async fn get_file_history(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    relative_path: &Path,
+) -> Result<Vec<FileHistoryEntry>, sqlx::Error> {
+    let rows = sqlx::query(
+        "select time, event, modified_time, file_size from file_history \
+        where vault_id == ? AND relative_path == ? order by history_id desc;",
+    )
+    .bind(vault_id)
+    .bind(relative_path.to_str().unwrap())
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| FileHistoryEntry {
+            time: row.get::<i64, _>(0),
+            event: row.get::<String, _>(1),
+            modified_time: row.get::<i64, _>(2),
+            file_size: row.get::<i64, _>(3),
+        })
+        .collect())
+}
+
+/// Removes the file_history rows and file_ancestors versions that are older than their vault's retention_days
+/// The newest version of each file is kept whatever its age, as it is the one a client's next change is merged against
+pub async fn prune_expired_history(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let vaults = sqlx::query("select vault_id, retention_days from vaults where retention_days > 0;")
+        .fetch_all(pool)
+        .await?;
+    for row in vaults {
+        let vault_id = row.get::<i32, _>(0);
+        let cutoff = now_secs() - row.get::<i64, _>(1) * 24 * 60 * 60;
+        sqlx::query("DELETE FROM file_history WHERE vault_id == ? AND time < ?;")
+            .bind(vault_id)
+            .bind(cutoff)
+            .execute(pool)
+            .await?;
+        sqlx::query(
+            "DELETE FROM file_ancestors WHERE vault_id == ? AND saved_at < ? AND ancestor_id NOT IN \
+            (select max(ancestor_id) from file_ancestors where vault_id == ? group by relative_path);",
+        )
+        .bind(vault_id)
+        .bind(cutoff)
+        .bind(vault_id)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Number of versions of a file kept in file_ancestors
+const ANCESTORS_KEPT: i64 = 8;
+
+/// Keeps the contents of a file at version so later changes to it can be merged, encrypted with cipher
+/// The oldest versions of the file are dropped once it has more than ANCESTORS_KEPT
+pub async fn save_ancestor(
+    pool: &Pool<Sqlite>,
+    cipher: &StorageCipher,
+    vault_id: i32,
+    relative_path: &Path,
+    version: &VersionVector,
+    contents: &[u8],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let relative_path = relative_path.to_str().unwrap();
+    let contents = cipher.encrypt(contents)?;
+    sqlx::query(
+        "INSERT INTO file_ancestors (vault_id, relative_path, version, contents, saved_at) VALUES (?, ?, ?, ?, ?) \
+        ON CONFLICT(vault_id, relative_path, version) DO NOTHING;",
+    )
+    .bind(vault_id)
+    .bind(relative_path)
+    .bind(version.to_db())
+    .bind(contents)
+    .bind(now_secs())
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM file_ancestors WHERE vault_id == ? AND relative_path == ? AND ancestor_id NOT IN \
+        (select ancestor_id from file_ancestors where vault_id == ? AND relative_path == ? \
+        order by ancestor_id desc limit ?);",
+    )
+    .bind(vault_id)
+    .bind(relative_path)
+    .bind(vault_id)
+    .bind(relative_path)
+    .bind(ANCESTORS_KEPT)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gets the decrypted contents of a file at version, None if that version isn't kept
+pub async fn get_ancestor(
+    pool: &Pool<Sqlite>,
+    cipher: &StorageCipher,
+    vault_id: i32,
+    relative_path: &Path,
+    version: &VersionVector,
+) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let row = sqlx::query(
+        "select contents from file_ancestors where vault_id == ? AND relative_path == ? AND version == ?;",
+    )
+    .bind(vault_id)
+    .bind(relative_path.to_str().unwrap())
+    .bind(version.to_db())
+    .fetch_optional(pool)
+    .await?;
+    match row {
+        Some(row) => Ok(Some(cipher.decrypt(&row.get::<Vec<u8>, _>(0))?)),
+        None => Ok(None),
+    }
+}
+
+/// The version of a file in file_metadata, empty if the file or its version is unknown
+pub async fn get_stored_version(pool: &Pool<Sqlite>, full_path: &Path) -> Result<VersionVector, sqlx::Error> {
+    let version = sqlx::query("select version from file_metadata where file_path == ?;")
+        .bind(full_path.to_str().unwrap())
+        .fetch_optional(pool)
+        .await?
+        .and_then(|row| row.get::<Option<String>, _>(0));
+    Ok(VersionVector::from_db(version))
+}
+
+/// Adds the changes counted in other to the version of a file in file_metadata, eg: once a client's
+/// copy has been merged into the server's
+pub async fn merge_stored_version(
+    pool: &Pool<Sqlite>,
+    full_path: &Path,
+    other: &VersionVector,
+) -> Result<(), sqlx::Error> {
+    let mut version = get_stored_version(pool, full_path).await?;
+    version.merge(other);
+    sqlx::query("UPDATE file_metadata SET version = ? WHERE file_path == ?;")
+        .bind(version.to_db())
+        .bind(full_path.to_str().unwrap())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Adds a file written outside of a sync to file_metadata so clients download it, or updates
+/// its metadata if the server already has it
+/// The write counts as a change made by the server in the file's version
+/// modified_time is (seconds, nanoseconds) since unix epoch as in `file_browser::modified_time_and_nanos`
+pub async fn upsert_stored_file(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    full_path: &Path,
+    root_directory: &str,
+    modified_time: (i64, u32),
+    file_size: i64,
+) -> Result<(), sqlx::Error> {
+    let version = get_stored_version(pool, full_path).await?.incremented(SERVER_DEVICE);
+
+    sqlx::query(
+        "INSERT INTO file_metadata (file_id, vault_id, file_path, root_directory, modified_time, modified_nanos, \
+        file_size, version) VALUES ((select coalesce(max(file_id), 0) + 1 from file_metadata), ?, ?, ?, ?, ?, ?, ?) \
+        ON CONFLICT(file_path) DO UPDATE SET modified_time = excluded.modified_time, \
+        modified_nanos = excluded.modified_nanos, file_size = excluded.file_size, version = excluded.version;",
+    )
+    .bind(vault_id)
+    .bind(full_path.to_str().unwrap())
+    .bind(root_directory)
+    .bind(modified_time.0)
+    .bind(modified_time.1 as i64)
+    .bind(file_size)
+    .bind(version.to_db())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Adds a directory created outside of a sync to file_metadata so clients create it too
+pub async fn upsert_stored_dir(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    full_path: &Path,
+    root_directory: &str,
+    modified_time: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO file_metadata (file_id, vault_id, file_path, root_directory, modified_time, file_size, is_dir) \
+        VALUES ((select coalesce(max(file_id), 0) + 1 from file_metadata), ?, ?, ?, ?, 0, 1) \
+        ON CONFLICT(file_path) DO NOTHING;",
+    )
+    .bind(vault_id)
+    .bind(full_path.to_str().unwrap())
+    .bind(root_directory)
+    .bind(modified_time)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Removes a file, or every file under a directory, from file_metadata
+pub async fn remove_stored_files(pool: &Pool<Sqlite>, full_path: &Path) -> Result<(), sqlx::Error> {
+    let path = full_path.to_str().unwrap();
+    sqlx::query("DELETE FROM file_metadata WHERE file_path == ? OR substr(file_path, 1, ?) == ?;")
+        .bind(path)
+        .bind(path.chars().count() as i64 + 1)
+        .bind(format!("{path}/"))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Rewrites the paths in file_metadata of a file, or of every file under a directory, that was moved
+/// Both paths must be in the same vault
+pub async fn move_stored_files(pool: &Pool<Sqlite>, from: &Path, to: &Path) -> Result<(), sqlx::Error> {
+    let from = from.to_str().unwrap();
+    sqlx::query(
+        "UPDATE file_metadata SET file_path = ? || substr(file_path, ?) \
+        WHERE file_path == ? OR substr(file_path, 1, ?) == ?;",
+    )
+    .bind(to.to_str().unwrap())
+    .bind(from.chars().count() as i64 + 1)
+    .bind(from)
+    .bind(from.chars().count() as i64 + 1)
+    .bind(format!("{from}/"))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Returns the ids of all vaults the server refuses writes to
+pub async fn get_read_only_vaults(pool: &Pool<Sqlite>) -> Result<HashSet<i32>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id from vaults where read_only != 0;")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(|row| row.get::<i32, _>(0)).collect())
+}
+
+/// Returns the ids of the vaults the user can write to, those that aren't read-only where their role allows it
+/// Anything sent for any other vault id, including one that doesn't exist, is refused
+pub async fn get_writable_vaults(pool: &Pool<Sqlite>, user: &CurrentUser) -> Result<HashSet<i32>, sqlx::Error> {
+    let read_only = get_read_only_vaults(pool).await?;
+    Ok(user
+        .roles(pool)
+        .await?
+        .into_iter()
+        .filter(|(vault_id, role)| *role >= Role::ReadWrite && !read_only.contains(vault_id))
+        .map(|(vault_id, _)| vault_id)
+        .collect())
+}
+
+/// Returns the first vault the blob has files for that isn't writable, if any
+pub fn find_unwritable_vault(blob: &MetadataBlob, writable: &HashSet<i32>) -> Option<i32> {
+    blob.vaults
+        .iter()
+        .find(|(id, vault)| !writable.contains(id) && !vault.files.is_empty())
+        .map(|(id, _)| *id)
+}
+
 /// Sends metadata blob to client when request by a GET request
 /// Reads from DB and maps file metadata to build a structure to be sent via TCP
 /// Intended for help in the initial sync of client and server
-pub async fn get_metadata_blob(State(state): State<Arc<Mutex<ApiState>>>) -> impl IntoResponse {
+/// The server's clock is sent in a header so the client can measure the skew between them
+pub async fn get_metadata_blob(State(state): State<Arc<Mutex<ApiState>>>, user: CurrentUser) -> impl IntoResponse {
     let pool = &state.lock().await.pool;
-    let blob = build_metadata_blob(pool)
+    let roles = user.roles(pool).await.expect("Error reading the user's vaults");
+    let blob = build_metadata_blob(pool, &roles)
         .await
         .expect(&*format!("Error reading metadata blob"));
     let id = get_latest_file_id(pool)
         .await
         .expect(&*format!("Error selecting max file_id"));
-    Json((id, blob))
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    ([(router_utils::SERVER_TIME_HEADER, now.to_string())], Json((id, blob)))
 }
 
 /// Gets the most recent file_id from db to allow client to update file_ids
-/// NB it needs to be incremented before use
+/// NB it needs to be incremented before use, 0 if the server has no files yet
 async fn get_latest_file_id(pool: &Pool<Sqlite>) -> Result<i32, sqlx::Error> {
-    let result = sqlx::query("select file_id from file_metadata order by file_id desc limit 1")
+    let result = sqlx::query("select coalesce(max(file_id), 0) from file_metadata")
         .fetch_one(pool)
         .await?;
     let latest = result.get::<i32, _>(0);
@@ -91,29 +550,125 @@ async fn get_latest_file_id(pool: &Pool<Sqlite>) -> Result<i32, sqlx::Error> {
 
 pub async fn insert_new_metadata_into_db(
     State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
     Json(client_blob): Json<MetadataBlob>,
 ) -> impl IntoResponse {
-    let pool = &state.lock().await.pool;
+    let state = state.lock().await;
+    let pool = &state.pool;
+
+    let writable = get_writable_vaults(pool, &user)
+        .await
+        .expect("Error reading writable vaults");
+    if let Some(vault_id) = find_unwritable_vault(&client_blob, &writable) {
+        println!("refused metadata for vault {vault_id} from {}", user.name);
+        return (StatusCode::FORBIDDEN, format!("vault {vault_id} is read-only or doesn't exist"));
+    }
+
+    // every path is joined to the vault's root, so one that isn't inside the vault is refused
+    let outside = client_blob
+        .vaults
+        .values()
+        .flat_map(|vault| vault.files.iter())
+        .find(|file| !file_utils::is_inside_vault(&file.relative_path()));
+    if let Some(file) = outside {
+        println!("refused path {:?} from {}", file.full_path, user.name);
+        return (StatusCode::BAD_REQUEST, format!("{:?} is not a path inside the vault", file.full_path));
+    }
+
+    // links are stored as they are, one that leaves the vault would expose files outside it
+    let escaping_link = client_blob
+        .vaults
+        .values()
+        .flat_map(|vault| vault.files.iter())
+        .find(|file| {
+            file.link_target
+                .as_ref()
+                .is_some_and(|target| !file_utils::link_stays_in_vault(&file.relative_path(), target))
+        });
+    if let Some(file) = escaping_link {
+        println!("refused link {:?} from {}", file.relative_path(), user.name);
+        return (StatusCode::BAD_REQUEST, format!("{:?} links outside the vault", file.relative_path()));
+    }
+
+    // a path locked through WebDAV can't be changed by a client until the lock is released
+    let locked = client_blob.vaults.iter().find_map(|(vault_id, vault)| {
+        vault
+            .files
+            .iter()
+            .find_map(|file| state.dav_locks.check_unlocked(*vault_id, &file.relative_path(), false, "").err())
+    });
+    if let Some((status, message)) = locked {
+        println!("refused metadata from {}: {message}", user.name);
+        return (status, message);
+    }
 
     let mut client = client_blob;
     convert_root_dirs_of_metadata(pool, &mut client)
         .await
         .expect(&*format!("Error converting file paths for {:?}", client));
 
+    let rules = common_db_utils::get_ignore_rules_for_vaults(pool, &state.default_ignore)
+        .await
+        .expect("Error reading vaults for ignore rules");
+    client.remove_ignored(&rules);
+
     let files = client.convert_to_metadata_vec();
 
+    // every refusal comes before anything is written so a refused request leaves storage as it was
+    let writes = files
+        .iter()
+        .filter(|file| !file.is_dir && file.link_target.is_none())
+        .map(|file| (file.vault_id, file.full_path.clone(), file.file_size))
+        .collect::<Vec<(i32, PathBuf, i64)>>();
+    if let Err((status, message)) = quota_api::check_writes(pool, &writes).await {
+        println!("refused metadata from {}: {message}", user.name);
+        return (status, message);
+    }
+
+    // directories and links have no contents to send so they are created as soon as the client reports them
+    for dir in files.iter().filter(|file| file.is_dir) {
+        if let Err(e) = fs::create_dir_all(&dir.full_path) {
+            println!("Error creating {} - {e}", dir.full_path.display());
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    }
+
+    for link in files.iter() {
+        if let Some(target) = &link.link_target {
+            if let Err(e) = file_utils::write_link(&link.full_path, target, link.modified_filetime()) {
+                println!("Error creating link {} - {e}", link.full_path.display());
+                return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        }
+    }
+
+    // files already here take the client's permissions now, new ones get them when they are uploaded
+    for file in files.iter() {
+        let mode = match file.mode {
+            Some(mode) => mode,
+            None => continue,
+        };
+        if fs::symlink_metadata(&file.full_path).is_ok_and(|metadata| metadata.is_file()) {
+            if let Err(e) = fs::set_permissions(&file.full_path, file_utils::synced_permissions(mode)) {
+                println!("Error setting permissions of {} - {e}", file.full_path.display());
+            }
+        }
+    }
+
     common_db_utils::upsert_database(pool, files)
         .await
         .expect(&*format!("Error inserting vec of  \n into database"));
-    StatusCode::OK
+    (StatusCode::OK, String::new())
 }
 
 pub async fn get_metadata_differences(
     State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
     Json(client_blob): Json<MetadataBlob>,
 ) -> impl IntoResponse {
     let pool = &state.lock().await.pool;
-    let server_blob = build_metadata_blob(pool)
+    let roles = user.roles(pool).await.expect("Error reading the user's vaults");
+    let server_blob = build_metadata_blob(pool, &roles)
         .await
         .expect("Error creating server MetadataBlob");
 
@@ -122,9 +677,19 @@ pub async fn get_metadata_differences(
     StatusCode::OK
 }
 
-/// Helper function that queries DB and returns a blob of Metadata
-async fn build_metadata_blob(pool: &Pool<Sqlite>) -> Result<MetadataBlob, sqlx::Error> {
-    let vault_query = sqlx::query("select vault_id, abs_path from vaults")
+/// Sends the ignore patterns applied to every vault, clients combine these with each vault's
+/// .datoxidizeignore so both sides ignore the same files
+pub async fn get_default_ignore_patterns(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    _user: CurrentUser,
+) -> impl IntoResponse {
+    Json(state.lock().await.default_ignore.clone())
+}
+
+/// Helper function that queries DB and returns a blob of Metadata for the vaults in roles
+/// Vaults the user can only read are sent as read-only so the client doesn't upload to them
+async fn build_metadata_blob(pool: &Pool<Sqlite>, roles: &HashMap<i32, Role>) -> Result<MetadataBlob, sqlx::Error> {
+    let vault_query = sqlx::query("select vault_id, abs_path, read_only from vaults")
         .fetch_all(pool)
         .await?;
 
@@ -132,8 +697,9 @@ async fn build_metadata_blob(pool: &Pool<Sqlite>) -> Result<MetadataBlob, sqlx::
         .iter()
         .map(|row|
             (row.get::<i32, _>(0),
-             row.get::<String, _>(1)))
-        .collect::<Vec<(i32, String)>>();
+             row.get::<String, _>(1),
+             row.get::<bool, _>(2)))
+        .collect::<Vec<(i32, String, bool)>>();
 
     let mut blob = MetadataBlob {
         vaults: HashMap::new(),
@@ -141,7 +707,14 @@ async fn build_metadata_blob(pool: &Pool<Sqlite>) -> Result<MetadataBlob, sqlx::
 
 
     for vault in vaults {
-        let query: Vec<SqliteRow> = sqlx::query("select * from file_metadata where vault_id == ? ;")
+        let role = match roles.get(&vault.0) {
+            Some(role) => *role,
+            None => continue,
+        };
+        let query: Vec<SqliteRow> = sqlx::query(
+            "select file_id, vault_id, file_path, root_directory, modified_time, file_size, is_dir, link_target, mode, modified_nanos, version \
+            from file_metadata where vault_id == ? ;",
+        )
             .bind(vault.0)
             .fetch_all(pool)
             .await?;
@@ -155,6 +728,8 @@ async fn build_metadata_blob(pool: &Pool<Sqlite>) -> Result<MetadataBlob, sqlx::
         let vault_md = VaultMetadata {
             files,
             vault_id: vault.0,
+            read_only: vault.2 || role < Role::ReadWrite,
+            ..Default::default()
         };
         blob.vaults.insert(vault.0, vault_md);
     }
@@ -174,6 +749,11 @@ fn map_metadata_query_to_blob(rows: Vec<SqliteRow>, absolute_root_dir: PathBuf)
             let root_directory = row.get::<String, _>(3);
             let modified_time = row.get::<i64, _>(4);
             let file_size = row.get::<i64, _>(5);
+            let is_dir = row.get::<bool, _>(6);
+            let link_target = row.get::<Option<String>, _>(7).map(PathBuf::from);
+            let mode = row.get::<Option<i64>, _>(8).map(|mode| mode as u32);
+            let modified_nanos = row.get::<i64, _>(9) as u32;
+            let version = VersionVector::from_db(row.get::<Option<String>, _>(10));
 
 
             let file = FileMetadata {
@@ -188,6 +768,11 @@ fn map_metadata_query_to_blob(rows: Vec<SqliteRow>, absolute_root_dir: PathBuf)
                     true => ServerPresent::Yes,
                     false => ServerPresent::No
                 },
+                is_dir,
+                link_target,
+                mode,
+                modified_nanos,
+                version,
             };
             result.push(file.clone());
         });
@@ -195,8 +780,54 @@ fn map_metadata_query_to_blob(rows: Vec<SqliteRow>, absolute_root_dir: PathBuf)
 }
 
 
-
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_history_is_pruned_after_retention_days() {
+        let pool = test_pool().await;
+        for (vault_id, retention_days) in [(0, Some(30)), (1, None)] {
+            sqlx::query(
+                "INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency, retention_days) \
+                VALUES (?, ?, 'papers', 5, ?);",
+            )
+            .bind(vault_id)
+            .bind(format!("/storage/vault{vault_id}/papers"))
+            .bind(retention_days)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        let cipher = StorageCipher::disabled();
+        let relative = Path::new("draft.txt");
+        let first = VersionVector::default().incremented(SERVER_DEVICE);
+        let second = first.incremented(SERVER_DEVICE);
+        for vault_id in [0, 1] {
+            add_file_history(&pool, vault_id, relative, "uploaded from browser", 1, 5).await.unwrap();
+            add_file_history(&pool, vault_id, relative, "uploaded from browser", 2, 5).await.unwrap();
+            save_ancestor(&pool, &cipher, vault_id, relative, &first, b"first").await.unwrap();
+            save_ancestor(&pool, &cipher, vault_id, relative, &second, b"second").await.unwrap();
+        }
+        // the first write and both versions were 40 days ago
+        let expired = now_secs() - 40 * 24 * 60 * 60;
+        sqlx::query("UPDATE file_history SET time = ? WHERE modified_time == 1;")
+            .bind(expired)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE file_ancestors SET saved_at = ?;").bind(expired).execute(&pool).await.unwrap();
+
+        prune_expired_history(&pool).await.unwrap();
+
+        let history = get_file_history(&pool, 0, relative).await.unwrap();
+        assert_eq!(history.iter().map(|entry| entry.modified_time).collect::<Vec<i64>>(), vec![2]);
+        assert_eq!(get_ancestor(&pool, &cipher, 0, relative, &first).await.unwrap(), None);
+        assert_eq!(get_ancestor(&pool, &cipher, 0, relative, &second).await.unwrap(), Some(b"second".to_vec()));
+        assert_eq!(get_file_history(&pool, 1, relative).await.unwrap().len(), 2);
+        assert!(get_ancestor(&pool, &cipher, 1, relative, &first).await.unwrap().is_some());
+    }
+}
 
 /*
 /// Meant for testing, populate the db with some dummy data to use
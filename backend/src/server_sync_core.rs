@@ -1,48 +1,507 @@
-use crate::ApiState;
+use crate::auth::CurrentUser;
+use crate::file_browser::{modified_time_and_nanos, save_stored_file};
+use crate::storage_crypto::StorageCipher;
+use crate::vault_api::{self, internal_error, ApiError};
+use crate::{quota_api, server_db_api, ApiState};
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
 use common::file_utils::{FileMetadata, MetadataBlob, ServerPresent};
+use common::merge_utils::{self, MergeRequest, MergedFile};
 use common::{common_db_utils, file_utils, RemoteFile};
+use sqlx::{Pool, Sqlite};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 pub async fn save_user_required_files(
     State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
     Json(payload): Json<MetadataBlob>,
 ) -> impl IntoResponse {
-    println!("client request: {:?}", payload);
+    println!("client request from {}: {:?}", user.name, payload);
+    let state = &mut state.lock().await;
+    // files are only sent from vaults the user can read
+    let roles = user.roles(&state.pool).await.expect("Error reading the user's vaults");
     let payload_on_server= payload
         .convert_to_metadata_vec()
         .into_iter()
-        .filter(|files| files.present_on_server == ServerPresent::Yes)
+        .filter(|files| files.present_on_server == ServerPresent::Yes && roles.contains_key(&files.vault_id))
         .collect::<Vec<FileMetadata>>();
 
-    state.lock().await.client_requested = payload_on_server;
+    state.client_requested.insert(user.user_id, payload_on_server);
     StatusCode::OK
 }
 
 pub async fn get_remote_files_for_client(
     State(state): State<Arc<Mutex<ApiState>>>,
-) -> impl IntoResponse {
+    user: CurrentUser,
+) -> Result<Json<Vec<RemoteFile>>, ApiError> {
     let state = &state.lock().await;
-    let files =
-        common_db_utils::read_file_contents_from_disk_and_metadata(&state.pool, &state.client_requested)
+    let requested = state.client_requested.get(&user.user_id).cloned().unwrap_or_default();
+    let mut files =
+        common_db_utils::read_file_contents_from_disk_and_metadata(&state.pool, &requested)
             .await;
-    Json(files)
+    state
+        .storage_cipher
+        .decrypt_remote_files(&mut files)
+        .map_err(internal_error)?;
+    keep_ancestors(&state.pool, &state.storage_cipher, &files)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(files))
 }
 
 pub async fn receive_files_from_client(
     State(state): State<Arc<Mutex<ApiState>>>,
-    Json(payload): Json<Vec<RemoteFile>>
+    user: CurrentUser,
+    Json(mut payload): Json<Vec<RemoteFile>>
 ) -> impl IntoResponse {
     let state = &state.lock().await;
+    let writable = server_db_api::get_writable_vaults(&state.pool, &user)
+        .await
+        .expect("Error reading writable vaults");
+    if let Some(file) = payload.iter().find(|file| !writable.contains(&file.vault_id)) {
+        println!("refused files for vault {} from {}", file.vault_id, user.name);
+        return (StatusCode::FORBIDDEN, format!("vault {} is read-only or doesn't exist", file.vault_id));
+    }
+
+    // every path is joined to the vault's root, so one that isn't inside the vault is refused
+    let outside = payload.iter().find(|file| match file.full_path.strip_prefix(&file.absolute_root_dir) {
+        Ok(relative) => !file_utils::is_inside_vault(relative),
+        Err(_) => true,
+    });
+    if let Some(file) = outside {
+        println!("refused path {:?} from {}", file.full_path, user.name);
+        return (StatusCode::BAD_REQUEST, format!("{:?} is not a path inside the vault", file.full_path));
+    }
+
+    // a file locked through WebDAV can't be changed by a client until the lock is released
+    let locked = payload.iter().find_map(|file| {
+        let relative = file.full_path.strip_prefix(&file.absolute_root_dir).ok()?;
+        state.dav_locks.check_unlocked(file.vault_id, relative, false, "").err()
+    });
+    if let Some((status, message)) = locked {
+        println!("refused files from {}: {message}", user.name);
+        return (status, message);
+    }
+
     let vault_and_root_paths = common_db_utils::get_vault_id_and_root_directories(&state.pool)
         .await
         .expect(&*format!("Error reading from database with {:?}", payload));
+
+    // files sent from the client are checked against the server's rules for the vault
+    let rules = common_db_utils::get_ignore_rules_for_vaults(&state.pool, &state.default_ignore)
+        .await
+        .expect("Error reading vaults for ignore rules");
+    payload.retain(|file| match rules.get(&file.vault_id) {
+        Some(vault_rules) => !vault_rules.is_ignored_in_vault(&file.full_path, &file.absolute_root_dir, false),
+        None => true,
+    });
+
+    // the same paths and sizes as file_metadata so replaced files only count the change in size
+    let writes = payload
+        .iter()
+        .filter_map(|file| {
+            let relative = file.full_path.strip_prefix(&file.absolute_root_dir).ok()?;
+            let (_, root) = vault_and_root_paths.iter().find(|(vault_id, _)| *vault_id == file.vault_id)?;
+            Some((file.vault_id, root.join(relative), file.contents.len() as i64))
+        })
+        .collect::<Vec<(i32, PathBuf, i64)>>();
+    if let Err((status, message)) = quota_api::check_writes(&state.pool, &writes).await {
+        println!("refused files from {}: {message}", user.name);
+        return (status, message);
+    }
+
+    // history is taken before encrypting so the plaintext size is recorded
+    let history = payload
+        .iter()
+        .filter_map(|file| {
+            let relative = file.full_path.strip_prefix(&file.absolute_root_dir).ok()?;
+            Some((file.vault_id, relative.to_path_buf(), file.modified_time, file.contents.len() as i64))
+        })
+        .collect::<Vec<(i32, PathBuf, i64, i64)>>();
+
+    if let Err(e) = keep_ancestors(&state.pool, &state.storage_cipher, &payload).await {
+        return internal_error(e);
+    }
+    if let Err(e) = state.storage_cipher.encrypt_remote_files(&mut payload) {
+        println!("Error encrypting files from {}: {e}", user.name);
+        return internal_error(e);
+    }
     file_utils::save_remote_files_to_disk(payload, vault_and_root_paths);
-    StatusCode::OK
+
+    for (vault_id, relative, modified_time, file_size) in history {
+        server_db_api::add_file_history(&state.pool, vault_id, &relative, "synced from client", modified_time, file_size)
+            .await
+            .expect("Error recording file history");
+    }
+    (StatusCode::OK, String::new())
+}
+
+/// Keeps the plaintext of text files sent to or from a client at their version in file_metadata
+/// The client and the server both have that version afterwards, so it is the common ancestor of the
+/// changes either of them makes next
+async fn keep_ancestors(
+    pool: &Pool<Sqlite>,
+    cipher: &StorageCipher,
+    files: &[RemoteFile],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let vault_and_root_paths = common_db_utils::get_vault_id_and_root_directories(pool).await?;
+    for file in files.iter().filter(|file| merge_utils::is_mergeable(&file.contents)) {
+        let relative = match file.full_path.strip_prefix(&file.absolute_root_dir) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        let root = match vault_and_root_paths.iter().find(|(vault_id, _)| *vault_id == file.vault_id) {
+            Some((_, root)) => root,
+            None => continue,
+        };
+        let version = server_db_api::get_stored_version(pool, &root.join(relative)).await?;
+        if !version.is_empty() {
+            server_db_api::save_ancestor(pool, cipher, file.vault_id, relative, &version, &file.contents).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Merges a client's copy of a text file into the server's when both changed it since they last synced
+/// The changes are merged against the version kept in file_ancestors that both copies started from
+/// The merged file is saved with a version that descends from both copies and sent back for the client
+/// to save, CONFLICT if the edits overlap or the common version is no longer kept
+pub async fn merge_file_from_client(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    Json(request): Json<MergeRequest>,
+) -> Result<Json<MergedFile>, ApiError> {
+    let state = &state.lock().await;
+    let pool = &state.pool;
+    let writable = server_db_api::get_writable_vaults(pool, &user)
+        .await
+        .map_err(internal_error)?;
+    if !writable.contains(&request.vault_id) {
+        return Err((StatusCode::FORBIDDEN, format!("vault {} is read-only or doesn't exist", request.vault_id)));
+    }
+
+    let relative = &request.relative_path;
+    if !file_utils::is_inside_vault(relative) {
+        return Err((StatusCode::BAD_REQUEST, format!("{:?} is not a path in the vault", relative)));
+    }
+    state.dav_locks.check_unlocked(request.vault_id, relative, false, "")?;
+    let vault = vault_api::read_vaults(pool, Some(request.vault_id))
+        .await
+        .map_err(internal_error)?
+        .pop()
+        .ok_or((StatusCode::NOT_FOUND, format!("vault {} not found", request.vault_id)))?;
+    let full_path = vault.abs_path.join(relative);
+    if !fs::symlink_metadata(&full_path).is_ok_and(|metadata| metadata.is_file()) {
+        return Err((StatusCode::NOT_FOUND, format!("{:?} is not a file on the server", relative)));
+    }
+
+    let server_version = server_db_api::get_stored_version(pool, &full_path)
+        .await
+        .map_err(internal_error)?;
+    let base_version = server_version.common_ancestor(&request.version);
+    let base = server_db_api::get_ancestor(pool, &state.storage_cipher, vault.vault_id, relative, &base_version)
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::CONFLICT, format!("the common version of {:?} is no longer kept", relative)))?;
+    let server_contents = state.storage_cipher.read_file(&full_path).map_err(internal_error)?;
+    let merged = merge_utils::merge_file(relative, &base, &server_contents, &request.contents)
+        .ok_or((StatusCode::CONFLICT, format!("both copies of {:?} changed the same lines", relative)))?;
+
+    server_db_api::merge_stored_version(pool, &full_path, &request.version)
+        .await
+        .map_err(internal_error)?;
+    save_stored_file(pool, &state.storage_cipher, &vault, relative, &merged, "merged with client").await?;
+    let (modified_time, modified_nanos) = modified_time_and_nanos(&fs::metadata(&full_path).map_err(internal_error)?);
+    let version = server_db_api::get_stored_version(pool, &full_path)
+        .await
+        .map_err(internal_error)?;
+    server_db_api::save_ancestor(pool, &state.storage_cipher, vault.vault_id, relative, &version, &merged)
+        .await
+        .map_err(internal_error)?;
+
+    println!("merged {:?} in vault {} with the copy from {}", relative, vault.vault_id, user.name);
+    Ok(Json(MergedFile {
+        contents: merged,
+        modified_time,
+        modified_nanos,
+        version,
+    }))
+}
+
+/// Removes the files and directories a client deleted since its last sync
+/// Directories are only removed once empty so files added by another client are kept
+pub async fn delete_files_from_client(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    Json(payload): Json<MetadataBlob>,
+) -> impl IntoResponse {
+    let state = &state.lock().await;
+    let writable = server_db_api::get_writable_vaults(&state.pool, &user)
+        .await
+        .expect("Error reading writable vaults");
+    if let Some(vault_id) = server_db_api::find_unwritable_vault(&payload, &writable) {
+        println!("refused deletes for vault {vault_id} from {}", user.name);
+        return (StatusCode::FORBIDDEN, format!("vault {vault_id} is read-only or doesn't exist"));
+    }
+
+    // nothing is deleted if any of it is locked through WebDAV, a locked directory keeps everything inside it
+    let locked = payload.vaults.iter().find_map(|(vault_id, vault)| {
+        vault
+            .files
+            .iter()
+            .find_map(|file| state.dav_locks.check_unlocked(*vault_id, &file.relative_path(), true, "").err())
+    });
+    if let Some((status, message)) = locked {
+        println!("refused deletes from {}: {message}", user.name);
+        return (status, message);
+    }
+
+    let vault_and_root_paths = common_db_utils::get_vault_id_and_root_directories(&state.pool)
+        .await
+        .expect("Error reading vault directories");
+
+    for (vault_id, vault) in payload.vaults.iter() {
+        let root = match vault_and_root_paths.iter().find(|(id, _)| id == vault_id) {
+            Some((_, root)) => root,
+            None => continue,
+        };
+        // only paths inside the vault are removed, never the vault itself
+        let files = vault
+            .files
+            .iter()
+            .map(|file| (file.relative_path(), file))
+            .filter(|(relative, _)| file_utils::is_inside_vault(relative))
+            .collect::<Vec<(PathBuf, &FileMetadata)>>();
+
+        let paths = files.iter().map(|(relative, _)| root.join(relative)).collect();
+        for path in file_utils::remove_paths_from_disk(paths) {
+            server_db_api::remove_stored_files(&state.pool, &path)
+                .await
+                .expect("Error removing deleted files from the database");
+
+            let (relative, file) = match files.iter().find(|(relative, _)| root.join(relative) == path) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            server_db_api::add_file_history(
+                &state.pool,
+                *vault_id,
+                relative,
+                "deleted by client",
+                file.modified_time,
+                file.file_size,
+            )
+            .await
+            .expect("Error recording file history");
+        }
+    }
+    (StatusCode::OK, String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use crate::users_api;
+    use crate::webdav::DavLocks;
+    use common::file_utils::VaultMetadata;
+    use common::version_vector::Causality;
+    use sqlx::Row;
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_merges_text_changed_on_both_sides() {
+        let pool = server_db_api::test_pool().await;
+        let dir = tempfile::tempdir().unwrap();
+        sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (0, ?, 'papers', 5);")
+            .bind(dir.path().to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let vault = vault_api::read_vaults(&pool, Some(0)).await.unwrap().pop().unwrap();
+        let cipher = StorageCipher::from_key(&[7; 32]);
+        let relative = Path::new("notes.txt");
+        let full_path = dir.path().join(relative);
+
+        // both sides synced the first version, then the server's copy was edited in the browser
+        save_stored_file(&pool, &cipher, &vault, relative, b"title\nbody\nend\n", "uploaded from browser")
+            .await
+            .unwrap();
+        let synced = server_db_api::get_stored_version(&pool, &full_path).await.unwrap();
+        server_db_api::save_ancestor(&pool, &cipher, 0, relative, &synced, b"title\nbody\nend\n").await.unwrap();
+        save_stored_file(&pool, &cipher, &vault, relative, b"Title\nbody\nend\n", "uploaded from browser")
+            .await
+            .unwrap();
+        let server_version = server_db_api::get_stored_version(&pool, &full_path).await.unwrap();
+
+        let state = Arc::new(Mutex::new(ApiState {
+            client_requested: HashMap::new(),
+            pool: pool.clone(),
+            storage_cipher: cipher.clone(),
+            default_ignore: vec![],
+            dav_locks: DavLocks::default(),
+        }));
+        let admin = CurrentUser { user_id: 1, name: "admin".to_string(), is_admin: true };
+        let client_copy = |contents: &[u8]| MergeRequest {
+            vault_id: 0,
+            relative_path: relative.to_path_buf(),
+            version: synced.incremented("laptop"),
+            contents: contents.to_vec(),
+        };
+
+        let overlapping = client_copy(b"TITLE\nbody\nend\n");
+        let overlapping = merge_file_from_client(State(state.clone()), admin.clone(), Json(overlapping))
+            .await
+            .unwrap_err();
+        assert_eq!(overlapping.0, StatusCode::CONFLICT);
+        assert_eq!(cipher.read_file(&full_path).unwrap(), b"Title\nbody\nend\n");
+
+        let separate = client_copy(b"title\nbody\nend\nmore\n");
+        let Json(merged) = merge_file_from_client(State(state), admin, Json(separate))
+            .await
+            .unwrap();
+        assert_eq!(merged.contents, b"Title\nbody\nend\nmore\n");
+        assert_eq!(cipher.read_file(&full_path).unwrap(), merged.contents);
+        assert_eq!(merged.version.compare(&server_version), Causality::Descendant);
+        assert_eq!(merged.version.compare(&synced.incremented("laptop")), Causality::Descendant);
+        assert_eq!(server_db_api::get_stored_version(&pool, &full_path).await.unwrap(), merged.version);
+        assert_eq!(
+            server_db_api::get_ancestor(&pool, &cipher, 0, relative, &merged.version).await.unwrap(),
+            Some(merged.contents.clone())
+        );
+        let stored = sqlx::query("select contents from file_ancestors;").fetch_all(&pool).await.unwrap();
+        assert!(stored.iter().all(|row| !row.get::<Vec<u8>, _>(0).ends_with(b"end\n")));
+        let written = fs::metadata(&full_path).unwrap().modified().unwrap();
+        let written = written.duration_since(std::time::UNIX_EPOCH).unwrap();
+        assert_eq!((merged.modified_time, merged.modified_nanos), (written.as_secs() as i64, written.subsec_nanos()));
+    }
+
+    #[tokio::test]
+    async fn test_writes_stay_inside_granted_vaults() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+        let root = storage.path().join("papers");
+        fs::create_dir(&root).unwrap();
+        sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (0, ?, 'papers', 5);")
+            .bind(root.to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO users (user_id, name, token_hash) VALUES (2, 'sam', 'hash');")
+            .execute(&pool)
+            .await
+            .unwrap();
+        users_api::set_role(&pool, 0, 2, Some(Role::ReadWrite)).await.unwrap();
+        let state = Arc::new(Mutex::new(ApiState {
+            client_requested: HashMap::new(),
+            pool: pool.clone(),
+            storage_cipher: StorageCipher::disabled(),
+            default_ignore: vec![],
+            dav_locks: DavLocks::default(),
+        }));
+        let sam = CurrentUser { user_id: 2, name: "sam".to_string(), is_admin: false };
+        let client_root = PathBuf::from("/home/sam/papers");
+        let upload = |vault_id: i32, relative: &str| RemoteFile {
+            full_path: client_root.join(relative),
+            root_directory: "papers".to_string(),
+            absolute_root_dir: client_root.clone(),
+            contents: b"escaped".to_vec(),
+            vault_id,
+            file_id: -1,
+            modified_time: 1,
+            modified_nanos: 0,
+            mode: None,
+        };
+        let dir = |vault_id: i32, relative: &str| {
+            let mut dir = FileMetadata::new_from_client(
+                client_root.join(relative),
+                "papers".to_string(),
+                client_root.clone(),
+                1,
+                0,
+                vault_id,
+                -1,
+            );
+            dir.is_dir = true;
+            MetadataBlob {
+                vaults: HashMap::from([(vault_id, VaultMetadata { files: vec![dir], vault_id, ..Default::default() })]),
+            }
+        };
+
+        for (vault_id, relative, status) in [
+            (999, "escaped.txt", StatusCode::FORBIDDEN),
+            (0, "../escaped.txt", StatusCode::BAD_REQUEST),
+        ] {
+            let files = Json(vec![upload(vault_id, relative)]);
+            let response = receive_files_from_client(State(state.clone()), sam.clone(), files).await;
+            assert_eq!(response.into_response().status(), status);
+            let blob = Json(dir(vault_id, relative));
+            let response = server_db_api::insert_new_metadata_into_db(State(state.clone()), sam.clone(), blob).await;
+            assert_eq!(response.into_response().status(), status);
+        }
+        assert!(!storage.path().join("escaped.txt").exists());
+        assert!(!Path::new("/home/sam/papers").exists());
+
+        let response = receive_files_from_client(State(state), sam, Json(vec![upload(0, "kept.txt")]))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(fs::read(root.join("kept.txt")).unwrap(), b"escaped");
+    }
+
+    #[tokio::test]
+    async fn test_client_modes_never_set_special_bits() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+        sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (0, ?, 'papers', 5);")
+            .bind(storage.path().to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(ApiState {
+            client_requested: HashMap::new(),
+            pool: pool.clone(),
+            storage_cipher: StorageCipher::disabled(),
+            default_ignore: vec![],
+            dav_locks: DavLocks::default(),
+        }));
+        let admin = CurrentUser { user_id: 1, name: "admin".to_string(), is_admin: true };
+        let client_root = PathBuf::from("/home/admin/papers");
+        let mode_of = |name: &str| fs::metadata(storage.path().join(name)).unwrap().permissions().mode() & 0o7777;
+
+        let upload = RemoteFile {
+            full_path: client_root.join("run.sh"),
+            root_directory: "papers".to_string(),
+            absolute_root_dir: client_root.clone(),
+            contents: b"echo".to_vec(),
+            vault_id: 0,
+            file_id: -1,
+            modified_time: 1,
+            modified_nanos: 0,
+            mode: Some(0o6755),
+        };
+        let response = receive_files_from_client(State(state.clone()), admin.clone(), Json(vec![upload])).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        assert_eq!(mode_of("run.sh"), 0o755);
+
+        let mut file =
+            FileMetadata::new_from_client(client_root.join("run.sh"), "papers".to_string(), client_root, 1, 4, 0, -1);
+        file.mode = Some(0o4777);
+        let blob = MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata { files: vec![file], vault_id: 0, ..Default::default() })]),
+        };
+        let response = server_db_api::insert_new_metadata_into_db(State(state), admin, Json(blob)).await;
+        assert_eq!(response.into_response().status(), StatusCode::OK);
+        assert_eq!(mode_of("run.sh"), 0o777);
+    }
 }
 
 /*-----------------------------OLD STUFF BELOW-----------------------------------------*/
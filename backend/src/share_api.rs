@@ -0,0 +1,437 @@
+//! Share links to a single file or directory of a vault for people without access to the server:
+//! GET /vaults/:vault_id/shares - lists the vault's share links
+//! POST /vaults/:vault_id/shares - creates a share link from a NewShare
+//! DELETE /vaults/:vault_id/shares/:token - revokes a share link
+//! GET /share/:token?format=zip|tar - public, sends the shared file or the directory as an archive,
+//!     asks for the password if the link has one
+//! POST /share/:token - public, the password form of a protected link
+//!
+//! Links stop working once they expire or the download limit is reached, passwords are stored as argon2 hashes
+
+use crate::archive::{self, ArchiveFormat, ArchiveOptions};
+use crate::auth::{CurrentUser, Role};
+use crate::file_browser::{self, not_found, relative_path};
+use crate::html_creation::HtmlTemplate;
+use crate::vault_api::{self, internal_error, ApiError};
+use crate::ApiState;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use askama::Template;
+use axum::extract::{Form, Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+/// Body of POST /vaults/:vault_id/shares
+/// path is relative to the vault root, an empty path shares the whole vault
+#[derive(Deserialize, Debug, Default)]
+pub struct NewShare {
+    #[serde(default)]
+    pub path: String,
+    pub expires_in_secs: Option<i64>,
+    pub max_downloads: Option<i64>,
+    pub password: Option<String>,
+}
+
+/// A share link as it is listed, the password hash is never sent
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShareLink {
+    pub token: String,
+    pub vault_id: i32,
+    pub path: PathBuf,
+    pub url: String,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub max_downloads: Option<i64>,
+    pub download_count: i64,
+    pub password_protected: bool,
+}
+
+impl ShareLink {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    fn is_used_up(&self) -> bool {
+        self.max_downloads.is_some_and(|max| self.download_count >= max)
+    }
+}
+
+/// Form of POST /share/:token
+#[derive(Deserialize, Debug, Default)]
+pub struct SharePassword {
+    pub password: String,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+}
+
+#[derive(Template)]
+#[template(path = "share_password.html")]
+struct SharePasswordTemplate {
+    action: String,
+    format: &'static str,
+    wrong_password: bool,
+}
+
+pub async fn list_shares(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Json<Vec<ShareLink>>, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::ReadWrite).await?;
+    let shares = read_shares(pool, Some(vault_id), None).await.map_err(internal_error)?;
+    Ok(Json(shares))
+}
+
+pub async fn create_share(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    Json(new_share): Json<NewShare>,
+) -> Result<(StatusCode, Json<ShareLink>), ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::ReadWrite).await?;
+    let share = insert_share(pool, vault_id, new_share, now_secs()).await?;
+    println!("shared {:?} of vault {vault_id} as {}", share.path, share.url);
+    Ok((StatusCode::CREATED, Json(share)))
+}
+
+pub async fn revoke_share(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, token)): UrlPath<(i32, String)>,
+) -> Result<StatusCode, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::ReadWrite).await?;
+    let result = sqlx::query("DELETE FROM share_links WHERE vault_id == ? AND token == ?;")
+        .bind(vault_id)
+        .bind(&token)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    if result.rows_affected() == 0 {
+        return Err(share_not_found());
+    }
+    println!("revoked share link {token} of vault {vault_id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn open_share(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    UrlPath(token): UrlPath<String>,
+    Query(options): Query<ArchiveOptions>,
+) -> Result<Response, ApiError> {
+    send_share(state, &token, None, options.format).await
+}
+
+pub async fn open_protected_share(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    UrlPath(token): UrlPath<String>,
+    Form(form): Form<SharePassword>,
+) -> Result<Response, ApiError> {
+    send_share(state, &token, Some(&form.password), form.format).await
+}
+
+async fn send_share(
+    state: Arc<Mutex<ApiState>>,
+    token: &str,
+    password: Option<&str>,
+    format: ArchiveFormat,
+) -> Result<Response, ApiError> {
+    let (pool, cipher) = {
+        let state = state.lock().await;
+        (state.pool.clone(), state.storage_cipher.clone())
+    };
+    let share = match claim_download(&pool, token, password, now_secs()).await? {
+        Ok(share) => share,
+        Err(wrong_password) => {
+            let page = SharePasswordTemplate {
+                action: format!("/share/{token}"),
+                format: format.extension(),
+                wrong_password,
+            };
+            return Ok((StatusCode::UNAUTHORIZED, HtmlTemplate(page)).into_response());
+        }
+    };
+
+    let vault = vault_api::find_vault(&pool, share.vault_id).await?;
+    println!("sending {:?} of vault {} through share link {token}", share.path, share.vault_id);
+    if vault.abs_path.join(&share.path).is_dir() {
+        archive::stream_archive(&vault, &share.path, cipher, format)
+    } else {
+        file_browser::send_file(&vault, &share.path, &cipher).await
+    }
+}
+
+async fn insert_share(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    new_share: NewShare,
+    now: i64,
+) -> Result<ShareLink, ApiError> {
+    let vault = vault_api::find_vault(pool, vault_id).await?;
+    let relative = relative_path(&new_share.path)?;
+    if !vault.abs_path.join(&relative).exists() {
+        return Err(not_found(&relative));
+    }
+    if new_share.expires_in_secs.is_some_and(|secs| secs <= 0) {
+        return Err((StatusCode::BAD_REQUEST, "expires_in_secs must be positive".to_string()));
+    }
+    if new_share.max_downloads.is_some_and(|max| max <= 0) {
+        return Err((StatusCode::BAD_REQUEST, "max_downloads must be positive".to_string()));
+    }
+    let password_hash = match new_share.password.filter(|password| !password.is_empty()) {
+        Some(password) => Some(
+            Argon2::default()
+                .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .map_err(internal_error)?
+                .to_string(),
+        ),
+        None => None,
+    };
+
+    let token = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    sqlx::query(
+        "INSERT INTO share_links (token, vault_id, relative_path, created_at, expires_at, max_downloads, password_hash) \
+        VALUES (?, ?, ?, ?, ?, ?, ?);",
+    )
+    .bind(&token)
+    .bind(vault_id)
+    .bind(relative.to_str().unwrap())
+    .bind(now)
+    .bind(new_share.expires_in_secs.map(|secs| now + secs))
+    .bind(new_share.max_downloads)
+    .bind(password_hash)
+    .execute(pool)
+    .await
+    .map_err(internal_error)?;
+
+    read_shares(pool, None, Some(&token))
+        .await
+        .map_err(internal_error)?
+        .pop()
+        .ok_or_else(share_not_found)
+}
+
+/// Checks a share link can be used and counts the download
+/// Returns Ok(Err(wrong_password)) when the link needs a password that wasn't given or doesn't match
+async fn claim_download(
+    pool: &Pool<Sqlite>,
+    token: &str,
+    password: Option<&str>,
+    now: i64,
+) -> Result<Result<ShareLink, bool>, ApiError> {
+    let share = read_shares(pool, None, Some(token))
+        .await
+        .map_err(internal_error)?
+        .pop()
+        .ok_or_else(share_not_found)?;
+    if share.is_expired(now) || share.is_used_up() {
+        return Err(share_gone());
+    }
+
+    if share.password_protected {
+        let password = match password {
+            Some(password) => password,
+            None => return Ok(Err(false)),
+        };
+        let hash = sqlx::query("select password_hash from share_links where token == ?;")
+            .bind(token)
+            .fetch_one(pool)
+            .await
+            .map_err(internal_error)?
+            .get::<String, _>(0);
+        let hash = PasswordHash::new(&hash).map_err(internal_error)?;
+        if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+            println!("wrong password for share link {token}");
+            return Ok(Err(true));
+        }
+    }
+
+    // the limits are checked again so concurrent downloads can't go over max_downloads
+    let result = sqlx::query(
+        "UPDATE share_links SET download_count = download_count + 1 WHERE token == ? \
+        AND (max_downloads IS NULL OR download_count < max_downloads) AND (expires_at IS NULL OR expires_at > ?);",
+    )
+    .bind(token)
+    .bind(now)
+    .execute(pool)
+    .await
+    .map_err(internal_error)?;
+    if result.rows_affected() == 0 {
+        return Err(share_gone());
+    }
+    Ok(Ok(ShareLink {
+        download_count: share.download_count + 1,
+        ..share
+    }))
+}
+
+/// Reads the share links of vault_id, or the link with token, or every link if neither is given
+async fn read_shares(
+    pool: &Pool<Sqlite>,
+    vault_id: Option<i32>,
+    token: Option<&str>,
+) -> Result<Vec<ShareLink>, sqlx::Error> {
+    let query = "select token, vault_id, relative_path, created_at, expires_at, max_downloads, download_count, \
+        password_hash IS NOT NULL from share_links where (? IS NULL OR vault_id == ?) AND (? IS NULL OR token == ?) \
+        order by created_at, token;";
+    let rows = sqlx::query(query)
+        .bind(vault_id)
+        .bind(vault_id)
+        .bind(token)
+        .bind(token)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let token = row.get::<String, _>(0);
+            ShareLink {
+                url: format!("/share/{token}"),
+                token,
+                vault_id: row.get::<i32, _>(1),
+                path: PathBuf::from(row.get::<String, _>(2)),
+                created_at: row.get::<i64, _>(3),
+                expires_at: row.get::<Option<i64>, _>(4),
+                max_downloads: row.get::<Option<i64>, _>(5),
+                download_count: row.get::<i64, _>(6),
+                password_protected: row.get::<bool, _>(7),
+            }
+        })
+        .collect())
+}
+
+/// Removes every share link of a vault, used when the vault is deleted
+pub async fn delete_vault_shares(pool: &Pool<Sqlite>, vault_id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM share_links WHERE vault_id == ?;")
+        .bind(vault_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn share_not_found() -> ApiError {
+    (StatusCode::NOT_FOUND, "share link does not exist".to_string())
+}
+
+fn share_gone() -> ApiError {
+    (StatusCode::GONE, "share link has expired".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_db_api;
+    use std::fs;
+    use std::path::Path;
+
+    async fn test_pool(storage: &Path) -> Pool<Sqlite> {
+        let pool = server_db_api::test_pool().await;
+        let vault_root = storage.join("papers");
+        fs::create_dir_all(vault_root.join("drafts")).unwrap();
+        fs::write(vault_root.join("drafts/intro.txt"), "intro").unwrap();
+        sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (0, ?, 'papers', 5);")
+            .bind(vault_root.to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_create_list_and_revoke() {
+        let storage = tempfile::tempdir().unwrap();
+        let pool = test_pool(storage.path()).await;
+
+        let new_share = NewShare {
+            path: "drafts/intro.txt".to_string(),
+            expires_in_secs: Some(60),
+            ..Default::default()
+        };
+        let share = insert_share(&pool, 0, new_share, 1000).await.unwrap();
+        assert_eq!(share.path, PathBuf::from("drafts/intro.txt"));
+        assert_eq!(share.expires_at, Some(1060));
+        assert_eq!(share.url, format!("/share/{}", share.token));
+        assert!(!share.password_protected);
+        assert_eq!(read_shares(&pool, Some(0), None).await.unwrap(), vec![share.clone()]);
+
+        let missing = NewShare {
+            path: "drafts/missing.txt".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(insert_share(&pool, 0, missing, 1000).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        let escape = NewShare {
+            path: "../".to_string(),
+            ..Default::default()
+        };
+        assert!(insert_share(&pool, 0, escape, 1000).await.is_err());
+
+        delete_vault_shares(&pool, 0).await.unwrap();
+        assert!(read_shares(&pool, Some(0), None).await.unwrap().is_empty());
+        let err = claim_download(&pool, &share.token, None, 1000).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_and_download_limit() {
+        let storage = tempfile::tempdir().unwrap();
+        let pool = test_pool(storage.path()).await;
+        let new_share = NewShare {
+            path: "drafts".to_string(),
+            expires_in_secs: Some(60),
+            max_downloads: Some(2),
+            ..Default::default()
+        };
+        let share = insert_share(&pool, 0, new_share, 1000).await.unwrap();
+
+        let first = claim_download(&pool, &share.token, None, 1001).await.unwrap().unwrap();
+        assert_eq!(first.download_count, 1);
+        claim_download(&pool, &share.token, None, 1002).await.unwrap().unwrap();
+        let used_up = claim_download(&pool, &share.token, None, 1003).await.unwrap_err();
+        assert_eq!(used_up.0, StatusCode::GONE);
+
+        let share = insert_share(&pool, 0, NewShare { expires_in_secs: Some(60), ..Default::default() }, 1000)
+            .await
+            .unwrap();
+        assert_eq!(share.path, PathBuf::new());
+        let expired = claim_download(&pool, &share.token, None, 1060).await.unwrap_err();
+        assert_eq!(expired.0, StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn test_password_is_checked_before_counting() {
+        let storage = tempfile::tempdir().unwrap();
+        let pool = test_pool(storage.path()).await;
+        let new_share = NewShare {
+            path: "drafts/intro.txt".to_string(),
+            max_downloads: Some(1),
+            password: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        let share = insert_share(&pool, 0, new_share, 1000).await.unwrap();
+        assert!(share.password_protected);
+
+        assert_eq!(claim_download(&pool, &share.token, None, 1001).await.unwrap(), Err(false));
+        assert_eq!(claim_download(&pool, &share.token, Some("hunter3"), 1001).await.unwrap(), Err(true));
+        let claimed = claim_download(&pool, &share.token, Some("hunter2"), 1001).await.unwrap().unwrap();
+        assert_eq!(claimed.download_count, 1);
+    }
+}
@@ -0,0 +1,577 @@
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use common::ignore_utils::IGNORE_FILE_NAME;
+use common::RemoteFile;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{Connection, Pool, Row, Sqlite, SqliteConnection};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// Every file the server encrypts starts with this header, followed by the stream nonce then the chunks
+/// Each chunk is CHUNK_LEN bytes of plaintext and its tag, encrypted with the STREAM construction so a file can be
+/// read a chunk at a time. The last chunk is shorter, empty when the plaintext fills every chunk, so a file
+/// that was cut short fails to decrypt
+/// Files without a header are treated as plaintext so storage written before encryption
+/// was turned on can still be read and is encrypted the next time it is written
+const HEADER: &[u8; 8] = b"DTXENC02";
+const STREAM_NONCE_LEN: usize = 19;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const ENCRYPTED_CHUNK_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+/// Files written by older servers are encrypted whole with this header, followed by the nonce then the ciphertext
+/// They can still be read, and are written in chunks the next time they are written
+const WHOLE_FILE_HEADER: &[u8; 8] = b"DTXENC01";
+const WHOLE_FILE_NONCE_LEN: usize = 24;
+
+/// The first bytes of every plaintext sqlite database, an encrypted one starts with random bytes
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Encrypts file bodies and the server database at rest using a master key loaded on startup
+/// The key is read from the file at MASTER_KEY_FILE which should contain 64 hex characters (32 bytes)
+/// eg: `head -c 32 /dev/urandom | xxd -p -c 64 > master.key`
+/// The database is encrypted by SQLCipher with a key derived from the master key, so it is never plaintext on disk
+/// If MASTER_KEY_FILE is not set the cipher is disabled and everything is stored as plaintext
+#[derive(Clone)]
+pub struct StorageCipher {
+    cipher: Option<XChaCha20Poly1305>,
+    database_key: Option<[u8; 32]>,
+}
+
+impl StorageCipher {
+    pub fn disabled() -> Self {
+        StorageCipher {
+            cipher: None,
+            database_key: None,
+        }
+    }
+
+    pub fn from_key(key: &[u8; 32]) -> Self {
+        // the database gets a key of its own so the master key is only ever used for files
+        let mut hasher = Sha256::new();
+        hasher.update(b"datoxidize database key");
+        hasher.update(key);
+        StorageCipher {
+            cipher: Some(XChaCha20Poly1305::new(key.into())),
+            database_key: Some(hasher.finalize().into()),
+        }
+    }
+
+    /// Reads the master key from the path in the MASTER_KEY_FILE environment variable
+    pub fn load_from_env() -> Result<Self, Box<dyn Error>> {
+        let key_path = match dotenvy::var("MASTER_KEY_FILE") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                println!("MASTER_KEY_FILE not set - storage will not be encrypted at rest");
+                return Ok(StorageCipher::disabled());
+            }
+        };
+
+        let hex_key = fs::read_to_string(&key_path)
+            .map_err(|e| format!("Error reading master key from {:?}: {e}", key_path))?;
+        let bytes = hex::decode(hex_key.trim())
+            .map_err(|e| format!("Master key at {:?} is not valid hex: {e}", key_path))?;
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| format!("Master key at {:?} must be 32 bytes", key_path))?;
+
+        println!("loaded master key, storage is encrypted at rest");
+        Ok(StorageCipher::from_key(&key))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Returns the header, stream nonce and encrypted chunks of the plaintext
+    /// Returns the plaintext unchanged if encryption is disabled
+    pub fn encrypt(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let cipher = match &self.cipher {
+            Some(c) => c,
+            None => return Ok(plaintext.to_vec()),
+        };
+
+        let mut nonce = [0u8; STREAM_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let mut encryptor = EncryptorBE32::from_aead(cipher.clone(), GenericArray::from_slice(&nonce));
+        let encrypt_error = |_| io::Error::other("Error encrypting file contents");
+
+        let mut sealed = Vec::with_capacity(encrypted_len(plaintext.len() as u64) as usize);
+        sealed.extend_from_slice(HEADER);
+        sealed.extend_from_slice(&nonce);
+        let mut chunks = plaintext.chunks_exact(CHUNK_LEN);
+        for chunk in &mut chunks {
+            sealed.extend_from_slice(&encryptor.encrypt_next(chunk).map_err(encrypt_error)?);
+        }
+        sealed.extend_from_slice(&encryptor.encrypt_last(chunks.remainder()).map_err(encrypt_error)?);
+        Ok(sealed)
+    }
+
+    /// Decrypts data written by `encrypt` or by older servers, data without a header is returned as is
+    pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if !is_encrypted(data) {
+            return Ok(data.to_vec());
+        }
+        let cipher = self.require_cipher()?;
+
+        if data.starts_with(WHOLE_FILE_HEADER) {
+            if data.len() < HEADER.len() + WHOLE_FILE_NONCE_LEN + TAG_LEN {
+                return Err(truncated());
+            }
+            let (nonce, ciphertext) = data[HEADER.len()..].split_at(WHOLE_FILE_NONCE_LEN);
+            return cipher
+                .decrypt(XNonce::from_slice(nonce), ciphertext)
+                .map_err(|_| decrypt_error());
+        }
+
+        if data.len() < HEADER.len() + STREAM_NONCE_LEN {
+            return Err(truncated());
+        }
+        let (nonce, mut rest) = data[HEADER.len()..].split_at(STREAM_NONCE_LEN);
+        let mut decryptor = DecryptorBE32::from_aead(cipher.clone(), GenericArray::from_slice(nonce));
+        let mut plaintext = Vec::with_capacity(rest.len());
+        while rest.len() >= ENCRYPTED_CHUNK_LEN {
+            let (chunk, remaining) = rest.split_at(ENCRYPTED_CHUNK_LEN);
+            plaintext.extend_from_slice(&decryptor.decrypt_next(chunk).map_err(|_| decrypt_error())?);
+            rest = remaining;
+        }
+        plaintext.extend_from_slice(&decryptor.decrypt_last(rest).map_err(|_| decrypt_error())?);
+        Ok(plaintext)
+    }
+
+    fn require_cipher(&self) -> io::Result<&XChaCha20Poly1305> {
+        self.cipher.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File is encrypted but no master key was loaded",
+            )
+        })
+    }
+
+    /// Reads a file from storage and decrypts it
+    pub fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.decrypt(&fs::read(path)?)
+    }
+
+    /// Opens a file in storage to be read and decrypted a chunk at a time
+    pub async fn open_file(&self, path: &Path) -> io::Result<StoredFileReader> {
+        StoredFileReader::open(self, path).await
+    }
+
+    /// Encrypts contents and writes them to storage
+    pub fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, self.encrypt(contents)?)
+    }
+
+    /// Encrypts the contents of files received from a client before they are saved to disk
+    /// The .datoxidizeignore in the vault root is left as plaintext as the server reads it when scanning the
+    /// vault, files of that name anywhere else are encrypted as usual
+    pub fn encrypt_remote_files(&self, files: &mut [RemoteFile]) -> io::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        for file in files.iter_mut() {
+            if file.full_path.strip_prefix(&file.absolute_root_dir) == Ok(Path::new(IGNORE_FILE_NAME)) {
+                continue;
+            }
+            file.contents = self.encrypt(&file.contents)?;
+        }
+        Ok(())
+    }
+
+    /// Decrypts the contents of files read from storage before they are sent to a client
+    pub fn decrypt_remote_files(&self, files: &mut [RemoteFile]) -> io::Result<()> {
+        for file in files.iter_mut() {
+            file.contents = self.decrypt(&file.contents)?;
+        }
+        Ok(())
+    }
+
+    /// Options to open the database at db_url with, the key SQLCipher needs is set when encryption is enabled
+    pub fn database_options(&self, db_url: &str) -> Result<SqliteConnectOptions, sqlx::Error> {
+        let options = db_url.parse::<SqliteConnectOptions>()?;
+        Ok(match self.database_key {
+            Some(key) => options.pragma("key", format!("\"x'{}'\"", hex::encode(key))),
+            None => options,
+        })
+    }
+
+    /// Brings a database written by an older server up to date before it is opened
+    /// A database sealed whole at shutdown (db_path with a .enc extension) is decrypted, then a plaintext
+    /// database is encrypted in place by SQLCipher. This is the only time a plaintext database is on disk
+    pub async fn encrypt_database(&self, db_path: &Path) -> Result<(), Box<dyn Error>> {
+        let sealed_path = sealed_database_path(db_path);
+        if sealed_path.exists() {
+            let plaintext = self.decrypt(&fs::read(&sealed_path)?)?;
+            fs::write(db_path, plaintext)?;
+            fs::remove_file(&sealed_path)?;
+            println!("unsealed database at {:?}", db_path);
+        }
+
+        let key = match self.database_key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let mut header = [0u8; SQLITE_HEADER.len()];
+        match fs::File::open(db_path) {
+            Ok(mut file) => {
+                if file.read(&mut header)? < header.len() || &header != SQLITE_HEADER {
+                    return Ok(());
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut encrypted_path = db_path.as_os_str().to_os_string();
+        encrypted_path.push(".encrypting");
+        let encrypted_path = PathBuf::from(encrypted_path);
+        if encrypted_path.exists() {
+            fs::remove_file(&encrypted_path)?;
+        }
+        // attached databases are opened with the same flags as the connection, which has to be allowed to create it
+        let options = SqliteConnectOptions::new().filename(db_path).create_if_missing(true);
+        let mut connection = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("ATTACH DATABASE ? AS encrypted KEY ?;")
+            .bind(encrypted_path.to_str().unwrap())
+            .bind(format!("x'{}'", hex::encode(key)))
+            .execute(&mut connection)
+            .await?;
+        sqlx::query("SELECT sqlcipher_export('encrypted');").execute(&mut connection).await?;
+        sqlx::query("DETACH DATABASE encrypted;").execute(&mut connection).await?;
+        connection.close().await?;
+
+        fs::rename(&encrypted_path, db_path)?;
+        for suffix in ["-wal", "-shm"] {
+            let mut leftover = db_path.as_os_str().to_os_string();
+            leftover.push(suffix);
+            if Path::new(&leftover).exists() {
+                fs::remove_file(leftover)?;
+            }
+        }
+        println!("encrypted database at {:?}", db_path);
+        Ok(())
+    }
+}
+
+/// A file in storage read a chunk at a time, each chunk is decrypted as it is read so large files are
+/// never held in memory
+pub struct StoredFileReader {
+    file: tokio::fs::File,
+    /// None once the last chunk has been read, plaintext files are read as they are
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    encrypted: bool,
+    /// bytes already read that are returned before anything else, the start of a plaintext file or the whole
+    /// of a file encrypted by an older server
+    pending: Vec<u8>,
+}
+
+impl StoredFileReader {
+    async fn open(cipher: &StorageCipher, path: &Path) -> io::Result<Self> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut header = vec![0u8; HEADER.len()];
+        let read = read_up_to(&mut file, &mut header).await?;
+        header.truncate(read);
+        let mut reader = StoredFileReader {
+            file,
+            decryptor: None,
+            encrypted: false,
+            pending: vec![],
+        };
+
+        if header.as_slice() == WHOLE_FILE_HEADER {
+            header.extend(read_to_end(&mut reader.file).await?);
+            reader.pending = cipher.decrypt(&header)?;
+        } else if header.as_slice() == HEADER {
+            let cipher = cipher.require_cipher()?;
+            let mut nonce = [0u8; STREAM_NONCE_LEN];
+            if read_up_to(&mut reader.file, &mut nonce).await? < nonce.len() {
+                return Err(truncated());
+            }
+            reader.decryptor = Some(DecryptorBE32::from_aead(cipher.clone(), GenericArray::from_slice(&nonce)));
+            reader.encrypted = true;
+        } else {
+            reader.pending = header;
+        }
+        Ok(reader)
+    }
+
+    /// The next chunk of plaintext, None once the whole file has been read
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.pending)));
+        }
+        if !self.encrypted {
+            let mut chunk = vec![0u8; CHUNK_LEN];
+            let read = read_up_to(&mut self.file, &mut chunk).await?;
+            chunk.truncate(read);
+            return Ok(Some(chunk).filter(|chunk| !chunk.is_empty()));
+        }
+
+        let mut decryptor = match self.decryptor.take() {
+            Some(decryptor) => decryptor,
+            None => return Ok(None),
+        };
+        let mut chunk = vec![0u8; ENCRYPTED_CHUNK_LEN];
+        let read = read_up_to(&mut self.file, &mut chunk).await?;
+        chunk.truncate(read);
+        // only the last chunk is shorter than a whole one
+        if read < ENCRYPTED_CHUNK_LEN {
+            return Ok(Some(decryptor.decrypt_last(chunk.as_slice()).map_err(|_| decrypt_error())?));
+        }
+        let plaintext = decryptor.decrypt_next(chunk.as_slice()).map_err(|_| decrypt_error())?;
+        self.decryptor = Some(decryptor);
+        Ok(Some(plaintext))
+    }
+}
+
+/// Fills buffer from file unless the file ends first, returns how many bytes were read
+async fn read_up_to(file: &mut tokio::fs::File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+async fn read_to_end(file: &mut tokio::fs::File) -> io::Result<Vec<u8>> {
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).await?;
+    Ok(contents)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Encrypted file is truncated")
+}
+
+fn decrypt_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Error decrypting file - wrong master key or corrupted file",
+    )
+}
+
+/// Size of a file of plaintext_len bytes once it is encrypted
+fn encrypted_len(plaintext_len: u64) -> u64 {
+    let chunks = plaintext_len / CHUNK_LEN as u64 + 1;
+    (HEADER.len() + STREAM_NONCE_LEN) as u64 + plaintext_len + chunks * TAG_LEN as u64
+}
+
+fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(HEADER) || data.starts_with(WHOLE_FILE_HEADER)
+}
+
+fn sealed_database_path(db_path: &Path) -> PathBuf {
+    let mut sealed = db_path.as_os_str().to_os_string();
+    sealed.push(".enc");
+    PathBuf::from(sealed)
+}
+
+/// Strips the scheme from a sqlite url, eg: "sqlite://./backend/resources/files.db"
+/// becomes "./backend/resources/files.db"
+pub fn database_path_from_url(db_url: &str) -> PathBuf {
+    PathBuf::from(db_url.trim_start_matches("sqlite://").trim_start_matches("sqlite:"))
+}
+
+/// Returns the size of the plaintext of a file in storage
+/// Reads only the header so large files aren't loaded into memory
+pub fn plaintext_len(path: &Path) -> io::Result<u64> {
+    let len = fs::metadata(path)?.len();
+    let mut header = [0u8; HEADER.len()];
+    let read = fs::File::open(path)?.read(&mut header)?;
+    if read < HEADER.len() {
+        return Ok(len);
+    }
+    if &header == WHOLE_FILE_HEADER {
+        return Ok(len.saturating_sub((HEADER.len() + WHOLE_FILE_NONCE_LEN + TAG_LEN) as u64));
+    }
+    if &header != HEADER {
+        return Ok(len);
+    }
+    let chunks = len.saturating_sub((HEADER.len() + STREAM_NONCE_LEN) as u64);
+    let (whole, last) = (chunks / ENCRYPTED_CHUNK_LEN as u64, chunks % ENCRYPTED_CHUNK_LEN as u64);
+    Ok(whole * CHUNK_LEN as u64 + last.saturating_sub(TAG_LEN as u64))
+}
+
+/// The initial scan of storage reads the file size from disk, which for encrypted files is the
+/// size of the ciphertext. Clients only ever see plaintext so the sizes are corrected after the scan
+pub async fn correct_encrypted_file_sizes(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query("select file_id, file_path from file_metadata where is_dir == 0 AND link_target IS NULL;")
+        .fetch_all(pool)
+        .await?;
+
+    for row in rows {
+        let file_id = row.get::<i32, _>(0);
+        let path = PathBuf::from(row.get::<String, _>(1));
+        let size = match plaintext_len(&path) {
+            Ok(s) => s as i64,
+            Err(e) => {
+                println!("Error reading size of {:?}: {e}", path);
+                continue;
+            }
+        };
+        sqlx::query("UPDATE file_metadata SET file_size = ? WHERE file_id == ? AND file_size != ?;")
+            .bind(size)
+            .bind(file_id)
+            .bind(size)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::SqlitePool;
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let plaintext = b"lat,long\n-27.4,153.0\n".to_vec();
+
+        let sealed = cipher.encrypt(&plaintext).unwrap();
+
+        assert_ne!(sealed, plaintext);
+        assert_eq!(sealed.len() as u64, encrypted_len(plaintext.len() as u64));
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), plaintext);
+    }
+
+    #[tokio::test]
+    async fn test_files_are_read_a_chunk_at_a_time() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.csv");
+
+        for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN + 5] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            cipher.write_file(&path, &plaintext).unwrap();
+
+            let mut reader = cipher.open_file(&path).await.unwrap();
+            let mut chunks = vec![];
+            while let Some(chunk) = reader.next_chunk().await.unwrap() {
+                assert!(chunk.len() <= CHUNK_LEN);
+                chunks.push(chunk);
+            }
+
+            assert_eq!(chunks.concat(), plaintext, "file of {len} bytes");
+            assert_eq!(plaintext_len(&path).unwrap(), len as u64);
+            assert_eq!(cipher.read_file(&path).unwrap(), plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_files_fail_to_decrypt() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("export.csv");
+        let sealed = cipher.encrypt(&vec![1u8; 2 * CHUNK_LEN + 10]).unwrap();
+        // cut at the end of a whole chunk, which would look complete if the last chunk wasn't marked
+        fs::write(&path, &sealed[..HEADER.len() + STREAM_NONCE_LEN + ENCRYPTED_CHUNK_LEN]).unwrap();
+
+        assert!(cipher.read_file(&path).is_err());
+        let mut reader = cipher.open_file(&path).await.unwrap();
+        assert_eq!(reader.next_chunk().await.unwrap().unwrap().len(), CHUNK_LEN);
+        assert!(reader.next_chunk().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_whole_file_encryption_from_older_servers_is_read() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.md");
+        let nonce = [3u8; WHOLE_FILE_NONCE_LEN];
+        let whole_file = cipher.cipher.as_ref().unwrap();
+        let ciphertext = whole_file.encrypt(XNonce::from_slice(&nonce), &b"# notes"[..]).unwrap();
+        fs::write(&path, [&WHOLE_FILE_HEADER[..], &nonce, &ciphertext].concat()).unwrap();
+
+        assert_eq!(cipher.read_file(&path).unwrap(), b"# notes");
+        assert_eq!(plaintext_len(&path).unwrap(), 7);
+        let mut reader = cipher.open_file(&path).await.unwrap();
+        assert_eq!(reader.next_chunk().await.unwrap().unwrap(), b"# notes");
+        assert_eq!(reader.next_chunk().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_database_is_encrypted_in_place() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("files.db");
+        let db_url = format!("sqlite://{}?mode=rwc", db_path.display());
+        let plaintext = SqlitePool::connect(&db_url).await.unwrap();
+        sqlx::query("CREATE TABLE vaults (vault_id INTEGER PRIMARY KEY NOT NULL, root_dir TEXT NOT NULL);")
+            .execute(&plaintext)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO vaults VALUES (3, 'papers');").execute(&plaintext).await.unwrap();
+        plaintext.close().await;
+
+        cipher.encrypt_database(&db_path).await.unwrap();
+
+        assert!(!fs::read(&db_path).unwrap().starts_with(SQLITE_HEADER));
+        let encrypted = SqlitePool::connect_with(cipher.database_options(&db_url).unwrap()).await.unwrap();
+        let root_dir: String = sqlx::query("SELECT root_dir FROM vaults WHERE vault_id == 3;")
+            .fetch_one(&encrypted)
+            .await
+            .unwrap()
+            .get(0);
+        assert_eq!(root_dir, "papers");
+        encrypted.close().await;
+
+        let without_key = SqlitePool::connect(&db_url).await.unwrap();
+        assert!(sqlx::query("SELECT * FROM vaults;").fetch_all(&without_key).await.is_err());
+        let wrong_key = StorageCipher::from_key(&[2u8; 32]).database_options(&db_url).unwrap();
+        if let Ok(pool) = SqlitePool::connect_with(wrong_key).await {
+            assert!(sqlx::query("SELECT * FROM vaults;").fetch_all(&pool).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_plaintext_passes_through_decrypt() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let legacy = b"written before encryption was enabled".to_vec();
+
+        assert_eq!(cipher.decrypt(&legacy).unwrap(), legacy);
+    }
+
+    #[test]
+    fn test_wrong_key_fails_to_decrypt() {
+        let sealed = StorageCipher::from_key(&[1u8; 32]).encrypt(b"secret").unwrap();
+
+        assert!(StorageCipher::from_key(&[2u8; 32]).decrypt(&sealed).is_err());
+        assert!(StorageCipher::disabled().decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_only_the_vault_ignore_file_stays_plaintext() {
+        let cipher = StorageCipher::from_key(&[7u8; 32]);
+        let remote_file = |path: &str| RemoteFile {
+            full_path: PathBuf::from("/home/sam/papers").join(path),
+            root_directory: "papers".to_string(),
+            absolute_root_dir: PathBuf::from("/home/sam/papers"),
+            contents: b"*.tmp\n".to_vec(),
+            vault_id: 0,
+            file_id: 1,
+            modified_time: 0,
+            modified_nanos: 0,
+            mode: None,
+        };
+        let mut files = vec![remote_file(IGNORE_FILE_NAME), remote_file("drafts/.datoxidizeignore")];
+
+        cipher.encrypt_remote_files(&mut files).unwrap();
+
+        assert_eq!(files[0].contents, b"*.tmp\n");
+        assert!(is_encrypted(&files[1].contents));
+    }
+}
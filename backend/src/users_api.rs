@@ -0,0 +1,370 @@
+//! Routes for managing users and who can access each vault:
+//! GET /users/me - the calling user
+//! GET /users - lists every user, admins only
+//! POST /users - creates a user from a NewUser and returns their api token, admins only
+//! DELETE /users/:user_id - removes a user and their access to every vault, admins only
+//! POST /users/:user_id/token - replaces a user's api token, admins or the user themselves
+//! GET /vaults/:vault_id/permissions - who can access the vault, owners only
+//! PUT /vaults/:vault_id/permissions/:user_id - gives a user a role in the vault from a PermissionUpdate, owners only
+//! DELETE /vaults/:vault_id/permissions/:user_id - removes a user's access to the vault, owners only
+//!
+//! Tokens are only returned when they are created, a vault always keeps at least one owner
+
+use crate::auth::{self, CurrentUser, Role};
+use crate::vault_api::{internal_error, ApiError};
+use crate::ApiState;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// A user as it is listed
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub user_id: i32,
+    pub name: String,
+    pub is_admin: bool,
+}
+
+/// Body of POST /users
+#[derive(Deserialize, Debug)]
+pub struct NewUser {
+    pub name: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+/// A user and their new api token
+#[derive(Serialize, Debug)]
+pub struct UserToken {
+    pub user: UserInfo,
+    pub token: String,
+}
+
+/// Body of PUT /vaults/:vault_id/permissions/:user_id
+#[derive(Deserialize, Debug)]
+pub struct PermissionUpdate {
+    pub role: Role,
+}
+
+/// A user's role in a vault
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct VaultPermission {
+    pub user_id: i32,
+    pub name: String,
+    pub role: Role,
+}
+
+pub async fn current_user(user: CurrentUser) -> Json<UserInfo> {
+    Json(UserInfo {
+        user_id: user.user_id,
+        name: user.name,
+        is_admin: user.is_admin,
+    })
+}
+
+pub async fn list_users(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+) -> Result<Json<Vec<UserInfo>>, ApiError> {
+    user.require_admin()?;
+    let pool = &state.lock().await.pool;
+    Ok(Json(read_users(pool).await.map_err(internal_error)?))
+}
+
+pub async fn create_user(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    Json(new_user): Json<NewUser>,
+) -> Result<(StatusCode, Json<UserToken>), ApiError> {
+    user.require_admin()?;
+    let pool = &state.lock().await.pool;
+    let created = insert_user(pool, &new_user.name, new_user.is_admin).await?;
+    println!("{} created user {}", user.name, created.user.name);
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub async fn delete_user(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(user_id): UrlPath<i32>,
+) -> Result<StatusCode, ApiError> {
+    user.require_admin()?;
+    let pool = &state.lock().await.pool;
+    remove_user(pool, user_id).await?;
+    println!("{} deleted user {user_id}", user.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn replace_token(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(user_id): UrlPath<i32>,
+) -> Result<Json<UserToken>, ApiError> {
+    if user.user_id != user_id {
+        user.require_admin()?;
+    }
+    let pool = &state.lock().await.pool;
+    let target = find_user(pool, user_id).await?;
+    let token = auth::new_token();
+    sqlx::query("UPDATE users SET token_hash = ? WHERE user_id == ?;")
+        .bind(auth::hash_token(&token))
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    println!("{} replaced the token of {}", user.name, target.name);
+    Ok(Json(UserToken { user: target, token }))
+}
+
+pub async fn list_permissions(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Json<Vec<VaultPermission>>, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::Owner).await?;
+    Ok(Json(read_permissions(pool, vault_id).await.map_err(internal_error)?))
+}
+
+pub async fn update_permission(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, user_id)): UrlPath<(i32, i32)>,
+    Json(update): Json<PermissionUpdate>,
+) -> Result<Json<Vec<VaultPermission>>, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::Owner).await?;
+    find_user(pool, user_id).await?;
+    set_role(pool, vault_id, user_id, Some(update.role)).await?;
+    println!("{} gave user {user_id} {} access to vault {vault_id}", user.name, update.role.as_str());
+    Ok(Json(read_permissions(pool, vault_id).await.map_err(internal_error)?))
+}
+
+pub async fn delete_permission(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, user_id)): UrlPath<(i32, i32)>,
+) -> Result<StatusCode, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::Owner).await?;
+    set_role(pool, vault_id, user_id, None).await?;
+    println!("{} removed the access of user {user_id} to vault {vault_id}", user.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Creates an admin user on a server without users so the server can be set up, returns its token
+pub async fn create_admin_if_missing(pool: &Pool<Sqlite>) -> Result<Option<UserToken>, ApiError> {
+    let users = read_users(pool).await.map_err(internal_error)?;
+    if !users.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(insert_user(pool, "admin", true).await?))
+}
+
+/// Gives a user a role in a vault, or removes their access if role is None
+/// Refuses to leave the vault without an owner
+pub async fn set_role(pool: &Pool<Sqlite>, vault_id: i32, user_id: i32, role: Option<Role>) -> Result<(), ApiError> {
+    let owners = read_permissions(pool, vault_id)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .filter(|permission| permission.role == Role::Owner)
+        .map(|permission| permission.user_id)
+        .collect::<Vec<i32>>();
+    if owners == [user_id] && role != Some(Role::Owner) {
+        return Err((StatusCode::CONFLICT, format!("vault {vault_id} needs another owner first")));
+    }
+
+    match role {
+        Some(role) => sqlx::query(
+            "INSERT INTO vault_permissions (vault_id, user_id, role) VALUES (?, ?, ?) \
+            ON CONFLICT(vault_id, user_id) DO UPDATE SET role = excluded.role;",
+        )
+        .bind(vault_id)
+        .bind(user_id)
+        .bind(role.as_str()),
+        None => sqlx::query("DELETE FROM vault_permissions WHERE vault_id == ? AND user_id == ?;")
+            .bind(vault_id)
+            .bind(user_id),
+    }
+    .execute(pool)
+    .await
+    .map_err(internal_error)?;
+    Ok(())
+}
+
+/// Removes everyone's access to a vault, used when the vault is deleted
+pub async fn delete_vault_permissions(pool: &Pool<Sqlite>, vault_id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM vault_permissions WHERE vault_id == ?;")
+        .bind(vault_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn insert_user(pool: &Pool<Sqlite>, name: &str, is_admin: bool) -> Result<UserToken, ApiError> {
+    if name.is_empty() || name.contains(':') {
+        return Err((StatusCode::BAD_REQUEST, format!("{:?} can't be used as a user name", name)));
+    }
+    if read_users(pool).await.map_err(internal_error)?.iter().any(|user| user.name == name) {
+        return Err((StatusCode::CONFLICT, format!("user {name} already exists")));
+    }
+
+    let token = auth::new_token();
+    let user_id = sqlx::query("INSERT INTO users (name, token_hash, is_admin) VALUES (?, ?, ?) RETURNING user_id;")
+        .bind(name)
+        .bind(auth::hash_token(&token))
+        .bind(is_admin)
+        .fetch_one(pool)
+        .await
+        .map_err(internal_error)?
+        .get::<i32, _>(0);
+    Ok(UserToken {
+        user: find_user(pool, user_id).await?,
+        token,
+    })
+}
+
+async fn remove_user(pool: &Pool<Sqlite>, user_id: i32) -> Result<(), ApiError> {
+    let user = find_user(pool, user_id).await?;
+    let users = read_users(pool).await.map_err(internal_error)?;
+    if user.is_admin && users.iter().filter(|user| user.is_admin).count() == 1 {
+        return Err((StatusCode::CONFLICT, "the last admin can't be deleted".to_string()));
+    }
+    // fails if the user is the only owner of a vault rather than leaving it without one
+    let owned = sqlx::query("select vault_id from vault_permissions where user_id == ? AND role == 'owner';")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(internal_error)?;
+    for row in owned {
+        let vault_id = row.get::<i32, _>(0);
+        set_role(pool, vault_id, user_id, None).await?;
+    }
+
+    sqlx::query("DELETE FROM vault_permissions WHERE user_id == ?;")
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    sqlx::query("DELETE FROM users WHERE user_id == ?;")
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    Ok(())
+}
+
+async fn read_users(pool: &Pool<Sqlite>) -> Result<Vec<UserInfo>, sqlx::Error> {
+    let rows = sqlx::query("select user_id, name, is_admin from users order by user_id;")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| UserInfo {
+            user_id: row.get::<i32, _>(0),
+            name: row.get::<String, _>(1),
+            is_admin: row.get::<bool, _>(2),
+        })
+        .collect())
+}
+
+pub async fn find_user(pool: &Pool<Sqlite>, user_id: i32) -> Result<UserInfo, ApiError> {
+    read_users(pool)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .find(|user| user.user_id == user_id)
+        .ok_or((StatusCode::NOT_FOUND, format!("user {user_id} does not exist")))
+}
+
+async fn read_permissions(pool: &Pool<Sqlite>, vault_id: i32) -> Result<Vec<VaultPermission>, sqlx::Error> {
+    let rows = sqlx::query(
+        "select users.user_id, name, role from vault_permissions join users on users.user_id == vault_permissions.user_id \
+        where vault_id == ? order by users.user_id;",
+    )
+    .bind(vault_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            Some(VaultPermission {
+                user_id: row.get::<i32, _>(0),
+                name: row.get::<String, _>(1),
+                role: Role::parse(&row.get::<String, _>(2))?,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_db_api;
+
+    async fn test_pool() -> Pool<Sqlite> {
+        let pool = server_db_api::test_pool().await;
+        for vault_id in [0, 1] {
+            sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (?, ?, 'papers', 5);")
+                .bind(vault_id)
+                .bind(format!("/storage/vault{vault_id}/papers"))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        pool
+    }
+
+    fn as_current(user: &UserInfo) -> CurrentUser {
+        CurrentUser {
+            user_id: user.user_id,
+            name: user.name.clone(),
+            is_admin: user.is_admin,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grants_decide_vault_access() {
+        let pool = test_pool().await;
+        let admin = create_admin_if_missing(&pool).await.unwrap().unwrap();
+        assert!(create_admin_if_missing(&pool).await.unwrap().is_none());
+        let sam = insert_user(&pool, "sam", false).await.unwrap();
+        assert_eq!(insert_user(&pool, "sam", false).await.unwrap_err().0, StatusCode::CONFLICT);
+        assert_ne!(admin.token, sam.token);
+
+        set_role(&pool, 0, admin.user.user_id, Some(Role::Owner)).await.unwrap();
+        set_role(&pool, 0, sam.user.user_id, Some(Role::ReadOnly)).await.unwrap();
+        let sam = as_current(&sam.user);
+        let admin = as_current(&admin.user);
+
+        assert_eq!(admin.roles(&pool).await.unwrap().len(), 2);
+        assert_eq!(sam.roles(&pool).await.unwrap().into_iter().collect::<Vec<_>>(), vec![(0, Role::ReadOnly)]);
+        assert_eq!(sam.require(&pool, 0, Role::ReadOnly).await.unwrap(), Role::ReadOnly);
+        assert_eq!(sam.require(&pool, 0, Role::ReadWrite).await.unwrap_err().0, StatusCode::FORBIDDEN);
+        assert_eq!(sam.require(&pool, 1, Role::ReadOnly).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        assert_eq!(admin.require(&pool, 1, Role::Owner).await.unwrap(), Role::Owner);
+    }
+
+    #[tokio::test]
+    async fn test_vaults_keep_an_owner() {
+        let pool = test_pool().await;
+        let admin = create_admin_if_missing(&pool).await.unwrap().unwrap().user;
+        let sam = insert_user(&pool, "sam", false).await.unwrap().user;
+        set_role(&pool, 0, sam.user_id, Some(Role::Owner)).await.unwrap();
+
+        let demote = set_role(&pool, 0, sam.user_id, Some(Role::ReadWrite)).await.unwrap_err();
+        assert_eq!(demote.0, StatusCode::CONFLICT);
+        assert_eq!(remove_user(&pool, sam.user_id).await.unwrap_err().0, StatusCode::CONFLICT);
+        assert_eq!(remove_user(&pool, admin.user_id).await.unwrap_err().0, StatusCode::CONFLICT);
+
+        set_role(&pool, 0, admin.user_id, Some(Role::Owner)).await.unwrap();
+        remove_user(&pool, sam.user_id).await.unwrap();
+        let permissions = read_permissions(&pool, 0).await.unwrap();
+        assert_eq!(permissions, vec![VaultPermission { user_id: admin.user_id, name: admin.name, role: Role::Owner }]);
+    }
+}
@@ -0,0 +1,596 @@
+//! Routes for managing vaults:
+//! GET /vaults - lists every vault the user can access
+//! POST /vaults - creates a vault from a NewVault, makes the storage directory and the db row, the user owns it
+//! GET /vaults/:vault_id - a single vault
+//! PATCH /vaults/:vault_id - renames and/or configures a vault from a VaultUpdate, owners only
+//! DELETE /vaults/:vault_id?delete_files=true - removes the vault and its metadata, the stored
+//!     files are only removed if delete_files is set, owners only
+//!
+//! Errors are returned as a status code with a plain text message
+
+use crate::auth::{CurrentUser, Role};
+use crate::{share_api, users_api};
+use crate::ApiState;
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::StatusCode;
+use axum::Json;
+use common::config_utils::VaultInfo;
+use common::ignore_utils::IGNORE_FILE_NAME;
+use serde::Deserialize;
+use sqlx::{Pool, Row, Sqlite};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+
+pub type ApiError = (StatusCode, String);
+
+const DEFAULT_SYNC_FREQUENCY: i32 = 5;
+
+/// Body of POST /vaults
+/// abs_path defaults to <VAULT_STORAGE>/vault<vault_id>/<root_dir>, if given it must end in root_dir and be
+/// under VAULT_STORAGE, only admins can give it
+/// A directory that already has files in it is refused unless use_existing is set, only admins can set it
+#[derive(Deserialize, Debug)]
+pub struct NewVault {
+    pub root_dir: String,
+    pub abs_path: Option<PathBuf>,
+    #[serde(default)]
+    pub use_existing: bool,
+    pub sync_frequency: Option<i32>,
+    #[serde(default)]
+    pub read_only: bool,
+    pub retention_days: Option<i32>,
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+}
+
+/// Body of PATCH /vaults/:vault_id, fields that are left out are unchanged
+/// Changing root_dir renames the vault's storage directory
+/// A retention_days of 0 or less keeps file history and old versions forever
+/// An empty list of ignore_patterns removes the vault's .datoxidizeignore
+#[derive(Deserialize, Debug, Default)]
+pub struct VaultUpdate {
+    pub root_dir: Option<String>,
+    pub sync_frequency: Option<i32>,
+    pub read_only: Option<bool>,
+    pub retention_days: Option<i32>,
+    pub ignore_patterns: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DeleteOptions {
+    #[serde(default)]
+    pub delete_files: bool,
+}
+
+pub async fn list_vaults(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+) -> Result<Json<Vec<VaultInfo>>, ApiError> {
+    let pool = &state.lock().await.pool;
+    Ok(Json(read_user_vaults(pool, &user).await?))
+}
+
+pub async fn get_vault(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Json<VaultInfo>, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::ReadOnly).await?;
+    Ok(Json(find_vault(pool, vault_id).await?))
+}
+
+pub async fn create_vault(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    Json(new_vault): Json<NewVault>,
+) -> Result<(StatusCode, Json<VaultInfo>), ApiError> {
+    if new_vault.abs_path.is_some() || new_vault.use_existing {
+        user.require_admin()?;
+    }
+    let pool = &state.lock().await.pool;
+    let vault = insert_vault(pool, &vault_storage_root(), new_vault).await?;
+    users_api::set_role(pool, vault.vault_id, user.user_id, Some(Role::Owner)).await?;
+    println!("{} created vault {} at {:?}", user.name, vault.vault_id, vault.abs_path);
+    Ok((StatusCode::CREATED, Json(vault)))
+}
+
+pub async fn update_vault(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    Json(update): Json<VaultUpdate>,
+) -> Result<Json<VaultInfo>, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::Owner).await?;
+    Ok(Json(apply_vault_update(pool, vault_id, update).await?))
+}
+
+pub async fn delete_vault(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    Query(options): Query<DeleteOptions>,
+) -> Result<StatusCode, ApiError> {
+    let pool = &state.lock().await.pool;
+    user.require(pool, vault_id, Role::Owner).await?;
+    remove_vault(pool, vault_id, options.delete_files).await?;
+    println!("deleted vault {vault_id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Directory new vaults are stored under, read from VAULT_STORAGE
+fn vault_storage_root() -> PathBuf {
+    PathBuf::from(dotenvy::var("VAULT_STORAGE").unwrap_or_else(|_| "./backend/storage".to_string()))
+}
+
+pub fn internal_error(e: impl Display) -> ApiError {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// Reads every vault, or only vault_id if given
+pub async fn read_vaults(pool: &Pool<Sqlite>, vault_id: Option<i32>) -> Result<Vec<VaultInfo>, sqlx::Error> {
+    let query = "select vault_id, abs_path, root_dir, sync_frequency, read_only, retention_days from vaults \
+        where ? IS NULL OR vault_id == ? order by vault_id;";
+    let rows = sqlx::query(query)
+        .bind(vault_id)
+        .bind(vault_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let abs_path = PathBuf::from(row.get::<String, _>(1));
+            VaultInfo {
+                vault_id: row.get::<i32, _>(0),
+                ignore_patterns: read_ignore_patterns(&abs_path),
+                abs_path,
+                root_dir: row.get::<String, _>(2),
+                sync_frequency: row.get::<i32, _>(3),
+                read_only: row.get::<bool, _>(4),
+                retention_days: row.get::<Option<i32>, _>(5),
+            }
+        })
+        .collect())
+}
+
+/// Reads every vault the user can access
+pub async fn read_user_vaults(pool: &Pool<Sqlite>, user: &CurrentUser) -> Result<Vec<VaultInfo>, ApiError> {
+    let roles = user.roles(pool).await.map_err(internal_error)?;
+    let mut vaults = read_vaults(pool, None).await.map_err(internal_error)?;
+    vaults.retain(|vault| roles.contains_key(&vault.vault_id));
+    Ok(vaults)
+}
+
+/// Reads a vault, NOT_FOUND if there is no vault with the id
+pub async fn find_vault(pool: &Pool<Sqlite>, vault_id: i32) -> Result<VaultInfo, ApiError> {
+    read_vaults(pool, Some(vault_id))
+        .await
+        .map_err(internal_error)?
+        .pop()
+        .ok_or((StatusCode::NOT_FOUND, format!("vault {vault_id} does not exist")))
+}
+
+async fn insert_vault(
+    pool: &Pool<Sqlite>,
+    storage_root: &Path,
+    new_vault: NewVault,
+) -> Result<VaultInfo, ApiError> {
+    validate_root_dir(&new_vault.root_dir)?;
+    let sync_frequency = new_vault.sync_frequency.unwrap_or(DEFAULT_SYNC_FREQUENCY);
+    validate_sync_frequency(sync_frequency)?;
+
+    // ids of deleted vaults are never given out again, clients may still be linked to them
+    let vault_id = sqlx::query(
+        "select coalesce(max(vault_id) + 1, 0) from \
+        (select vault_id from vaults union all select vault_id from deleted_vaults);",
+    )
+        .fetch_one(pool)
+        .await
+        .map_err(internal_error)?
+        .get::<i32, _>(0);
+
+    fs::create_dir_all(storage_root).map_err(internal_error)?;
+    let storage_root = storage_root.canonicalize().map_err(internal_error)?;
+    let abs_path = match new_vault.abs_path {
+        Some(path) => resolve_new_dir(&path)?,
+        None => storage_root
+            .join(format!("vault{vault_id}"))
+            .join(&new_vault.root_dir),
+    };
+    if abs_path.file_name() != Some(new_vault.root_dir.as_ref()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("abs_path {:?} must end in the root_dir {}", abs_path, new_vault.root_dir),
+        ));
+    }
+    if !abs_path.starts_with(&storage_root) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("abs_path {:?} must be inside the vault storage {:?}", abs_path, storage_root),
+        ));
+    }
+
+    let existing = read_vaults(pool, None).await.map_err(internal_error)?;
+    if existing
+        .iter()
+        .any(|vault| vault.abs_path.starts_with(&abs_path) || abs_path.starts_with(&vault.abs_path))
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("a vault is already stored at or inside {:?}", abs_path),
+        ));
+    }
+    let has_files = fs::read_dir(&abs_path)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if has_files && !new_vault.use_existing {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{:?} already has files in it, set use_existing to store the vault there", abs_path),
+        ));
+    }
+
+    fs::create_dir_all(&abs_path).map_err(internal_error)?;
+
+    write_ignore_patterns(&abs_path, &new_vault.ignore_patterns).map_err(internal_error)?;
+
+    sqlx::query(
+        "INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency, read_only, retention_days) \
+        VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(vault_id)
+    .bind(abs_path.to_str().unwrap())
+    .bind(&new_vault.root_dir)
+    .bind(sync_frequency)
+    .bind(new_vault.read_only)
+    .bind(new_vault.retention_days.filter(|days| *days > 0))
+    .execute(pool)
+    .await
+    .map_err(internal_error)?;
+
+    find_vault(pool, vault_id).await
+}
+
+async fn apply_vault_update(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    update: VaultUpdate,
+) -> Result<VaultInfo, ApiError> {
+    let vault = find_vault(pool, vault_id).await?;
+
+    if let Some(root_dir) = update.root_dir.filter(|name| *name != vault.root_dir) {
+        rename_vault(pool, &vault, &root_dir).await?;
+    }
+
+    if let Some(sync_frequency) = update.sync_frequency {
+        validate_sync_frequency(sync_frequency)?;
+        sqlx::query("UPDATE vaults SET sync_frequency = ? WHERE vault_id == ?;")
+            .bind(sync_frequency)
+            .bind(vault_id)
+            .execute(pool)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    if let Some(read_only) = update.read_only {
+        sqlx::query("UPDATE vaults SET read_only = ? WHERE vault_id == ?;")
+            .bind(read_only)
+            .bind(vault_id)
+            .execute(pool)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    if let Some(retention_days) = update.retention_days {
+        sqlx::query("UPDATE vaults SET retention_days = ? WHERE vault_id == ?;")
+            .bind(Some(retention_days).filter(|days| *days > 0))
+            .bind(vault_id)
+            .execute(pool)
+            .await
+            .map_err(internal_error)?;
+    }
+
+    let vault = find_vault(pool, vault_id).await?;
+    if let Some(patterns) = update.ignore_patterns {
+        write_ignore_patterns(&vault.abs_path, &patterns).map_err(internal_error)?;
+        return find_vault(pool, vault_id).await;
+    }
+    Ok(vault)
+}
+
+/// Moves the vault's storage directory to root_dir and rewrites the paths of its files
+/// Clients are unaffected as they convert paths using their own root for the vault
+async fn rename_vault(pool: &Pool<Sqlite>, vault: &VaultInfo, root_dir: &str) -> Result<(), ApiError> {
+    validate_root_dir(root_dir)?;
+    let new_path = vault.abs_path.with_file_name(root_dir);
+    if new_path.exists() {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{:?} already exists", new_path),
+        ));
+    }
+    fs::rename(&vault.abs_path, &new_path).map_err(internal_error)?;
+
+    let old_prefix = vault.abs_path.to_str().unwrap();
+    let new_prefix = new_path.to_str().unwrap();
+
+    sqlx::query("UPDATE vaults SET abs_path = ?, root_dir = ? WHERE vault_id == ?;")
+        .bind(new_prefix)
+        .bind(root_dir)
+        .bind(vault.vault_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+
+    sqlx::query(
+        "UPDATE file_metadata SET file_path = ? || substr(file_path, ?), root_directory = ? \
+        WHERE vault_id == ?;",
+    )
+    .bind(new_prefix)
+    .bind(old_prefix.chars().count() as i64 + 1)
+    .bind(root_dir)
+    .bind(vault.vault_id)
+    .execute(pool)
+    .await
+    .map_err(internal_error)?;
+
+    println!("renamed vault {} to {root_dir}", vault.vault_id);
+    Ok(())
+}
+
+/// Removes the vault and everything kept about its files, its id is kept in deleted_vaults so it is never reused
+async fn remove_vault(pool: &Pool<Sqlite>, vault_id: i32, delete_files: bool) -> Result<(), ApiError> {
+    let vault = find_vault(pool, vault_id).await?;
+
+    for table in ["file_metadata", "file_history", "file_ancestors"] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE vault_id == ?;"))
+            .bind(vault_id)
+            .execute(pool)
+            .await
+            .map_err(internal_error)?;
+    }
+    sqlx::query("DELETE FROM vaults WHERE vault_id == ?;")
+        .bind(vault_id)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    let deleted_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    sqlx::query("INSERT OR IGNORE INTO deleted_vaults (vault_id, deleted_at) VALUES (?, ?);")
+        .bind(vault_id)
+        .bind(deleted_at)
+        .execute(pool)
+        .await
+        .map_err(internal_error)?;
+    share_api::delete_vault_shares(pool, vault_id)
+        .await
+        .map_err(internal_error)?;
+    users_api::delete_vault_permissions(pool, vault_id)
+        .await
+        .map_err(internal_error)?;
+
+    if delete_files && vault.abs_path.exists() {
+        fs::remove_dir_all(&vault.abs_path).map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+/// The absolute path a directory that may not exist yet will have, with links in the part that exists resolved
+/// The part that doesn't exist yet can only be plain directory names
+fn resolve_new_dir(path: &Path) -> Result<PathBuf, ApiError> {
+    let invalid = || {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("abs_path {:?} must be an absolute path", path),
+        )
+    };
+    if !path.is_absolute() {
+        return Err(invalid());
+    }
+    let existing = path.ancestors().find(|ancestor| ancestor.exists()).ok_or_else(invalid)?;
+    let rest = path.strip_prefix(existing).map_err(internal_error)?;
+    if !rest.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(invalid());
+    }
+    Ok(existing.canonicalize().map_err(internal_error)?.join(rest))
+}
+
+/// root_dir is a single directory name as it is mirrored on every client
+fn validate_root_dir(root_dir: &str) -> Result<(), ApiError> {
+    let mut components = Path::new(root_dir).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            format!("root_dir {:?} must be a single directory name", root_dir),
+        )),
+    }
+}
+
+fn validate_sync_frequency(sync_frequency: i32) -> Result<(), ApiError> {
+    if sync_frequency > 0 {
+        return Ok(());
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        "sync_frequency must be a positive number of seconds".to_string(),
+    ))
+}
+
+fn read_ignore_patterns(vault_root: &Path) -> Vec<String> {
+    fs::read_to_string(vault_root.join(IGNORE_FILE_NAME))
+        .map(|contents| contents.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn write_ignore_patterns(vault_root: &Path, patterns: &[String]) -> std::io::Result<()> {
+    let ignore_file = vault_root.join(IGNORE_FILE_NAME);
+    if patterns.is_empty() {
+        if ignore_file.exists() {
+            fs::remove_file(ignore_file)?;
+        }
+        return Ok(());
+    }
+    fs::write(ignore_file, patterns.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_db_api;
+    use crate::storage_crypto::StorageCipher;
+    use common::version_vector::{VersionVector, SERVER_DEVICE};
+
+    fn new_vault(root_dir: &str) -> NewVault {
+        NewVault {
+            root_dir: root_dir.to_string(),
+            abs_path: None,
+            use_existing: false,
+            sync_frequency: None,
+            read_only: false,
+            retention_days: Some(30),
+            ignore_patterns: vec!["*.tmp".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_makes_directory_and_row() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+
+        let first = insert_vault(&pool, storage.path(), new_vault("papers")).await.unwrap();
+        let second = insert_vault(&pool, storage.path(), new_vault("photos")).await.unwrap();
+
+        assert_eq!((first.vault_id, second.vault_id), (0, 1));
+        assert!(first.abs_path.ends_with("vault0/papers"));
+        assert!(first.abs_path.join(IGNORE_FILE_NAME).exists());
+        assert_eq!(first.sync_frequency, DEFAULT_SYNC_FREQUENCY);
+        assert_eq!(first.retention_days, Some(30));
+        assert_eq!(first.ignore_patterns, vec!["*.tmp".to_string()]);
+        assert_eq!(read_vaults(&pool, None).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_root_dir_rejected() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+
+        for root_dir in ["", "../escape", "nested/dir"] {
+            let err = insert_vault(&pool, storage.path(), new_vault(root_dir)).await.unwrap_err();
+            assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abs_path_stays_in_storage() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let with_path = |path: PathBuf, use_existing: bool| NewVault {
+            abs_path: Some(path),
+            use_existing,
+            ..new_vault("papers")
+        };
+
+        for path in [
+            outside.path().join("papers"),
+            storage.path().join("../papers"),
+            storage.path().join("custom/../../papers"),
+            PathBuf::from("custom/papers"),
+        ] {
+            let err = insert_vault(&pool, storage.path(), with_path(path, false)).await.unwrap_err();
+            assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        }
+        assert!(!outside.path().join("papers").exists());
+
+        let existing = storage.path().join("old/papers");
+        fs::create_dir_all(&existing).unwrap();
+        fs::write(existing.join("notes.txt"), "notes").unwrap();
+        let err = insert_vault(&pool, storage.path(), with_path(existing.clone(), false)).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::CONFLICT);
+
+        let vault = insert_vault(&pool, storage.path(), with_path(existing.clone(), true)).await.unwrap();
+        assert_eq!(vault.abs_path, existing.canonicalize().unwrap());
+        let nested = existing.join("inner/papers");
+        let err = insert_vault(&pool, storage.path(), with_path(nested, false)).await.unwrap_err();
+        assert_eq!(err.0, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_rename_moves_directory_and_file_paths() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+        let vault = insert_vault(&pool, storage.path(), new_vault("papers")).await.unwrap();
+        let file = vault.abs_path.join("draft.txt");
+        fs::write(&file, "draft").unwrap();
+        sqlx::query("INSERT INTO file_metadata (vault_id, file_path, root_directory, modified_time, file_size) VALUES (0, ?, 'papers', 1, 5);")
+            .bind(file.to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let update = VaultUpdate {
+            root_dir: Some("articles".to_string()),
+            retention_days: Some(0),
+            ignore_patterns: Some(vec![]),
+            ..Default::default()
+        };
+        let renamed = apply_vault_update(&pool, 0, update).await.unwrap();
+
+        assert!(renamed.abs_path.ends_with("vault0/articles"));
+        assert!(renamed.abs_path.join("draft.txt").exists());
+        assert!(!vault.abs_path.exists());
+        assert_eq!(renamed.retention_days, None);
+        assert!(renamed.ignore_patterns.is_empty());
+        let row = sqlx::query("select file_path, root_directory from file_metadata;")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(PathBuf::from(row.get::<String, _>(0)), renamed.abs_path.join("draft.txt"));
+        assert_eq!(row.get::<String, _>(1), "articles");
+    }
+
+    #[tokio::test]
+    async fn test_delete_keeps_files_unless_asked() {
+        let pool = server_db_api::test_pool().await;
+        let storage = tempfile::tempdir().unwrap();
+        let kept = insert_vault(&pool, storage.path(), new_vault("kept")).await.unwrap();
+        let removed = insert_vault(&pool, storage.path(), new_vault("removed")).await.unwrap();
+
+        let relative = Path::new("draft.txt");
+        let version = VersionVector::default().incremented(SERVER_DEVICE);
+        let cipher = StorageCipher::disabled();
+        for vault_id in [kept.vault_id, removed.vault_id] {
+            server_db_api::add_file_history(&pool, vault_id, relative, "uploaded from browser", 1, 5)
+                .await
+                .unwrap();
+            server_db_api::save_ancestor(&pool, &cipher, vault_id, relative, &version, b"draft")
+                .await
+                .unwrap();
+        }
+
+        remove_vault(&pool, kept.vault_id, false).await.unwrap();
+        remove_vault(&pool, removed.vault_id, true).await.unwrap();
+
+        assert!(kept.abs_path.exists());
+        assert!(!removed.abs_path.exists());
+        assert!(read_vaults(&pool, None).await.unwrap().is_empty());
+        assert_eq!(find_vault(&pool, kept.vault_id).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        for vault_id in [kept.vault_id, removed.vault_id] {
+            assert!(server_db_api::get_file_history(&pool, vault_id, relative).await.unwrap().is_empty());
+            let ancestor = server_db_api::get_ancestor(&pool, &cipher, vault_id, relative, &version);
+            assert_eq!(ancestor.await.unwrap(), None);
+        }
+        // clients may still be linked to a removed vault, so its id is never given to a new one
+        let next = insert_vault(&pool, storage.path(), new_vault("next")).await.unwrap();
+        assert!(next.vault_id > removed.vault_id);
+    }
+}
@@ -0,0 +1,990 @@
+//! WebDAV access to vaults for apps that can't run the client, each vault is a collection at /dav/:vault_id/
+//! Supports OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE, COPY, LOCK and UNLOCK
+//!
+//! Files changed through WebDAV are updated in file_metadata and file_history the same way as files synced from a
+//! client, so clients pick up the changes on their next sync
+//! Locks are only kept in memory and are lost when the server restarts. They block every writer, clients syncing
+//! and browser uploads can't send a lock token so they can't change a locked file until the lock is released
+
+use crate::auth::{CurrentUser, Role};
+use crate::file_browser::{self, modified_secs, modified_time_and_nanos, not_found, relative_path};
+use crate::html_creation::civil_time;
+use crate::{quota_api, server_db_api};
+use crate::storage_crypto::{self, StorageCipher};
+use crate::vault_api::{self, internal_error, ApiError};
+use crate::ApiState;
+use axum::body::Bytes;
+use axum::extract::{Path as UrlPath, State};
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use common::config_utils::VaultInfo;
+use common::file_utils;
+use common::ignore_utils::IgnoreRules;
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE, MKCOL, MOVE, COPY, LOCK, UNLOCK";
+const MAX_LOCK_SECS: u64 = 60 * 60;
+const SUPPORTED_LOCK: &str = "<D:supportedlock>\
+    <D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+    <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+    </D:supportedlock>";
+
+/// A write lock a WebDAV client holds on a file or directory
+#[derive(Debug, Clone)]
+pub struct DavLock {
+    vault_id: i32,
+    path: PathBuf,
+    exclusive: bool,
+    infinite_depth: bool,
+    owner: String,
+    timeout_secs: u64,
+    expires: Instant,
+}
+
+impl DavLock {
+    /// A lock on a directory with infinite depth covers everything in it
+    fn covers(&self, vault_id: i32, relative: &Path) -> bool {
+        self.vault_id == vault_id && (self.path == relative || (self.infinite_depth && relative.starts_with(&self.path)))
+    }
+}
+
+/// Active WebDAV locks by lock token
+#[derive(Debug, Clone, Default)]
+pub struct DavLocks(Arc<std::sync::Mutex<HashMap<String, DavLock>>>);
+
+impl DavLocks {
+    /// Locks that cover relative, or that are anywhere inside it if whole_tree is set, expired locks are dropped
+    fn find(&self, vault_id: i32, relative: &Path, whole_tree: bool) -> Vec<(String, DavLock)> {
+        let mut locks = self.0.lock().unwrap();
+        let now = Instant::now();
+        locks.retain(|_, lock| lock.expires > now);
+        locks
+            .iter()
+            .filter(|(_, lock)| {
+                lock.covers(vault_id, relative)
+                    || (whole_tree && lock.vault_id == vault_id && lock.path.starts_with(relative))
+            })
+            .map(|(token, lock)| (token.clone(), lock.clone()))
+            .collect()
+    }
+
+    /// Refuses a write to relative, or to anything inside it if whole_tree is set, while it is locked
+    /// Only WebDAV requests can send the lock token in their If header, other writers pass an empty one
+    pub fn check_unlocked(
+        &self,
+        vault_id: i32,
+        relative: &Path,
+        whole_tree: bool,
+        if_header: &str,
+    ) -> Result<(), ApiError> {
+        match self
+            .find(vault_id, relative, whole_tree)
+            .iter()
+            .any(|(token, _)| !if_header.contains(token.as_str()))
+        {
+            true => Err((StatusCode::LOCKED, format!("{:?} is locked", relative))),
+            false => Ok(()),
+        }
+    }
+
+    /// Drops the locks on relative and everything inside it after it was deleted or moved
+    fn release_all(&self, vault_id: i32, relative: &Path) {
+        let mut locks = self.0.lock().unwrap();
+        locks.retain(|_, lock| !(lock.vault_id == vault_id && lock.path.starts_with(relative)));
+    }
+}
+
+pub async fn dav_vault_root(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath(vault_id): UrlPath<i32>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    dav(state, &user, vault_id, "", method, headers, body).await
+}
+
+pub async fn dav_path(
+    State(state): State<Arc<Mutex<ApiState>>>,
+    user: CurrentUser,
+    UrlPath((vault_id, path)): UrlPath<(i32, String)>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    dav(state, &user, vault_id, &path, method, headers, body).await
+}
+
+async fn dav(
+    state: Arc<Mutex<ApiState>>,
+    user: &CurrentUser,
+    vault_id: i32,
+    path: &str,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    let (pool, cipher, default_ignore, locks) = {
+        let state = state.lock().await;
+        (
+            state.pool.clone(),
+            state.storage_cipher.clone(),
+            state.default_ignore.clone(),
+            state.dav_locks.clone(),
+        )
+    };
+    let role = user.require(&pool, vault_id, Role::ReadOnly).await?;
+    let vault = vault_api::find_vault(&pool, vault_id).await?;
+    let request = DavRequest {
+        read_only: vault.read_only || role < Role::ReadWrite,
+        vault,
+        pool,
+        cipher,
+        default_ignore,
+        locks,
+        headers,
+    };
+    let relative = relative_path(path)?;
+
+    match method.as_str() {
+        "OPTIONS" => Ok((
+            StatusCode::OK,
+            [
+                (header::HeaderName::from_static("dav"), "1, 2"),
+                (header::ALLOW, ALLOWED_METHODS),
+                (header::HeaderName::from_static("ms-author-via"), "DAV"),
+            ],
+        )
+            .into_response()),
+        "PROPFIND" => request.propfind(&relative),
+        "GET" | "HEAD" => request.get(&relative).await,
+        "PUT" => request.put(&relative, &body).await,
+        "DELETE" => request.delete(&relative).await,
+        "MKCOL" => request.mkcol(&relative, &body).await,
+        "MOVE" => request.transfer(&relative, true).await,
+        "COPY" => request.transfer(&relative, false).await,
+        "LOCK" => request.lock(&relative, &body).await,
+        "UNLOCK" => request.unlock(&relative),
+        _ => Err((StatusCode::METHOD_NOT_ALLOWED, format!("{method} is not supported"))),
+    }
+}
+
+/// A WebDAV request on a vault
+struct DavRequest {
+    pool: Pool<Sqlite>,
+    cipher: StorageCipher,
+    default_ignore: Vec<String>,
+    locks: DavLocks,
+    vault: VaultInfo,
+    /// the vault is read-only or the user can't write to it
+    read_only: bool,
+    headers: HeaderMap,
+}
+
+impl DavRequest {
+    fn propfind(&self, relative: &Path) -> Result<Response, ApiError> {
+        let full_path = self.vault.abs_path.join(relative);
+        if !full_path.exists() {
+            return Err(not_found(relative));
+        }
+        // listing a whole vault in one response can be huge so clients have to walk it one level at a time
+        let depth = self.header("Depth").unwrap_or("infinity");
+        if depth == "infinity" {
+            let error = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>";
+            return Ok((StatusCode::FORBIDDEN, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], error)
+                .into_response());
+        }
+
+        let mut xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">".to_string();
+        xml.push_str(&self.prop_response(relative).map_err(internal_error)?);
+        if depth == "1" && full_path.is_dir() {
+            let mut names = fs::read_dir(&full_path)
+                .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.file_name())).collect::<io::Result<Vec<_>>>())
+                .map_err(internal_error)?;
+            names.sort();
+            for name in names {
+                xml.push_str(&self.prop_response(&relative.join(name)).map_err(internal_error)?);
+            }
+        }
+        xml.push_str("</D:multistatus>");
+        Ok((StatusCode::MULTI_STATUS, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], xml).into_response())
+    }
+
+    /// The properties of a file or directory in a PROPFIND response
+    fn prop_response(&self, relative: &Path) -> io::Result<String> {
+        let full_path = self.vault.abs_path.join(relative);
+        let metadata = fs::metadata(&full_path)?;
+        let modified = modified_secs(&metadata);
+        let name = match relative.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => self.vault.root_dir.clone(),
+        };
+
+        let mut props = format!("<D:displayname>{}</D:displayname>", xml_escape(&name));
+        if metadata.is_dir() {
+            props.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            let size = storage_crypto::plaintext_len(&full_path)?;
+            props.push_str(&format!(
+                "<D:resourcetype/><D:getcontentlength>{size}</D:getcontentlength>\
+                <D:getcontenttype>application/octet-stream</D:getcontenttype>\
+                <D:getetag>\"{modified}-{size}\"</D:getetag>"
+            ));
+        }
+        props.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>", http_date(modified)));
+        props.push_str(SUPPORTED_LOCK);
+        props.push_str("<D:lockdiscovery>");
+        for (token, lock) in self.locks.find(self.vault.vault_id, relative, false) {
+            props.push_str(&self.active_lock(&token, &lock));
+        }
+        props.push_str("</D:lockdiscovery>");
+
+        Ok(format!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}</D:prop>\
+            <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+            self.href(relative, metadata.is_dir())
+        ))
+    }
+
+    async fn get(&self, relative: &Path) -> Result<Response, ApiError> {
+        if self.vault.abs_path.join(relative).is_dir() {
+            let link = file_browser::link("browse", self.vault.vault_id, relative);
+            return Ok(Redirect::to(&link).into_response());
+        }
+        file_browser::send_file(&self.vault, relative, &self.cipher).await
+    }
+
+    async fn put(&self, relative: &Path, contents: &[u8]) -> Result<Response, ApiError> {
+        self.check_writable()?;
+        let full_path = self.vault.abs_path.join(relative);
+        if full_path.is_dir() {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, format!("{:?} is a directory", relative)));
+        }
+        self.check_parent(&full_path)?;
+        let rules = IgnoreRules::for_vault(&self.vault.abs_path, &self.default_ignore);
+        if rules.is_ignored_in_vault(&full_path, &self.vault.abs_path, false) {
+            return Err((StatusCode::FORBIDDEN, format!("{:?} is ignored in this vault", relative)));
+        }
+        self.check_unlocked(relative, false)?;
+
+        let existed = full_path.exists();
+        file_browser::save_stored_file(&self.pool, &self.cipher, &self.vault, relative, contents, "uploaded through WebDAV")
+            .await?;
+        println!("saved {:?} to vault {} through WebDAV", relative, self.vault.vault_id);
+        Ok(created_or_replaced(existed))
+    }
+
+    async fn delete(&self, relative: &Path) -> Result<Response, ApiError> {
+        self.check_writable()?;
+        if relative.as_os_str().is_empty() {
+            return Err((StatusCode::FORBIDDEN, "the vault root can't be deleted".to_string()));
+        }
+        if !self.vault.abs_path.join(relative).exists() {
+            return Err(not_found(relative));
+        }
+        self.check_unlocked(relative, true)?;
+
+        self.remove(relative).await?;
+        self.locks.release_all(self.vault.vault_id, relative);
+        println!("deleted {:?} from vault {} through WebDAV", relative, self.vault.vault_id);
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    async fn mkcol(&self, relative: &Path, body: &[u8]) -> Result<Response, ApiError> {
+        self.check_writable()?;
+        if !body.is_empty() {
+            return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "MKCOL doesn't take a body".to_string()));
+        }
+        let full_path = self.vault.abs_path.join(relative);
+        if full_path.exists() {
+            return Err((StatusCode::METHOD_NOT_ALLOWED, format!("{:?} already exists", relative)));
+        }
+        self.check_parent(&full_path)?;
+        self.check_unlocked(relative, false)?;
+
+        fs::create_dir(&full_path).map_err(internal_error)?;
+        let metadata = fs::metadata(&full_path).map_err(internal_error)?;
+        server_db_api::upsert_stored_dir(
+            &self.pool,
+            self.vault.vault_id,
+            &full_path,
+            &self.vault.root_dir,
+            modified_secs(&metadata),
+        )
+        .await
+        .map_err(internal_error)?;
+        Ok(StatusCode::CREATED.into_response())
+    }
+
+    /// MOVE and COPY, the destination must be in the same vault
+    async fn transfer(&self, relative: &Path, is_move: bool) -> Result<Response, ApiError> {
+        self.check_writable()?;
+        let source = self.vault.abs_path.join(relative);
+        if !source.exists() {
+            return Err(not_found(relative));
+        }
+        let header = self
+            .header("Destination")
+            .ok_or((StatusCode::BAD_REQUEST, "the Destination header is missing".to_string()))?;
+        let destination = destination_path(header, self.vault.vault_id)?;
+        if destination.starts_with(relative) {
+            return Err((StatusCode::FORBIDDEN, format!("{:?} can't be moved or copied into itself", relative)));
+        }
+        let target = self.vault.abs_path.join(&destination);
+        self.check_parent(&target)?;
+        self.check_destination_not_ignored(&source, &target, &destination)?;
+        let existed = target.exists();
+        if existed && self.header("Overwrite") == Some("F") {
+            return Err((StatusCode::PRECONDITION_FAILED, format!("{:?} already exists", destination)));
+        }
+        if is_move {
+            self.check_unlocked(relative, true)?;
+        }
+        self.check_unlocked(&destination, true)?;
+        if !is_move {
+            self.check_copy_quota(&source, &target).await?;
+        }
+
+        if existed {
+            self.remove(&destination).await?;
+        }
+        if is_move {
+            fs::rename(&source, &target).map_err(internal_error)?;
+            server_db_api::move_stored_files(&self.pool, &source, &target)
+                .await
+                .map_err(internal_error)?;
+            self.locks.release_all(self.vault.vault_id, relative);
+            self.add_history(&target, "moved through WebDAV").await?;
+        } else {
+            copy_tree(&source, &target, self.header("Depth") == Some("0")).map_err(internal_error)?;
+            for file in files_under(&target).map_err(internal_error)? {
+                let metadata = fs::metadata(&file).map_err(internal_error)?;
+                let file_size = storage_crypto::plaintext_len(&file).map_err(internal_error)? as i64;
+                server_db_api::upsert_stored_file(
+                    &self.pool,
+                    self.vault.vault_id,
+                    &file,
+                    &self.vault.root_dir,
+                    modified_time_and_nanos(&metadata),
+                    file_size,
+                )
+                .await
+                .map_err(internal_error)?;
+            }
+            self.add_history(&target, "copied through WebDAV").await?;
+        }
+        println!(
+            "{} {:?} to {:?} in vault {} through WebDAV",
+            if is_move { "moved" } else { "copied" },
+            relative,
+            destination,
+            self.vault.vault_id
+        );
+        Ok(created_or_replaced(existed))
+    }
+
+    async fn lock(&self, relative: &Path, body: &[u8]) -> Result<Response, ApiError> {
+        // a lock blocks writers so only users who can write may take or refresh one
+        self.check_writable()?;
+        let timeout_secs = self.lock_timeout();
+        let body = String::from_utf8_lossy(body);
+
+        // a client refreshes its lock by sending the lock token in the If header without a body
+        if body.trim().is_empty() {
+            let if_header = self.header("If").unwrap_or_default();
+            let mut locks = self.locks.0.lock().unwrap();
+            let (token, lock) = locks
+                .iter_mut()
+                .find(|(token, lock)| if_header.contains(token.as_str()) && lock.covers(self.vault.vault_id, relative))
+                .ok_or((StatusCode::PRECONDITION_FAILED, "no lock to refresh".to_string()))?;
+            lock.timeout_secs = timeout_secs;
+            lock.expires = Instant::now() + Duration::from_secs(timeout_secs);
+            let xml = self.lock_discovery(token, lock);
+            return Ok(
+                (StatusCode::OK, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], xml).into_response(),
+            );
+        }
+
+        let exclusive = !xml_element(&body, "lockscope").is_some_and(|scope| scope.contains("shared"));
+        let owner = match xml_element(&body, "owner") {
+            Some(owner) => xml_element(owner, "href").unwrap_or(owner).trim().to_string(),
+            None => String::new(),
+        };
+        let infinite_depth = self.header("Depth") != Some("0");
+        let lock = DavLock {
+            vault_id: self.vault.vault_id,
+            path: relative.to_path_buf(),
+            exclusive,
+            infinite_depth,
+            owner,
+            timeout_secs,
+            expires: Instant::now() + Duration::from_secs(timeout_secs),
+        };
+        let conflicts = self
+            .locks
+            .find(self.vault.vault_id, relative, infinite_depth)
+            .into_iter()
+            .any(|(_, existing)| existing.exclusive || exclusive);
+        if conflicts {
+            return Err((StatusCode::LOCKED, format!("{:?} is already locked", relative)));
+        }
+
+        // locking a path that doesn't exist creates an empty file
+        let full_path = self.vault.abs_path.join(relative);
+        let created = !full_path.exists();
+        if created {
+            self.check_parent(&full_path)?;
+            file_browser::save_stored_file(&self.pool, &self.cipher, &self.vault, relative, &[], "uploaded through WebDAV")
+                .await?;
+        }
+
+        let token = lock_token();
+        let xml = self.lock_discovery(&token, &lock);
+        self.locks.0.lock().unwrap().insert(token.clone(), lock);
+        let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+        Ok((
+            status,
+            [
+                (header::CONTENT_TYPE, "application/xml; charset=utf-8".to_string()),
+                (header::HeaderName::from_static("lock-token"), format!("<{token}>")),
+            ],
+            xml,
+        )
+            .into_response())
+    }
+
+    fn unlock(&self, relative: &Path) -> Result<Response, ApiError> {
+        let token = self
+            .header("Lock-Token")
+            .map(|token| token.trim_matches(|c| c == '<' || c == '>'))
+            .ok_or((StatusCode::BAD_REQUEST, "the Lock-Token header is missing".to_string()))?;
+        let mut locks = self.locks.0.lock().unwrap();
+        match locks.get(token) {
+            Some(lock) if lock.covers(self.vault.vault_id, relative) => {
+                locks.remove(token);
+                Ok(StatusCode::NO_CONTENT.into_response())
+            }
+            _ => Err((StatusCode::CONFLICT, format!("{:?} isn't locked with {token}", relative))),
+        }
+    }
+
+    /// Deletes a file or directory and removes its files from file_metadata, the removal is kept in their history
+    async fn remove(&self, relative: &Path) -> Result<(), ApiError> {
+        let full_path = self.vault.abs_path.join(relative);
+        self.add_history(&full_path, "deleted through WebDAV").await?;
+        match full_path.is_dir() {
+            true => fs::remove_dir_all(&full_path),
+            false => fs::remove_file(&full_path),
+        }
+        .map_err(internal_error)?;
+        server_db_api::remove_stored_files(&self.pool, &full_path)
+            .await
+            .map_err(internal_error)
+    }
+
+    /// Adds event to the history of a file, or of every file under a directory
+    async fn add_history(&self, full_path: &Path, event: &str) -> Result<(), ApiError> {
+        for file in files_under(full_path).map_err(internal_error)? {
+            let metadata = fs::metadata(&file).map_err(internal_error)?;
+            let file_size = storage_crypto::plaintext_len(&file).map_err(internal_error)? as i64;
+            let relative = file.strip_prefix(&self.vault.abs_path).map_err(internal_error)?;
+            server_db_api::add_file_history(
+                &self.pool,
+                self.vault.vault_id,
+                relative,
+                event,
+                modified_secs(&metadata),
+                file_size,
+            )
+            .await
+            .map_err(internal_error)?;
+        }
+        Ok(())
+    }
+
+    /// A copy adds every file under source to the vault, a move doesn't change what the vault stores
+    async fn check_copy_quota(&self, source: &Path, target: &Path) -> Result<(), ApiError> {
+        let files = match self.header("Depth") == Some("0") && source.is_dir() {
+            true => vec![],
+            false => files_under(source).map_err(internal_error)?,
+        };
+        let mut writes = vec![];
+        for file in files {
+            // joining an empty path would add a trailing slash when source is a file
+            let copied = match file.strip_prefix(source) {
+                Ok(rest) if !rest.as_os_str().is_empty() => target.join(rest),
+                _ => target.to_path_buf(),
+            };
+            let file_size = storage_crypto::plaintext_len(&file).map_err(internal_error)? as i64;
+            writes.push((self.vault.vault_id, copied, file_size));
+        }
+        quota_api::check_writes(&self.pool, &writes).await
+    }
+
+    fn check_writable(&self) -> Result<(), ApiError> {
+        match self.read_only {
+            true => Err((StatusCode::FORBIDDEN, format!("vault {} is read-only", self.vault.vault_id))),
+            false => Ok(()),
+        }
+    }
+
+    /// MOVE and COPY can't put files where the vault ignores them, the same as PUT
+    /// Files inside a directory are checked at the path they'd have under the destination
+    fn check_destination_not_ignored(&self, source: &Path, target: &Path, destination: &Path) -> Result<(), ApiError> {
+        let rules = IgnoreRules::for_vault(&self.vault.abs_path, &self.default_ignore);
+        let ignored = rules.is_ignored_in_vault(target, &self.vault.abs_path, source.is_dir())
+            || files_under(source)
+                .map_err(internal_error)?
+                .iter()
+                .filter_map(|file| file.strip_prefix(source).ok())
+                .any(|inside| rules.is_ignored_in_vault(&target.join(inside), &self.vault.abs_path, false));
+        match ignored {
+            true => Err((StatusCode::FORBIDDEN, format!("{:?} is ignored in this vault", destination))),
+            false => Ok(()),
+        }
+    }
+
+    fn check_parent(&self, full_path: &Path) -> Result<(), ApiError> {
+        match full_path.parent().is_some_and(Path::is_dir) {
+            true => Ok(()),
+            false => Err((StatusCode::CONFLICT, "the parent directory does not exist".to_string())),
+        }
+    }
+
+    /// A locked path can only be changed by a request that sends the lock token in its If header
+    fn check_unlocked(&self, relative: &Path, whole_tree: bool) -> Result<(), ApiError> {
+        let if_header = self.header("If").unwrap_or_default();
+        self.locks.check_unlocked(self.vault.vault_id, relative, whole_tree, if_header)
+    }
+
+    /// Seconds from the Timeout header eg: "Second-600", locks last at most MAX_LOCK_SECS
+    fn lock_timeout(&self) -> u64 {
+        self.header("Timeout")
+            .and_then(|timeout| timeout.split(',').next())
+            .and_then(|timeout| timeout.trim().strip_prefix("Second-"))
+            .and_then(|secs| secs.parse().ok())
+            .map_or(MAX_LOCK_SECS, |secs: u64| secs.min(MAX_LOCK_SECS))
+    }
+
+    fn lock_discovery(&self, token: &str, lock: &DavLock) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+            self.active_lock(token, lock)
+        )
+    }
+
+    fn active_lock(&self, token: &str, lock: &DavLock) -> String {
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{}</D:lockscope>\
+            <D:depth>{}</D:depth><D:owner>{}</D:owner><D:timeout>Second-{}</D:timeout>\
+            <D:locktoken><D:href>{token}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot>\
+            </D:activelock>",
+            if lock.exclusive { "<D:exclusive/>" } else { "<D:shared/>" },
+            if lock.infinite_depth { "infinity" } else { "0" },
+            xml_escape(&lock.owner),
+            lock.timeout_secs,
+            self.href(&lock.path, self.vault.abs_path.join(&lock.path).is_dir()),
+        )
+    }
+
+    /// Collections end in / so clients resolve the paths of their members correctly
+    fn href(&self, relative: &Path, is_dir: bool) -> String {
+        let link = file_browser::link("dav", self.vault.vault_id, relative);
+        match is_dir {
+            true => link + "/",
+            false => link,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+fn created_or_replaced(existed: bool) -> Response {
+    match existed {
+        true => StatusCode::NO_CONTENT.into_response(),
+        false => StatusCode::CREATED.into_response(),
+    }
+}
+
+/// The path inside the vault of a Destination header, which is usually a full url
+fn destination_path(destination: &str, vault_id: i32) -> Result<PathBuf, ApiError> {
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => destination,
+    };
+    let path = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    match path.strip_prefix(&format!("/dav/{vault_id}")) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => relative_path(rest),
+        _ => Err((StatusCode::FORBIDDEN, "files can only be moved or copied inside their vault".to_string())),
+    }
+}
+
+/// A file, or every file under a directory
+fn files_under(full_path: &Path) -> io::Result<Vec<PathBuf>> {
+    match full_path.is_dir() {
+        true => file_utils::get_all_files_from_path(&full_path.to_path_buf()),
+        false => Ok(vec![full_path.to_path_buf()]),
+    }
+}
+
+/// Copies a file, or a directory and everything in it unless depth_zero is set
+/// Stored files are copied as they are, encrypted files stay encrypted with the same key
+fn copy_tree(source: &Path, target: &Path, depth_zero: bool) -> io::Result<()> {
+    if source.is_file() {
+        fs::copy(source, target)?;
+        return Ok(());
+    }
+    fs::create_dir(target)?;
+    if depth_zero {
+        return Ok(());
+    }
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &target.join(entry.file_name()), false)?;
+    }
+    Ok(())
+}
+
+fn lock_token() -> String {
+    let hex = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    format!("opaquelocktoken:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Contents of the first element called name in a request body, namespace prefixes are ignored
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    fn local_name(tag: &str) -> &str {
+        let tag = tag.split(|c: char| c.is_whitespace() || c == '>' || c == '/').next().unwrap_or_default();
+        tag.rsplit(':').next().unwrap_or(tag)
+    }
+
+    let mut content_start = None;
+    for (index, _) in xml.match_indices('<') {
+        let tag = &xml[index + 1..];
+        match (content_start, tag.strip_prefix('/')) {
+            (None, None) if local_name(tag) == name => {
+                let end = tag.find('>')?;
+                if tag[..end].ends_with('/') {
+                    return Some("");
+                }
+                content_start = Some(index + end + 2);
+            }
+            (Some(start), Some(closing)) if local_name(closing) == name => return Some(&xml[start..index]),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Formats seconds since unix epoch as an http date eg: "Tue, 14 Nov 2023 22:13:20 GMT"
+fn http_date(secs: i64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second) = civil_time(secs);
+    // 1970-01-01 was a Thursday
+    let weekday = WEEKDAYS[secs.div_euclid(60 * 60 * 24).rem_euclid(7) as usize];
+    let month = MONTHS[month as usize - 1];
+    format!("{weekday}, {day:02} {month} {year} {hour:02}:{minute:02}:{second:02} GMT")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server_sync_core, users_api};
+    use axum::body::HttpBody;
+    use axum::http::HeaderValue;
+    use axum::Json;
+    use common::file_utils::{FileMetadata, MetadataBlob, VaultMetadata};
+    use common::merge_utils::MergeRequest;
+    use common::RemoteFile;
+    use sqlx::Row;
+
+    async fn test_state(storage: &Path) -> Arc<Mutex<ApiState>> {
+        let pool = server_db_api::test_pool().await;
+        fs::create_dir_all(storage.join("papers/drafts")).unwrap();
+        sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (0, ?, 'papers', 5);")
+            .bind(storage.join("papers").to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        Arc::new(Mutex::new(ApiState {
+            client_requested: HashMap::new(),
+            pool,
+            storage_cipher: StorageCipher::from_key(&[7; 32]),
+            default_ignore: vec![],
+            dav_locks: DavLocks::default(),
+        }))
+    }
+
+    async fn request(
+        state: &Arc<Mutex<ApiState>>,
+        method: &str,
+        path: &str,
+        headers: &[(&'static str, &str)],
+        body: &str,
+    ) -> Result<Response, ApiError> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        let method = Method::from_bytes(method.as_bytes()).unwrap();
+        let admin = CurrentUser { user_id: 1, name: "admin".to_string(), is_admin: true };
+        dav(state.clone(), &admin, 0, path, method, header_map, Bytes::from(body.to_string())).await
+    }
+
+    async fn body_text(response: Response) -> String {
+        let mut body = response.into_body();
+        let mut text = vec![];
+        while let Some(chunk) = body.data().await {
+            text.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(text).unwrap()
+    }
+
+    async fn stored_paths(state: &Arc<Mutex<ApiState>>) -> Vec<String> {
+        let pool = state.lock().await.pool.clone();
+        sqlx::query("select file_path from file_metadata order by file_path;")
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get::<String, _>(0).rsplit("papers/").next().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_changes_update_file_metadata() {
+        let storage = tempfile::tempdir().unwrap();
+        let state = test_state(storage.path()).await;
+        let vault_root = storage.path().join("papers");
+
+        let put = request(&state, "PUT", "drafts/intro.txt", &[], "hello").await.unwrap();
+        assert_eq!(put.status(), StatusCode::CREATED);
+        let overwrite = request(&state, "PUT", "drafts/intro.txt", &[], "hello again").await.unwrap();
+        assert_eq!(overwrite.status(), StatusCode::NO_CONTENT);
+        assert_eq!(request(&state, "MKCOL", "final", &[], "").await.unwrap().status(), StatusCode::CREATED);
+        let orphan = request(&state, "PUT", "missing/intro.txt", &[], "hello").await.unwrap_err();
+        assert_eq!(orphan.0, StatusCode::CONFLICT);
+
+        let copy = request(&state, "COPY", "drafts", &[("Destination", "http://localhost:3000/dav/0/final/v1")], "")
+            .await
+            .unwrap();
+        assert_eq!(copy.status(), StatusCode::CREATED);
+        let moved = request(&state, "MOVE", "drafts/intro.txt", &[("Destination", "/dav/0/final/intro%20v2.txt")], "")
+            .await
+            .unwrap();
+        assert_eq!(moved.status(), StatusCode::CREATED);
+        let cipher = StorageCipher::from_key(&[7; 32]);
+        assert_eq!(cipher.read_file(&vault_root.join("final/v1/intro.txt")).unwrap(), b"hello again");
+        assert_eq!(stored_paths(&state).await, vec!["final", "final/intro v2.txt", "final/v1/intro.txt"]);
+
+        let kept = request(&state, "MOVE", "final/v1/intro.txt", &[("Destination", "/dav/0/final/intro%20v2.txt"), ("Overwrite", "F")], "")
+            .await
+            .unwrap_err();
+        assert_eq!(kept.0, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(request(&state, "DELETE", "final/v1", &[], "").await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert!(!vault_root.join("final/v1").exists());
+        assert_eq!(stored_paths(&state).await, vec!["final", "final/intro v2.txt"]);
+
+        let pool = state.lock().await.pool.clone();
+        let history = server_db_api::get_file_history(&pool, 0, Path::new("final/v1/intro.txt")).await.unwrap();
+        let events = history.iter().map(|entry| entry.event.as_str()).collect::<Vec<_>>();
+        assert_eq!(events, vec!["deleted through WebDAV", "copied through WebDAV"]);
+    }
+
+    #[tokio::test]
+    async fn test_propfind_lists_one_level() {
+        let storage = tempfile::tempdir().unwrap();
+        let state = test_state(storage.path()).await;
+        request(&state, "PUT", "drafts/a & b.txt", &[], "hello").await.unwrap();
+
+        let response = request(&state, "PROPFIND", "", &[("Depth", "1")], "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let xml = body_text(response).await;
+        assert!(xml.contains("<D:href>/dav/0/</D:href>"));
+        assert!(xml.contains("<D:href>/dav/0/drafts/</D:href>"));
+        assert!(!xml.contains("a &amp; b.txt"));
+
+        let response = request(&state, "PROPFIND", "drafts/a & b.txt", &[("Depth", "0")], "").await.unwrap();
+        let xml = body_text(response).await;
+        assert!(xml.contains("<D:href>/dav/0/drafts/a%20%26%20b.txt</D:href>"));
+        assert!(xml.contains("<D:displayname>a &amp; b.txt</D:displayname>"));
+        assert!(xml.contains("<D:getcontentlength>5</D:getcontentlength>"));
+
+        let infinite = request(&state, "PROPFIND", "", &[], "").await.unwrap();
+        assert_eq!(infinite.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_lock_blocks_other_writers() {
+        let storage = tempfile::tempdir().unwrap();
+        let state = test_state(storage.path()).await;
+        let body = "<?xml version=\"1.0\"?><a:lockinfo xmlns:a=\"DAV:\"><a:lockscope><a:exclusive/></a:lockscope>\
+            <a:locktype><a:write/></a:locktype><a:owner><a:href>mailto:sam@example.com</a:href></a:owner></a:lockinfo>";
+
+        let lock = request(&state, "LOCK", "drafts/report.txt", &[("Timeout", "Second-600")], body).await.unwrap();
+        assert_eq!(lock.status(), StatusCode::CREATED);
+        let token = lock.headers()["lock-token"].to_str().unwrap().to_string();
+        let if_header = format!("({token})");
+
+        let blocked = request(&state, "PUT", "drafts/report.txt", &[], "mine").await.unwrap_err();
+        assert_eq!(blocked.0, StatusCode::LOCKED);
+        let parent = request(&state, "DELETE", "drafts", &[], "").await.unwrap_err();
+        assert_eq!(parent.0, StatusCode::LOCKED);
+        let second = request(&state, "LOCK", "drafts", &[], body).await.unwrap_err();
+        assert_eq!(second.0, StatusCode::LOCKED);
+        let owner = request(&state, "PUT", "drafts/report.txt", &[("If", &if_header)], "mine").await.unwrap();
+        assert_eq!(owner.status(), StatusCode::NO_CONTENT);
+
+        let unlock = request(&state, "UNLOCK", "drafts/report.txt", &[("Lock-Token", &token)], "").await.unwrap();
+        assert_eq!(unlock.status(), StatusCode::NO_CONTENT);
+        let after = request(&state, "PUT", "drafts/report.txt", &[], "anyone").await.unwrap();
+        assert_eq!(after.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_lock_blocks_clients_syncing() {
+        let storage = tempfile::tempdir().unwrap();
+        let state = test_state(storage.path()).await;
+        let body = "<D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope></D:lockinfo>";
+        request(&state, "PUT", "drafts/report.txt", &[], "draft").await.unwrap();
+        request(&state, "LOCK", "drafts/report.txt", &[], body).await.unwrap();
+
+        // clients can't send the lock token so every sync write to the locked file is refused
+        let admin = CurrentUser { user_id: 1, name: "admin".to_string(), is_admin: true };
+        let client_root = PathBuf::from("/home/admin/papers");
+        let upload = RemoteFile {
+            full_path: client_root.join("drafts/report.txt"),
+            root_directory: "papers".to_string(),
+            absolute_root_dir: client_root.clone(),
+            contents: b"from the laptop".to_vec(),
+            vault_id: 0,
+            file_id: -1,
+            modified_time: 1,
+            modified_nanos: 0,
+            mode: None,
+        };
+        let files = Json(vec![upload]);
+        let response = server_sync_core::receive_files_from_client(State(state.clone()), admin.clone(), files).await;
+        assert_eq!(response.into_response().status(), StatusCode::LOCKED);
+
+        let merge = MergeRequest {
+            vault_id: 0,
+            relative_path: PathBuf::from("drafts/report.txt"),
+            version: Default::default(),
+            contents: b"from the laptop".to_vec(),
+        };
+        let merged = server_sync_core::merge_file_from_client(State(state.clone()), admin.clone(), Json(merge)).await;
+        assert_eq!(merged.unwrap_err().0, StatusCode::LOCKED);
+
+        let mut drafts = FileMetadata::new_from_client(
+            client_root.join("drafts"),
+            "papers".to_string(),
+            client_root.clone(),
+            1,
+            0,
+            0,
+            -1,
+        );
+        drafts.is_dir = true;
+        let deleted = MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata { files: vec![drafts], vault_id: 0, ..Default::default() })]),
+        };
+        let response = server_sync_core::delete_files_from_client(State(state.clone()), admin, Json(deleted)).await;
+        assert_eq!(response.into_response().status(), StatusCode::LOCKED);
+
+        let cipher = state.lock().await.storage_cipher.clone();
+        assert_eq!(cipher.read_file(&storage.path().join("papers/drafts/report.txt")).unwrap(), b"draft");
+    }
+
+    #[tokio::test]
+    async fn test_move_and_copy_respect_ignore_rules() {
+        let storage = tempfile::tempdir().unwrap();
+        let state = test_state(storage.path()).await;
+        state.lock().await.default_ignore = vec!["*.tmp".to_string(), "build/".to_string()];
+        request(&state, "PUT", "drafts/intro.txt", &[], "hello").await.unwrap();
+
+        let renamed = request(&state, "MOVE", "drafts/intro.txt", &[("Destination", "/dav/0/drafts/intro.tmp")], "")
+            .await
+            .unwrap_err();
+        assert_eq!(renamed.0, StatusCode::FORBIDDEN);
+        let into_ignored = request(&state, "COPY", "drafts", &[("Destination", "/dav/0/build")], "").await.unwrap_err();
+        assert_eq!(into_ignored.0, StatusCode::FORBIDDEN);
+        assert!(!storage.path().join("papers/build").exists());
+        assert!(storage.path().join("papers/drafts/intro.txt").exists());
+
+        let allowed = request(&state, "COPY", "drafts", &[("Destination", "/dav/0/final")], "").await.unwrap();
+        assert_eq!(allowed.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_readers_cannot_write() {
+        let storage = tempfile::tempdir().unwrap();
+        let state = test_state(storage.path()).await;
+        let pool = state.lock().await.pool.clone();
+        sqlx::query("INSERT INTO users (user_id, name, token_hash) VALUES (2, 'sam', 'hash');")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let sam = CurrentUser { user_id: 2, name: "sam".to_string(), is_admin: false };
+        let put = |user: CurrentUser| {
+            let state = state.clone();
+            async move { dav(state, &user, 0, "drafts/a.txt", Method::PUT, HeaderMap::new(), Bytes::from("a")).await }
+        };
+
+        assert_eq!(put(sam.clone()).await.unwrap_err().0, StatusCode::NOT_FOUND);
+        users_api::set_role(&pool, 0, 2, Some(Role::ReadOnly)).await.unwrap();
+        assert_eq!(put(sam.clone()).await.unwrap_err().0, StatusCode::FORBIDDEN);
+        let mut infinite = HeaderMap::new();
+        infinite.insert("Depth", HeaderValue::from_static("infinity"));
+        let body = Bytes::from("<D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope></D:lockinfo>");
+        let lock = dav(state.clone(), &sam, 0, "", Method::from_bytes(b"LOCK").unwrap(), infinite, body);
+        assert_eq!(lock.await.unwrap_err().0, StatusCode::FORBIDDEN);
+        assert!(state.lock().await.dav_locks.0.lock().unwrap().is_empty());
+        let mut depth = HeaderMap::new();
+        depth.insert("Depth", HeaderValue::from_static("0"));
+        let listing = dav(state.clone(), &sam, 0, "", Method::from_bytes(b"PROPFIND").unwrap(), depth, Bytes::new());
+        assert_eq!(listing.await.unwrap().status(), StatusCode::MULTI_STATUS);
+        users_api::set_role(&pool, 0, 2, Some(Role::ReadWrite)).await.unwrap();
+        assert_eq!(put(sam).await.unwrap().status(), StatusCode::CREATED);
+    }
+
+    #[test]
+    fn test_request_parsing() {
+        let body = "<D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:shared/></D:lockscope>\
+            <D:owner>\n<D:href>sam</D:href>\n</D:owner></D:lockinfo>";
+        assert_eq!(xml_element(body, "lockscope"), Some("<D:shared/>"));
+        assert_eq!(xml_element(body, "shared"), Some(""));
+        assert_eq!(xml_element(xml_element(body, "owner").unwrap(), "href"), Some("sam"));
+        assert_eq!(xml_element(body, "locktype"), None);
+
+        assert_eq!(destination_path("https://example.com:3000/dav/2/a%20b/c.txt", 2).unwrap(), PathBuf::from("a b/c.txt"));
+        assert_eq!(destination_path("/dav/2/", 2).unwrap(), PathBuf::new());
+        assert_eq!(destination_path("/dav/23/c.txt", 2).unwrap_err().0, StatusCode::FORBIDDEN);
+        assert_eq!(destination_path("/dav/2/../3/c.txt", 2).unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(http_date(1_700_000_000), "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+}
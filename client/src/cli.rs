@@ -0,0 +1,302 @@
+use crate::client_db_api::LogEntry;
+use crate::conflicts::Keep;
+use crate::control::DaemonStatus;
+use crate::sync_plan::SyncPlan;
+use clap::{Parser, Subcommand, ValueEnum};
+use common::config_utils::VaultInfo;
+use common::file_utils::{ConflictStrategy, MetadataDiff, SymlinkPolicy, SyncDirection, VaultMetadata};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Exit codes for scripts, clap exits with 2 for invalid arguments
+pub const EXIT_OK: i32 = 0;
+/// The command failed, the reason is printed
+pub const EXIT_ERROR: i32 = 1;
+/// The server could not be reached
+pub const EXIT_SERVER_UNREACHABLE: i32 = 3;
+/// `status` and `diff` when there are changes waiting to be synced
+pub const EXIT_PENDING_CHANGES: i32 = 4;
+
+/// Sync your files between your devices
+/// Settings are read from ./client/.env, running without a command does a single sync
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Syncs every linked vault once
+    Sync {
+        /// Prints what the sync would do without changing anything locally or on the server
+        #[arg(long)]
+        dry_run: bool,
+        /// Format of the dry run plan
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Syncs, then keeps syncing whenever files change or the vault's sync frequency passes
+    #[command(alias = "daemon")]
+    Watch,
+    /// Shows how many uploads and downloads are pending for each vault
+    /// If a daemon is running its current sync and transfers are shown instead
+    Status,
+    /// Prints the files that would be uploaded and downloaded without syncing them
+    Diff {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Lists, links and unlinks vaults
+    #[command(subcommand)]
+    Vault(VaultCommand),
+    /// Stops syncing a vault, or every vault if no id is given
+    Pause { vault_id: Option<i32> },
+    /// Starts syncing a paused vault again, or every vault if no id is given
+    Resume { vault_id: Option<i32> },
+    /// Shows the most recent sync activity
+    Log {
+        /// Number of entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: i64,
+        /// Only shows failed syncs
+        #[arg(long)]
+        errors: bool,
+    },
+    /// Only syncs the given subdirectory of a vault, path is relative to the vault root
+    Select { vault_id: i32, path: PathBuf },
+    /// Stops syncing a subdirectory selected with `select`
+    Unselect { vault_id: i32, path: PathBuf },
+    /// Writes placeholders for new files instead of downloading them
+    OnDemand { vault_id: i32, state: Toggle },
+    /// Sets which way files are synced for a vault
+    Direction {
+        vault_id: i32,
+        #[arg(value_parser = parse_direction)]
+        direction: SyncDirection,
+    },
+    /// Sets how symbolic links in a vault are synced: skip, link or follow
+    Symlinks {
+        vault_id: i32,
+        #[arg(value_parser = parse_symlink_policy)]
+        policy: SymlinkPolicy,
+    },
+    /// Sets how conflicting files in a vault are settled: newest, server, client, keep-both or manual
+    Conflicts {
+        vault_id: i32,
+        #[arg(value_parser = parse_conflict_strategy)]
+        strategy: ConflictStrategy,
+    },
+    /// Resolves a pending conflict by keeping the local or the server copy, path is relative to the vault root
+    Resolve {
+        vault_id: i32,
+        path: PathBuf,
+        #[arg(value_enum)]
+        keep: Keep,
+    },
+    /// Downloads the file behind a placeholder
+    Fetch { path: PathBuf },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VaultCommand {
+    /// Lists the vaults on the server and where they are linked on this device
+    List,
+    /// Links a server vault to a local directory and runs its first sync
+    Add { vault_id: i32, local_dir: PathBuf },
+    /// Unlinks a vault, the local files are left in place
+    Remove { vault_id: i32 },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+fn parse_direction(s: &str) -> Result<SyncDirection, String> {
+    s.parse()
+}
+
+fn parse_symlink_policy(s: &str) -> Result<SymlinkPolicy, String> {
+    s.parse()
+}
+
+fn parse_conflict_strategy(s: &str) -> Result<ConflictStrategy, String> {
+    s.parse()
+}
+
+/// Picks the exit code for an error, connection errors get their own code so scripts can retry
+pub fn exit_code_for(error: &(dyn Error + 'static)) -> i32 {
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_connect() || e.is_timeout() => EXIT_SERVER_UNREACHABLE,
+        _ => EXIT_ERROR,
+    }
+}
+
+fn file_count(vaults: &HashMap<i32, VaultMetadata>, vault_id: i32) -> usize {
+    vaults.get(&vault_id).map_or(0, |vault| vault.files.len())
+}
+
+/// Prints the pending uploads and downloads of every linked vault
+/// Returns true if anything is waiting to be synced
+pub fn print_status(diff: &MetadataDiff, vaults: &[(i32, PathBuf)], paused: &HashSet<i32>) -> bool {
+    let mut pending = false;
+    for (vault_id, root) in vaults {
+        if paused.contains(vault_id) {
+            println!("vault {vault_id} ({}): paused", root.display());
+            continue;
+        }
+        let uploads = file_count(&diff.new_for_server, *vault_id);
+        let downloads = file_count(&diff.new_for_client, *vault_id);
+        let blocked = file_count(&diff.blocked_for_server, *vault_id);
+        let conflicts = diff.conflicts.get(vault_id).map_or(0, |c| c.len());
+        pending |= uploads + downloads + conflicts > 0;
+
+        print!("vault {vault_id} ({}): {uploads} to upload, {downloads} to download", root.display());
+        if blocked > 0 {
+            print!(", {blocked} held back by the sync direction");
+        }
+        if conflicts > 0 {
+            print!(", {conflicts} in conflict");
+        }
+        println!();
+    }
+    pending
+}
+
+/// Prints the state of a running daemon, returns true if a sync is running
+pub fn print_daemon_status(status: &DaemonStatus) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    match (status.syncing, status.last_sync) {
+        (true, _) => println!("daemon: syncing"),
+        (false, Some(time)) => println!("daemon: idle, last sync {}", format_age(now - time)),
+        (false, None) => println!("daemon: idle, not synced yet"),
+    }
+    if let Some(error) = &status.last_error {
+        println!("last sync failed: {error}");
+    }
+    if !status.paused_vaults.is_empty() {
+        println!("paused vaults: {:?}", status.paused_vaults);
+    }
+    for file in status.transfers.iter() {
+        println!(
+            "  vault {}\t{:<16} {} ({} bytes)",
+            file.vault_id,
+            file.action.label(),
+            file.path.display(),
+            file.file_size
+        );
+    }
+    status.syncing
+}
+
+/// Prints a sync plan as text or JSON
+pub fn print_plan(plan: &SyncPlan, format: OutputFormat) {
+    match format {
+        OutputFormat::Text => print!("{plan}"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(plan).unwrap()),
+    }
+}
+
+/// Prints the vaults on the server and where they are linked on this device
+pub fn print_vaults(vaults: &[VaultInfo], linked: &[(i32, PathBuf)]) {
+    for vault in vaults {
+        let read_only = if vault.read_only { " (read-only)" } else { "" };
+        match linked.iter().find(|(id, _)| *id == vault.vault_id) {
+            Some((_, path)) => println!("{}\t{}{read_only}\tlinked to {}", vault.vault_id, vault.root_dir, path.display()),
+            None => println!("{}\t{}{read_only}\tnot linked", vault.vault_id, vault.root_dir),
+        }
+    }
+}
+
+/// Prints log entries, oldest first
+pub fn print_log(entries: &[LogEntry]) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    for entry in entries.iter().rev() {
+        let vault = entry
+            .vault_id
+            .map_or("-".to_string(), |id| format!("vault {id}"));
+        println!("{}\t{}\t{vault}\t{}", format_age(now - entry.time), entry.action, entry.detail);
+    }
+}
+
+/// Formats a number of seconds as a short age eg: 90 becomes "1m ago"
+pub fn format_age(secs: i64) -> String {
+    match secs.max(0) {
+        s if s < 60 => format!("{s}s ago"),
+        s if s < 60 * 60 => format!("{}m ago", s / 60),
+        s if s < 60 * 60 * 24 => format!("{}h ago", s / (60 * 60)),
+        s => format!("{}d ago", s / (60 * 60 * 24)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_subcommands() {
+        let cli = Cli::try_parse_from(["client", "vault", "add", "2", "./papers"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Vault(VaultCommand::Add { vault_id: 2, .. }))
+        ));
+
+        let cli = Cli::try_parse_from(["client", "direction", "1", "download-only"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Direction { vault_id: 1, direction: SyncDirection::DownloadOnly })
+        ));
+
+        let cli = Cli::try_parse_from(["client", "symlinks", "1", "follow"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Symlinks { vault_id: 1, policy: SymlinkPolicy::Follow })
+        ));
+
+        let cli = Cli::try_parse_from(["client", "conflicts", "1", "keep-both"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Conflicts { vault_id: 1, strategy: ConflictStrategy::KeepBoth })
+        ));
+
+        let cli = Cli::try_parse_from(["client", "resolve", "1", "notes.txt", "server"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Resolve { vault_id: 1, keep: Keep::Server, .. })));
+
+        let cli = Cli::try_parse_from(["client", "sync", "--dry-run", "--format", "json"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Sync { dry_run: true, format: OutputFormat::Json })
+        ));
+
+        let cli = Cli::try_parse_from(["client", "daemon"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Watch)));
+
+        assert!(Cli::try_parse_from(["client"]).unwrap().command.is_none());
+        assert!(Cli::try_parse_from(["client", "direction", "1", "sideways"]).is_err());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(5), "5s ago");
+        assert_eq!(format_age(90), "1m ago");
+        assert_eq!(format_age(2 * 60 * 60), "2h ago");
+        assert_eq!(format_age(3 * 24 * 60 * 60), "3d ago");
+    }
+}
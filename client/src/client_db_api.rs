@@ -1,10 +1,18 @@
 use common::common_db_utils::upsert_database;
-use common::file_utils::{FileMetadata, MetadataBlob, ServerPresent, VaultMetadata};
-use common::{file_utils};
-use sqlx::sqlite::{ SqlitePoolOptions, SqliteRow};
+use common::file_utils::{
+    compare_times, ConflictStrategy, FileMetadata, MetadataBlob, ServerPresent, SymlinkPolicy, SyncDirection,
+    SyncedEntry, VaultMetadata,
+};
+use common::{common_db_utils, file_utils};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow};
 use sqlx::{Pool, Row, Sqlite};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use common::version_vector::VersionVector;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Component, Path, PathBuf};
 
 pub async fn init_db(db_url: String) -> Result<Pool<Sqlite>, sqlx::Error> {
     let pool = SqlitePoolOptions::new()
@@ -12,11 +20,615 @@ pub async fn init_db(db_url: String) -> Result<Pool<Sqlite>, sqlx::Error> {
         .connect(db_url.as_str())
         .await?;
 
-    println!("Pool loaded");
-
     Ok(pool)
 }
 
+/// Copies every table of the client db into an in memory db, so a dry run can rescan and plan
+/// without changing client.db
+pub async fn copy_to_memory(pool: &Pool<Sqlite>) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let db_file = sqlx::query("select file from pragma_database_list where name == 'main';")
+        .fetch_one(pool)
+        .await?
+        .get::<String, _>(0);
+    // sqlx's sqlite::memory: would open the attached db in memory as well so sqlite's own :memory: is used,
+    // with one connection that is kept open as each connection to it is a separate db
+    let memory = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(SqliteConnectOptions::new().filename(":memory:"))
+        .await?;
+
+    sqlx::query("ATTACH DATABASE ? AS disk;")
+        .bind(&db_file)
+        .execute(&memory)
+        .await?;
+    let tables = sqlx::query(
+        "select name, sql from disk.sqlite_master where type == 'table' AND name NOT LIKE 'sqlite_%';",
+    )
+    .fetch_all(&memory)
+    .await?;
+    for table in tables {
+        let name = table.get::<String, _>(0);
+        sqlx::query(&table.get::<String, _>(1)).execute(&memory).await?;
+        sqlx::query(&format!("INSERT INTO main.\"{name}\" SELECT * FROM disk.\"{name}\";"))
+            .execute(&memory)
+            .await?;
+    }
+    sqlx::query("DETACH DATABASE disk;").execute(&memory).await?;
+    Ok(memory)
+}
+
+/// Creates the client only tables if they don't exist yet
+/// selected_paths holds the subdirectories of a vault that are synced, relative to the vault root
+/// A vault with no rows in selected_paths is synced in full
+/// vault_settings holds the client side settings of a vault, a vault without a row uses the defaults
+///     on_demand - 1 if files not on disk get a placeholder instead of being downloaded
+///     sync_direction - two_way, upload_only or download_only
+///     paused - 1 if the vault is skipped when syncing
+///     symlinks - skip, link or follow, how symbolic links in the vault are synced
+///     conflicts - newest, server, client, keep_both or manual, how conflicting files are settled
+/// sync_log holds what each sync did, action is one of upload, download, placeholder, blocked, conflict, merged,
+///     conflict_copy, resolved, delete, server_delete, chmod, server_chmod, clock_skew or error
+///     vault_id is NULL for errors that aren't specific to a vault
+/// synced_entries holds every path that was the same on the client and the server after the last sync
+///     with its modified time and version, used to tell deleted files from new ones and local changes
+/// device holds the id this client counts its changes under in version vectors, made on first use
+pub async fn create_tables(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS selected_paths
+    (
+    vault_id       INTEGER NOT NULL,
+    relative_path  TEXT    NOT NULL,
+    UNIQUE (vault_id, relative_path)
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS vault_settings
+    (
+    vault_id       INTEGER PRIMARY KEY NOT NULL,
+    on_demand      INTEGER             NOT NULL DEFAULT 0
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    common_db_utils::add_column_if_missing(
+        pool,
+        "vault_settings",
+        "sync_direction",
+        "TEXT NOT NULL DEFAULT 'two_way'",
+    )
+    .await?;
+    common_db_utils::add_column_if_missing(pool, "vault_settings", "paused", "INTEGER NOT NULL DEFAULT 0")
+        .await?;
+    common_db_utils::add_column_if_missing(pool, "vault_settings", "symlinks", "TEXT NOT NULL DEFAULT 'skip'")
+        .await?;
+    common_db_utils::add_column_if_missing(pool, "vault_settings", "conflicts", "TEXT NOT NULL DEFAULT 'newest'")
+        .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sync_log
+    (
+    log_id         INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    time           BIGINT                            NOT NULL,
+    vault_id       INTEGER,
+    action         TEXT                              NOT NULL,
+    detail         TEXT                              NOT NULL
+    );",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS synced_entries
+    (
+    vault_id       INTEGER NOT NULL,
+    relative_path  TEXT    NOT NULL,
+    modified_time  BIGINT  NOT NULL,
+    modified_nanos INTEGER NOT NULL DEFAULT 0,
+    mode           INTEGER,
+    version        TEXT,
+    PRIMARY KEY (vault_id, relative_path)
+    );",
+    )
+    .execute(pool)
+    .await?;
+    common_db_utils::add_column_if_missing(pool, "synced_entries", "mode", "INTEGER").await?;
+    common_db_utils::add_column_if_missing(pool, "synced_entries", "modified_nanos", "INTEGER NOT NULL DEFAULT 0")
+        .await?;
+    common_db_utils::add_column_if_missing(pool, "synced_entries", "version", "TEXT").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS device
+    (
+    device_id      TEXT    NOT NULL
+    );",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// An entry of the sync_log table, time is in seconds since unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogEntry {
+    pub time: i64,
+    pub vault_id: Option<i32>,
+    pub action: String,
+    pub detail: String,
+}
+
+/// Records what a sync did, entries are (vault_id, action, detail)
+pub async fn add_log_entries(
+    pool: &Pool<Sqlite>,
+    entries: &[(Option<i32>, &str, String)],
+) -> Result<(), sqlx::Error> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    for (vault_id, action, detail) in entries {
+        sqlx::query("INSERT INTO sync_log (time, vault_id, action, detail) VALUES (?, ?, ?, ?);")
+            .bind(time)
+            .bind(vault_id)
+            .bind(action)
+            .bind(detail)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Gets the most recent log entries, newest first
+/// action limits the entries to one action eg: "error"
+pub async fn get_recent_log(
+    pool: &Pool<Sqlite>,
+    limit: i64,
+    action: Option<&str>,
+) -> Result<Vec<LogEntry>, sqlx::Error> {
+    let rows = sqlx::query(
+        "select time, vault_id, action, detail from sync_log where ? IS NULL OR action == ? \
+        order by log_id desc limit ?;",
+    )
+    .bind(action)
+    .bind(action)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| LogEntry {
+            time: row.get::<i64, _>(0),
+            vault_id: row.get::<Option<i32>, _>(1),
+            action: row.get::<String, _>(2),
+            detail: row.get::<String, _>(3),
+        })
+        .collect())
+}
+
+/// Gets the ids of the vaults that are skipped when syncing
+pub async fn get_paused_vaults(pool: &Pool<Sqlite>) -> Result<HashSet<i32>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id from vault_settings where paused == 1;")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(|row| row.get::<i32, _>(0)).collect())
+}
+
+/// Pauses or resumes syncing of a vault
+pub async fn set_paused(pool: &Pool<Sqlite>, vault_id: i32, paused: bool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO vault_settings (vault_id, paused) VALUES (?, ?)
+        ON CONFLICT(vault_id) DO UPDATE SET paused = excluded.paused;",
+    )
+    .bind(vault_id)
+    .bind(paused)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Pauses or resumes a vault, or every linked vault if vault_id is None
+/// Returns the ids of the vaults that were changed
+pub async fn set_paused_vaults(
+    pool: &Pool<Sqlite>,
+    vault_id: Option<i32>,
+    paused: bool,
+) -> Result<Vec<i32>, sqlx::Error> {
+    let vault_ids = match vault_id {
+        Some(id) => vec![id],
+        None => common_db_utils::get_vault_id_and_root_directories(pool)
+            .await?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect(),
+    };
+    for id in vault_ids.iter() {
+        set_paused(pool, *id, paused).await?;
+    }
+    Ok(vault_ids)
+}
+
+/// Empties file_metadata and reads every vault from disk again so the db matches the local files
+/// Each file's version is worked out from the last sync
+pub async fn rescan_local_files(
+    pool: &Pool<Sqlite>,
+    default_ignore: &[String],
+) -> Result<(), Box<dyn Error>> {
+    common_db_utils::delete_db_and_recreate_for_client(pool).await?;
+
+    let scan_pool = pool.clone();
+    let patterns = default_ignore.to_vec();
+    let symlinks = get_symlink_policies(pool).await?;
+    tokio::task::spawn_blocking(move || {
+        common_db_utils::init_metadata_into_db(&scan_pool, false, &patterns, &symlinks)
+    })
+    .await??;
+    assign_local_versions(pool).await?;
+    Ok(())
+}
+
+/// Gets the id this client counts its changes under, one is made the first time it is needed
+pub async fn get_device_id(pool: &Pool<Sqlite>) -> Result<String, sqlx::Error> {
+    if let Some(row) = sqlx::query("select device_id from device;").fetch_optional(pool).await? {
+        return Ok(row.get::<String, _>(0));
+    }
+    let device_id = format!("{:016x}", rand::random::<u64>());
+    sqlx::query("INSERT INTO device (device_id) VALUES (?);")
+        .bind(&device_id)
+        .execute(pool)
+        .await?;
+    Ok(device_id)
+}
+
+/// Gives every scanned file its version, the version it had at the last sync, counting a change by this
+/// device when it was modified since or wasn't synced before
+async fn assign_local_versions(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let device_id = get_device_id(pool).await?;
+    for (vault_id, root) in get_all_vaults(pool).await? {
+        let synced = get_synced_entries(pool, vault_id)
+            .await?
+            .into_iter()
+            .map(|entry| (entry.relative_path.clone(), entry))
+            .collect::<HashMap<PathBuf, SyncedEntry>>();
+        let rows = sqlx::query(
+            "select file_path, modified_time, modified_nanos from file_metadata where vault_id == ? AND is_dir == 0;",
+        )
+        .bind(vault_id)
+        .fetch_all(pool)
+        .await?;
+
+        for row in rows {
+            let path = row.get::<String, _>(0);
+            let relative = Path::new(&path).strip_prefix(&root).unwrap_or(Path::new(&path));
+            let modified = (row.get::<i64, _>(1), row.get::<i64, _>(2) as u32);
+            let version = match synced.get(relative) {
+                Some(entry) if compare_times(modified, (entry.modified_time, entry.modified_nanos)) == Ordering::Equal => {
+                    entry.version.clone()
+                }
+                Some(entry) => entry.version.incremented(&device_id),
+                None => VersionVector::default().incremented(&device_id),
+            };
+            sqlx::query("UPDATE file_metadata SET version = ? WHERE file_path == ?;")
+                .bind(common_db_utils::version_for_db(&version))
+                .bind(&path)
+                .execute(pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Gets the sync direction of every vault that isn't two way, keyed by vault_id
+pub async fn get_sync_directions(
+    pool: &Pool<Sqlite>,
+) -> Result<HashMap<i32, SyncDirection>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id, sync_direction from vault_settings;")
+        .fetch_all(pool)
+        .await?;
+
+    let mut directions = HashMap::new();
+    for row in rows {
+        let vault_id = row.get::<i32, _>(0);
+        let raw = row.get::<String, _>(1);
+        match raw.parse::<SyncDirection>() {
+            Ok(SyncDirection::TwoWay) => {}
+            Ok(direction) => {
+                directions.insert(vault_id, direction);
+            }
+            Err(e) => println!("vault {vault_id} uses two way sync: {e}"),
+        }
+    }
+    Ok(directions)
+}
+
+/// Sets which way files are synced for a vault
+pub async fn set_sync_direction(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    direction: SyncDirection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO vault_settings (vault_id, sync_direction) VALUES (?, ?)
+        ON CONFLICT(vault_id) DO UPDATE SET sync_direction = excluded.sync_direction;",
+    )
+    .bind(vault_id)
+    .bind(direction.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gets the symlink policy of every vault that doesn't skip links, keyed by vault_id
+pub async fn get_symlink_policies(
+    pool: &Pool<Sqlite>,
+) -> Result<HashMap<i32, SymlinkPolicy>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id, symlinks from vault_settings;")
+        .fetch_all(pool)
+        .await?;
+
+    let mut policies = HashMap::new();
+    for row in rows {
+        let vault_id = row.get::<i32, _>(0);
+        let raw = row.get::<String, _>(1);
+        match raw.parse::<SymlinkPolicy>() {
+            Ok(SymlinkPolicy::Skip) => {}
+            Ok(policy) => {
+                policies.insert(vault_id, policy);
+            }
+            Err(e) => println!("vault {vault_id} skips symlinks: {e}"),
+        }
+    }
+    Ok(policies)
+}
+
+/// Sets how symbolic links are synced for a vault
+pub async fn set_symlink_policy(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    policy: SymlinkPolicy,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO vault_settings (vault_id, symlinks) VALUES (?, ?)
+        ON CONFLICT(vault_id) DO UPDATE SET symlinks = excluded.symlinks;",
+    )
+    .bind(vault_id)
+    .bind(policy.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gets the conflict strategy of every vault that doesn't keep the newest copy, keyed by vault_id
+pub async fn get_conflict_strategies(
+    pool: &Pool<Sqlite>,
+) -> Result<HashMap<i32, ConflictStrategy>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id, conflicts from vault_settings;")
+        .fetch_all(pool)
+        .await?;
+
+    let mut strategies = HashMap::new();
+    for row in rows {
+        let vault_id = row.get::<i32, _>(0);
+        let raw = row.get::<String, _>(1);
+        match raw.parse::<ConflictStrategy>() {
+            Ok(ConflictStrategy::Newest) => {}
+            Ok(strategy) => {
+                strategies.insert(vault_id, strategy);
+            }
+            Err(e) => println!("vault {vault_id} keeps the newest copy of conflicts: {e}"),
+        }
+    }
+    Ok(strategies)
+}
+
+/// Sets how conflicting files are settled for a vault
+pub async fn set_conflict_strategy(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    strategy: ConflictStrategy,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO vault_settings (vault_id, conflicts) VALUES (?, ?)
+        ON CONFLICT(vault_id) DO UPDATE SET conflicts = excluded.conflicts;",
+    )
+    .bind(vault_id)
+    .bind(strategy.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Adds a vault to the vaults table, abs_path is where the vault is stored on this client
+pub async fn insert_vault(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    abs_path: &Path,
+    root_dir: &str,
+    sync_frequency: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO vaults (vault_id, abs_path, root_dir, sync_frequency) VALUES (?, ?, ?, ?);")
+        .bind(vault_id)
+        .bind(abs_path.to_str().unwrap())
+        .bind(root_dir)
+        .bind(sync_frequency)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Unlinks a vault from this client, the files on disk are left untouched
+/// Returns false if the vault wasn't linked
+pub async fn remove_vault(pool: &Pool<Sqlite>, vault_id: i32) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query("DELETE FROM vaults WHERE vault_id == ?;")
+        .bind(vault_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    for table in ["file_metadata", "selected_paths", "vault_settings", "synced_entries"] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE vault_id == ?;"))
+            .bind(vault_id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(removed > 0)
+}
+
+/// Gets the ids of the vaults that write placeholders instead of downloading new files
+pub async fn get_on_demand_vaults(pool: &Pool<Sqlite>) -> Result<HashSet<i32>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id from vault_settings where on_demand == 1;")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows.iter().map(|row| row.get::<i32, _>(0)).collect())
+}
+
+/// Turns on-demand mode on or off for a vault
+pub async fn set_on_demand(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    on_demand: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO vault_settings (vault_id, on_demand) VALUES (?, ?)
+        ON CONFLICT(vault_id) DO UPDATE SET on_demand = excluded.on_demand;",
+    )
+    .bind(vault_id)
+    .bind(on_demand)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Gets the selected subdirectories of every vault with a selection, keyed by vault_id
+pub async fn get_selected_paths(
+    pool: &Pool<Sqlite>,
+) -> Result<HashMap<i32, Vec<PathBuf>>, sqlx::Error> {
+    let rows = sqlx::query("select vault_id, relative_path from selected_paths;")
+        .fetch_all(pool)
+        .await?;
+
+    let mut selections: HashMap<i32, Vec<PathBuf>> = HashMap::new();
+    for row in rows {
+        selections
+            .entry(row.get::<i32, _>(0))
+            .or_default()
+            .push(PathBuf::from(row.get::<String, _>(1)));
+    }
+    Ok(selections)
+}
+
+/// Adds a subdirectory of a vault to the selection, once a vault has a selection
+/// only the selected subdirectories are synced
+pub async fn select_path(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    relative_path: &Path,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT OR IGNORE INTO selected_paths (vault_id, relative_path) VALUES (?, ?);")
+        .bind(vault_id)
+        .bind(normalise_relative_path(relative_path))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Removes a subdirectory from the selection, if it was the last one the whole vault is synced again
+pub async fn unselect_path(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    relative_path: &Path,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM selected_paths WHERE vault_id == ? AND relative_path == ?;")
+        .bind(vault_id)
+        .bind(normalise_relative_path(relative_path))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Strips leading ./ and / so "./papers/" and "papers" are stored the same way
+fn normalise_relative_path(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect::<PathBuf>()
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Gets the paths of a vault that were in sync after the last sync
+pub async fn get_synced_entries(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+) -> Result<Vec<SyncedEntry>, sqlx::Error> {
+    let rows = sqlx::query(
+        "select relative_path, modified_time, modified_nanos, mode, version from synced_entries where vault_id == ?;",
+    )
+        .bind(vault_id)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| SyncedEntry {
+            relative_path: PathBuf::from(row.get::<String, _>(0)),
+            modified_time: row.get::<i64, _>(1),
+            modified_nanos: row.get::<i64, _>(2) as u32,
+            mode: row.get::<Option<i64>, _>(3).map(|mode| mode as u32),
+            version: VersionVector::from_db(row.get::<Option<String>, _>(4)),
+        })
+        .collect())
+}
+
+/// Replaces the synced state of each vault with the paths that were in sync after this sync
+pub async fn save_synced_entries(
+    pool: &Pool<Sqlite>,
+    in_sync: &HashMap<i32, Vec<SyncedEntry>>,
+) -> Result<(), sqlx::Error> {
+    for (vault_id, entries) in in_sync {
+        sqlx::query("DELETE FROM synced_entries WHERE vault_id == ?;")
+            .bind(vault_id)
+            .execute(pool)
+            .await?;
+        for entry in entries {
+            save_synced_entry(pool, *vault_id, entry).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Gets the version a file was given when it was last scanned, unknown if it wasn't
+pub async fn get_file_version(pool: &Pool<Sqlite>, full_path: &Path) -> Result<VersionVector, sqlx::Error> {
+    let row = sqlx::query("select version from file_metadata where file_path == ?;")
+        .bind(full_path.to_str().unwrap())
+        .fetch_optional(pool)
+        .await?;
+    Ok(VersionVector::from_db(row.and_then(|row| row.get::<Option<String>, _>(0))))
+}
+
+/// Records a single path as in sync, replacing what was recorded for it before
+pub async fn save_synced_entry(pool: &Pool<Sqlite>, vault_id: i32, entry: &SyncedEntry) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO synced_entries (vault_id, relative_path, modified_time, modified_nanos, mode, version) \
+        VALUES (?, ?, ?, ?, ?, ?);",
+    )
+    .bind(vault_id)
+    .bind(normalise_relative_path(&entry.relative_path))
+    .bind(entry.modified_time)
+    .bind(entry.modified_nanos as i64)
+    .bind(entry.mode.map(|mode| mode as i64))
+    .bind(common_db_utils::version_for_db(&entry.version))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 /// Accepts server metadata and inserts it into client db. Assumes server metadata consists of new files
 /// not present on local
 pub async fn insert_server_metadata_into_client_db(
@@ -30,11 +642,15 @@ pub async fn insert_server_metadata_into_client_db(
 }
 
 /// Loads metadata from DB into a MetadataBlob struct to send to the server
+/// Only files in the selected subdirectories of a vault are loaded
 pub async fn load_file_metadata(
     pool: &Pool<Sqlite>,
     file_id: i32,
 ) -> Result<MetadataBlob, sqlx::Error> {
     let vaults = get_all_vaults(pool).await?;
+    let selections = get_selected_paths(pool).await?;
+    let directions = get_sync_directions(pool).await?;
+    let strategies = get_conflict_strategies(pool).await?;
     let mut blob = MetadataBlob {
         vaults: HashMap::new(),
     };
@@ -43,17 +659,30 @@ pub async fn load_file_metadata(
     for (vault, absolute_root_dir) in vaults {
 
 
-        let rows = sqlx::query("select file_id, file_path, root_directory, modified_time, file_size from file_metadata where vault_id == ?;")
+        let mut rows = sqlx::query("select file_id, file_path, root_directory, modified_time, file_size, is_dir, link_target, mode, modified_nanos, version from file_metadata where vault_id == ?;")
             .bind(vault)
             .fetch_all(pool)
             .await?;
 
+        // files outside the selected subdirectories are left out so they are never uploaded
+        if let Some(selected) = selections.get(&vault) {
+            rows.retain(|row| {
+                let path = PathBuf::from(row.get::<String, _>(1));
+                let relative = path.strip_prefix(&absolute_root_dir).unwrap_or(&path);
+                file_utils::is_path_selected(relative, selected)
+            });
+        }
+
         let (id, files) = build_file_metadata_for_vault(vault, rows, cur_id, absolute_root_dir);
         cur_id = id;
 
         let vault_metadata = VaultMetadata {
             files,
             vault_id: vault,
+            sync_direction: directions.get(&vault).copied().unwrap_or_default(),
+            conflict_strategy: strategies.get(&vault).copied().unwrap_or_default(),
+            synced: get_synced_entries(pool, vault).await?,
+            ..Default::default()
         };
 
         blob.vaults.insert(vault, vault_metadata);
@@ -90,7 +719,6 @@ fn build_file_metadata_for_vault(
     rows.iter().for_each(|row| {
         let path = PathBuf::from(row.get::<String, _>(1));
         let absolute_root_dir = absolute_root_dir.clone();
-        println!("latest id: {id} and file id: {} for file {:?}", row.get::<i32, _>(0), path);
         let mut file = FileMetadata {
             full_path: path,
             root_directory: row.get::<String, _>(2),
@@ -107,6 +735,11 @@ fn build_file_metadata_for_vault(
                 x => x,
             },
             present_on_server: ServerPresent::Unknown,
+            is_dir: row.get::<bool, _>(5),
+            link_target: row.get::<Option<String>, _>(6).map(PathBuf::from),
+            mode: row.get::<Option<i64>, _>(7).map(|mode| mode as u32),
+            modified_nanos: row.get::<i64, _>(8) as u32,
+            version: VersionVector::from_db(row.get::<Option<String>, _>(9)),
         };
         if row.get::<i32, _>(0) == -1 {
             file.present_on_server = ServerPresent::No;
@@ -117,3 +750,25 @@ fn build_file_metadata_for_vault(
     });
     (id, files)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_copy_leaves_the_db_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_url = format!("sqlite://{}?mode=rwc", dir.path().join("client.db").display());
+        let pool = init_db(db_url).await.unwrap();
+        create_tables(&pool).await.unwrap();
+        let device_id = get_device_id(&pool).await.unwrap();
+
+        let copy = copy_to_memory(&pool).await.unwrap();
+        assert_eq!(get_device_id(&copy).await.unwrap(), device_id);
+        sqlx::query("DELETE FROM device;").execute(&copy).await.unwrap();
+        add_log_entries(&copy, &[(None, "error", "only in the copy".to_string())]).await.unwrap();
+
+        assert_eq!(get_device_id(&pool).await.unwrap(), device_id);
+        assert!(get_recent_log(&pool, 10, None).await.unwrap().is_empty());
+    }
+}
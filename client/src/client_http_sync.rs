@@ -1,36 +1,187 @@
+use crate::client_db_api;
 use crate::client_db_api::load_file_metadata;
-use common::file_utils::{MetadataBlob};
+use common::config_utils::VaultInfo;
+use common::file_utils::{ConflictResolution, FileConflict, MetadataBlob, MetadataDiff, SyncedEntry, VaultMetadata};
+use common::merge_utils::{self, MergeRequest, MergedFile};
 use common::RemoteFile;
+use common::router_utils::SERVER_TIME_HEADER;
 use common::{common_db_utils, file_utils};
-use reqwest::{Client, Url};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Response, StatusCode, Url};
 use sqlx::{Pool, Sqlite};
 use common::common_db_utils::{read_file_contents_from_disk_and_metadata};
+use crate::placeholders;
+use crate::sync_plan::SyncPlan;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A client that sends the api token in SERVER_TOKEN with every request
+/// Without a token the server answers every request with UNAUTHORIZED
+pub fn http_client() -> Client {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = dotenvy::var("SERVER_TOKEN").ok().filter(|token| !token.trim().is_empty()) {
+        match HeaderValue::from_str(&format!("Bearer {}", token.trim())) {
+            Ok(value) => {
+                headers.insert(AUTHORIZATION, value);
+            }
+            Err(e) => eprintln!("SERVER_TOKEN is not a valid token: {e}"),
+        }
+    }
+    Client::builder()
+        .default_headers(headers)
+        .build()
+        .expect("Error building the http client")
+}
 
 /// Main api that is called on launch of client
 /// Will make request to server for a list of all files and their metadata
 /// Once received, go through the list of files, if there is something more recent on server
 /// It makes a request for that file, if the file is more recent on the client, send it to server
-pub async fn init_metadata_sync(url: Url, pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
-    let client = Client::new();
+/// Everything that was transferred is recorded in the sync_log table
+/// With dry_run set the plan is returned without anything being written locally or on the server
+pub async fn init_metadata_sync(
+    url: Url,
+    pool: &Pool<Sqlite>,
+    default_ignore: &[String],
+    dry_run: bool,
+) -> Result<SyncPlan, Box<dyn Error>> {
+    let client = http_client();
+
+    let (metadata_diff, plan) = plan_sync(&client, &url, pool, default_ignore).await?;
+    if dry_run {
+        return Ok(plan);
+    }
+    log_clock_skew(pool, &plan).await?;
+    apply_sync(&client, &url, pool, metadata_diff).await?;
+    Ok(plan)
+}
 
-    // Gets metadata from server via http
-    let (file_id, server_metadata) = get_metadata_from_server(&client, &url).await;
+/// Records a warning in the sync_log table when the clocks are too far apart to trust the times of
+/// files changed on both sides
+async fn log_clock_skew(pool: &Pool<Sqlite>, plan: &SyncPlan) -> Result<(), sqlx::Error> {
+    if let Some(warning) = plan.clock_skew_warning() {
+        println!("warning: {warning}");
+        client_db_api::add_log_entries(pool, &[(None, "clock_skew", warning)]).await?;
+    }
+    Ok(())
+}
 
-    // Gets local metadata from DB - Also updates file id's to newest based upon the latest_file_id
-    // received from server
-    let local_metadata = load_file_metadata(pool, file_id).await?;
-    println!("local metadata: {:?}", local_metadata);
+/// Transfers the files in the diff and records what was done in the sync_log table
+async fn apply_sync(
+    client: &Client,
+    url: &Url,
+    pool: &Pool<Sqlite>,
+    mut metadata_diff: MetadataDiff,
+) -> Result<(), Box<dyn Error>> {
+    let mut log = vec![];
+    let delete_on_server = MetadataBlob {
+        vaults: std::mem::take(&mut metadata_diff.delete_on_server),
+    };
+    let delete_on_client = std::mem::take(&mut metadata_diff.delete_on_client);
+    let modes_for_server = MetadataBlob {
+        vaults: std::mem::take(&mut metadata_diff.modes_for_server),
+    };
+    let modes_for_client = std::mem::take(&mut metadata_diff.modes_for_client);
+    let mut in_sync = std::mem::take(&mut metadata_diff.in_sync);
+    let conflicts = std::mem::take(&mut metadata_diff.conflicts);
+
+    // in vaults that keep the newest copy text files changed on both sides are merged by the server first,
+    // the rest are settled by the vault's conflict strategy, pending ones are left alone on both sides
+    // until they are resolved
+    for (vault_id, conflicts) in conflicts.iter() {
+        for conflict in conflicts.iter() {
+            let local_path = &conflict.client.full_path;
+            let merged = match conflict.merge {
+                true => merge_with_server(client, url, conflict).await?,
+                false => None,
+            };
+            if let Some(entry) = merged {
+                // the merged file takes the place of whichever copy the strategy kept
+                for vaults in [&mut metadata_diff.new_for_client, &mut metadata_diff.new_for_server] {
+                    if let Some(vault) = vaults.get_mut(vault_id) {
+                        vault.files.retain(|file| file.relative_path() != entry.relative_path);
+                    }
+                }
+                let entries = in_sync.entry(*vault_id).or_default();
+                entries.retain(|synced| synced.relative_path != entry.relative_path);
+                entries.push(entry);
+                log.push((Some(*vault_id), "merged", local_path.display().to_string()));
+                continue;
+            }
+
+            match conflict.resolution {
+                ConflictResolution::Pending => {
+                    println!("conflict on {:?}, both copies changed", local_path);
+                    log.push((Some(*vault_id), "conflict", local_path.display().to_string()));
+                }
+                ConflictResolution::KeepServer => {
+                    log.push((Some(*vault_id), "resolved", format!("{} kept the server copy", local_path.display())));
+                }
+                ConflictResolution::KeepClient => {
+                    log.push((Some(*vault_id), "resolved", format!("{} kept the local copy", local_path.display())));
+                }
+                // the local copy is moved aside before the server's is downloaded, it is uploaded next sync
+                ConflictResolution::KeepBoth => {
+                    let device = client_db_api::get_device_id(pool).await?;
+                    let copy = file_utils::conflict_copy_path(local_path, &device);
+                    fs::rename(local_path, &copy)?;
+                    println!("moved {:?} to {:?} to keep both copies", local_path, copy);
+                    log.push((Some(*vault_id), "conflict_copy", copy.display().to_string()));
+                }
+            }
+        }
+    }
 
-    // Gets metadata diff and sends it to server which is then inserted into db
-    let metadata_diff = file_utils::get_metadata_diff(local_metadata, server_metadata);
+    // local changes to download only or read-only vaults that the server has no copy of stay local
+    for (vault_id, vault) in metadata_diff.blocked_for_server.iter() {
+        for file in vault.files.iter() {
+            println!("not uploading {:?}, vault {vault_id} is download only or read-only", file.full_path);
+            log.push((Some(*vault_id), "blocked", file.full_path.display().to_string()));
+        }
+    }
 
-    let (new_for_client, new_for_server) = metadata_diff.destruct_into_tuple();
+    let (mut new_for_client, new_for_server) = metadata_diff.destruct_into_tuple();
     println!("new for client: {:?}", new_for_client);
     println!("new for server: {:#?}", new_for_server);
 
     //upsert_database(pool, new_for_client.clone().convert_to_metadata_vec()).await?;
 
-    post_metadata_diff_to_server(&client, &url, &new_for_server).await;
+    post_metadata_diff_to_server(client, url, &new_for_server).await?;
+    // the server creates new directories and links from their metadata alone
+    for vault in new_for_server.vaults.values() {
+        for dir in vault.files.iter().filter(|file| file.is_dir || file.link_target.is_some()) {
+            log.push((Some(dir.vault_id), "upload", dir.relative_path().display().to_string()));
+        }
+    }
+
+    // files whose permissions alone changed here only have their metadata sent, the server applies the mode
+    if !modes_for_server.vaults.is_empty() {
+        post_metadata_diff_to_server(client, url, &modes_for_server).await?;
+        for (vault_id, vault) in modes_for_server.vaults.iter() {
+            for file in vault.files.iter() {
+                log.push((Some(*vault_id), "server_chmod", file.relative_path().display().to_string()));
+            }
+        }
+    }
+
+    // paths deleted here since the last sync are removed from the server
+    delete_files_on_server(client, url, &delete_on_server).await?;
+    for (vault_id, vault) in delete_on_server.vaults.iter() {
+        for file in vault.files.iter() {
+            log.push((Some(*vault_id), "server_delete", file.relative_path().display().to_string()));
+        }
+    }
+
+    // paths the server deleted since the last sync are removed here, directories only once empty
+    for (vault_id, vault) in delete_on_client.iter() {
+        let paths = vault.files.iter().map(|file| file.full_path.clone()).collect();
+        for path in file_utils::remove_paths_from_disk(paths) {
+            log.push((Some(*vault_id), "delete", path.display().to_string()));
+        }
+    }
 
     //todo stop some of this metadata sending
     //general structure should be:
@@ -50,9 +201,48 @@ pub async fn init_metadata_sync(url: Url, pool: &Pool<Sqlite>) -> Result<(), sql
 
 
 
-    // requests for files from server to update and/or add, also upsert database
-    let files = get_new_files_for_client(&client, &url, &new_for_client).await;
+    // on-demand vaults get placeholders for files that aren't on disk instead of downloading them
     let vault_and_root_paths = common_db_utils::get_vault_id_and_root_directories(pool).await?;
+
+    // files whose permissions alone changed on the server take its mode without being downloaded again
+    let mode_changes = modes_for_client
+        .into_values()
+        .flat_map(|vault| vault.files)
+        .collect::<Vec<_>>();
+    for (vault_id, path) in file_utils::apply_remote_modes(&mode_changes, &vault_and_root_paths) {
+        log.push((Some(vault_id), "chmod", path.display().to_string()));
+    }
+
+    // new directories and links are created straight away, they have nothing to download or stand in for
+    let new_dirs = new_for_client.take_directories();
+    file_utils::create_remote_directories(&new_dirs, &vault_and_root_paths);
+    let new_links = new_for_client.take_links();
+    file_utils::create_remote_links(&new_links, &vault_and_root_paths);
+    for entry in new_dirs.iter().chain(new_links.iter()) {
+        log.push((Some(entry.vault_id), "download", entry.relative_path().display().to_string()));
+    }
+
+    let on_demand_vaults = client_db_api::get_on_demand_vaults(pool).await?;
+    let (to_download, to_placeholder) = placeholders::split_on_demand_files(
+        new_for_client,
+        &on_demand_vaults,
+        &vault_and_root_paths,
+    );
+    placeholders::write_placeholders(&to_placeholder);
+    for (path, file) in to_placeholder.iter() {
+        log.push((Some(file.vault_id), "placeholder", path.display().to_string()));
+        // a placeholder isn't a copy, so the file doesn't count as synced
+        if let Some(entries) = in_sync.get_mut(&file.vault_id) {
+            let relative = file.relative_path();
+            entries.retain(|entry| entry.relative_path != relative);
+        }
+    }
+
+    // requests for files from server to update and/or add, also upsert database
+    let files = get_new_files_for_client(client, url, &to_download).await?;
+    for file in files.iter() {
+        log.push((Some(file.vault_id), "download", file.full_path.display().to_string()));
+    }
     //common_db_utils::upsert_database(pool, new_for_client.convert_to_metadata_vec()).await?;
     file_utils::save_remote_files_to_disk(files, vault_and_root_paths);
 
@@ -61,15 +251,263 @@ pub async fn init_metadata_sync(url: Url, pool: &Pool<Sqlite>) -> Result<(), sql
         pool,
         &new_for_server.convert_to_metadata_vec())
         .await;
-    send_files_to_server(&client, &url, local_files)
-        .await;
+    for file in local_files.iter() {
+        log.push((Some(file.vault_id), "upload", file.full_path.display().to_string()));
+    }
+    send_files_to_server(client, url, local_files)
+        .await?;
 
+    client_db_api::save_synced_entries(pool, &in_sync).await?;
+    client_db_api::add_log_entries(pool, &log).await?;
     Ok(())
 }
 
+/// Sends the local copy of a conflicting text file to be merged with the server's and saves the merged
+/// file over it, returning the synced entry both copies now share
+/// None if the file isn't text or the server couldn't merge it, eg: both copies changed the same lines
+async fn merge_with_server(
+    client: &Client,
+    parent_url: &Url,
+    conflict: &FileConflict,
+) -> Result<Option<SyncedEntry>, Box<dyn Error>> {
+    let local = &conflict.client;
+    if local.is_dir || conflict.server.is_dir || local.link_target.is_some() || conflict.server.link_target.is_some() {
+        return Ok(None);
+    }
+    let contents = match fs::read(&local.full_path) {
+        Ok(contents) if merge_utils::is_mergeable(&contents) => contents,
+        _ => return Ok(None),
+    };
+
+    let mut endpoint = parent_url.clone();
+    endpoint.set_path("/copy/merge_from_client");
+    let response = client
+        .post(endpoint)
+        .json(&MergeRequest {
+            vault_id: local.vault_id,
+            relative_path: local.relative_path(),
+            version: local.version.clone(),
+            contents,
+        })
+        .send()
+        .await?;
+    if response.status() == StatusCode::CONFLICT {
+        println!("not merging {:?}: {}", local.full_path, response.text().await.unwrap_or_default());
+        return Ok(None);
+    }
+    let merged: MergedFile = response.error_for_status()?.json().await?;
+
+    fs::write(&local.full_path, &merged.contents)?;
+    filetime::set_file_mtime(
+        &local.full_path,
+        filetime::FileTime::from_unix_time(merged.modified_time, merged.modified_nanos),
+    )?;
+    println!("merged {:?} with the server's copy", local.full_path);
+    Ok(Some(SyncedEntry {
+        relative_path: local.relative_path(),
+        modified_time: merged.modified_time,
+        modified_nanos: merged.modified_nanos,
+        mode: local.mode,
+        version: merged.version,
+    }))
+}
+
+/// Compares the local files with the server's and returns what needs to be synced and the plan of
+/// what a sync will do, nothing is changed
+/// Ignored files and unselected subtrees are left out, as are paused vaults
+pub async fn plan_sync(
+    client: &Client,
+    url: &Url,
+    pool: &Pool<Sqlite>,
+    default_ignore: &[String],
+) -> Result<(MetadataDiff, SyncPlan), Box<dyn Error>> {
+    // Gets metadata from server via http
+    let (file_id, mut server_metadata, clock_skew_ms) = get_metadata_from_server(client, url).await?;
+
+    // Gets local metadata from DB - Also updates file id's to newest based upon the latest_file_id
+    // received from server
+    let mut local_metadata = load_file_metadata(pool, file_id).await?;
+
+    // times of files changed here are shifted onto the server's clock before they are compared
+    for vault in local_metadata.vaults.values_mut() {
+        vault.clock_skew_ms = clock_skew_ms;
+    }
+
+    // only vaults in the local metadata are diffed, so removing a paused vault skips it entirely
+    for vault_id in client_db_api::get_paused_vaults(pool).await? {
+        local_metadata.vaults.remove(&vault_id);
+    }
+
+    // Ignored files are left out of the diff on both sides so they are never uploaded or downloaded
+    let ignore_rules = common_db_utils::get_ignore_rules_for_vaults(pool, default_ignore).await?;
+    local_metadata.remove_ignored(&ignore_rules);
+    server_metadata.remove_ignored(&ignore_rules);
+
+    // Unselected subtrees are left out of the diff on both sides so they are neither downloaded nor seen as deleted
+    let selections = client_db_api::get_selected_paths(pool).await?;
+    local_metadata.retain_selected(&selections);
+    server_metadata.retain_selected(&selections);
+
+    // links only come down to vaults that sync them as links, the others leave them on the server
+    server_metadata.remove_unsynced_links(&client_db_api::get_symlink_policies(pool).await?);
+
+    let on_demand_vaults = client_db_api::get_on_demand_vaults(pool).await?;
+    let mut diff = file_utils::get_metadata_diff(local_metadata.clone(), server_metadata.clone());
+
+    // anything still on disk wasn't deleted, eg: a link left out by the vault's symlink policy
+    let vault_and_root_paths = common_db_utils::get_vault_id_and_root_directories(pool).await?;
+    for (vault_id, vault) in diff.delete_on_server.iter_mut() {
+        if let Some((_, root)) = vault_and_root_paths.iter().find(|(id, _)| id == vault_id) {
+            vault
+                .files
+                .retain(|file| fs::symlink_metadata(root.join(file.relative_path())).is_err());
+        }
+    }
+    diff.delete_on_server.retain(|_, vault| !vault.files.is_empty());
+
+    let mut plan = SyncPlan::build(&diff, &local_metadata, &server_metadata, &on_demand_vaults);
+    plan.clock_skew_ms = clock_skew_ms;
+    Ok((diff, plan))
+}
+
+/// Scans the local files then syncs, errors are recorded in the sync_log table before being returned
+/// on_plan is called with the plan before any file is transferred
+pub async fn sync_once(
+    url: &Url,
+    pool: &Pool<Sqlite>,
+    default_ignore: &[String],
+    on_plan: impl FnOnce(&SyncPlan),
+) -> Result<(), Box<dyn Error>> {
+    async fn scan_and_sync(
+        url: &Url,
+        pool: &Pool<Sqlite>,
+        default_ignore: &[String],
+        on_plan: impl FnOnce(&SyncPlan),
+    ) -> Result<(), Box<dyn Error>> {
+        let client = http_client();
+        client_db_api::rescan_local_files(pool, default_ignore).await?;
+        let (metadata_diff, plan) = plan_sync(&client, url, pool, default_ignore).await?;
+        on_plan(&plan);
+        log_clock_skew(pool, &plan).await?;
+        apply_sync(&client, url, pool, metadata_diff).await
+    }
+
+    let result = scan_and_sync(url, pool, default_ignore, on_plan).await;
+    if let Err(e) = &result {
+        client_db_api::add_log_entries(pool, &[(None, "error", e.to_string())]).await?;
+    }
+    result
+}
+
+
+/// Downloads the real contents of a placeholder and removes the placeholder
+/// path can be the placeholder or the path of the file it stands in for
+pub async fn fetch_placeholder(
+    url: Url,
+    pool: &Pool<Sqlite>,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let client = http_client();
+    let (placeholder, metadata) = placeholders::read_placeholder(path)?;
+
+    let vault_id = metadata.vault_id;
+    let blob = MetadataBlob {
+        vaults: HashMap::from([(vault_id, VaultMetadata {
+            files: vec![metadata],
+            vault_id,
+            ..Default::default()
+        })]),
+    };
+    let files = get_new_files_for_client(&client, &url, &blob).await?;
+    if files.is_empty() {
+        return Err(format!("Server no longer has the file for {:?}", placeholder).into());
+    }
+
+    let vault_and_root_paths = common_db_utils::get_vault_id_and_root_directories(pool).await?;
+    file_utils::save_remote_files_to_disk(files, vault_and_root_paths);
+    fs::remove_file(&placeholder)?;
+    println!("fetched {:?}", placeholder);
+    Ok(())
+}
+
+/// Gets the ignore patterns the server applies to every vault
+/// If the server can't be reached no default patterns are used, the vault's .datoxidizeignore still applies
+pub async fn get_default_ignore_patterns(parent_url: &Url) -> Vec<String> {
+    let mut endpoint = parent_url.clone();
+    endpoint.set_path("/copy/ignore_defaults");
+
+    let response = match http_client().get(endpoint).send().await {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Error getting default ignore patterns: {e}");
+            return vec![];
+        }
+    };
+    response.json().await.unwrap_or_else(|e| {
+        eprintln!("Error reading default ignore patterns: {e}");
+        vec![]
+    })
+}
+
+/// Gets the list of vaults on the server
+pub async fn get_server_vaults(parent_url: &Url) -> Result<Vec<VaultInfo>, reqwest::Error> {
+    let mut endpoint = parent_url.clone();
+    endpoint.set_path("/vaults");
+
+    http_client()
+        .get(endpoint)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+/// Links a vault on the server to a local directory by adding it to the client's vaults table
+/// The directory is created if it doesn't exist, an empty directory is populated by the first sync
+/// and the files of an existing directory are merged with the server's
+/// Returns the absolute path of the local directory
+pub async fn link_vault(
+    parent_url: &Url,
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    local_dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let vault = get_server_vaults(parent_url)
+        .await?
+        .into_iter()
+        .find(|vault| vault.vault_id == vault_id)
+        .ok_or(format!("vault {vault_id} does not exist on the server"))?;
+
+    fs::create_dir_all(local_dir)?;
+    let abs_path = local_dir.canonicalize()?;
+
+    for (linked_id, linked_path) in common_db_utils::get_vault_id_and_root_directories(pool).await? {
+        if linked_id == vault_id {
+            return Err(format!("vault {vault_id} is already linked to {:?}", linked_path).into());
+        }
+        if abs_path.starts_with(&linked_path) || linked_path.starts_with(&abs_path) {
+            return Err(format!(
+                "{:?} overlaps {:?} which is linked to vault {linked_id}",
+                abs_path, linked_path
+            )
+            .into());
+        }
+    }
+
+    client_db_api::insert_vault(pool, vault_id, &abs_path, &vault.root_dir, vault.sync_frequency)
+        .await?;
+    println!("linked vault {vault_id} ({}) to {:?}", vault.root_dir, abs_path);
+    Ok(abs_path)
+}
 
 /// Gets the every file and its update time from server
-async fn get_metadata_from_server(client: &Client, parent_url: &Url) -> (i32, MetadataBlob) {
+/// Also returns how far the server's clock is ahead of the client's in milliseconds, taking the
+/// server's time to be halfway through the request, 0 if the server doesn't send its time
+async fn get_metadata_from_server(
+    client: &Client,
+    parent_url: &Url,
+) -> Result<(i32, MetadataBlob, i64), reqwest::Error> {
     fn create_get_metadata_url(parent_url: &Url) -> Url {
         let mut endpoint = parent_url.clone();
         endpoint.set_path("/copy/metadata_blob_send");
@@ -78,16 +516,36 @@ async fn get_metadata_from_server(client: &Client, parent_url: &Url) -> (i32, Me
 
     let get_metadata_url = create_get_metadata_url(parent_url);
 
-    client.get(get_metadata_url)
+    let sent = unix_millis();
+    let response = client.get(get_metadata_url)
         .send()
-        .await
-        .unwrap()
-        .json()
-        .await
+        .await?
+        .error_for_status()?;
+    let received = unix_millis();
+
+    let clock_skew_ms = response
+        .headers()
+        .get(SERVER_TIME_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .map(|server_time| server_time - (sent + received) / 2)
+        .unwrap_or(0);
+    let (file_id, blob) = response.json().await?;
+    Ok((file_id, blob, clock_skew_ms))
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
         .unwrap()
+        .as_millis() as i64
 }
 
-async fn post_metadata_diff_to_server(client: &Client, parent_url: &Url, diff: &MetadataBlob) {
+async fn post_metadata_diff_to_server(
+    client: &Client,
+    parent_url: &Url,
+    diff: &MetadataBlob,
+) -> Result<(), Box<dyn Error>> {
     fn create_post_metadata_diff_url(parent_url: &Url) -> Url {
         let mut endpoint = parent_url.clone();
         endpoint.set_path("/copy/metadata_diff_receive");
@@ -96,11 +554,11 @@ async fn post_metadata_diff_to_server(client: &Client, parent_url: &Url, diff: &
 
     let metadata_diff_url = create_post_metadata_diff_url(parent_url);
 
-    client.post(metadata_diff_url)
+    let response = client.post(metadata_diff_url)
         .json(&diff)
         .send()
-        .await
-        .unwrap();
+        .await?;
+    check_upload_response(response).await
 }
 
 /// Part of init sync for server and client:
@@ -111,7 +569,7 @@ async fn get_new_files_for_client(
     client: &Client,
     parent_url: &Url,
     blob: &MetadataBlob,
-) -> Vec<RemoteFile> {
+) -> Result<Vec<RemoteFile>, reqwest::Error> {
     fn create_post_required_files_url(parent_url: &Url) -> Url {
         let mut endpoint = parent_url.clone();
         endpoint.set_path("/copy/client_needs");
@@ -131,22 +589,25 @@ async fn get_new_files_for_client(
         .post(update_state_url)
         .json(&blob)
         .send()
-        .await
-        .unwrap();
+        .await?
+        .error_for_status()?;
 
     //requests for the files from the server (files not present on client)
     let get_files_url = create_get_files_init_url(parent_url);
     client
         .get(get_files_url)
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json()
         .await
-        .unwrap()
 }
 
-async fn send_files_to_server(client: &Client, parent_url: &Url, files: Vec<RemoteFile>) {
+async fn send_files_to_server(
+    client: &Client,
+    parent_url: &Url,
+    files: Vec<RemoteFile>,
+) -> Result<(), Box<dyn Error>> {
     fn create_url_to_send_files_to_server(parent_url: &Url) -> Url {
         let mut endpoint = parent_url.clone();
         endpoint.set_path("/copy/receive_files_from_client");
@@ -154,11 +615,40 @@ async fn send_files_to_server(client: &Client, parent_url: &Url, files: Vec<Remo
     }
 
     let url = create_url_to_send_files_to_server(parent_url);
-    client.post(url)
+    let response = client.post(url)
         .json(&files)
         .send()
-        .await
-        .unwrap();
+        .await?;
+    check_upload_response(response).await
+}
+
+/// Removes the paths the client deleted from the server, paths are the server's own
+async fn delete_files_on_server(
+    client: &Client,
+    parent_url: &Url,
+    deletions: &MetadataBlob,
+) -> Result<(), Box<dyn Error>> {
+    if deletions.vaults.values().all(|vault| vault.files.is_empty()) {
+        return Ok(());
+    }
+
+    let mut endpoint = parent_url.clone();
+    endpoint.set_path("/copy/delete_files_from_client");
+    let response = client.post(endpoint)
+        .json(&deletions)
+        .send()
+        .await?;
+    check_upload_response(response).await
+}
+
+/// Turns a refused upload into an error with the server's reason, eg: a vault that is over its quota
+async fn check_upload_response(response: Response) -> Result<(), Box<dyn Error>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let reason = response.text().await.unwrap_or_default();
+    Err(format!("server refused the upload ({status}): {reason}").into())
 }
 
 
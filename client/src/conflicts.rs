@@ -0,0 +1,80 @@
+//! Resolving the conflicts a sync left pending, from the dashboard or with `client resolve`
+
+use crate::client_db_api;
+use crate::sync_plan::PlannedFile;
+use clap::ValueEnum;
+use common::file_utils::SyncedEntry;
+use common::version_vector::VersionVector;
+use filetime::FileTime;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+/// Which copy of a conflicting file is kept
+#[derive(Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Keep {
+    Local,
+    Server,
+}
+
+impl Keep {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Keep::Local => "local",
+            Keep::Server => "server",
+        }
+    }
+}
+
+/// Resolves a conflict by making the kept copy the newer one, the next sync overwrites the other copy
+/// root is the local root of the conflict's vault, returns the path of the local copy
+/// Keeping the local copy records it as based on both copies and moves it to now, keeping the server
+/// copy records the local file as unchanged since the last sync with an unknown version and moves it a
+/// second into the past, so the server copy is taken as the newer one
+pub async fn keep_copy(
+    pool: &Pool<Sqlite>,
+    root: &Path,
+    conflict: &PlannedFile,
+    keep: Keep,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let full_path = root.join(&conflict.path);
+    let metadata = std::fs::metadata(&full_path)?;
+    let current_time = FileTime::from_last_modification_time(&metadata);
+    let (modified_time, recorded_time, version) = match keep {
+        Keep::Local => {
+            let mut version = client_db_api::get_file_version(pool, &full_path).await?;
+            version.merge(&conflict.server_version);
+            (FileTime::now(), current_time, version)
+        }
+        Keep::Server => {
+            let moved = FileTime::from_unix_time(current_time.unix_seconds() - 1, 0);
+            (moved, moved, VersionVector::default())
+        }
+    };
+    client_db_api::save_synced_entry(
+        pool,
+        conflict.vault_id,
+        &SyncedEntry {
+            relative_path: conflict.path.clone(),
+            modified_time: recorded_time.unix_seconds(),
+            modified_nanos: recorded_time.nanoseconds(),
+            mode: None,
+            version,
+        },
+    )
+    .await?;
+    filetime::set_file_mtime(&full_path, modified_time)?;
+
+    client_db_api::add_log_entries(
+        pool,
+        &[(
+            Some(conflict.vault_id),
+            "resolved",
+            format!("{} kept the {} copy", full_path.display(), keep.as_str()),
+        )],
+    )
+    .await?;
+    Ok(full_path)
+}
@@ -0,0 +1,355 @@
+//! Local control API of the daemon, a Unix socket that speaks newline delimited JSON
+//! Each line sent is a ControlRequest eg: {"command": "pause", "vault_id": 2} and each reply
+//! is a single ControlResponse line. Any tool on this device can use it, the CLI uses it to
+//! talk to a running `watch`
+
+use crate::client_db_api;
+use crate::sync_plan::{PlannedAction, PlannedFile, SyncPlan};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Notify;
+
+/// Used when CONTROL_SOCKET is not set in the .env
+const DEFAULT_SOCKET_PATH: &str = "./client/resources/client.sock";
+
+const DEFAULT_ERROR_LIMIT: i64 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Whether a sync is running, when the last one finished and the paused vaults
+    Status,
+    /// The files being transferred by the current sync
+    Transfers,
+    /// The most recent sync errors, newest first
+    Errors {
+        #[serde(default = "default_error_limit")]
+        limit: i64,
+    },
+    /// Starts a sync now instead of waiting for changes or the sync frequency
+    Sync,
+    /// Pauses a vault, or every vault if vault_id is left out
+    Pause { vault_id: Option<i32> },
+    /// Resumes a vault, or every vault if vault_id is left out
+    Resume { vault_id: Option<i32> },
+}
+
+fn default_error_limit() -> i64 {
+    DEFAULT_ERROR_LIMIT
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What the daemon is doing, last_sync is in seconds since unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DaemonStatus {
+    pub syncing: bool,
+    pub last_sync: Option<i64>,
+    pub last_error: Option<String>,
+    pub transfers: Vec<PlannedFile>,
+    /// Conflicts found by the last sync that haven't been resolved
+    pub conflicts: Vec<PlannedFile>,
+    pub paused_vaults: Vec<i32>,
+}
+
+/// Shared between the sync loop and the control connections
+#[derive(Clone)]
+pub struct ControlState {
+    status: Arc<Mutex<DaemonStatus>>,
+    force_sync: Arc<Notify>,
+    pool: Pool<Sqlite>,
+}
+
+impl ControlState {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        ControlState {
+            status: Arc::new(Mutex::new(DaemonStatus::default())),
+            force_sync: Arc::new(Notify::new()),
+            pool,
+        }
+    }
+
+    pub fn start_sync(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.syncing = true;
+        status.transfers.clear();
+    }
+
+    /// Called with the plan of the current sync before anything is transferred
+    pub fn set_plan(&self, plan: &SyncPlan) {
+        let mut status = self.status.lock().unwrap();
+        status.transfers = plan.transfers();
+        status.conflicts = plan
+            .files
+            .iter()
+            .filter(|file| file.action == PlannedAction::Conflict)
+            .cloned()
+            .collect();
+    }
+
+    /// Drops a conflict from the status once it has been resolved, it isn't reported again
+    /// unless the next sync still finds it
+    pub fn remove_conflict(&self, vault_id: i32, path: &Path) {
+        self.status
+            .lock()
+            .unwrap()
+            .conflicts
+            .retain(|file| file.vault_id != vault_id || file.path != path);
+    }
+
+    /// A copy of the current status, paused_vaults is only filled in by the Status request
+    pub fn status(&self) -> DaemonStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Starts a sync as soon as the daemon is waiting
+    pub fn request_sync(&self) {
+        self.force_sync.notify_one();
+    }
+
+    pub fn finish_sync(&self, error: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+        status.syncing = false;
+        status.transfers.clear();
+        status.last_sync = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+        );
+        status.last_error = error;
+    }
+
+    /// Completes when a client asks for a sync, a request made while syncing is kept for the next wait
+    pub async fn sync_requested(&self) {
+        self.force_sync.notified().await
+    }
+}
+
+/// The socket path from CONTROL_SOCKET in the .env
+pub fn socket_path() -> PathBuf {
+    dotenvy::var("CONTROL_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_SOCKET_PATH))
+}
+
+/// Binds the control socket, a socket file left behind by a daemon that didn't stop cleanly is
+/// replaced but a socket another daemon is still listening on is an error
+pub async fn bind_socket(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {:?}", path),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Accepts connections forever, each connection can send any number of requests
+pub async fn serve(listener: UnixListener, state: ControlState) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, state).await {
+                        println!("control connection closed: {e}");
+                    }
+                });
+            }
+            Err(e) => println!("Error accepting control connection: {e}"),
+        }
+    }
+}
+
+async fn handle_connection(stream: UnixStream, state: ControlState) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let result = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => handle_request(&state, request).await,
+            Err(e) => Err(format!("invalid request: {e}")),
+        };
+        let response = match result {
+            Ok(data) => ControlResponse { ok: true, data: Some(data), error: None },
+            Err(error) => ControlResponse { ok: false, data: None, error: Some(error) },
+        };
+        let mut reply = serde_json::to_string(&response).unwrap();
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Errors are strings so they can be sent back to the client
+async fn handle_request(state: &ControlState, request: ControlRequest) -> Result<Value, String> {
+    match request {
+        ControlRequest::Status => {
+            let mut status = state.status();
+            let mut paused = client_db_api::get_paused_vaults(&state.pool)
+                .await
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .collect::<Vec<i32>>();
+            paused.sort();
+            status.paused_vaults = paused;
+            Ok(serde_json::to_value(status).unwrap())
+        }
+        ControlRequest::Transfers => {
+            let transfers = state.status.lock().unwrap().transfers.clone();
+            Ok(serde_json::to_value(transfers).unwrap())
+        }
+        ControlRequest::Errors { limit } => {
+            let errors = client_db_api::get_recent_log(&state.pool, limit, Some("error"))
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(serde_json::to_value(errors).unwrap())
+        }
+        ControlRequest::Sync => {
+            state.request_sync();
+            Ok(Value::Null)
+        }
+        ControlRequest::Pause { vault_id } | ControlRequest::Resume { vault_id } => {
+            let paused = matches!(request, ControlRequest::Pause { .. });
+            let vault_ids = client_db_api::set_paused_vaults(&state.pool, vault_id, paused)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(serde_json::to_value(vault_ids).unwrap())
+        }
+    }
+}
+
+/// Sends a request to the daemon listening on path and returns the data of the reply
+/// Returns Ok(None) if no daemon is running so callers can do the work themselves
+pub async fn send_request(
+    path: &Path,
+    request: &ControlRequest,
+) -> Result<Option<Value>, Box<dyn Error>> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or("the daemon closed the connection without replying")?;
+    let response: ControlResponse = serde_json::from_str(&reply)?;
+    match response.ok {
+        true => Ok(Some(response.data.unwrap_or(Value::Null))),
+        false => Err(response.error.unwrap_or_default().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[test]
+    fn test_request_format() {
+        let request: ControlRequest = serde_json::from_str(r#"{"command": "pause", "vault_id": 2}"#).unwrap();
+        assert_eq!(request, ControlRequest::Pause { vault_id: Some(2) });
+
+        let request: ControlRequest = serde_json::from_str(r#"{"command": "resume"}"#).unwrap();
+        assert_eq!(request, ControlRequest::Resume { vault_id: None });
+
+        let request: ControlRequest = serde_json::from_str(r#"{"command": "errors"}"#).unwrap();
+        assert_eq!(request, ControlRequest::Errors { limit: DEFAULT_ERROR_LIMIT });
+
+        assert_eq!(serde_json::to_string(&ControlRequest::Sync).unwrap(), r#"{"command":"sync"}"#);
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command": "shutdown"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_socket() {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        client_db_api::create_tables(&pool).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.sock");
+
+        // nothing is listening yet
+        assert!(send_request(&path, &ControlRequest::Status).await.unwrap().is_none());
+
+        let state = ControlState::new(pool.clone());
+        let listener = bind_socket(&path).await.unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+        assert!(bind_socket(&path).await.is_err());
+
+        state.start_sync();
+        state.set_plan(&SyncPlan {
+            files: vec![
+                PlannedFile {
+                    vault_id: 1,
+                    action: PlannedAction::Upload,
+                    path: PathBuf::from("notes.txt"),
+                    file_size: 3,
+                    server_version: Default::default(),
+                },
+                PlannedFile {
+                    vault_id: 1,
+                    action: PlannedAction::Conflict,
+                    path: PathBuf::from("draft.txt"),
+                    file_size: 4,
+                    server_version: Default::default(),
+                },
+            ],
+            ..Default::default()
+        });
+        let transfers = send_request(&path, &ControlRequest::Transfers).await.unwrap().unwrap();
+        assert_eq!(transfers.as_array().unwrap().len(), 1);
+        assert_eq!(transfers[0]["path"], "notes.txt");
+
+        let paused = send_request(&path, &ControlRequest::Pause { vault_id: Some(1) })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(paused, serde_json::json!([1]));
+
+        state.finish_sync(Some("server unreachable".to_string()));
+        let status: DaemonStatus = serde_json::from_value(
+            send_request(&path, &ControlRequest::Status).await.unwrap().unwrap(),
+        )
+        .unwrap();
+        assert!(!status.syncing);
+        assert!(status.transfers.is_empty());
+        assert_eq!(status.last_error.as_deref(), Some("server unreachable"));
+        assert_eq!(status.paused_vaults, vec![1]);
+        assert_eq!(status.conflicts[0].path, PathBuf::from("draft.txt"));
+
+        send_request(&path, &ControlRequest::Sync).await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), state.sync_requested())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use crate::client_http_sync;
+use crate::control;
+use crate::control::ControlState;
+use crate::dashboard;
+use common::common_db_utils;
+use common::file_utils;
+use common::ignore_utils::IgnoreRules;
+use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use reqwest::Url;
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// How long files have to stop changing before a sync is started, so a file that is saved many
+/// times in a row is only synced once
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+
+/// Used when no vault has a sync frequency
+const DEFAULT_SYNC_FREQUENCY: Duration = Duration::from_secs(5);
+
+/// Syncs, then watches every linked vault and syncs again once files have stopped changing
+/// Also syncs every time the shortest sync_frequency of the vaults passes so changes on the
+/// server are pulled down. Runs until ctrl-c is pressed, a failed sync is logged and retried
+/// While running it can be queried and controlled through the socket at control::socket_path()
+/// and the dashboard at dashboard::dashboard_addr()
+pub async fn watch(url: Url, pool: Pool<Sqlite>, default_ignore: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let socket_path = control::socket_path();
+    let listener = control::bind_socket(&socket_path).await?;
+    let control_state = ControlState::new(pool.clone());
+    tokio::spawn(control::serve(listener, control_state.clone()));
+    println!("control socket listening on {:?}", socket_path);
+
+    let dashboard_addr = dashboard::dashboard_addr()?;
+    let dashboard_listener = std::net::TcpListener::bind(dashboard_addr)?;
+    tokio::spawn(dashboard::serve(dashboard_listener, pool.clone(), control_state.clone())?);
+    println!("dashboard at http://{dashboard_addr}");
+
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let mut watcher = RecommendedWatcher::new(
+        move |result: notify::Result<Event>| {
+            if let Ok(event) = result {
+                let _ = sender.send(event);
+            }
+        },
+        Config::default(),
+    )?;
+
+    let vaults = common_db_utils::get_vault_id_and_root_directories(&pool).await?;
+    for (vault_id, root) in vaults.iter() {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        println!("watching vault {vault_id} at {:?}", root);
+    }
+    let rules = common_db_utils::get_ignore_rules_for_vaults(&pool, &default_ignore).await?;
+    let interval = get_sync_interval(&pool).await?;
+
+    loop {
+        control_state.start_sync();
+        let result = client_http_sync::sync_once(&url, &pool, &default_ignore, |plan| {
+            control_state.set_plan(plan)
+        })
+        .await;
+        if let Err(e) = &result {
+            println!("Error syncing, retrying in {:?}: {e}", interval);
+        }
+        control_state.finish_sync(result.err().map(|e| e.to_string()));
+        // the sync's own writes to disk show up as events, they don't need another sync
+        while events.try_recv().is_ok() {}
+
+        tokio::select! {
+            _ = wait_for_changes(&mut events, &vaults, &rules) => {},
+            _ = tokio::time::sleep(interval) => {},
+            _ = control_state.sync_requested() => {},
+            _ = tokio::signal::ctrl_c() => {
+                println!("stopped watching");
+                std::fs::remove_file(&socket_path)?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Waits for a change to a file that is synced then waits for QUIET_PERIOD without changes
+async fn wait_for_changes(
+    events: &mut UnboundedReceiver<Event>,
+    vaults: &[(i32, PathBuf)],
+    rules: &HashMap<i32, IgnoreRules>,
+) {
+    loop {
+        match events.recv().await {
+            Some(event) if is_relevant(&event, vaults, rules) => break,
+            Some(_) => continue,
+            // the watcher is gone, fall back to syncing on the interval
+            None => return std::future::pending().await,
+        }
+    }
+    while let Ok(Some(_)) = tokio::time::timeout(QUIET_PERIOD, events.recv()).await {}
+}
+
+/// An event is relevant if it touches a path that is synced, changes to ignored files and
+/// placeholders don't start a sync
+fn is_relevant(event: &Event, vaults: &[(i32, PathBuf)], rules: &HashMap<i32, IgnoreRules>) -> bool {
+    if event.kind.is_access() {
+        return false;
+    }
+    event.paths.iter().any(|path| {
+        if file_utils::is_placeholder(path) {
+            return false;
+        }
+        match find_vault(path, vaults) {
+            Some((vault_id, root)) => !rules
+                .get(&vault_id)
+                .is_some_and(|vault_rules| vault_rules.is_ignored_in_vault(path, root, path.is_dir())),
+            None => false,
+        }
+    })
+}
+
+fn find_vault<'a>(path: &Path, vaults: &'a [(i32, PathBuf)]) -> Option<(i32, &'a Path)> {
+    vaults
+        .iter()
+        .find(|(_, root)| path.starts_with(root))
+        .map(|(id, root)| (*id, root.as_path()))
+}
+
+/// The shortest sync_frequency of the linked vaults, in seconds in the vaults table
+async fn get_sync_interval(pool: &Pool<Sqlite>) -> Result<Duration, sqlx::Error> {
+    let row = sqlx::query("select min(sync_frequency) from vaults where sync_frequency > 0;")
+        .fetch_one(pool)
+        .await?;
+    Ok(row
+        .get::<Option<i64>, _>(0)
+        .map(|secs| Duration::from_secs(secs as u64))
+        .unwrap_or(DEFAULT_SYNC_FREQUENCY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, EventKind};
+
+    #[test]
+    fn test_ignored_and_placeholder_changes_are_not_relevant() {
+        let vaults = vec![(0, PathBuf::from("/home/sync_dir"))];
+        let rules = HashMap::from([(0, IgnoreRules::from_patterns(&["*.swp".to_string()]))]);
+        let event = |path: &str| {
+            Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from(path))
+        };
+
+        assert!(is_relevant(&event("/home/sync_dir/notes.txt"), &vaults, &rules));
+        assert!(!is_relevant(&event("/home/sync_dir/notes.txt.swp"), &vaults, &rules));
+        assert!(!is_relevant(&event("/home/sync_dir/data.csv.dxstub"), &vaults, &rules));
+        assert!(!is_relevant(&event("/elsewhere/notes.txt"), &vaults, &rules));
+
+        let access = Event::new(EventKind::Access(AccessKind::Any))
+            .add_path(PathBuf::from("/home/sync_dir/notes.txt"));
+        assert!(!is_relevant(&access, &vaults, &rules));
+    }
+}
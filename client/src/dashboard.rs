@@ -0,0 +1,330 @@
+//! Dashboard served by `watch` on localhost, shows the linked vaults, the running sync and its
+//! transfers, unresolved conflicts and recent errors
+//! The buttons post forms back to the dashboard which redirects to the page once done
+
+use crate::cli::format_age;
+use crate::client_db_api;
+use crate::conflicts::{self, Keep};
+use crate::control::{ControlState, DaemonStatus};
+use crate::sync_plan::PlannedFile;
+use askama::Template;
+use axum::extract::{Form, Path as UrlPath, State};
+use axum::http::{header, HeaderMap, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use common::common_db_utils;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use std::error::Error;
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Used when DASHBOARD_ADDR is not set in the .env
+const DEFAULT_DASHBOARD_ADDR: &str = "127.0.0.1:8384";
+
+/// Number of errors shown in the error history
+const ERROR_HISTORY: i64 = 20;
+
+type ApiError = (StatusCode, String);
+
+#[derive(Clone)]
+pub struct DashboardState {
+    pool: Pool<Sqlite>,
+    control: ControlState,
+}
+
+struct VaultRow {
+    vault_id: i32,
+    path: String,
+    paused: bool,
+    on_demand: bool,
+    direction: &'static str,
+}
+
+struct ErrorRow {
+    age: String,
+    vault: String,
+    detail: String,
+}
+
+#[derive(Template)]
+#[template(path = "dashboard.html")]
+struct DashboardTemplate {
+    status: DaemonStatus,
+    last_sync: String,
+    transfer_bytes: i64,
+    vaults: Vec<VaultRow>,
+    errors: Vec<ErrorRow>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolveForm {
+    pub vault_id: i32,
+    pub path: PathBuf,
+    pub keep: Keep,
+}
+
+/// The address from DASHBOARD_ADDR in the .env, anything but a loopback address is refused as
+/// the dashboard has no login
+pub fn dashboard_addr() -> Result<SocketAddr, Box<dyn Error>> {
+    let addr: SocketAddr = dotenvy::var("DASHBOARD_ADDR")
+        .unwrap_or_else(|_| DEFAULT_DASHBOARD_ADDR.to_string())
+        .parse()?;
+    if !addr.ip().is_loopback() {
+        return Err(format!("the dashboard only listens on localhost, {addr} is not a loopback address").into());
+    }
+    Ok(addr)
+}
+
+/// Builds the dashboard server on an already bound listener, the returned future runs it
+pub fn serve(
+    listener: TcpListener,
+    pool: Pool<Sqlite>,
+    control: ControlState,
+) -> Result<impl Future<Output = ()>, Box<dyn Error>> {
+    let server = axum::Server::from_tcp(listener)?.serve(router(pool, control).into_make_service());
+    Ok(async move {
+        if let Err(e) = server.await {
+            println!("Error serving the dashboard: {e}");
+        }
+    })
+}
+
+fn router(pool: Pool<Sqlite>, control: ControlState) -> Router {
+    Router::new()
+        .route("/", get(show_dashboard))
+        .route("/sync", post(force_sync))
+        .route("/vaults/:vault_id/pause", post(pause_vault))
+        .route("/vaults/:vault_id/resume", post(resume_vault))
+        .route("/conflicts/resolve", post(resolve_conflict))
+        .layer(middleware::from_fn(local_only))
+        .with_state(DashboardState { pool, control })
+}
+
+/// Rejects requests made through another host name or posted from another site, a page on the
+/// internet could otherwise use the browser to press the dashboard's buttons
+async fn local_only<B>(request: Request<B>, next: Next<B>) -> Response {
+    match is_local_request(request.headers()) {
+        true => next.run(request).await,
+        false => (StatusCode::FORBIDDEN, "the dashboard can only be used from localhost").into_response(),
+    }
+}
+
+fn is_local_request(headers: &HeaderMap) -> bool {
+    let is_local = |value: &str| {
+        let host = value.split("://").last().unwrap_or_default();
+        let host = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+            None => host.split(':').next().unwrap_or_default(),
+        };
+        matches!(host, "localhost" | "127.0.0.1" | "::1")
+    };
+    [header::HOST, header::ORIGIN]
+        .iter()
+        .filter_map(|name| headers.get(name))
+        .all(|value| value.to_str().is_ok_and(is_local))
+}
+
+fn internal_error(e: impl Error) -> ApiError {
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+async fn show_dashboard(State(state): State<DashboardState>) -> Result<impl IntoResponse, ApiError> {
+    let pool = &state.pool;
+    let paused = client_db_api::get_paused_vaults(pool).await.map_err(internal_error)?;
+    let on_demand = client_db_api::get_on_demand_vaults(pool).await.map_err(internal_error)?;
+    let directions = client_db_api::get_sync_directions(pool).await.map_err(internal_error)?;
+    let vaults = common_db_utils::get_vault_id_and_root_directories(pool)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|(vault_id, root)| VaultRow {
+            vault_id,
+            path: root.display().to_string(),
+            paused: paused.contains(&vault_id),
+            on_demand: on_demand.contains(&vault_id),
+            direction: directions.get(&vault_id).copied().unwrap_or_default().as_str(),
+        })
+        .collect();
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let errors = client_db_api::get_recent_log(pool, ERROR_HISTORY, Some("error"))
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|entry| ErrorRow {
+            age: format_age(now - entry.time),
+            vault: entry.vault_id.map_or("-".to_string(), |id| id.to_string()),
+            detail: entry.detail,
+        })
+        .collect();
+
+    let status = state.control.status();
+    let template = DashboardTemplate {
+        last_sync: status
+            .last_sync
+            .map_or("never".to_string(), |time| format_age(now - time)),
+        transfer_bytes: status.transfers.iter().map(|file| file.file_size).sum(),
+        status,
+        vaults,
+        errors,
+    };
+    template
+        .render()
+        .map(Html)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to render template. Error: {e}")))
+}
+
+async fn force_sync(State(state): State<DashboardState>) -> Redirect {
+    state.control.request_sync();
+    Redirect::to("/")
+}
+
+async fn pause_vault(
+    State(state): State<DashboardState>,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Redirect, ApiError> {
+    client_db_api::set_paused(&state.pool, vault_id, true)
+        .await
+        .map_err(internal_error)?;
+    Ok(Redirect::to("/"))
+}
+
+async fn resume_vault(
+    State(state): State<DashboardState>,
+    UrlPath(vault_id): UrlPath<i32>,
+) -> Result<Redirect, ApiError> {
+    client_db_api::set_paused(&state.pool, vault_id, false)
+        .await
+        .map_err(internal_error)?;
+    state.control.request_sync();
+    Ok(Redirect::to("/"))
+}
+
+/// Resolves a conflict by keeping one copy, then syncs so the sync overwrites the other copy
+async fn resolve_conflict(
+    State(state): State<DashboardState>,
+    Form(form): Form<ResolveForm>,
+) -> Result<Redirect, ApiError> {
+    let conflict = find_conflict(&state.control.status().conflicts, &form)
+        .ok_or((StatusCode::NOT_FOUND, format!("{:?} is not in conflict", form.path)))?;
+    let root = common_db_utils::get_vault_id_and_root_directories(&state.pool)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .find(|(id, _)| *id == conflict.vault_id)
+        .map(|(_, root)| root)
+        .ok_or((StatusCode::NOT_FOUND, format!("vault {} is not linked", conflict.vault_id)))?;
+
+    conflicts::keep_copy(&state.pool, &root, &conflict, form.keep)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    state.control.remove_conflict(conflict.vault_id, &conflict.path);
+    state.control.request_sync();
+    Ok(Redirect::to("/"))
+}
+
+/// Only files the last sync found in conflict can be resolved, so the form can't touch any other file
+fn find_conflict(conflicts: &[PlannedFile], form: &ResolveForm) -> Option<PlannedFile> {
+    conflicts
+        .iter()
+        .find(|file| file.vault_id == form.vault_id && file.path == form.path)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_plan::{PlannedAction, SyncPlan};
+    use axum::http::HeaderValue;
+    use filetime::FileTime;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[test]
+    fn test_only_local_requests_are_allowed() {
+        let headers = |host: &str, origin: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+            if let Some(origin) = origin {
+                headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+            }
+            headers
+        };
+
+        assert!(is_local_request(&headers("127.0.0.1:8384", None)));
+        assert!(is_local_request(&headers("localhost:8384", Some("http://localhost:8384"))));
+        assert!(is_local_request(&headers("[::1]:8384", None)));
+        assert!(!is_local_request(&headers("127.0.0.1:8384", Some("https://example.com"))));
+        // a dns name pointed at 127.0.0.1 by an attacker
+        assert!(!is_local_request(&headers("rebind.example.com:8384", None)));
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_resolves_conflicts() {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        client_db_api::create_tables(&pool).await.unwrap();
+        sqlx::query("CREATE TABLE vaults (vault_id INTEGER PRIMARY KEY, abs_path TEXT NOT NULL);")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        sqlx::query("INSERT INTO vaults (vault_id, abs_path) VALUES (1, ?);")
+            .bind(dir.path().to_str().unwrap())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let file = dir.path().join("draft.txt");
+        std::fs::write(&file, "mine").unwrap();
+        filetime::set_file_mtime(&file, FileTime::from_unix_time(1000, 0)).unwrap();
+
+        let control = ControlState::new(pool.clone());
+        control.set_plan(&SyncPlan {
+            files: vec![PlannedFile {
+                vault_id: 1,
+                action: PlannedAction::Conflict,
+                path: PathBuf::from("draft.txt"),
+                file_size: 4,
+                server_version: Default::default(),
+            }],
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, pool.clone(), control.clone()).unwrap());
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let page = client.get(&url).send().await.unwrap().text().await.unwrap();
+        assert!(page.contains("draft.txt"));
+
+        let resolve = |path: &str| {
+            client
+                .post(format!("{url}/conflicts/resolve"))
+                .form(&[("vault_id", "1"), ("path", path), ("keep", "server")])
+                .send()
+        };
+        assert_eq!(resolve("../secret.txt").await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(resolve("draft.txt").await.unwrap().status(), StatusCode::SEE_OTHER);
+
+        let modified = FileTime::from_last_modification_time(&std::fs::metadata(&file).unwrap());
+        assert_eq!(modified.unix_seconds(), 999);
+        // recorded as unchanged since the last sync so the server's copy is the newer one
+        let synced = client_db_api::get_synced_entries(&pool, 1).await.unwrap();
+        assert_eq!((synced[0].modified_time, synced[0].version.is_empty()), (999, true));
+        assert!(control.status().conflicts.is_empty());
+    }
+}
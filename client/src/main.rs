@@ -1,92 +1,195 @@
 extern crate core;
 
+mod cli;
 mod client_http_sync;
 mod client_db_api;
-
-use notify::*;
+mod conflicts;
+mod control;
+mod daemon;
+mod dashboard;
+mod placeholders;
+mod sync_plan;
+
+use crate::cli::{Cli, Command, OutputFormat, Toggle, VaultCommand, EXIT_OK, EXIT_PENDING_CHANGES};
+use crate::control::{ControlRequest, DaemonStatus};
+use crate::sync_plan::PlannedAction;
+use clap::Parser;
 use common::common_db_utils;
+use reqwest::Url;
+use sqlx::{Pool, Sqlite};
+use std::error::Error;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     //init environment variables
     dotenvy::from_path("./client/.env").unwrap();
+    let cli = Cli::parse();
 
-    // Initial load of db - spawns two lots of pools, gives one to common_utils to read
-    // local files and insert/update database accordingly
-    // second pool is used for general communication between client and db
     let pool = client_db_api::init_db(
         dotenvy::var("DATABASE_URL")
             .unwrap())
         .await
         .unwrap();
-    let pool2 = client_db_api::init_db(
-        dotenvy::var("DATABASE_URL")
-            .unwrap())
-        .await
-        .unwrap();
-
-    println!("database was: ");
-    common_db_utils::select_all_from_file_metadata(&pool).await.unwrap();
-
-    common_db_utils::delete_db_and_recreate_for_client(&pool).await.unwrap();
-    println!("recreated db");
-
-    tokio::task::spawn_blocking(move || {
-        common_db_utils::init_metadata_into_db(&pool2, false).unwrap();
-    })
-        .await
-        .unwrap();
+    client_db_api::create_tables(&pool).await.unwrap();
 
     let url = reqwest::Url::parse(
         &*dotenvy::var("LOCAL_HOST")
             .unwrap())
         .unwrap();
 
-    // Does initial communication with server, client and url is returned for later reuse
-    // local_metadata is read from db, server_data is retrieved from server
-    // file_id is the latest key from the servers db, used to update local files
-    // that do not exist on server
-    client_http_sync::init_metadata_sync(url, &pool).await.unwrap();
-
-
-    // send_metadata_to_server needs to be called after the initial sync to ensure threads are joined
-    //send_metadata_to_server(&client, url, local_data).await;
-
-    //todo - metadata diffing on client to ensure only new files are being inserted
-    //client_db_api::insert_server_metadata_into_client_db(&pool, &mut server_data).await.unwrap();
-
-
-
-    //sync_logic::initial_sync(&dir_settings);
-
-    //todo - create a atomic boolean value to limit writes in short succession
-    //todo - use a timer to determine when file was last modified
-    //todo - if there is another event before this timer reaches 0, reset the timer
-    //todo - so if a file is edited many times it will only be synced after a period of inactivity
-    /*
-    //NB - This watcher needs to be initiated like this to allow for asynchronous runtime be
-    //between web server and notifications
-    let mut watcher =
-        RecommendedWatcher::new(move |result: Result<Event>| {
-            let event = result.unwrap();
+    // running without a command does a single sync
+    let command = cli.command.unwrap_or(Command::Sync {
+        dry_run: false,
+        format: OutputFormat::Text,
+    });
+    let code = match run(command, &url, &pool).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            cli::exit_code_for(e.as_ref())
+        }
+    };
+    pool.close().await;
+    std::process::exit(code);
+}
 
-            if event.kind.is_modify() {
-                sync_changed_file(&event.paths, &dir_settings);
-            } else if event.kind.is_create()  && event.kind == Create(Folder) {
-                create_folder_on_remote(&event.paths, &dir_settings);
-            } else if event.kind.is_remove() {
-                old_sync_logic::remove_files_and_dirs_from_remote(&event.paths, &dir_settings);
+/// Runs a command and returns the exit code
+async fn run(command: Command, url: &Url, pool: &Pool<Sqlite>) -> Result<i32, Box<dyn Error>> {
+    match command {
+        Command::Sync { dry_run: false, .. } => {
+            // a running daemon does the sync so two syncs never run at once
+            if control::send_request(&control::socket_path(), &ControlRequest::Sync).await?.is_some() {
+                println!("sync started by the running daemon");
+                return Ok(EXIT_OK);
             }
-        }, Config::default()
-            .with_poll_interval(frequency))?;
-
-    watcher.watch(Path::new(watched_dir.as_str()), RecursiveMode::Recursive)?;
-
-
-
-     */
-
-    Ok(())
+            // The server's default ignore patterns are needed before the scan so ignored files never reach the db
+            let default_ignore = client_http_sync::get_default_ignore_patterns(url).await;
+            client_http_sync::sync_once(url, pool, &default_ignore, |_| {}).await?;
+        }
+        // a dry run scans and plans against an in memory copy of the db, nothing is transferred, logged or saved
+        Command::Sync { dry_run: true, format } | Command::Diff { format } => {
+            let default_ignore = client_http_sync::get_default_ignore_patterns(url).await;
+            let scratch = client_db_api::copy_to_memory(pool).await?;
+            client_db_api::rescan_local_files(&scratch, &default_ignore).await?;
+            let plan =
+                client_http_sync::init_metadata_sync(url.clone(), &scratch, &default_ignore, true).await?;
+            scratch.close().await;
+            cli::print_plan(&plan, format);
+            if matches!(command, Command::Diff { .. }) && plan.has_changes() {
+                return Ok(EXIT_PENDING_CHANGES);
+            }
+        }
+        Command::Watch => {
+            let default_ignore = client_http_sync::get_default_ignore_patterns(url).await;
+            daemon::watch(url.clone(), pool.clone(), default_ignore).await?;
+        }
+        Command::Status => {
+            if let Some(data) = control::send_request(&control::socket_path(), &ControlRequest::Status).await? {
+                let status: DaemonStatus = serde_json::from_value(data)?;
+                if cli::print_daemon_status(&status) {
+                    return Ok(EXIT_PENDING_CHANGES);
+                }
+                return Ok(EXIT_OK);
+            }
+            let default_ignore = client_http_sync::get_default_ignore_patterns(url).await;
+            client_db_api::rescan_local_files(pool, &default_ignore).await?;
+            let (diff, _) = client_http_sync::plan_sync(&client_http_sync::http_client(), url, pool, &default_ignore).await?;
+
+            let vaults = common_db_utils::get_vault_id_and_root_directories(pool).await?;
+            let paused = client_db_api::get_paused_vaults(pool).await?;
+            if cli::print_status(&diff, &vaults, &paused) {
+                return Ok(EXIT_PENDING_CHANGES);
+            }
+        }
+        Command::Vault(VaultCommand::List) => {
+            let linked = common_db_utils::get_vault_id_and_root_directories(pool).await?;
+            let vaults = client_http_sync::get_server_vaults(url).await?;
+            cli::print_vaults(&vaults, &linked);
+        }
+        Command::Vault(VaultCommand::Add { vault_id, local_dir }) => {
+            client_http_sync::link_vault(url, pool, vault_id, &local_dir).await?;
+            // the vault's first sync fills an empty directory or merges an existing one
+            let default_ignore = client_http_sync::get_default_ignore_patterns(url).await;
+            client_http_sync::sync_once(url, pool, &default_ignore, |_| {}).await?;
+        }
+        Command::Vault(VaultCommand::Remove { vault_id }) => {
+            if !client_db_api::remove_vault(pool, vault_id).await? {
+                return Err(format!("vault {vault_id} is not linked").into());
+            }
+            println!("unlinked vault {vault_id}, local files were left in place");
+        }
+        Command::Pause { vault_id } | Command::Resume { vault_id } => {
+            let paused = matches!(command, Command::Pause { .. });
+            let request = match paused {
+                true => ControlRequest::Pause { vault_id },
+                false => ControlRequest::Resume { vault_id },
+            };
+            let vault_ids = match control::send_request(&control::socket_path(), &request).await? {
+                Some(data) => serde_json::from_value(data)?,
+                None => client_db_api::set_paused_vaults(pool, vault_id, paused).await?,
+            };
+            for id in vault_ids {
+                println!("vault {id} {}", if paused { "paused" } else { "resumed" });
+            }
+        }
+        Command::Log { limit, errors } => {
+            let action = errors.then_some("error");
+            cli::print_log(&client_db_api::get_recent_log(pool, limit, action).await?);
+        }
+        Command::Select { vault_id, path } => {
+            client_db_api::select_path(pool, vault_id, &path).await?;
+            println!("selection for vault {vault_id}: {:?}", client_db_api::get_selected_paths(pool).await?.get(&vault_id));
+        }
+        Command::Unselect { vault_id, path } => {
+            client_db_api::unselect_path(pool, vault_id, &path).await?;
+            println!("selection for vault {vault_id}: {:?}", client_db_api::get_selected_paths(pool).await?.get(&vault_id));
+        }
+        Command::OnDemand { vault_id, state } => {
+            client_db_api::set_on_demand(pool, vault_id, state == Toggle::On).await?;
+            println!("on-demand for vault {vault_id}: {}", state == Toggle::On);
+        }
+        Command::Direction { vault_id, direction } => {
+            client_db_api::set_sync_direction(pool, vault_id, direction).await?;
+            println!("sync direction for vault {vault_id}: {}", direction.as_str());
+        }
+        Command::Symlinks { vault_id, policy } => {
+            client_db_api::set_symlink_policy(pool, vault_id, policy).await?;
+            println!("symlinks in vault {vault_id}: {}", policy.as_str());
+        }
+        Command::Conflicts { vault_id, strategy } => {
+            client_db_api::set_conflict_strategy(pool, vault_id, strategy).await?;
+            println!("conflicts in vault {vault_id}: {}", strategy.as_str());
+        }
+        Command::Resolve { vault_id, path, keep } => {
+            let default_ignore = client_http_sync::get_default_ignore_patterns(url).await;
+            client_db_api::rescan_local_files(pool, &default_ignore).await?;
+            let (_, plan) =
+                client_http_sync::plan_sync(&client_http_sync::http_client(), url, pool, &default_ignore).await?;
+            // only files the sync would leave pending can be resolved
+            let conflict = plan
+                .files
+                .into_iter()
+                .find(|file| file.action == PlannedAction::Conflict && file.vault_id == vault_id && file.path == path)
+                .ok_or(format!("{:?} is not in conflict in vault {vault_id}", path))?;
+            let root = common_db_utils::get_vault_id_and_root_directories(pool)
+                .await?
+                .into_iter()
+                .find(|(id, _)| *id == vault_id)
+                .map(|(_, root)| root)
+                .ok_or(format!("vault {vault_id} is not linked"))?;
+
+            let full_path = conflicts::keep_copy(pool, &root, &conflict, keep).await?;
+            println!("{} kept the {} copy", full_path.display(), keep.as_str());
+            // a running daemon does the sync so two syncs never run at once
+            if control::send_request(&control::socket_path(), &ControlRequest::Sync).await?.is_none() {
+                client_http_sync::sync_once(url, pool, &default_ignore, |_| {}).await?;
+            }
+        }
+        Command::Fetch { path } => {
+            client_http_sync::fetch_placeholder(url.clone(), pool, &path).await?;
+        }
+    }
+    Ok(EXIT_OK)
 }
 
 //todo - config should have direct
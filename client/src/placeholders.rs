@@ -0,0 +1,145 @@
+use common::file_utils::{
+    convert_path_to_local, placeholder_path, FileMetadata, MetadataBlob, VaultMetadata,
+};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// On-demand vaults don't download files that aren't on disk yet, a placeholder is written instead
+/// The placeholder is a small json file next to where the file would be, named with a .dxstub extension
+/// It holds the server metadata of the file so it can be fetched later with `client fetch <path>`
+/// Files that have already been fetched keep syncing as normal
+///
+/// Splits the files the client needs into those to download and those to write placeholders for
+/// Returns (to_download, to_placeholder)
+pub fn split_on_demand_files(
+    new_for_client: MetadataBlob,
+    on_demand_vaults: &HashSet<i32>,
+    id_and_root_dirs: &[(i32, PathBuf)],
+) -> (MetadataBlob, Vec<(PathBuf, FileMetadata)>) {
+    let mut to_download = MetadataBlob {
+        vaults: HashMap::new(),
+    };
+    let mut to_placeholder = Vec::new();
+
+    for (vault_id, vault) in new_for_client.vaults {
+        let local_root = id_and_root_dirs
+            .iter()
+            .find(|(id, _)| *id == vault_id)
+            .map(|(_, root)| root.clone());
+
+        let mut download = VaultMetadata {
+            files: vec![],
+            vault_id,
+            ..Default::default()
+        };
+        for file in vault.files {
+            match (&local_root, on_demand_vaults.contains(&vault_id)) {
+                (Some(root), true) => {
+                    let local_path =
+                        convert_path_to_local(&file.full_path, &file.absolute_root_dir, root);
+                    if local_path.exists() {
+                        download.files.push(file);
+                    } else {
+                        to_placeholder.push((local_path, file));
+                    }
+                }
+                _ => download.files.push(file),
+            }
+        }
+        to_download.vaults.insert(vault_id, download);
+    }
+
+    (to_download, to_placeholder)
+}
+
+/// Writes a placeholder for each file, the placeholder's modified time is set to the
+/// modified time of the file on the server so the tree can be browsed as if it were local
+pub fn write_placeholders(files: &[(PathBuf, FileMetadata)]) {
+    for (local_path, metadata) in files {
+        let placeholder = placeholder_path(local_path);
+        if let Some(parent) = placeholder.parent() {
+            fs::create_dir_all(parent)
+                .unwrap_or_else(|e| panic!("Error creating directory {:?}: {e}", parent));
+        }
+        let contents = serde_json::to_string_pretty(metadata).unwrap();
+        fs::write(&placeholder, contents)
+            .unwrap_or_else(|e| panic!("Error writing placeholder {:?}: {e}", placeholder));
+        filetime::set_file_mtime(
+            &placeholder,
+            metadata.modified_filetime(),
+        )
+        .unwrap();
+        println!("wrote placeholder {:?}", placeholder);
+    }
+}
+
+/// Reads the server metadata stored in a placeholder, accepts either the placeholder itself
+/// or the path of the file it stands in for
+/// Returns the path of the placeholder and the metadata
+pub fn read_placeholder(path: &Path) -> Result<(PathBuf, FileMetadata), Box<dyn Error>> {
+    let placeholder = if common::file_utils::is_placeholder(path) {
+        path.to_path_buf()
+    } else {
+        placeholder_path(path)
+    };
+    let contents = fs::read_to_string(&placeholder)
+        .map_err(|e| format!("No placeholder at {:?}: {e}", placeholder))?;
+    let metadata = serde_json::from_str(&contents)?;
+    Ok((placeholder, metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_file(name: &str, file_id: i32) -> FileMetadata {
+        let root = PathBuf::from("/server/vault0");
+        let (modified_time, file_size) = (1_600_000_000, 2_000_000_000);
+        FileMetadata::new_from_server(file_id, 0, root.join(name), root, "vault0".to_string(), modified_time, file_size)
+    }
+
+    #[test]
+    fn test_missing_files_become_placeholders_and_round_trip() {
+        let local = tempfile::tempdir().unwrap();
+        let root = local.path().to_path_buf();
+        fs::write(root.join("fetched.csv"), "already here").unwrap();
+        let blob = MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata {
+                files: vec![server_file("fetched.csv", 1), server_file("raw/huge.csv", 2)],
+                vault_id: 0,
+                ..Default::default()
+            })]),
+        };
+
+        let (download, placeholders) =
+            split_on_demand_files(blob, &HashSet::from([0]), &[(0, root.clone())]);
+        write_placeholders(&placeholders);
+
+        assert_eq!(download.vaults[&0].files.len(), 1);
+        assert_eq!(download.vaults[&0].files[0].file_id, 1);
+        assert!(!root.join("raw/huge.csv").exists());
+        let (placeholder, metadata) = read_placeholder(&root.join("raw/huge.csv")).unwrap();
+        assert_eq!(placeholder, root.join("raw/huge.csv.dxstub"));
+        assert_eq!(metadata.file_id, 2);
+        assert_eq!(metadata.file_size, 2_000_000_000);
+    }
+
+    #[test]
+    fn test_vaults_not_on_demand_download_everything() {
+        let blob = MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata {
+                files: vec![server_file("a.csv", 1), server_file("b.csv", 2)],
+                vault_id: 0,
+                ..Default::default()
+            })]),
+        };
+
+        let (download, placeholders) =
+            split_on_demand_files(blob, &HashSet::new(), &[(0, PathBuf::from("/nowhere"))]);
+
+        assert_eq!(download.vaults[&0].files.len(), 2);
+        assert!(placeholders.is_empty());
+    }
+}
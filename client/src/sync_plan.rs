@@ -0,0 +1,428 @@
+use common::file_utils::{ConflictResolution, FileMetadata, MetadataBlob, MetadataDiff};
+use common::version_vector::VersionVector;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What a sync will do to a single file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedAction {
+    /// The file is new and will be sent to the server
+    Upload,
+    /// The server's copy will be replaced by the newer local copy
+    OverwriteServer,
+    /// The file was deleted here since the last sync and will be deleted on the server
+    DeleteServer,
+    /// Only the permissions changed here since the last sync, they will be applied on the server
+    ChmodServer,
+    /// The file is new and will be downloaded
+    Download,
+    /// The local copy will be replaced by the newer server copy
+    OverwriteLocal,
+    /// The file was deleted on the server since the last sync and will be deleted here
+    DeleteLocal,
+    /// Only the permissions changed on the server since the last sync, they will be applied here
+    ChmodLocal,
+    /// The file is new and a placeholder will be written as the vault is on-demand
+    Placeholder,
+    /// The local change stays local as the vault is download only or read-only
+    Blocked,
+    /// Both copies changed, the local copy will be moved to a conflict copy so the server copy can be downloaded
+    ConflictCopy,
+    /// Both copies changed and the vault's strategy doesn't pick one, nothing will be transferred
+    Conflict,
+}
+
+impl PlannedAction {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PlannedAction::Upload => "upload",
+            PlannedAction::OverwriteServer => "overwrite server",
+            PlannedAction::DeleteServer => "delete on server",
+            PlannedAction::ChmodServer => "chmod server",
+            PlannedAction::Download => "download",
+            PlannedAction::OverwriteLocal => "overwrite local",
+            PlannedAction::DeleteLocal => "delete local",
+            PlannedAction::ChmodLocal => "chmod local",
+            PlannedAction::Placeholder => "placeholder",
+            PlannedAction::Blocked => "blocked",
+            PlannedAction::ConflictCopy => "conflict copy",
+            PlannedAction::Conflict => "conflict",
+        }
+    }
+}
+
+/// A single file in the plan, path is relative to the vault root
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlannedFile {
+    pub vault_id: i32,
+    pub action: PlannedAction,
+    pub path: PathBuf,
+    pub file_size: i64,
+    /// Version of the server's copy, only set for conflicts so resolving one can make the kept copy win
+    #[serde(default, skip_serializing_if = "VersionVector::is_empty")]
+    pub server_version: VersionVector,
+}
+
+/// Every action a sync will take, built from the diff before anything is transferred
+/// Displays as human readable text and serializes to JSON
+#[derive(Serialize, Debug, Default)]
+pub struct SyncPlan {
+    pub files: Vec<PlannedFile>,
+    /// How far the server's clock is ahead of this device's in milliseconds
+    pub clock_skew_ms: i64,
+}
+
+/// Clock skew past which the plan warns that the times of files changed on both sides can't be trusted
+pub const CLOCK_SKEW_WARNING_MS: i64 = 30_000;
+
+impl SyncPlan {
+    /// local and server are the metadata the diff was built from, they are used to tell new files
+    /// apart from ones that will be overwritten
+    pub fn build(
+        diff: &MetadataDiff,
+        local: &MetadataBlob,
+        server: &MetadataBlob,
+        on_demand_vaults: &HashSet<i32>,
+    ) -> Self {
+        let mut files = vec![];
+
+        for (vault_id, vault) in diff.new_for_server.iter() {
+            for file in vault.files.iter() {
+                let action = match contains_path(server, *vault_id, &file.relative_path()) {
+                    true => PlannedAction::OverwriteServer,
+                    false => PlannedAction::Upload,
+                };
+                files.push(planned(*vault_id, action, file));
+            }
+        }
+
+        for (vault_id, vault) in diff.new_for_client.iter() {
+            for file in vault.files.iter() {
+                let action = match contains_path(local, *vault_id, &file.relative_path()) {
+                    true => PlannedAction::OverwriteLocal,
+                    false if on_demand_vaults.contains(vault_id) => PlannedAction::Placeholder,
+                    false => PlannedAction::Download,
+                };
+                files.push(planned(*vault_id, action, file));
+            }
+        }
+
+        for (vault_id, vault) in diff.delete_on_server.iter() {
+            for file in vault.files.iter() {
+                files.push(planned(*vault_id, PlannedAction::DeleteServer, file));
+            }
+        }
+
+        for (vault_id, vault) in diff.delete_on_client.iter() {
+            for file in vault.files.iter() {
+                files.push(planned(*vault_id, PlannedAction::DeleteLocal, file));
+            }
+        }
+
+        for (vault_id, vault) in diff.modes_for_server.iter() {
+            for file in vault.files.iter() {
+                files.push(planned(*vault_id, PlannedAction::ChmodServer, file));
+            }
+        }
+
+        for (vault_id, vault) in diff.modes_for_client.iter() {
+            for file in vault.files.iter() {
+                files.push(planned(*vault_id, PlannedAction::ChmodLocal, file));
+            }
+        }
+
+        for (vault_id, vault) in diff.blocked_for_server.iter() {
+            for file in vault.files.iter() {
+                files.push(planned(*vault_id, PlannedAction::Blocked, file));
+            }
+        }
+
+        // conflicts one copy wins are already planned as overwrites
+        for (vault_id, conflicts) in diff.conflicts.iter() {
+            for conflict in conflicts.iter() {
+                match conflict.resolution {
+                    ConflictResolution::Pending => files.push(PlannedFile {
+                        server_version: conflict.server.version.clone(),
+                        ..planned(*vault_id, PlannedAction::Conflict, &conflict.client)
+                    }),
+                    ConflictResolution::KeepBoth => {
+                        files.push(planned(*vault_id, PlannedAction::ConflictCopy, &conflict.client))
+                    }
+                    ConflictResolution::KeepServer | ConflictResolution::KeepClient => {}
+                }
+            }
+        }
+
+        files.sort_by(|a, b| (a.vault_id, a.action, &a.path).cmp(&(b.vault_id, b.action, &b.path)));
+        SyncPlan { files, clock_skew_ms: 0 }
+    }
+
+    /// A warning when the server's clock and this device's are too far apart
+    pub fn clock_skew_warning(&self) -> Option<String> {
+        if self.clock_skew_ms.abs() <= CLOCK_SKEW_WARNING_MS {
+            return None;
+        }
+        Some(format!(
+            "the server's clock is {:.1}s {} this device's, check the time settings on both",
+            self.clock_skew_ms.abs() as f64 / 1000.0,
+            if self.clock_skew_ms > 0 { "ahead of" } else { "behind" },
+        ))
+    }
+
+    /// True if the sync will transfer anything or there are conflicts to resolve
+    pub fn has_changes(&self) -> bool {
+        self.files
+            .iter()
+            .any(|file| file.action != PlannedAction::Blocked)
+    }
+
+    /// The files that will be sent or written, blocked and conflicting files are left out
+    pub fn transfers(&self) -> Vec<PlannedFile> {
+        self.files
+            .iter()
+            .filter(|file| {
+                !matches!(
+                    file.action,
+                    PlannedAction::Blocked | PlannedAction::ConflictCopy | PlannedAction::Conflict
+                )
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Number of files for each action
+    pub fn counts(&self) -> BTreeMap<PlannedAction, usize> {
+        let mut counts = BTreeMap::new();
+        for file in self.files.iter() {
+            *counts.entry(file.action).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(warning) = self.clock_skew_warning() {
+            writeln!(f, "warning: {warning}")?;
+        }
+        if self.files.is_empty() {
+            return writeln!(f, "nothing to sync");
+        }
+
+        let mut current_vault = None;
+        for file in self.files.iter() {
+            if current_vault != Some(file.vault_id) {
+                writeln!(f, "vault {}", file.vault_id)?;
+                current_vault = Some(file.vault_id);
+            }
+            writeln!(
+                f,
+                "  {:<16} {} ({} bytes)",
+                file.action.label(),
+                file.path.display(),
+                file.file_size
+            )?;
+        }
+
+        let summary = self
+            .counts()
+            .iter()
+            .map(|(action, count)| format!("{count} {}", action.label()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(f, "{summary}")
+    }
+}
+
+fn planned(vault_id: i32, action: PlannedAction, file: &FileMetadata) -> PlannedFile {
+    PlannedFile {
+        vault_id,
+        action,
+        path: file.relative_path(),
+        file_size: file.file_size,
+        server_version: VersionVector::default(),
+    }
+}
+
+fn contains_path(blob: &MetadataBlob, vault_id: i32, relative_path: &Path) -> bool {
+    blob.vaults
+        .get(&vault_id)
+        .is_some_and(|vault| vault.files.iter().any(|file| file.relative_path() == relative_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::file_utils::{get_metadata_diff, ConflictStrategy, SyncedEntry, VaultMetadata};
+    use std::collections::HashMap;
+
+    fn file(root: &str, name: &str, modified_time: i64, file_size: i64) -> FileMetadata {
+        let root = PathBuf::from(root);
+        FileMetadata::new_from_server(1, 0, root.join(name), root, "sync_dir".to_string(), modified_time, file_size)
+    }
+
+    fn blob(files: Vec<FileMetadata>) -> MetadataBlob {
+        MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata {
+                files,
+                vault_id: 0,
+                ..Default::default()
+            })]),
+        }
+    }
+
+    #[test]
+    fn test_plan_classifies_every_change() {
+        let local = blob(vec![
+            file("/home/sync_dir", "new_local.txt", 100, 1),
+            file("/home/sync_dir", "edited_local.txt", 200, 2),
+            file("/home/sync_dir", "edited_server.txt", 100, 3),
+            file("/home/sync_dir", "both.txt", 100, 4),
+        ]);
+        let server = blob(vec![
+            file("/srv/sync_dir", "edited_local.txt", 100, 2),
+            file("/srv/sync_dir", "edited_server.txt", 200, 3),
+            file("/srv/sync_dir", "both.txt", 100, 5),
+            file("/srv/sync_dir", "new_server.txt", 100, 6),
+        ]);
+        let diff = get_metadata_diff(local.clone(), server.clone());
+
+        let plan = SyncPlan::build(&diff, &local, &server, &HashSet::new());
+
+        let actions = plan
+            .files
+            .iter()
+            .map(|f| (f.action, f.path.to_str().unwrap()))
+            .collect::<Vec<(PlannedAction, &str)>>();
+        assert_eq!(
+            actions,
+            vec![
+                (PlannedAction::Upload, "new_local.txt"),
+                (PlannedAction::OverwriteServer, "edited_local.txt"),
+                (PlannedAction::Download, "new_server.txt"),
+                (PlannedAction::OverwriteLocal, "edited_server.txt"),
+                (PlannedAction::Conflict, "both.txt"),
+            ]
+        );
+        assert!(plan.has_changes());
+        assert!(plan.to_string().contains("1 upload, 1 overwrite server"));
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["files"][1]["action"], "overwrite_server");
+    }
+
+    #[test]
+    fn test_plan_lists_deletions() {
+        let mut local = blob(vec![file("/home/sync_dir", "gone_from_server.txt", 100, 1)]);
+        local.vaults.get_mut(&0).unwrap().synced = vec![
+            SyncedEntry { relative_path: PathBuf::from("gone_from_server.txt"), modified_time: 100, modified_nanos: 0, mode: None, version: VersionVector::default() },
+            SyncedEntry { relative_path: PathBuf::from("deleted_here.txt"), modified_time: 100, modified_nanos: 0, mode: None, version: VersionVector::default() },
+        ];
+        let server = blob(vec![file("/srv/sync_dir", "deleted_here.txt", 100, 2)]);
+        let diff = get_metadata_diff(local.clone(), server.clone());
+
+        let plan = SyncPlan::build(&diff, &local, &server, &HashSet::new());
+
+        let actions = plan
+            .files
+            .iter()
+            .map(|f| (f.action, f.path.to_str().unwrap()))
+            .collect::<Vec<(PlannedAction, &str)>>();
+        assert_eq!(
+            actions,
+            vec![
+                (PlannedAction::DeleteServer, "deleted_here.txt"),
+                (PlannedAction::DeleteLocal, "gone_from_server.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_follows_the_conflict_strategy() {
+        let local = blob(vec![
+            file("/home/sync_dir", "older_here.txt", 100, 1),
+            file("/home/sync_dir", "newer_here.txt", 300, 2),
+        ]);
+        let server = blob(vec![
+            file("/srv/sync_dir", "older_here.txt", 200, 3),
+            file("/srv/sync_dir", "newer_here.txt", 200, 4),
+        ]);
+        let concurrent = |mut blob: MetadataBlob, device: &str| {
+            for file in blob.vaults.get_mut(&0).unwrap().files.iter_mut() {
+                file.version = VersionVector::default().incremented(device);
+            }
+            blob
+        };
+        let local = concurrent(local, "laptop");
+        let server = concurrent(server, "server");
+        let plan_for = |strategy: ConflictStrategy| {
+            let mut local = local.clone();
+            local.vaults.get_mut(&0).unwrap().conflict_strategy = strategy;
+            let diff = get_metadata_diff(local.clone(), server.clone());
+            SyncPlan::build(&diff, &local, &server, &HashSet::new())
+                .files
+                .iter()
+                .map(|f| (f.action, f.path.to_str().unwrap().to_string()))
+                .collect::<Vec<(PlannedAction, String)>>()
+        };
+        let action = |action: PlannedAction, path: &str| (action, path.to_string());
+
+        assert_eq!(
+            plan_for(ConflictStrategy::Newest),
+            vec![
+                action(PlannedAction::OverwriteServer, "newer_here.txt"),
+                action(PlannedAction::OverwriteLocal, "older_here.txt"),
+            ]
+        );
+        assert_eq!(
+            plan_for(ConflictStrategy::Client),
+            vec![
+                action(PlannedAction::OverwriteServer, "newer_here.txt"),
+                action(PlannedAction::OverwriteServer, "older_here.txt"),
+            ]
+        );
+        assert_eq!(
+            plan_for(ConflictStrategy::KeepBoth),
+            vec![
+                action(PlannedAction::OverwriteLocal, "newer_here.txt"),
+                action(PlannedAction::OverwriteLocal, "older_here.txt"),
+                action(PlannedAction::ConflictCopy, "newer_here.txt"),
+                action(PlannedAction::ConflictCopy, "older_here.txt"),
+            ]
+        );
+        assert_eq!(
+            plan_for(ConflictStrategy::Manual),
+            vec![
+                action(PlannedAction::Conflict, "newer_here.txt"),
+                action(PlannedAction::Conflict, "older_here.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_on_demand_downloads_become_placeholders() {
+        let local = blob(vec![]);
+        let server = blob(vec![file("/srv/sync_dir", "huge.csv", 100, 6)]);
+        let diff = get_metadata_diff(local.clone(), server.clone());
+
+        let plan = SyncPlan::build(&diff, &local, &server, &HashSet::from([0]));
+
+        assert_eq!(plan.files[0].action, PlannedAction::Placeholder);
+        assert_eq!(plan.to_string(), "vault 0\n  placeholder      huge.csv (6 bytes)\n1 placeholder\n");
+    }
+
+    #[test]
+    fn test_large_clock_skew_is_warned_about() {
+        let mut plan = SyncPlan { clock_skew_ms: -CLOCK_SKEW_WARNING_MS, ..Default::default() };
+        assert!(plan.clock_skew_warning().is_none());
+
+        plan.clock_skew_ms = -90_000;
+        assert_eq!(
+            plan.to_string(),
+            "warning: the server's clock is 90.0s behind this device's, check the time settings on both\nnothing to sync\n"
+        );
+    }
+}
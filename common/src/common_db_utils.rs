@@ -1,8 +1,11 @@
-use crate::file_utils::{FileMetadata, MetadataBlob};
+use crate::file_utils::{compare_times, FileMetadata, MetadataBlob, SymlinkPolicy};
+use crate::ignore_utils::IgnoreRules;
+use crate::version_vector::{VersionVector, SERVER_DEVICE};
 use crate::{file_utils, RemoteFile};
 use sqlx::sqlite::SqliteRow;
 use sqlx::{Pool, Row, Sqlite};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 /// Reads data from the file_system and updates the Database accordingly
 /// Ensures that files that have changed on disk while syncing is not active are
@@ -10,59 +13,123 @@ use std::path::PathBuf;
 /// NB - All functions in this file assume that the client and database table names are identical
 /// could be separated by feeding in the queries but simpler to have a tighter dependence
 /// Assumes table called vaults
+/// Files matching the default ignore patterns or the vault's .datoxidizeignore are not added
+/// Symbolic links are treated the way symlink_policies says for their vault, the server always keeps them as links
+/// The server gives files back the versions they had before it restarted
 #[tokio::main]
 pub async fn init_metadata_into_db(
     pool: &Pool<Sqlite>,
     is_server: bool,
+    default_ignore_patterns: &[String],
+    symlink_policies: &HashMap<i32, SymlinkPolicy>,
 ) -> Result<(), sqlx::Error> {
     let vault_rows = sqlx::query("select * from vaults;").fetch_all(pool).await?;
 
     let vaults = get_vaults_from_rows(vault_rows);
 
     for (vault_id, vault_path, root_dir) in vaults {
-        let paths = file_utils::get_all_files_from_path(&vault_path)
+        let rules = IgnoreRules::for_vault(&vault_path, default_ignore_patterns);
+        let symlinks = match is_server {
+            true => SymlinkPolicy::Link,
+            false => symlink_policies.get(&vault_id).copied().unwrap_or_default(),
+        };
+        let paths = file_utils::get_all_paths_from_path_with_rules(&vault_path, &rules, symlinks)
             .expect(&*format!("Could not find paths: {:?}", vault_path));
 
         remove_old_entries_from_db(pool).await?;
+        remove_ignored_entries_from_db(pool, vault_id, &vault_path, &rules).await?;
 
         let path_with_id = assign_file_ids(pool, paths, is_server).await?;
 
         let file_metadata =
-            file_utils::get_file_metadata_from_path(path_with_id, root_dir, vault_path, vault_id);
+            file_utils::get_file_metadata_from_path(path_with_id, root_dir, vault_path, vault_id, symlinks);
 
         upsert_database(pool, file_metadata).await?;
     }
+    if is_server {
+        restore_server_versions(pool).await?;
+    }
     Ok(())
 }
 
+/// Copies the versions saved by `delete_db_and_recreate_for_server` onto the rescanned files
+/// A file modified while the server was down counts as a change made by the server
+async fn restore_server_versions(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query(
+        "select p.file_path, p.modified_time, p.modified_nanos, p.version, f.modified_time, f.modified_nanos \
+        from previous_versions p join file_metadata f on f.file_path == p.file_path where f.is_dir == 0;",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for row in rows {
+        let mut version = VersionVector::from_db(row.get::<Option<String>, _>(3));
+        let saved_time = (row.get::<i64, _>(1), row.get::<i64, _>(2) as u32);
+        let scanned_time = (row.get::<i64, _>(4), row.get::<i64, _>(5) as u32);
+        if compare_times(saved_time, scanned_time) != std::cmp::Ordering::Equal {
+            version.increment(SERVER_DEVICE);
+        }
+        sqlx::query("UPDATE file_metadata SET version = ? WHERE file_path == ?;")
+            .bind(version.to_db())
+            .bind(row.get::<String, _>(0))
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// The version column of file_metadata, NULL when the version is unknown
+pub fn version_for_db(version: &VersionVector) -> Option<String> {
+    match version.is_empty() {
+        true => None,
+        false => Some(version.to_db()),
+    }
+}
+
 /// Does an update/insert on the database, insert files or update them if already exists
 /// This is intended for initial DB load
-/// sets modified_time and file_size to the current file
+/// sets modified_time, file_size, is_dir and link_target to the current file
 pub async fn upsert_database(
     pool: &Pool<Sqlite>,
     files: Vec<FileMetadata>,
 ) -> Result<(), sqlx::Error> {
     for file in files {
-        println!("executing upsert for: {:?}", file);
-
         sqlx::query(
-            "INSERT OR IGNORE INTO file_metadata (file_id, vault_id, file_path, root_directory, modified_time, file_size)\
-                VALUES (?, ?, ?, ?, ?, ?);")
+            "INSERT OR IGNORE INTO file_metadata (file_id, vault_id, file_path, root_directory, modified_time, file_size, is_dir, link_target, mode, modified_nanos, version)\
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);")
             .bind(file.file_id)
             .bind(file.vault_id)
             .bind(file.full_path.to_str().unwrap().to_string())
             .bind(file.root_directory)
             .bind(file.modified_time)
             .bind(file.file_size)
+            .bind(file.is_dir)
+            .bind(file.link_target.as_ref().map(|target| target.to_str().unwrap().to_string()))
+            .bind(file.mode.map(|mode| mode as i64))
+            .bind(file.modified_nanos as i64)
+            .bind(version_for_db(&file.version))
             .execute(pool)
             .await?;
 
-        sqlx::query("UPDATE file_metadata SET modified_time = ?, file_size = ? WHERE file_id == ? AND (modified_time != ? OR file_size != ?);")
+        // matched by path as every file the client hasn't synced yet shares the file_id -1
+        sqlx::query("UPDATE file_metadata SET modified_time = ?, file_size = ?, is_dir = ?, link_target = ?, mode = ?, modified_nanos = ?, \
+            version = ? WHERE file_path == ? AND (modified_time != ? OR file_size != ? OR is_dir != ? \
+            OR link_target IS NOT ? OR mode IS NOT ? OR modified_nanos != ? OR version IS NOT ?);")
             .bind(file.modified_time)
             .bind(file.file_size)
-            .bind(file.file_id)
+            .bind(file.is_dir)
+            .bind(file.link_target.as_ref().map(|target| target.to_str().unwrap().to_string()))
+            .bind(file.mode.map(|mode| mode as i64))
+            .bind(file.modified_nanos as i64)
+            .bind(version_for_db(&file.version))
+            .bind(file.full_path.to_str().unwrap().to_string())
             .bind(file.modified_time)
             .bind(file.file_size)
+            .bind(file.is_dir)
+            .bind(file.link_target.as_ref().map(|target| target.to_str().unwrap().to_string()))
+            .bind(file.mode.map(|mode| mode as i64))
+            .bind(file.modified_nanos as i64)
+            .bind(version_for_db(&file.version))
             .execute(pool)
             .await?;
     }
@@ -88,11 +155,36 @@ async fn remove_old_entries_from_db(pool: &Pool<Sqlite>) -> Result<(), sqlx::Err
     let db_paths = get_paths_from_db(pool).await?;
 
     for path in db_paths {
-        println!("checking path: {:?}", path);
         if path.exists() {
             continue;
         }
-        println!("deleting {:?}", path);
+        eprintln!("deleting {:?}", path);
+        sqlx::query("delete from file_metadata where file_path == ?")
+            .bind(path.to_str().unwrap())
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Removes entries of a vault that are now matched by its ignore rules
+async fn remove_ignored_entries_from_db(
+    pool: &Pool<Sqlite>,
+    vault_id: i32,
+    vault_path: &Path,
+    rules: &IgnoreRules,
+) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query("select file_path, is_dir from file_metadata where vault_id == ?;")
+        .bind(vault_id)
+        .fetch_all(pool)
+        .await?;
+
+    for row in rows {
+        let path = PathBuf::from(row.get::<String, _>(0));
+        if !rules.is_ignored_in_vault(&path, vault_path, row.get::<bool, _>(1)) {
+            continue;
+        }
+        eprintln!("removing ignored {:?}", path);
         sqlx::query("delete from file_metadata where file_path == ?")
             .bind(path.to_str().unwrap())
             .execute(pool)
@@ -184,18 +276,12 @@ pub async fn read_file_contents_from_disk_and_metadata(
         };
 
 
-        if !path.exists() {
+        // directories and links have no contents to send, they are created from their metadata
+        if data.link_target.is_some() || !path.is_file() {
             continue
         }
 
-        let file = RemoteFile::new(
-            path,
-            data.absolute_root_dir.clone(),
-            data.root_directory.clone(),
-            data.vault_id,
-            data.file_id,
-            data.modified_time
-        );
+        let file = RemoteFile::from_metadata(path, data);
         files.push(file);
     }
     files
@@ -238,6 +324,18 @@ pub async fn get_vault_id_and_root_directories(
     Ok(root_paths)
 }
 
+/// Builds the ignore rules for every vault in the vaults table, keyed by vault_id
+pub async fn get_ignore_rules_for_vaults(
+    pool: &Pool<Sqlite>,
+    default_ignore_patterns: &[String],
+) -> Result<HashMap<i32, IgnoreRules>, sqlx::Error> {
+    let vaults = get_vault_id_and_root_directories(pool).await?;
+    Ok(vaults
+        .into_iter()
+        .map(|(id, root)| (id, IgnoreRules::for_vault(&root, default_ignore_patterns)))
+        .collect())
+}
+
 /// Iterates through a metadata blob - finds matching vaults then updates all the paths from the metadatablob
 /// to the correct path for the server using file_utils
 pub async fn convert_root_dirs_of_metadata(
@@ -264,11 +362,53 @@ pub async fn convert_root_dirs_of_metadata(
     Ok(())
 }
 
+/// Adds a column to a table if it isn't already there, so databases created by an older version
+/// pick up new settings without being recreated
+/// definition is everything after the column name eg: "INTEGER NOT NULL DEFAULT 0"
+pub async fn add_column_if_missing(
+    pool: &Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let columns = sqlx::query(&format!("pragma table_info({table});"))
+        .fetch_all(pool)
+        .await?;
+
+    if columns.iter().any(|row| row.get::<String, _>(1) == column) {
+        return Ok(());
+    }
+
+    sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition};"))
+        .execute(pool)
+        .await?;
+    println!("added column {column} to {table}");
+    Ok(())
+}
+
+/// The versions of the files are kept in previous_versions until the vaults are scanned again
 pub async fn delete_db_and_recreate_for_server(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    add_column_if_missing(pool, "file_metadata", "modified_nanos", "INTEGER NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "file_metadata", "version", "TEXT").await?;
+    sqlx::query("DROP TABLE IF EXISTS previous_versions;")
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "CREATE TABLE previous_versions AS \
+        SELECT file_path, modified_time, modified_nanos, version FROM file_metadata WHERE version IS NOT NULL;",
+    )
+    .execute(pool)
+    .await?;
+
     let _ = sqlx::query("drop table file_metadata;")
         .execute(pool)
         .await?;
 
+    create_server_file_metadata(pool).await
+}
+
+/// Creates the server's file_metadata table, the one rebuilt on every scan of the vaults
+pub async fn create_server_file_metadata(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
     let _ = sqlx::query(
         "CREATE TABLE file_metadata
     (
@@ -277,7 +417,12 @@ pub async fn delete_db_and_recreate_for_server(pool: &Pool<Sqlite>) -> Result<()
     file_path      TEXT UNIQUE                       NOT NULL,
     root_directory TEXT                              NOT NULL,
     modified_time  BIGINT                            NOT NULL,
-    file_size      BIGINT                            NOT NULL
+    file_size      BIGINT                            NOT NULL,
+    is_dir         INTEGER                           NOT NULL DEFAULT 0,
+    link_target    TEXT,
+    mode           INTEGER,
+    modified_nanos INTEGER                           NOT NULL DEFAULT 0,
+    version        TEXT
     );",
     )
     .execute(pool)
@@ -299,7 +444,12 @@ pub async fn delete_db_and_recreate_for_client(pool: &Pool<Sqlite>) -> Result<()
     file_path      TEXT UNIQUE                       NOT NULL,
     root_directory TEXT                              NOT NULL,
     modified_time  BIGINT                            NOT NULL,
-    file_size      BIGINT                            NOT NULL
+    file_size      BIGINT                            NOT NULL,
+    is_dir         INTEGER                           NOT NULL DEFAULT 0,
+    link_target    TEXT,
+    mode           INTEGER,
+    modified_nanos INTEGER                           NOT NULL DEFAULT 0,
+    version        TEXT
     );",
     )
     .execute(pool)
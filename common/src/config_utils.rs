@@ -15,6 +15,23 @@ pub struct VaultConfig {
     pub vault_root: String,
 }
 
+/// A vault as stored in the server's vaults table, sent by the /vaults routes
+/// abs_path is the vault's storage directory on the server, its last component is root_dir
+/// retention_days is how long the server keeps file history and old versions, None keeps them forever
+/// ignore_patterns are the contents of the vault's .datoxidizeignore
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VaultInfo {
+    pub vault_id: i32,
+    pub root_dir: String,
+    pub abs_path: PathBuf,
+    pub sync_frequency: i32,
+    pub read_only: bool,
+    pub retention_days: Option<i32>,
+    pub ignore_patterns: Vec<String>,
+}
+
+/// Stale - vaults now live in the vaults table of the database and are managed with the
+/// backend's /vaults routes. Kept for the old tests that still read vault_config.json
 /// Deserializes a vault relative to the working directory of where it is called from
 /// If called from the backend it will be looking for a directory of ./backend/resources/vault_config.json
 /// This allows for different test configs and production configs to be retrieved from the same address
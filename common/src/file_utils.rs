@@ -1,10 +1,21 @@
+use crate::ignore_utils::IgnoreRules;
+use crate::version_vector::{Causality, VersionVector};
 use rayon::prelude::*;
 pub use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
 use std::time::{UNIX_EPOCH};
 
+/// Extension added to the placeholder of a file that has not been downloaded by an on-demand vault
+/// eg: the placeholder of data.csv is data.csv.dxstub
+pub const PLACEHOLDER_EXTENSION: &str = "dxstub";
+
+/// Permission bits that are synced, setuid, setgid and sticky are never taken from another device
+pub const SYNCED_MODE_BITS: u32 = 0o777;
+
 /// Metadata tuple format: (access_time, modified_time, file_size_bytes)
 /// Modified time should be identical and latency with networks can cause different times
 /// Even with a straight copy
@@ -18,6 +29,12 @@ pub struct RemoteFile {
     pub vault_id: i32,
     pub file_id: i32,
     pub modified_time: i64,
+    /// Nanoseconds past modified_time
+    #[serde(default)]
+    pub modified_nanos: u32,
+    /// Unix permission bits applied after the file is written, None leaves the default permissions
+    #[serde(default)]
+    pub mode: Option<u32>,
 }
 
 impl RemoteFile {
@@ -28,6 +45,7 @@ impl RemoteFile {
         vault_id: i32,
         file_id: i32,
         modified_time: i64,
+        mode: Option<u32>,
     ) -> Self {
         RemoteFile {
             full_path: path.clone(),
@@ -37,6 +55,24 @@ impl RemoteFile {
             vault_id,
             file_id,
             modified_time,
+            modified_nanos: 0,
+            mode,
+        }
+    }
+
+    /// Reads the file at path, taking everything else from its metadata
+    pub fn from_metadata(path: PathBuf, metadata: &FileMetadata) -> Self {
+        RemoteFile {
+            modified_nanos: metadata.modified_nanos,
+            ..RemoteFile::new(
+                path,
+                metadata.absolute_root_dir.clone(),
+                metadata.root_directory.clone(),
+                metadata.vault_id,
+                metadata.file_id,
+                metadata.modified_time,
+                metadata.mode,
+            )
         }
     }
 
@@ -50,6 +86,8 @@ impl RemoteFile {
             vault_id,
             file_id,
             modified_time: 0,
+            modified_nanos: 0,
+            mode: None,
         }
     }
 }
@@ -78,12 +116,128 @@ impl MetadataBlob {
         }
         files
     }
+
+    /// Removes the directories from every vault and returns them, leaving only files
+    pub fn take_directories(&mut self) -> Vec<FileMetadata> {
+        self.take_matching(|file| file.is_dir)
+    }
+
+    /// Removes the symbolic links from every vault and returns them
+    pub fn take_links(&mut self) -> Vec<FileMetadata> {
+        self.take_matching(|file| file.link_target.is_some())
+    }
+
+    fn take_matching(&mut self, matches: impl Fn(&FileMetadata) -> bool) -> Vec<FileMetadata> {
+        let mut taken = vec![];
+        for vault in self.vaults.values_mut() {
+            let (matching, rest): (Vec<FileMetadata>, Vec<FileMetadata>) =
+                vault.files.drain(..).partition(|file| matches(file));
+            vault.files = rest;
+            taken.extend(matching);
+        }
+        taken
+    }
+
+    /// Removes the symbolic links of every vault that doesn't sync links as links
+    /// so they are neither downloaded nor seen as deleted
+    pub fn remove_unsynced_links(&mut self, policies: &HashMap<i32, SymlinkPolicy>) {
+        for (vault_id, vault) in self.vaults.iter_mut() {
+            if policies.get(vault_id).copied().unwrap_or_default() != SymlinkPolicy::Link {
+                vault.files.retain(|file| file.link_target.is_none());
+            }
+        }
+    }
+
+    /// Removes every file matching the ignore rules of its vault
+    /// Vaults without an entry in rules are left untouched
+    pub fn remove_ignored(&mut self, rules: &HashMap<i32, IgnoreRules>) {
+        for (vault_id, vault) in self.vaults.iter_mut() {
+            if let Some(vault_rules) = rules.get(vault_id) {
+                vault
+                    .files
+                    .retain(|file| !vault_rules.is_ignored(&file.relative_path(), file.is_dir));
+            }
+        }
+    }
+
+    /// Removes every file, and what was synced at the last sync, outside the selected subdirectories of its vault
+    /// Both sides of a diff are filtered so files outside the selection are never seen as deleted
+    /// Vaults without a selection are synced in full and left untouched
+    pub fn retain_selected(&mut self, selections: &HashMap<i32, Vec<PathBuf>>) {
+        for (vault_id, vault) in self.vaults.iter_mut() {
+            if let Some(selected) = selections.get(vault_id) {
+                vault
+                    .files
+                    .retain(|file| is_path_selected(&file.relative_path(), selected));
+                vault
+                    .synced
+                    .retain(|entry| is_path_selected(&entry.relative_path, selected));
+            }
+        }
+    }
+}
+
+/// Returns true if a path relative to the vault root is inside one of the selected subdirectories
+/// An empty selection means the whole vault is synced
+pub fn is_path_selected(relative_path: &Path, selected: &[PathBuf]) -> bool {
+    selected.is_empty() || selected.iter().any(|dir| relative_path.starts_with(dir))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MetadataDiff {
     pub new_for_server: HashMap<i32, VaultMetadata>,
     pub new_for_client: HashMap<i32, VaultMetadata>,
+    /// Files and directories the client deleted since the last sync, to be removed from the server
+    #[serde(default)]
+    pub delete_on_server: HashMap<i32, VaultMetadata>,
+    /// Files and directories the server deleted since the last sync, to be removed from the client
+    #[serde(default)]
+    pub delete_on_client: HashMap<i32, VaultMetadata>,
+    /// Files whose permissions changed here since the last sync, only their mode is sent to the server
+    #[serde(default)]
+    pub modes_for_server: HashMap<i32, VaultMetadata>,
+    /// Files whose permissions changed on the server since the last sync, only their mode is applied here
+    #[serde(default)]
+    pub modes_for_client: HashMap<i32, VaultMetadata>,
+    /// Every path that is the same on both sides once this diff is applied
+    /// The client keeps it as the synced state of its next sync
+    #[serde(default)]
+    pub in_sync: HashMap<i32, Vec<SyncedEntry>>,
+    /// Local changes that are not sent to the server because the vault is download only or read-only
+    /// and there is no server copy to revert them to
+    #[serde(default)]
+    pub blocked_for_server: HashMap<i32, VaultMetadata>,
+    /// Files changed on both sides, pending ones aren't transferred, the copy that wins the others is
+    /// also in new_for_client or new_for_server
+    #[serde(default)]
+    pub conflicts: HashMap<i32, Vec<FileConflict>>,
+}
+
+/// A path that was on both the client and the server after the last sync and its modified time then
+/// Lets the diff tell a path deleted on one side apart from a path that is new on the other
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncedEntry {
+    pub relative_path: PathBuf,
+    pub modified_time: i64,
+    #[serde(default)]
+    pub modified_nanos: u32,
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Version both copies had, or the server's when they were identical but their versions differed
+    #[serde(default)]
+    pub version: VersionVector,
+}
+
+/// The client and server copies of a file that conflict
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileConflict {
+    pub client: FileMetadata,
+    pub server: FileMetadata,
+    /// True if the server is asked to merge text files before the resolution is applied
+    #[serde(default)]
+    pub merge: bool,
+    #[serde(default)]
+    pub resolution: ConflictResolution,
 }
 
 impl MetadataDiff {
@@ -126,51 +280,263 @@ impl MetadataDiff {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Which way files move between a client and the server for a vault, set per vault on the client
+/// TwoWay - the default, changes are pushed and pulled
+/// UploadOnly - the client pushes but never pulls, eg: camera or backup folders
+/// DownloadOnly - the client mirrors the server, local edits are reverted to the server copy and
+///     local files the server doesn't have are flagged but not uploaded
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncDirection {
+    #[default]
+    TwoWay,
+    UploadOnly,
+    DownloadOnly,
+}
+
+impl SyncDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncDirection::TwoWay => "two_way",
+            SyncDirection::UploadOnly => "upload_only",
+            SyncDirection::DownloadOnly => "download_only",
+        }
+    }
+}
+
+impl std::str::FromStr for SyncDirection {
+    type Err = String;
+
+    /// Accepts the stored form eg: "upload_only" and the dashed form eg: "upload-only"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.replace('-', "_").as_str() {
+            "two_way" => Ok(SyncDirection::TwoWay),
+            "upload_only" => Ok(SyncDirection::UploadOnly),
+            "download_only" => Ok(SyncDirection::DownloadOnly),
+            _ => Err(format!(
+                "Unknown sync direction {s}, expected two-way, upload-only or download-only"
+            )),
+        }
+    }
+}
+
+/// How the client treats symbolic links in a vault, set per vault on the client
+/// Skip - the default, links are left out of the sync
+/// Link - relative links that stay inside the vault are synced as links, their target is kept in the metadata
+/// Follow - links to files and directories inside the vault are synced as if they were the file or directory
+///     itself, links that leave the vault or loop back on themselves are skipped
+/// The server always keeps links as links, it never follows them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    #[default]
+    Skip,
+    Link,
+    Follow,
+}
+
+impl SymlinkPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SymlinkPolicy::Skip => "skip",
+            SymlinkPolicy::Link => "link",
+            SymlinkPolicy::Follow => "follow",
+        }
+    }
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "link" => Ok(SymlinkPolicy::Link),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            _ => Err(format!("Unknown symlink policy {s}, expected skip, link or follow")),
+        }
+    }
+}
+
+/// How conflicting files are settled in a vault, set per vault on the client
+/// Newest - the default, text files are first merged by the server, of the files that couldn't be merged
+///     the copy with the later modified time wins and copies with the same time are left pending
+/// Server - the server's copy wins
+/// Client - the client's copy wins
+/// KeepBoth - the server's copy wins and the client's is kept next to it as a conflict copy
+/// Manual - both copies are left as they are until the conflict is resolved from the CLI or the dashboard
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+    #[default]
+    Newest,
+    Server,
+    Client,
+    KeepBoth,
+    Manual,
+}
+
+impl ConflictStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConflictStrategy::Newest => "newest",
+            ConflictStrategy::Server => "server",
+            ConflictStrategy::Client => "client",
+            ConflictStrategy::KeepBoth => "keep_both",
+            ConflictStrategy::Manual => "manual",
+        }
+    }
+}
+
+impl std::str::FromStr for ConflictStrategy {
+    type Err = String;
+
+    /// Accepts the stored form eg: "keep_both" and the dashed form eg: "keep-both"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.replace('-', "_").as_str() {
+            "newest" => Ok(ConflictStrategy::Newest),
+            "server" => Ok(ConflictStrategy::Server),
+            "client" => Ok(ConflictStrategy::Client),
+            "keep_both" => Ok(ConflictStrategy::KeepBoth),
+            "manual" => Ok(ConflictStrategy::Manual),
+            _ => Err(format!(
+                "Unknown conflict strategy {s}, expected newest, server, client, keep-both or manual"
+            )),
+        }
+    }
+}
+
+/// What a sync does with a conflicting file once the vault's strategy is applied
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictResolution {
+    /// Neither copy is transferred until the conflict is resolved
+    #[default]
+    Pending,
+    /// The server's copy is downloaded over the client's
+    KeepServer,
+    /// The client's copy is uploaded over the server's
+    KeepClient,
+    /// The client's copy is moved to a conflict copy then the server's is downloaded
+    KeepBoth,
+}
+
+/// sync_direction and conflict_strategy are set by the client and read_only by the server, each side
+/// leaves the other's settings at their default
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct VaultMetadata {
     pub files: Vec<FileMetadata>,
     pub vault_id: i32,
+    #[serde(default)]
+    pub sync_direction: SyncDirection,
+    #[serde(default)]
+    pub conflict_strategy: ConflictStrategy,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Paths that were in sync after the client's last sync, empty before the first one
+    #[serde(default)]
+    pub synced: Vec<SyncedEntry>,
+    /// How far the server's clock is ahead of the client's in milliseconds, measured by the client
+    #[serde(default)]
+    pub clock_skew_ms: i64,
 }
 
-impl VaultMetadata {
-    /// Returns a new VaultMetadata tuple, 0 idx is new for client, 1st is new for server
-    pub fn get_differences_from_server(
-        &mut self,
-        server: &VaultMetadata,
-    ) -> (VaultMetadata, VaultMetadata) {
-        let mut new_for_client = VaultMetadata {
+/// Everything that differs between the client and server copies of a vault
+/// Files new for the client and modes for the client hold the server's metadata, the others the client's
+#[derive(Debug, Default)]
+pub struct VaultDifferences {
+    pub new_for_client: VaultMetadata,
+    pub new_for_server: VaultMetadata,
+    pub delete_on_client: VaultMetadata,
+    pub delete_on_server: VaultMetadata,
+    /// Files whose contents match but whose permissions changed on one side
+    pub modes_for_client: VaultMetadata,
+    pub modes_for_server: VaultMetadata,
+}
+
+impl VaultDifferences {
+    fn new(vault_id: i32) -> Self {
+        let empty = || VaultMetadata {
             files: vec![],
-            vault_id: server.vault_id,
+            vault_id,
+            ..Default::default()
         };
+        VaultDifferences {
+            new_for_client: empty(),
+            new_for_server: empty(),
+            delete_on_client: empty(),
+            delete_on_server: empty(),
+            modes_for_client: empty(),
+            modes_for_server: empty(),
+        }
+    }
+}
 
-        let mut new_for_server = VaultMetadata {
-            files: vec![],
-            vault_id: server.vault_id,
+impl VaultMetadata {
+    /// Compares the client's copy of a vault, self, with the server's
+    /// A path on one side only that was in sync last time was deleted on the other side, unless it
+    /// has been modified since, then it is sent back instead
+    /// A file with the same modified time and size on both sides but different permissions only has
+    /// its mode synced, from the side whose mode changed since the last sync or from the server if unknown
+    pub fn get_differences_from_server(&mut self, server: &VaultMetadata) -> VaultDifferences {
+        let mut differences = VaultDifferences::new(server.vault_id);
+
+        let synced = self
+            .synced
+            .iter()
+            .map(|entry| (entry.relative_path.clone(), ((entry.modified_time, entry.modified_nanos), entry.mode)))
+            .collect::<HashMap<PathBuf, ((i64, u32), Option<u32>)>>();
+        // directories have no contents to lose so they go whenever the other side removed them
+        let deleted_since_sync = |file: &FileMetadata| {
+            synced
+                .get(&file.relative_path())
+                .is_some_and(|(synced_time, _)| {
+                    file.is_dir
+                        || compare_times((file.modified_time, file.modified_nanos), *synced_time) != Ordering::Greater
+                })
         };
 
+        let synced_time = |relative: &PathBuf| synced.get(relative).map(|(time, _)| *time);
+        let clock_skew_ms = self.clock_skew_ms;
+
+        // files are matched by their path relative to the vault root as the client and server
+        // store the vault in different places, this also works when either side has no files yet
         for client_file in self.files.iter_mut() {
-            //if client_file.present_on_server == ServerPresent::No {
-            //    new_for_server.files.push(client_file.clone());
-            //    continue;
-            //}
             let mut present = false;
-            let client_path_in_server_format = convert_path_to_local(
-                &client_file.full_path,
-                &client_file.absolute_root_dir,
-                &server.files[0].absolute_root_dir);
-
+            let client_relative = client_file.relative_path();
 
             for server_file in server.files.iter() {
                 //make sure we are comparing same file
-                if client_path_in_server_format == server_file.full_path {
-                    println!("comparing {:#?} and {:#?}", client_file, server_file);
-                    // is client file newer than server file
-                    if client_file.compare_to(&server_file) == 1 {
-                        new_for_server.files.push(client_file.clone());
+                if client_relative == server_file.relative_path() {
+                    if client_file.is_dir || server_file.is_dir {
+                        // a directory on both sides is already in sync, a file on one side and a
+                        // directory on the other is left to find_conflicts
                     }
-                    //is server file newer than client file
-                    else if client_file.compare_to(&server_file) == -1 {
-                        new_for_client.files.push(server_file.clone())
+                    else {
+                        let order =
+                            order_copies(client_file, server_file, synced_time(&client_relative), clock_skew_ms);
+                        // is client file newer than server file
+                        if order == Some(Ordering::Greater) {
+                            differences.new_for_server.files.push(client_file.clone());
+                        }
+                        //is server file newer than client file
+                        else if order == Some(Ordering::Less) {
+                            differences.new_for_client.files.push(server_file.clone())
+                        }
+                        // copies changed concurrently, order None, are left to find_conflicts
+                        else if order == Some(Ordering::Equal)
+                            && client_file.file_size == server_file.file_size
+                            && client_file.mode.is_some()
+                            && server_file.mode.is_some()
+                            && client_file.mode != server_file.mode
+                        {
+                            let synced_mode = synced.get(&client_relative).and_then(|(_, mode)| *mode);
+                            if synced_mode.is_some() && synced_mode == server_file.mode {
+                                differences.modes_for_server.files.push(client_file.clone());
+                            } else {
+                                differences.modes_for_client.files.push(server_file.clone());
+                            }
+                        }
                     }
 
                     client_file.file_id = server_file.file_id;
@@ -179,31 +545,197 @@ impl VaultMetadata {
                 }
             }
             if !present {
-                new_for_server.files.push(client_file.clone());
+                if deleted_since_sync(client_file) {
+                    differences.delete_on_client.files.push(client_file.clone());
+                } else {
+                    differences.new_for_server.files.push(client_file.clone());
+                }
             }
         }
 
-
-        // Checks if file_id matches any client files, if not the client needs it
+        // Checks if the path matches any client files, if not the client needs it
         for server_file in server.files.iter() {
-            let mut present = false;
-            let server_path_in_client_format = convert_path_to_local(
-                &server_file.full_path,
-                &server_file.absolute_root_dir,
-                &self.files[0].absolute_root_dir);
+            let server_relative = server_file.relative_path();
+            let present = self
+                .files
+                .iter()
+                .any(|client_file| client_file.relative_path() == server_relative);
+            if !present {
+                if deleted_since_sync(server_file) {
+                    differences.delete_on_server.files.push(server_file.clone());
+                } else {
+                    differences.new_for_client.files.push(server_file.clone());
+                }
+            }
+        }
 
-            for client_file in self.files.iter() {
-                if server_path_in_client_format == client_file.full_path {
-                    present = true;
+        // a deleted directory that still holds a file being sent back is recreated instead
+        keep_parent_directories(&mut differences.delete_on_client, &mut differences.new_for_server);
+        keep_parent_directories(&mut differences.delete_on_server, &mut differences.new_for_client);
+
+        differences
+    }
+
+    /// Returns the files that were changed on both sides since they were last the same
+    /// These are files whose versions are concurrent and whose contents differ, or when either version
+    /// is unknown, files with the same modified time on both sides but a different size
+    /// A file on one side and a directory on the other at the same path also conflict
+    pub fn find_conflicts(&self, server: &VaultMetadata) -> Vec<FileConflict> {
+        let mut conflicts = vec![];
+        for client_file in self.files.iter() {
+            let client_relative = client_file.relative_path();
+            let server_file = server
+                .files
+                .iter()
+                .find(|server_file| server_file.relative_path() == client_relative);
+
+            if let Some(server_file) = server_file {
+                let same_kind = client_file.is_dir == server_file.is_dir;
+                let changed_on_both = !client_file.is_dir
+                    && !server_file.is_dir
+                    && match version_causality(client_file, server_file) {
+                        Some(Causality::Concurrent) => !same_contents(client_file, server_file),
+                        Some(_) => false,
+                        None => {
+                            client_file.compare_to(server_file) == 0
+                                && client_file.file_size != server_file.file_size
+                        }
+                    };
+                if !same_kind || changed_on_both {
+                    conflicts.push(FileConflict {
+                        client: client_file.clone(),
+                        server: server_file.clone(),
+                        merge: false,
+                        resolution: ConflictResolution::Pending,
+                    });
                 }
             }
-            if !present {
-                new_for_client.files.push(server_file.clone());
+        }
+        conflicts
+    }
+
+    /// Applies the vault's conflict strategy to the conflicts returned by `find_conflicts`
+    /// Only conflicts between two files are settled, a file and a directory or a link at the same path
+    /// stay pending, as do resolutions the sync direction or read-only flag don't allow
+    /// A client copy that wins takes a version that descends from both copies
+    pub fn resolve_conflicts(&self, server: &VaultMetadata, conflicts: &mut [FileConflict]) {
+        let can_upload = self.sync_direction != SyncDirection::DownloadOnly && !server.read_only;
+        let can_download = self.sync_direction != SyncDirection::UploadOnly;
+        for conflict in conflicts.iter_mut() {
+            let (client, server) = (&conflict.client, &conflict.server);
+            let plain_files = !client.is_dir
+                && !server.is_dir
+                && client.link_target.is_none()
+                && server.link_target.is_none();
+            if !plain_files {
+                continue;
+            }
+
+            let resolution = match self.conflict_strategy {
+                ConflictStrategy::Newest => {
+                    let client_time = shift_time((client.modified_time, client.modified_nanos), self.clock_skew_ms);
+                    match compare_times(client_time, (server.modified_time, server.modified_nanos)) {
+                        Ordering::Greater => ConflictResolution::KeepClient,
+                        Ordering::Less => ConflictResolution::KeepServer,
+                        Ordering::Equal => ConflictResolution::Pending,
+                    }
+                }
+                ConflictStrategy::Server => ConflictResolution::KeepServer,
+                ConflictStrategy::Client => ConflictResolution::KeepClient,
+                ConflictStrategy::KeepBoth => ConflictResolution::KeepBoth,
+                ConflictStrategy::Manual => ConflictResolution::Pending,
+            };
+            conflict.resolution = match resolution {
+                ConflictResolution::KeepClient if !can_upload => ConflictResolution::Pending,
+                ConflictResolution::KeepServer | ConflictResolution::KeepBoth if !can_download => {
+                    ConflictResolution::Pending
+                }
+                resolution => resolution,
+            };
+            // the other strategies always pick the copy they were configured with
+            conflict.merge = self.conflict_strategy == ConflictStrategy::Newest && can_upload && can_download;
+            if conflict.resolution == ConflictResolution::KeepClient {
+                let server_version = conflict.server.version.clone();
+                conflict.client.version.merge(&server_version);
+            }
+        }
+    }
+
+    /// Applies the client's sync direction and the server's read-only flag to the differences
+    /// returned by `get_differences_from_server`
+    /// Deletions and mode changes that can't be carried out are undone by sending the client's or
+    /// server's copy back
+    /// Returns the local changes that can't be uploaded or reverted, these are left on the client
+    pub fn enforce_sync_direction(
+        &self,
+        server: &VaultMetadata,
+        differences: &mut VaultDifferences,
+    ) -> VaultMetadata {
+        let can_upload = self.sync_direction != SyncDirection::DownloadOnly && !server.read_only;
+        let can_download = self.sync_direction != SyncDirection::UploadOnly;
+        let find = |vault: &VaultMetadata, relative: &Path| {
+            vault.files.iter().find(|file| file.relative_path() == relative).cloned()
+        };
+
+        let mut blocked = VaultMetadata {
+            files: vec![],
+            vault_id: server.vault_id,
+            ..Default::default()
+        };
+
+        if !can_download {
+            differences.new_for_client.files.clear();
+            // the server deleted these, the client puts them back when it can
+            for client_file in differences.delete_on_client.files.drain(..) {
+                if can_upload {
+                    differences.new_for_server.files.push(client_file);
+                }
+            }
+            // the server changed these modes, the client's are sent back when it can
+            for server_file in differences.modes_for_client.files.drain(..) {
+                match find(self, &server_file.relative_path()) {
+                    Some(client_file) if can_upload => differences.modes_for_server.files.push(client_file),
+                    _ => {}
+                }
+            }
+        }
+        if can_upload {
+            return blocked;
+        }
+
+        // the client deleted these, the server's copy is downloaded again when it can
+        for server_file in differences.delete_on_server.files.drain(..) {
+            if can_download {
+                differences.new_for_client.files.push(server_file);
             }
         }
 
+        // the client changed these modes, the server's are restored when it can
+        for client_file in differences.modes_for_server.files.drain(..) {
+            match find(server, &client_file.relative_path()) {
+                Some(server_file) if can_download => differences.modes_for_client.files.push(server_file),
+                _ => blocked.files.push(client_file),
+            }
+        }
 
-        (new_for_client, new_for_server)
+        for client_file in differences.new_for_server.files.drain(..) {
+            let relative = client_file.relative_path();
+            let server_copy = server
+                .files
+                .iter()
+                .find(|server_file| server_file.relative_path() == relative);
+
+            match server_copy {
+                // the local edit is overwritten with the server's copy
+                Some(server_file) if can_download => {
+                    if !differences.new_for_client.files.contains(server_file) {
+                        differences.new_for_client.files.push(server_file.clone());
+                    }
+                }
+                _ => blocked.files.push(client_file),
+            }
+        }
+        blocked
     }
 
     pub fn get_metadata_vec(&self) -> Vec<FileMetadata> {
@@ -221,6 +753,21 @@ pub struct FileMetadata {
     pub vault_id: i32,
     pub file_id: i32,
     pub present_on_server: ServerPresent,
+    /// Directories are synced as entries of their own so empty ones aren't lost, their size is 0
+    #[serde(default)]
+    pub is_dir: bool,
+    /// Target of a symbolic link synced as a link, relative to the directory holding the link
+    #[serde(default)]
+    pub link_target: Option<PathBuf>,
+    /// Unix permission bits of a file eg: 0o755, None for directories, links and files from older clients
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Nanoseconds past modified_time, 0 on filesystems that only store whole seconds
+    #[serde(default)]
+    pub modified_nanos: u32,
+    /// Changes each device made to the file, empty for directories and when unknown
+    #[serde(default)]
+    pub version: VersionVector,
 }
 
 impl PartialEq for FileMetadata {
@@ -248,6 +795,11 @@ impl FileMetadata {
             vault_id,
             file_id,
             present_on_server: ServerPresent::Yes,
+            is_dir: false,
+            link_target: None,
+            mode: None,
+            modified_nanos: 0,
+            version: VersionVector::default(),
         }
     }
 
@@ -269,6 +821,20 @@ impl FileMetadata {
             vault_id,
             file_id,
             present_on_server: ServerPresent::Unknown,
+            is_dir: false,
+            link_target: None,
+            mode: None,
+            modified_nanos: 0,
+            version: VersionVector::default(),
+        }
+    }
+
+    /// Path of the file relative to the root of its vault
+    /// eg: /home/root_dir/nested/file.txt with a root of /home/root_dir returns nested/file.txt
+    pub fn relative_path(&self) -> PathBuf {
+        match self.full_path.strip_prefix(&self.absolute_root_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => self.full_path.clone(),
         }
     }
 
@@ -276,14 +842,117 @@ impl FileMetadata {
     /// Returns -1 if the calling struct is older than the other struct
     /// 0 if equal
     pub fn compare_to(&self, other: &FileMetadata) -> i32 {
-        if self.modified_time > other.modified_time {
-            return 1;
+        match compare_times(
+            (self.modified_time, self.modified_nanos),
+            (other.modified_time, other.modified_nanos),
+        ) {
+            Ordering::Greater => 1,
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
         }
-        if self.modified_time < other.modified_time {
-            return -1;
+    }
+
+    /// The modified time to restore on disk
+    pub fn modified_filetime(&self) -> filetime::FileTime {
+        filetime::FileTime::from_unix_time(self.modified_time, self.modified_nanos)
+    }
+}
+
+/// Compares two (seconds, nanoseconds) modified times
+/// A filesystem that only stores whole seconds reports 0 nanoseconds, so when either side has none
+/// only the seconds are compared, otherwise a copy on such a filesystem would always look older
+pub fn compare_times(a: (i64, u32), b: (i64, u32)) -> Ordering {
+    if a.1 == 0 || b.1 == 0 {
+        return a.0.cmp(&b.0);
+    }
+    a.cmp(&b)
+}
+
+/// Orders the client's copy of a file against the server's, None when both were changed concurrently
+/// Version vectors decide when both copies have one, otherwise `compare_copies` does
+fn order_copies(
+    client_file: &FileMetadata,
+    server_file: &FileMetadata,
+    synced_time: Option<(i64, u32)>,
+    clock_skew_ms: i64,
+) -> Option<Ordering> {
+    match version_causality(client_file, server_file) {
+        Some(Causality::Equal) => Some(Ordering::Equal),
+        Some(Causality::Descendant) => Some(Ordering::Greater),
+        Some(Causality::Ancestor) => Some(Ordering::Less),
+        // the same change made on both sides, eg: a file copied onto both before they were synced
+        Some(Causality::Concurrent) if same_contents(client_file, server_file) => Some(Ordering::Equal),
+        Some(Causality::Concurrent) => None,
+        None => Some(compare_copies(client_file, server_file, synced_time, clock_skew_ms)),
+    }
+}
+
+/// How the client's copy of a file relates to the server's, None when either version is unknown
+fn version_causality(client_file: &FileMetadata, server_file: &FileMetadata) -> Option<Causality> {
+    if client_file.version.is_empty() || server_file.version.is_empty() {
+        return None;
+    }
+    Some(client_file.version.compare(&server_file.version))
+}
+
+/// True if both copies have the same modified time and size, so are taken to have the same contents
+fn same_contents(client_file: &FileMetadata, server_file: &FileMetadata) -> bool {
+    client_file.compare_to(server_file) == 0 && client_file.file_size == server_file.file_size
+}
+
+/// Orders the client's copy of a file against the server's
+/// A side still at the time recorded at the last sync hasn't changed, so the other side is newer
+/// whatever either clock says, only when both changed, or there is no record, are the times compared,
+/// with the client's shifted onto the server's clock
+fn compare_copies(
+    client_file: &FileMetadata,
+    server_file: &FileMetadata,
+    synced_time: Option<(i64, u32)>,
+    clock_skew_ms: i64,
+) -> Ordering {
+    let client_time = (client_file.modified_time, client_file.modified_nanos);
+    let server_time = (server_file.modified_time, server_file.modified_nanos);
+    if compare_times(client_time, server_time) == Ordering::Equal {
+        return Ordering::Equal;
+    }
+    if let Some(synced_time) = synced_time {
+        let client_changed = compare_times(client_time, synced_time) != Ordering::Equal;
+        let server_changed = compare_times(server_time, synced_time) != Ordering::Equal;
+        match (client_changed, server_changed) {
+            (true, false) => return Ordering::Greater,
+            (false, true) => return Ordering::Less,
+            _ => {}
         }
-        0
     }
+    compare_times(shift_time(client_time, clock_skew_ms), server_time)
+}
+
+/// Moves a (seconds, nanoseconds) modified time by the given milliseconds
+/// A time without nanoseconds is moved by whole seconds so it is still compared to the second
+fn shift_time(time: (i64, u32), milliseconds: i64) -> (i64, u32) {
+    let nanos = time.0 as i128 * 1_000_000_000 + time.1 as i128 + milliseconds as i128 * 1_000_000;
+    let shifted = (nanos.div_euclid(1_000_000_000) as i64, nanos.rem_euclid(1_000_000_000) as u32);
+    match time.1 {
+        0 => ((nanos + 500_000_000).div_euclid(1_000_000_000) as i64, 0),
+        _ => shifted,
+    }
+}
+
+/// Moves every deleted directory that still holds a path being sent back over to the transfers
+/// so it is recreated on the side that deleted it
+fn keep_parent_directories(deletions: &mut VaultMetadata, transfers: &mut VaultMetadata) {
+    let kept = transfers
+        .files
+        .iter()
+        .map(|file| file.relative_path())
+        .collect::<Vec<PathBuf>>();
+    let (keep, delete): (Vec<FileMetadata>, Vec<FileMetadata>) =
+        deletions.files.drain(..).partition(|file| {
+            let relative = file.relative_path();
+            file.is_dir && kept.iter().any(|path| path != &relative && path.starts_with(&relative))
+        });
+    deletions.files = delete;
+    transfers.files.extend(keep);
 }
 
 //todo unit test me!!!!!
@@ -295,22 +964,120 @@ pub fn get_metadata_diff(client: MetadataBlob, server: MetadataBlob) -> Metadata
     let mut metadata_diff = MetadataDiff {
         new_for_server: HashMap::new(),
         new_for_client: HashMap::new(),
+        delete_on_server: HashMap::new(),
+        delete_on_client: HashMap::new(),
+        modes_for_server: HashMap::new(),
+        modes_for_client: HashMap::new(),
+        blocked_for_server: HashMap::new(),
+        conflicts: HashMap::new(),
+        in_sync: HashMap::new(),
     };
 
     let client_vaults = client.vaults;
     for mut client_vault in client_vaults.into_iter() {
         let vault_id = client_vault.0;
-        let server_vault = server.vaults.get(&vault_id).unwrap();
+        let server_vault = match server.vaults.get(&vault_id) {
+            Some(vault) => vault,
+            None => {
+                eprintln!("vault {vault_id} no longer exists on the server, skipping");
+                continue;
+            }
+        };
+
+        let mut differences = client_vault.1.get_differences_from_server(server_vault);
+        let blocked = client_vault.1.enforce_sync_direction(server_vault, &mut differences);
+        let mut conflicts = client_vault.1.find_conflicts(server_vault);
+        client_vault.1.resolve_conflicts(server_vault, &mut conflicts);
+        // settled conflicts are transferred like any other change, they stay listed so the client can
+        // merge them first and make conflict copies
+        for conflict in conflicts.iter() {
+            match conflict.resolution {
+                ConflictResolution::KeepServer | ConflictResolution::KeepBoth => {
+                    differences.new_for_client.files.push(conflict.server.clone())
+                }
+                ConflictResolution::KeepClient => differences.new_for_server.files.push(conflict.client.clone()),
+                ConflictResolution::Pending => {}
+            }
+        }
 
-        let (client_differences, server_differences) =
-            client_vault.1.get_differences_from_server(server_vault);
+        let mut changed = conflicts
+            .iter()
+            .map(|conflict| conflict.client.relative_path())
+            .collect::<HashSet<PathBuf>>();
+        for vault in [
+            &differences.new_for_client,
+            &differences.new_for_server,
+            &differences.delete_on_client,
+            &differences.delete_on_server,
+            &differences.modes_for_client,
+            &differences.modes_for_server,
+            &blocked,
+        ] {
+            changed.extend(vault.files.iter().map(|file| file.relative_path()));
+        }
+        let server_versions = server_vault
+            .files
+            .iter()
+            .map(|file| (file.relative_path(), &file.version))
+            .collect::<HashMap<PathBuf, &VersionVector>>();
+        let synced_entry = |file: &FileMetadata, version: &VersionVector| SyncedEntry {
+            relative_path: file.relative_path(),
+            modified_time: file.modified_time,
+            modified_nanos: file.modified_nanos,
+            mode: file.mode,
+            version: version.clone(),
+        };
+        // untouched files take the server's version so identical copies with different histories
+        // agree from the next sync on
+        let untouched = client_vault.1.files.iter().filter_map(|file| {
+            let relative = file.relative_path();
+            match server_versions.get(&relative) {
+                Some(version) if !changed.contains(&relative) => Some(synced_entry(file, version)),
+                _ => None,
+            }
+        });
+        let transferred = differences
+            .new_for_client
+            .files
+            .iter()
+            .chain(differences.new_for_server.files.iter())
+            .chain(differences.modes_for_client.files.iter())
+            .chain(differences.modes_for_server.files.iter())
+            .map(|file| synced_entry(file, &file.version));
+        let in_sync = untouched.chain(transferred).collect::<Vec<SyncedEntry>>();
+        metadata_diff.in_sync.insert(vault_id, in_sync);
+
+        if !blocked.files.is_empty() {
+            metadata_diff.blocked_for_server.insert(vault_id, blocked);
+        }
+        if !conflicts.is_empty() {
+            metadata_diff.conflicts.insert(vault_id, conflicts);
+        }
+        let VaultDifferences {
+            new_for_client,
+            new_for_server,
+            delete_on_client,
+            delete_on_server,
+            modes_for_client,
+            modes_for_server,
+        } = differences;
+        for (diff, vault) in [
+            (&mut metadata_diff.delete_on_client, delete_on_client),
+            (&mut metadata_diff.delete_on_server, delete_on_server),
+            (&mut metadata_diff.modes_for_client, modes_for_client),
+            (&mut metadata_diff.modes_for_server, modes_for_server),
+        ] {
+            if !vault.files.is_empty() {
+                diff.insert(vault_id, vault);
+            }
+        }
 
         metadata_diff
             .new_for_client
-            .insert(vault_id, client_differences);
+            .insert(vault_id, new_for_client);
         metadata_diff
             .new_for_server
-            .insert(vault_id, server_differences);
+            .insert(vault_id, new_for_server);
     }
 
     metadata_diff
@@ -318,24 +1085,148 @@ pub fn get_metadata_diff(client: MetadataBlob, server: MetadataBlob) -> Metadata
 
 /// Convenience function to read all files in all subdirs of a supplied path
 pub fn get_all_files_from_path(path: &PathBuf) -> std::io::Result<Vec<PathBuf>> {
-    fn recursive_walk(path: &PathBuf, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    get_all_files_from_path_with_rules(path, &IgnoreRules::empty())
+}
+
+/// Reads all files in all subdirs of a supplied path, skipping anything matched by the ignore rules
+/// Ignored directories are not walked at all
+/// Placeholders are skipped as they stand in for files that only exist on the server, as are symbolic links
+pub fn get_all_files_from_path_with_rules(
+    path: &PathBuf,
+    rules: &IgnoreRules,
+) -> std::io::Result<Vec<PathBuf>> {
+    walk_vault(path, rules, false, SymlinkPolicy::Skip)
+}
+
+/// Same as `get_all_files_from_path_with_rules` but also returns every directory below the path
+/// and treats symbolic links the way symlinks says
+/// Used to build the metadata of a vault, where directories are entries of their own
+pub fn get_all_paths_from_path_with_rules(
+    path: &PathBuf,
+    rules: &IgnoreRules,
+    symlinks: SymlinkPolicy,
+) -> std::io::Result<Vec<PathBuf>> {
+    walk_vault(path, rules, true, symlinks)
+}
+
+fn walk_vault(
+    path: &PathBuf,
+    rules: &IgnoreRules,
+    include_dirs: bool,
+    symlinks: SymlinkPolicy,
+) -> std::io::Result<Vec<PathBuf>> {
+    /// ancestors are the canonical paths of the directories being walked, a followed link to one
+    /// of them would never end
+    fn recursive_walk(
+        root: &PathBuf,
+        path: &PathBuf,
+        rules: &IgnoreRules,
+        include_dirs: bool,
+        symlinks: SymlinkPolicy,
+        ancestors: &mut Vec<PathBuf>,
+        files: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
         for entry in fs::read_dir(path)? {
             let entry = entry?;
             let cur_path = entry.path();
+            let file_type = entry.file_type()?;
+            let mut is_dir = file_type.is_dir();
+
+            if file_type.is_symlink() {
+                match symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Link => {
+                        let target = fs::read_link(&cur_path)?;
+                        let relative = cur_path.strip_prefix(root).unwrap_or(&cur_path);
+                        if !link_stays_in_vault(relative, &target) {
+                            eprintln!("skipping {:?}, it links outside the vault", cur_path);
+                            continue;
+                        }
+                        is_dir = false;
+                    }
+                    SymlinkPolicy::Follow => {
+                        let target = match fs::canonicalize(&cur_path) {
+                            Ok(target) => target,
+                            Err(e) => {
+                                eprintln!("skipping {:?}, its target can't be read - {e}", cur_path);
+                                continue;
+                            }
+                        };
+                        if !target.starts_with(&ancestors[0]) {
+                            eprintln!("skipping {:?}, it links outside the vault", cur_path);
+                            continue;
+                        }
+                        if ancestors.contains(&target) {
+                            eprintln!("skipping {:?}, it links to a directory it is in", cur_path);
+                            continue;
+                        }
+                        is_dir = target.is_dir();
+                    }
+                }
+            }
+
+            if rules.is_ignored_in_vault(&cur_path, root, is_dir) {
+                continue;
+            }
 
-            if entry.file_type()?.is_dir() {
-                recursive_walk(&cur_path, files)?;
-            } else {
+            if is_dir {
+                if include_dirs {
+                    files.push(cur_path.clone());
+                }
+                ancestors.push(fs::canonicalize(&cur_path)?);
+                recursive_walk(root, &cur_path, rules, include_dirs, symlinks, ancestors, files)?;
+                ancestors.pop();
+            } else if !is_placeholder(&cur_path) {
                 files.push(cur_path);
             }
         }
         Ok(())
     }
     let mut files = Vec::new();
-    recursive_walk(path, &mut files)?;
+    let mut ancestors = vec![fs::canonicalize(path)?];
+    recursive_walk(path, path, rules, include_dirs, symlinks, &mut ancestors, &mut files)?;
     Ok(files)
 }
 
+/// Returns true if a path relative to the vault root names something inside the vault
+/// Only plain file and directory names are allowed, so joining it to the root can't leave the vault
+pub fn is_inside_vault(relative: &Path) -> bool {
+    relative.components().next().is_some()
+        && relative.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Returns true if a link at relative_link, relative to the vault root, points somewhere inside the vault
+/// Only relative targets are accepted as an absolute one means something else on every other device
+pub fn link_stays_in_vault(relative_link: &Path, target: &Path) -> bool {
+    let mut resolved = relative_link.parent().map(Path::to_path_buf).unwrap_or_default();
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Returns true if the path is the placeholder of a file that hasn't been downloaded
+pub fn is_placeholder(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == PLACEHOLDER_EXTENSION)
+}
+
+/// Returns the path of the placeholder for a file eg: data.csv becomes data.csv.dxstub
+pub fn placeholder_path(path: &Path) -> PathBuf {
+    let mut placeholder = path.as_os_str().to_os_string();
+    placeholder.push(".");
+    placeholder.push(PLACEHOLDER_EXTENSION);
+    PathBuf::from(placeholder)
+}
+
 /// Convenience function to convert a MetadataBlob to a vector of FileMetadata
 pub fn convert_blob_to_vec_metadata(blob: &mut MetadataBlob) -> Vec<FileMetadata> {
     let mut files = Vec::with_capacity(blob.vaults.len());
@@ -362,6 +1253,7 @@ pub fn read_all_local_file_metadata(vaults: HashMap<i32, Vec<FileMetadata>>) ->
         let new_vault = VaultMetadata {
             files: vault.1,
             vault_id: vault.0,
+            ..Default::default()
         };
         blob.vaults.insert(vault.0, new_vault);
     }
@@ -371,28 +1263,43 @@ pub fn read_all_local_file_metadata(vaults: HashMap<i32, Vec<FileMetadata>>) ->
 
 /// Takes a vec of path and file_id tuples, root_directory of vault and vault id
 /// then reads the file system and creates a Vec<FileMetadata> and returns it
+/// With SymlinkPolicy::Link symbolic links are read as links, otherwise their target is read
 pub fn get_file_metadata_from_path(
     paths: Vec<(i32, PathBuf)>,
     root_dir: String,
     absolute_root_dir: PathBuf,
     vault_id: i32,
+    symlinks: SymlinkPolicy,
 ) -> Vec<FileMetadata> {
     let mut files = Vec::new();
 
     for path in paths {
-        let metadata =
-            fs::metadata(&path.1).expect(&*format!("Error reading metadata from {:?}", path));
+        let link_target = match symlinks {
+            SymlinkPolicy::Link => fs::read_link(&path.1).ok(),
+            _ => None,
+        };
+        let metadata = match link_target {
+            Some(_) => fs::symlink_metadata(&path.1),
+            None => fs::metadata(&path.1),
+        }
+        .expect(&*format!("Error reading metadata from {:?}", path));
 
         let vault_id = vault_id;
         let file_path = path.clone();
         let root_directory = root_dir.clone();
-        let modified_time = metadata
+        let since_epoch = metadata
             .modified()
             .expect(&*format!("Error reading modified metadata from {:?}", path))
             .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        let file_size = metadata.len() as i64;
+            .unwrap();
+        let modified_time = since_epoch.as_secs() as i64;
+        let modified_nanos = since_epoch.subsec_nanos();
+        let is_dir = metadata.is_dir();
+        let file_size = if is_dir || link_target.is_some() { 0 } else { metadata.len() as i64 };
+        let mode = match metadata.is_file() {
+            true => Some(metadata.permissions().mode() & SYNCED_MODE_BITS),
+            false => None,
+        };
         let file = FileMetadata {
             full_path: file_path.1,
             root_directory,
@@ -405,6 +1312,11 @@ pub fn get_file_metadata_from_path(
                 -1 => ServerPresent::No,
                 _ => ServerPresent::Yes,
             },
+            is_dir,
+            link_target,
+            mode,
+            modified_nanos,
+            version: VersionVector::default(),
         };
         files.push(file);
     }
@@ -449,38 +1361,164 @@ pub fn convert_path_to_local(
 /// done in parallel for greater speed
 
 pub fn save_remote_files_to_disk(files: Vec<RemoteFile>, id_and_root_dirs: Vec<(i32, PathBuf)>) {
-    /// Loops through all the vec of (vault_id, root_path) until the vault id of the file
-    /// is matched, then the root_path for that vault is returned
-    fn get_local_root(file: &RemoteFile, id_and_dirs: &Vec<(i32, PathBuf)>) -> PathBuf {
-        let mut local_root = &Default::default();
-        for (id, local_r) in id_and_dirs.iter() {
-            if *id == file.vault_id {
-                local_root = local_r;
-                break;
-            }
-        }
-        local_root.clone()
-    }
-
     let iter = files.into_par_iter();
     let _ = iter.for_each(|file| {
-        let local_root = get_local_root(&file, &id_and_root_dirs);
+        let local_root = get_local_root(file.vault_id, &id_and_root_dirs);
 
         let local_path =
             convert_path_to_local(&file.full_path, &file.absolute_root_dir, &local_root);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent)
+                .unwrap_or_else(|e| panic!("Error creating {} - {e}", parent.display()));
+        }
+        // a file replacing a link is written in its place rather than through it to the target
+        if fs::symlink_metadata(&local_path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            fs::remove_file(&local_path)
+                .unwrap_or_else(|e| panic!("Error removing link {} - {e}", local_path.display()));
+        }
         fs::write(&local_path, file.contents)
             .expect(&*format!("Error writing {} to disk", local_path.display()));
+        if let Some(mode) = file.mode {
+            if let Err(e) = fs::set_permissions(&local_path, synced_permissions(mode)) {
+                println!("Error setting permissions of {} - {e}", local_path.display());
+            }
+        }
 
-        set_modified_time(&local_path, file.modified_time);
+        set_modified_time(&local_path, file.modified_time, file.modified_nanos);
     });
 }
 
+/// Loops through all the vec of (vault_id, root_path) until the vault id is matched,
+/// then the root_path for that vault is returned
+fn get_local_root(vault_id: i32, id_and_dirs: &[(i32, PathBuf)]) -> PathBuf {
+    id_and_dirs
+        .iter()
+        .find(|(id, _)| *id == vault_id)
+        .map(|(_, local_root)| local_root.clone())
+        .unwrap_or_default()
+}
+
+/// Creates the remote directories on the local system, converting their paths the same way
+/// `save_remote_files_to_disk` does
+pub fn create_remote_directories(dirs: &[FileMetadata], id_and_root_dirs: &[(i32, PathBuf)]) {
+    for dir in dirs {
+        let local_root = get_local_root(dir.vault_id, id_and_root_dirs);
+        let local_path = convert_path_to_local(&dir.full_path, &dir.absolute_root_dir, &local_root);
+        fs::create_dir_all(&local_path)
+            .unwrap_or_else(|e| panic!("Error creating {} - {e}", local_path.display()));
+    }
+}
+
+/// Creates the remote symbolic links on the local system, converting their paths the same way
+/// `save_remote_files_to_disk` does
+pub fn create_remote_links(links: &[FileMetadata], id_and_root_dirs: &[(i32, PathBuf)]) {
+    for link in links {
+        let target = match &link.link_target {
+            Some(target) => target,
+            None => continue,
+        };
+        let local_root = get_local_root(link.vault_id, id_and_root_dirs);
+        let local_path = convert_path_to_local(&link.full_path, &link.absolute_root_dir, &local_root);
+        if let Err(e) = write_link(&local_path, target, link.modified_filetime()) {
+            println!("Error creating link {} - {e}", local_path.display());
+        }
+    }
+}
+
+/// A free path next to path for a conflict copy made by device, eg: notes (conflict a1b2).txt
+/// A number is added after the device when that copy already exists
+pub fn conflict_copy_path(path: &Path, device: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|copy| match copy {
+            1 => path.with_file_name(format!("{stem} (conflict {device}){extension}")),
+            copy => path.with_file_name(format!("{stem} (conflict {device} {copy}){extension}")),
+        })
+        .find(|copy| fs::symlink_metadata(copy).is_err())
+        .unwrap()
+}
+
+/// Applies the permissions of remote files to their local copies, converting their paths the same way
+/// `save_remote_files_to_disk` does
+/// Returns the vault and local path of every file whose permissions were changed
+pub fn apply_remote_modes(files: &[FileMetadata], id_and_root_dirs: &[(i32, PathBuf)]) -> Vec<(i32, PathBuf)> {
+    let mut changed = vec![];
+    for file in files {
+        let mode = match file.mode {
+            Some(mode) => mode,
+            None => continue,
+        };
+        let local_root = get_local_root(file.vault_id, id_and_root_dirs);
+        let local_path = convert_path_to_local(&file.full_path, &file.absolute_root_dir, &local_root);
+        // never follow a link that replaced the file since the diff was made
+        if !fs::symlink_metadata(&local_path).is_ok_and(|metadata| metadata.is_file()) {
+            continue;
+        }
+        match fs::set_permissions(&local_path, synced_permissions(mode)) {
+            Ok(()) => changed.push((file.vault_id, local_path)),
+            Err(e) => println!("Error setting permissions of {} - {e}", local_path.display()),
+        }
+    }
+    changed
+}
+
+/// Permissions for a mode sent by another device, keeping only the bits that are synced
+pub fn synced_permissions(mode: u32) -> fs::Permissions {
+    fs::Permissions::from_mode(mode & SYNCED_MODE_BITS)
+}
+
+/// Creates a symbolic link at path, replacing a file or link that is already there
+/// A directory in the way is left alone and returned as an error
+pub fn write_link(path: &Path, target: &Path, modified: filetime::FileTime) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "a directory is in the way",
+            ))
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    filetime::set_symlink_file_times(path, modified, modified)
+}
+
+/// Removes files and directories from disk, deepest paths first so a directory's contents
+/// go before it
+/// Directories are only removed once empty, one that still holds something is left alone
+/// Returns the paths that are gone afterwards
+pub fn remove_paths_from_disk(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    paths.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+    let mut removed = Vec::with_capacity(paths.len());
+    for path in paths {
+        let result = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir(&path),
+            Ok(_) => fs::remove_file(&path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => removed.push(path),
+            Err(e) => println!("Error removing {} - {e}", path.display()),
+        }
+    }
+    removed
+}
+
 /// Update the metadata to ensure file won't be synced unnecessarily
-fn set_modified_time(path: &PathBuf, modified_time: i64) {
-    println!("set {:?} modified time to {modified_time}", path);
+fn set_modified_time(path: &PathBuf, modified_time: i64, modified_nanos: u32) {
+    println!("set {:?} modified time to {modified_time}.{modified_nanos:09}", path);
     filetime::set_file_mtime(
         path,
-        filetime::FileTime::from_unix_time(modified_time, 0),
+        filetime::FileTime::from_unix_time(modified_time, modified_nanos),
     )
         .unwrap()
 }
@@ -517,6 +1555,11 @@ mod tests {
                     vault_id: 0,
                     file_id: 1,
                     present_on_server: ServerPresent::Yes,
+                    is_dir: false,
+                    link_target: None,
+                    mode: None,
+                    modified_nanos: 0,
+                    version: VersionVector::default(),
                 },
                 FileMetadata {
                     full_path: PathBuf::from("/home/sync_dir/nested/memes2.txt"),
@@ -527,9 +1570,15 @@ mod tests {
                     vault_id: 0,
                     file_id: -1,
                     present_on_server: ServerPresent::No,
+                    is_dir: false,
+                    link_target: None,
+                    mode: None,
+                    modified_nanos: 0,
+                    version: VersionVector::default(),
                 },
             ],
             vault_id: 0,
+            ..Default::default()
         };
 
         let server_mdata = VaultMetadata {
@@ -542,6 +1591,11 @@ mod tests {
                 vault_id: 0,
                 file_id: 1,
                 present_on_server: ServerPresent::Yes,
+                is_dir: false,
+                link_target: None,
+                mode: None,
+                modified_nanos: 0,
+                version: VersionVector::default(),
             }, FileMetadata {
                 full_path: PathBuf::from("/other_home/sync_dir/nested/memes3.txt"),
                 root_directory: "sync_dir".to_string(),
@@ -551,8 +1605,14 @@ mod tests {
                 vault_id: 0,
                 file_id: 2,
                 present_on_server: ServerPresent::Yes,
+                is_dir: false,
+                link_target: None,
+                mode: None,
+                modified_nanos: 0,
+                version: VersionVector::default(),
             }],
             vault_id: 0,
+            ..Default::default()
         };
 
         let client_metadata_blob = MetadataBlob {
@@ -570,5 +1630,713 @@ mod tests {
         assert!(client.contains("memes3.txt"));
         assert!(server.contains("memes2.txt"));
     }
-}
 
+    fn entry(root: &str, name: &str, modified_time: i64, is_dir: bool) -> FileMetadata {
+        let root = PathBuf::from(root);
+        let file_size = if is_dir { 0 } else { 10 };
+        let sync_dir = "sync_dir".to_string();
+        let file = FileMetadata::new_from_client(root.join(name), sync_dir, root, modified_time, file_size, 0, -1);
+        FileMetadata { is_dir, ..file }
+    }
+
+    fn on_server(mut file: FileMetadata, file_id: i32) -> FileMetadata {
+        file.file_id = file_id;
+        file.present_on_server = ServerPresent::Yes;
+        file
+    }
+
+    #[test]
+    fn test_ignored_files_removed_from_blob() {
+        let file = |name: &str, file_id: i32| {
+            on_server(entry("/home/sync_dir", name, 1_000_000_000_000, false), file_id)
+        };
+        let mut blob = MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata {
+                files: vec![
+                    file("data.csv", 1),
+                    file(".DS_Store", 2),
+                    file("build/out.o", 3),
+                ],
+                vault_id: 0,
+                ..Default::default()
+            })]),
+        };
+        let rules = IgnoreRules::from_patterns(&[".DS_Store".to_string(), "build/".to_string()]);
+
+        blob.remove_ignored(&HashMap::from([(0, rules)]));
+
+        let files = blob.convert_to_metadata_vec();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].full_path, PathBuf::from("/home/sync_dir/data.csv"));
+    }
+
+    #[test]
+    fn test_unselected_subtrees_removed_from_blob() {
+        let file = |name: &str, file_id: i32| {
+            on_server(entry("/home/sync_dir", name, 1_000_000_000_000, false), file_id)
+        };
+        let mut blob = MetadataBlob {
+            vaults: HashMap::from([
+                (0, VaultMetadata {
+                    files: vec![
+                        file("papers/draft.txt", 1),
+                        file("papers_old/draft.txt", 2),
+                        file("raw/run1/data.csv", 3),
+                        file("raw/run2/data.csv", 4),
+                    ],
+                    vault_id: 0,
+                    ..Default::default()
+                }),
+                (1, VaultMetadata {
+                    files: vec![file("other.txt", 5)],
+                    vault_id: 1,
+                    ..Default::default()
+                }),
+            ]),
+        };
+        let selections = HashMap::from([(
+            0,
+            vec![PathBuf::from("papers"), PathBuf::from("raw/run2")],
+        )]);
+
+        blob.retain_selected(&selections);
+
+        let mut ids = blob
+            .convert_to_metadata_vec()
+            .iter()
+            .map(|f| f.file_id)
+            .collect::<Vec<i32>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn test_placeholder_path() {
+        let path = PathBuf::from("/home/sync_dir/data.csv");
+
+        let placeholder = placeholder_path(&path);
+
+        assert_eq!(placeholder, PathBuf::from("/home/sync_dir/data.csv.dxstub"));
+        assert!(is_placeholder(&placeholder));
+        assert!(!is_placeholder(&path));
+    }
+
+    #[test]
+    fn test_scan_skips_ignored_files_and_directories() {
+        let vault = tempfile::tempdir().unwrap();
+        let root = vault.path().to_path_buf();
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join("target/debug/app"), "binary").unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        fs::write(root.join("notes.txt.swp"), "swap").unwrap();
+        fs::write(placeholder_path(&root.join("remote.csv")), "{}").unwrap();
+        let rules = IgnoreRules::from_patterns(&["target/".to_string(), "*.swp".to_string()]);
+
+        let files = get_all_files_from_path_with_rules(&root, &rules).unwrap();
+
+        assert_eq!(files, vec![root.join("notes.txt")]);
+    }
+
+    fn direction_test_blobs(direction: SyncDirection, read_only: bool) -> (MetadataBlob, MetadataBlob) {
+        let file = |root: &str, name: &str, file_id: i32, modified_time: i64| {
+            on_server(entry(root, name, modified_time, false), file_id)
+        };
+        let client = VaultMetadata {
+            files: vec![
+                file("/home/sync_dir", "edited.txt", 1, 200),
+                file("/home/sync_dir", "local_only.txt", -1, 200),
+                file("/home/sync_dir", "stale.txt", 2, 100),
+            ],
+            vault_id: 0,
+            sync_direction: direction,
+            ..Default::default()
+        };
+        let server = VaultMetadata {
+            files: vec![
+                file("/srv/sync_dir", "edited.txt", 1, 100),
+                file("/srv/sync_dir", "stale.txt", 2, 200),
+            ],
+            vault_id: 0,
+            read_only,
+            ..Default::default()
+        };
+        (
+            MetadataBlob { vaults: HashMap::from([(0, client)]) },
+            MetadataBlob { vaults: HashMap::from([(0, server)]) },
+        )
+    }
+
+    fn file_names(vaults: &HashMap<i32, VaultMetadata>) -> Vec<String> {
+        let mut names = vaults
+            .values()
+            .flat_map(|vault| vault.files.iter())
+            .map(|file| file.full_path.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_download_only_reverts_edits_and_blocks_new_files() {
+        let (client, server) = direction_test_blobs(SyncDirection::DownloadOnly, false);
+
+        let diff = get_metadata_diff(client, server);
+
+        assert!(file_names(&diff.new_for_server).is_empty());
+        assert_eq!(file_names(&diff.new_for_client), vec!["edited.txt", "stale.txt"]);
+        assert_eq!(file_names(&diff.blocked_for_server), vec!["local_only.txt"]);
+    }
+
+    #[test]
+    fn test_upload_only_never_pulls() {
+        let (client, server) = direction_test_blobs(SyncDirection::UploadOnly, false);
+
+        let diff = get_metadata_diff(client, server);
+
+        assert!(file_names(&diff.new_for_client).is_empty());
+        assert_eq!(file_names(&diff.new_for_server), vec!["edited.txt", "local_only.txt"]);
+        assert!(diff.blocked_for_server.is_empty());
+    }
+
+    #[test]
+    fn test_read_only_vault_accepts_no_changes() {
+        let (client, server) = direction_test_blobs(SyncDirection::UploadOnly, true);
+
+        let diff = get_metadata_diff(client, server);
+
+        assert!(file_names(&diff.new_for_client).is_empty());
+        assert!(file_names(&diff.new_for_server).is_empty());
+        assert_eq!(file_names(&diff.blocked_for_server), vec!["edited.txt", "local_only.txt"]);
+    }
+
+    fn synced(name: &str, modified_time: i64) -> SyncedEntry {
+        SyncedEntry {
+            relative_path: PathBuf::from(name),
+            modified_time,
+            modified_nanos: 0,
+            mode: None,
+            version: VersionVector::default(),
+        }
+    }
+
+    fn with_version(mut file: FileMetadata, changes: &[&str]) -> FileMetadata {
+        for device in changes {
+            file.version.increment(device);
+        }
+        file
+    }
+
+    fn with_mode(mut file: FileMetadata, mode: u32) -> FileMetadata {
+        file.mode = Some(mode);
+        file
+    }
+
+    fn diff_of(client: Vec<FileMetadata>, server: Vec<FileMetadata>, last_sync: Vec<SyncedEntry>) -> MetadataDiff {
+        let client = VaultMetadata {
+            files: client,
+            vault_id: 0,
+            synced: last_sync,
+            ..Default::default()
+        };
+        let server = VaultMetadata {
+            files: server,
+            vault_id: 0,
+            ..Default::default()
+        };
+        get_metadata_diff(
+            MetadataBlob { vaults: HashMap::from([(0, client)]) },
+            MetadataBlob { vaults: HashMap::from([(0, server)]) },
+        )
+    }
+
+    #[test]
+    fn test_deletions_propagate_both_ways() {
+        let diff = diff_of(
+            vec![
+                entry("/home/sync_dir", "deleted_on_server.txt", 100, false),
+                entry("/home/sync_dir", "empty", 100, true),
+            ],
+            vec![
+                entry("/srv/sync_dir", "deleted_on_client.txt", 100, false),
+                entry("/srv/sync_dir", "empty", 300, true),
+                entry("/srv/sync_dir", "new.txt", 100, false),
+            ],
+            vec![
+                synced("deleted_on_server.txt", 100),
+                synced("deleted_on_client.txt", 100),
+                synced("empty", 100),
+            ],
+        );
+
+        assert_eq!(file_names(&diff.delete_on_client), vec!["deleted_on_server.txt"]);
+        assert_eq!(file_names(&diff.delete_on_server), vec!["deleted_on_client.txt"]);
+        assert_eq!(file_names(&diff.new_for_client), vec!["new.txt"]);
+        assert!(file_names(&diff.new_for_server).is_empty());
+        let mut in_sync = diff.in_sync[&0]
+            .iter()
+            .map(|entry| entry.relative_path.clone())
+            .collect::<Vec<PathBuf>>();
+        in_sync.sort();
+        assert_eq!(in_sync, vec![PathBuf::from("empty"), PathBuf::from("new.txt")]);
+    }
+
+    #[test]
+    fn test_unselected_subtrees_are_not_deleted() {
+        let vault = |files: Vec<FileMetadata>, synced: Vec<SyncedEntry>| MetadataBlob {
+            vaults: HashMap::from([(0, VaultMetadata { files, vault_id: 0, synced, ..Default::default() })]),
+        };
+        let mut client = vault(
+            vec![
+                entry("/home/sync_dir", "papers/draft.txt", 100, false),
+                entry("/home/sync_dir", "raw/data.csv", 100, false),
+            ],
+            vec![synced("papers/draft.txt", 100), synced("raw/data.csv", 100)],
+        );
+        let mut server = vault(
+            vec![
+                entry("/srv/sync_dir", "papers/draft.txt", 100, false),
+                entry("/srv/sync_dir", "raw/data.csv", 100, false),
+            ],
+            vec![],
+        );
+        let selections = HashMap::from([(0, vec![PathBuf::from("papers")])]);
+
+        client.retain_selected(&selections);
+        server.retain_selected(&selections);
+        let diff = get_metadata_diff(client, server);
+
+        assert!(file_names(&diff.delete_on_client).is_empty());
+        assert!(file_names(&diff.delete_on_server).is_empty());
+        assert!(file_names(&diff.new_for_client).is_empty());
+        assert!(file_names(&diff.new_for_server).is_empty());
+    }
+
+    #[test]
+    fn test_edits_since_last_sync_are_not_deleted() {
+        let diff = diff_of(
+            vec![entry("/home/sync_dir", "edited.txt", 200, false)],
+            vec![],
+            vec![synced("edited.txt", 100)],
+        );
+
+        assert!(diff.delete_on_client.is_empty());
+        assert_eq!(file_names(&diff.new_for_server), vec!["edited.txt"]);
+    }
+
+    #[test]
+    fn test_deleted_directory_is_kept_for_new_contents() {
+        let diff = diff_of(
+            vec![
+                entry("/home/sync_dir", "docs", 100, true),
+                entry("/home/sync_dir", "docs/old.txt", 100, false),
+                entry("/home/sync_dir", "docs/new.txt", 200, false),
+            ],
+            vec![],
+            vec![synced("docs", 100), synced("docs/old.txt", 100)],
+        );
+
+        assert_eq!(file_names(&diff.delete_on_client), vec!["old.txt"]);
+        assert_eq!(file_names(&diff.new_for_server), vec!["docs", "new.txt"]);
+    }
+
+    #[test]
+    fn test_download_only_restores_deleted_files() {
+        let client = VaultMetadata {
+            files: vec![],
+            vault_id: 0,
+            sync_direction: SyncDirection::DownloadOnly,
+            synced: vec![synced("gone.txt", 100)],
+            ..Default::default()
+        };
+        let server = VaultMetadata {
+            files: vec![entry("/srv/sync_dir", "gone.txt", 100, false)],
+            vault_id: 0,
+            ..Default::default()
+        };
+
+        let diff = get_metadata_diff(
+            MetadataBlob { vaults: HashMap::from([(0, client)]) },
+            MetadataBlob { vaults: HashMap::from([(0, server)]) },
+        );
+
+        assert!(diff.delete_on_server.is_empty());
+        assert_eq!(file_names(&diff.new_for_client), vec!["gone.txt"]);
+    }
+
+    #[test]
+    fn test_file_and_directory_at_same_path_conflict() {
+        let diff = diff_of(
+            vec![entry("/home/sync_dir", "notes", 100, false)],
+            vec![entry("/srv/sync_dir", "notes", 100, true)],
+            vec![],
+        );
+
+        assert_eq!(diff.conflicts[&0].len(), 1);
+        assert!(file_names(&diff.new_for_client).is_empty());
+        assert!(file_names(&diff.new_for_server).is_empty());
+    }
+
+    #[test]
+    fn test_scan_includes_empty_directories() {
+        let vault = tempfile::tempdir().unwrap();
+        let root = vault.path().to_path_buf();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("notes.txt"), "notes").unwrap();
+        let rules = IgnoreRules::from_patterns(&["target/".to_string()]);
+
+        let mut paths = get_all_paths_from_path_with_rules(&root, &rules, SymlinkPolicy::Skip).unwrap();
+        paths.sort();
+        let metadata = get_file_metadata_from_path(
+            paths.iter().map(|path| (-1, path.clone())).collect(),
+            "sync_dir".to_string(),
+            root.clone(),
+            0,
+            SymlinkPolicy::Skip,
+        );
+
+        assert_eq!(paths, vec![root.join("empty"), root.join("notes.txt")]);
+        assert!(metadata[0].is_dir);
+        assert_eq!(metadata[0].file_size, 0);
+        assert!(!metadata[1].is_dir);
+    }
+
+    #[test]
+    fn test_remove_paths_from_disk_leaves_non_empty_directories() {
+        let vault = tempfile::tempdir().unwrap();
+        let root = vault.path().to_path_buf();
+        fs::create_dir_all(root.join("gone/nested")).unwrap();
+        fs::create_dir_all(root.join("kept")).unwrap();
+        fs::write(root.join("gone/nested/a.txt"), "a").unwrap();
+        fs::write(root.join("kept/b.txt"), "b").unwrap();
+
+        let removed = remove_paths_from_disk(vec![
+            root.join("gone"),
+            root.join("kept"),
+            root.join("gone/nested/a.txt"),
+            root.join("gone/nested"),
+        ]);
+
+        assert_eq!(removed.len(), 3);
+        assert!(!root.join("gone").exists());
+        assert!(root.join("kept/b.txt").exists());
+    }
+
+    /// vault/docs/a.txt, vault/latest -> docs, vault/docs/loop -> .. and vault/outside -> the directory above the vault
+    fn vault_with_links() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("vault");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("docs", root.join("latest")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("docs/loop")).unwrap();
+        std::os::unix::fs::symlink("..", root.join("outside")).unwrap();
+        (dir, root)
+    }
+
+    fn scan(root: &PathBuf, symlinks: SymlinkPolicy) -> Vec<PathBuf> {
+        let mut paths = get_all_paths_from_path_with_rules(root, &IgnoreRules::empty(), symlinks)
+            .unwrap()
+            .iter()
+            .map(|path| path.strip_prefix(root).unwrap().to_path_buf())
+            .collect::<Vec<PathBuf>>();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_skip_policy_leaves_links_out() {
+        let (_dir, root) = vault_with_links();
+
+        assert_eq!(scan(&root, SymlinkPolicy::Skip), vec![PathBuf::from("docs"), PathBuf::from("docs/a.txt")]);
+    }
+
+    #[test]
+    fn test_link_policy_keeps_links_inside_the_vault() {
+        let (_dir, root) = vault_with_links();
+
+        let paths = scan(&root, SymlinkPolicy::Link);
+        let metadata = get_file_metadata_from_path(
+            vec![(-1, root.join("latest"))],
+            "vault".to_string(),
+            root.clone(),
+            0,
+            SymlinkPolicy::Link,
+        );
+
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("docs"), PathBuf::from("docs/a.txt"), PathBuf::from("docs/loop"), PathBuf::from("latest")]
+        );
+        assert_eq!(metadata[0].link_target, Some(PathBuf::from("docs")));
+        assert!(!metadata[0].is_dir);
+        assert_eq!(metadata[0].file_size, 0);
+    }
+
+    #[test]
+    fn test_follow_policy_stops_at_loops_and_the_vault_root() {
+        let (_dir, root) = vault_with_links();
+
+        assert_eq!(
+            scan(&root, SymlinkPolicy::Follow),
+            vec![
+                PathBuf::from("docs"),
+                PathBuf::from("docs/a.txt"),
+                PathBuf::from("latest"),
+                PathBuf::from("latest/a.txt"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_link_stays_in_vault() {
+        assert!(link_stays_in_vault(Path::new("docs/latest"), Path::new("v2")));
+        assert!(link_stays_in_vault(Path::new("docs/latest"), Path::new("../other/./v2")));
+        assert!(!link_stays_in_vault(Path::new("docs/latest"), Path::new("../../etc")));
+        assert!(!link_stays_in_vault(Path::new("latest"), Path::new("/etc/passwd")));
+    }
+
+    #[test]
+    fn test_is_inside_vault() {
+        assert!(is_inside_vault(Path::new("docs/notes.txt")));
+        assert!(!is_inside_vault(Path::new("")));
+        assert!(!is_inside_vault(Path::new("docs/../../etc/passwd")));
+        assert!(!is_inside_vault(Path::new("/etc/passwd")));
+        assert!(!is_inside_vault(Path::new("./notes.txt")));
+    }
+
+    #[test]
+    fn test_links_are_written_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("nested/latest");
+        fs::create_dir_all(dir.path().join("v1")).unwrap();
+        fs::write(dir.path().join("file.txt"), "in the way").unwrap();
+
+        write_link(&link, Path::new("../v1"), filetime::FileTime::from_unix_time(1_000, 0)).unwrap();
+        write_link(&link, Path::new("../v2"), filetime::FileTime::from_unix_time(2_000, 5)).unwrap();
+
+        assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("../v2"));
+        let metadata = fs::symlink_metadata(&link).unwrap();
+        assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), filetime::FileTime::from_unix_time(2_000, 5));
+        assert!(write_link(&dir.path().join("v1"), Path::new("file.txt"), filetime::FileTime::zero()).is_err());
+    }
+
+    #[test]
+    fn test_mode_changes_sync_from_the_side_that_changed() {
+        let last_sync = vec![
+            SyncedEntry { mode: Some(0o644), ..synced("chmod_here.sh", 100) },
+            SyncedEntry { mode: Some(0o644), ..synced("chmod_on_server.sh", 100) },
+        ];
+        let diff = diff_of(
+            vec![
+                with_mode(entry("/home/sync_dir", "chmod_here.sh", 100, false), 0o755),
+                with_mode(entry("/home/sync_dir", "chmod_on_server.sh", 100, false), 0o644),
+                with_mode(entry("/home/sync_dir", "never_synced.sh", 100, false), 0o600),
+            ],
+            vec![
+                with_mode(entry("/srv/sync_dir", "chmod_here.sh", 100, false), 0o644),
+                with_mode(entry("/srv/sync_dir", "chmod_on_server.sh", 100, false), 0o700),
+                with_mode(entry("/srv/sync_dir", "never_synced.sh", 100, false), 0o640),
+            ],
+            last_sync,
+        );
+
+        assert_eq!(file_names(&diff.modes_for_server), vec!["chmod_here.sh"]);
+        assert_eq!(file_names(&diff.modes_for_client), vec!["chmod_on_server.sh", "never_synced.sh"]);
+        assert_eq!(diff.modes_for_client[&0].files[0].mode, Some(0o700));
+        assert!(file_names(&diff.new_for_client).is_empty());
+        assert!(file_names(&diff.new_for_server).is_empty());
+        assert_eq!(diff.in_sync[&0].len(), 3);
+    }
+
+    #[test]
+    fn test_saved_files_keep_their_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let mut file = RemoteFile::new_empty(PathBuf::from("/srv/sync_dir/run.sh"), "sync_dir".to_string(), 0, 1);
+        file.absolute_root_dir = PathBuf::from("/srv/sync_dir");
+        file.contents = b"#!/bin/sh".to_vec();
+        file.mode = Some(0o750);
+
+        save_remote_files_to_disk(vec![file], vec![(0, root.clone())]);
+
+        let metadata = get_file_metadata_from_path(
+            vec![(-1, root.join("run.sh"))],
+            "sync_dir".to_string(),
+            root.clone(),
+            0,
+            SymlinkPolicy::Skip,
+        );
+        assert_eq!(metadata[0].mode, Some(0o750));
+    }
+
+    #[test]
+    fn test_saves_within_one_second_are_synced() {
+        let mut client = entry("/home/sync_dir", "notes.txt", 100, false);
+        let mut server = entry("/srv/sync_dir", "notes.txt", 100, false);
+        client.modified_nanos = 900_000_000;
+        server.modified_nanos = 100_000_000;
+
+        let diff = diff_of(vec![client.clone()], vec![server.clone()], vec![]);
+        assert_eq!(file_names(&diff.new_for_server), vec!["notes.txt"]);
+
+        // a copy on a filesystem without sub-second times is the same file
+        server.modified_nanos = 0;
+        let diff = diff_of(vec![client], vec![server], vec![]);
+        assert!(file_names(&diff.new_for_server).is_empty());
+        assert!(file_names(&diff.new_for_client).is_empty());
+    }
+
+    #[test]
+    fn test_saved_files_keep_their_exact_modified_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let mut file = RemoteFile::new_empty(PathBuf::from("/srv/sync_dir/notes.txt"), "sync_dir".to_string(), 0, 1);
+        file.absolute_root_dir = PathBuf::from("/srv/sync_dir");
+        file.modified_time = 1_600_000_000;
+        file.modified_nanos = 123_456_789;
+
+        save_remote_files_to_disk(vec![file], vec![(0, root.clone())]);
+
+        let metadata = get_file_metadata_from_path(
+            vec![(-1, root.join("notes.txt"))],
+            "sync_dir".to_string(),
+            root.clone(),
+            0,
+            SymlinkPolicy::Skip,
+        );
+        assert_eq!((metadata[0].modified_time, metadata[0].modified_nanos), (1_600_000_000, 123_456_789));
+    }
+
+    #[test]
+    fn test_clock_skew_only_decides_when_both_sides_changed() {
+        // this device's clock is 10 minutes slow, so its edit at 500 happened after the server's at 800
+        let both_changed = |client_time, server_time, last_sync: Vec<SyncedEntry>| {
+            let client = VaultMetadata {
+                files: vec![entry("/home/sync_dir", "notes.txt", client_time, false)],
+                vault_id: 0,
+                synced: last_sync,
+                clock_skew_ms: 600_000,
+                ..Default::default()
+            };
+            let server = VaultMetadata {
+                files: vec![entry("/srv/sync_dir", "notes.txt", server_time, false)],
+                vault_id: 0,
+                ..Default::default()
+            };
+            get_metadata_diff(
+                MetadataBlob { vaults: HashMap::from([(0, client)]) },
+                MetadataBlob { vaults: HashMap::from([(0, server)]) },
+            )
+        };
+
+        let diff = both_changed(500, 800, vec![]);
+        assert_eq!(file_names(&diff.new_for_server), vec!["notes.txt"]);
+
+        // only the server changed since the last sync, so its copy wins whatever the clocks say
+        let diff = both_changed(2_000, 1_500, vec![synced("notes.txt", 2_000)]);
+        assert_eq!(file_names(&diff.new_for_client), vec!["notes.txt"]);
+        assert!(file_names(&diff.new_for_server).is_empty());
+    }
+
+    #[test]
+    fn test_shift_time() {
+        assert_eq!(shift_time((100, 900_000_000), 250), (101, 150_000_000));
+        assert_eq!(shift_time((100, 100_000_000), -250), (99, 850_000_000));
+        assert_eq!(shift_time((100, 0), 1_600), (102, 0));
+    }
+
+    #[test]
+    fn test_versions_decide_over_modified_times() {
+        // the server's copy is based on this one, even though the other device's clock made it look older
+        let diff = diff_of(
+            vec![with_version(entry("/home/sync_dir", "notes.txt", 500, false), &["laptop"])],
+            vec![with_version(entry("/srv/sync_dir", "notes.txt", 300, false), &["laptop", "desktop"])],
+            vec![],
+        );
+
+        assert_eq!(file_names(&diff.new_for_client), vec!["notes.txt"]);
+        assert!(file_names(&diff.new_for_server).is_empty());
+        assert!(diff.conflicts.is_empty());
+    }
+
+    #[test]
+    fn test_only_concurrent_versions_conflict() {
+        let mut same_on_both = with_version(entry("/home/sync_dir", "same.txt", 100, false), &["laptop"]);
+        same_on_both.file_size = 7;
+        let mut server_same = with_version(entry("/srv/sync_dir", "same.txt", 100, false), &["desktop"]);
+        server_same.file_size = 7;
+        let diff = diff_of(
+            vec![
+                with_version(entry("/home/sync_dir", "notes.txt", 500, false), &["laptop", "laptop"]),
+                same_on_both,
+            ],
+            vec![
+                with_version(entry("/srv/sync_dir", "notes.txt", 300, false), &["laptop", "desktop"]),
+                server_same,
+            ],
+            vec![],
+        );
+
+        assert_eq!(diff.conflicts[&0].len(), 1);
+        assert_eq!(diff.conflicts[&0][0].client.relative_path(), PathBuf::from("notes.txt"));
+        // by default the newer copy wins, with a version that has seen both
+        assert_eq!(diff.conflicts[&0][0].resolution, ConflictResolution::KeepClient);
+        assert!(file_names(&diff.new_for_client).is_empty());
+        assert_eq!(file_names(&diff.new_for_server), vec!["notes.txt"]);
+        let uploaded = &diff.new_for_server[&0].files[0].version;
+        assert_eq!(uploaded.compare(&diff.conflicts[&0][0].server.version), Causality::Descendant);
+        // identical copies take the server's version so they agree from the next sync on
+        let synced = diff.in_sync[&0]
+            .iter()
+            .find(|entry| entry.relative_path == Path::new("same.txt"))
+            .unwrap();
+        assert_eq!(synced.version, with_version(entry("/", "same.txt", 0, false), &["desktop"]).version);
+    }
+
+    #[test]
+    fn test_conflict_strategy_respects_sync_direction() {
+        let conflict = |strategy: ConflictStrategy, direction: SyncDirection| {
+            let client = VaultMetadata {
+                files: vec![with_version(entry("/home/sync_dir", "notes.txt", 500, false), &["laptop"])],
+                conflict_strategy: strategy,
+                sync_direction: direction,
+                ..Default::default()
+            };
+            let server = VaultMetadata {
+                files: vec![with_version(entry("/srv/sync_dir", "notes.txt", 300, false), &["desktop"])],
+                ..Default::default()
+            };
+            let mut conflicts = client.find_conflicts(&server);
+            client.resolve_conflicts(&server, &mut conflicts);
+            (conflicts[0].resolution, conflicts[0].merge)
+        };
+
+        assert_eq!(conflict(ConflictStrategy::Newest, SyncDirection::TwoWay), (ConflictResolution::KeepClient, true));
+        assert_eq!(conflict(ConflictStrategy::Server, SyncDirection::TwoWay), (ConflictResolution::KeepServer, false));
+        assert_eq!(conflict(ConflictStrategy::KeepBoth, SyncDirection::TwoWay), (ConflictResolution::KeepBoth, false));
+        assert_eq!(conflict(ConflictStrategy::Manual, SyncDirection::TwoWay), (ConflictResolution::Pending, false));
+        assert_eq!(
+            conflict(ConflictStrategy::Client, SyncDirection::DownloadOnly),
+            (ConflictResolution::Pending, false)
+        );
+        assert_eq!(
+            conflict(ConflictStrategy::KeepBoth, SyncDirection::UploadOnly),
+            (ConflictResolution::Pending, false)
+        );
+    }
+
+    #[test]
+    fn test_conflict_copy_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let notes = dir.path().join("notes.txt");
+
+        assert_eq!(conflict_copy_path(&notes, "a1b2"), dir.path().join("notes (conflict a1b2).txt"));
+        fs::write(dir.path().join("notes (conflict a1b2).txt"), "older").unwrap();
+        assert_eq!(conflict_copy_path(&notes, "a1b2"), dir.path().join("notes (conflict a1b2 2).txt"));
+        assert_eq!(
+            conflict_copy_path(&dir.path().join("Makefile"), "a1b2"),
+            dir.path().join("Makefile (conflict a1b2)")
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file in the root of a vault holding the ignore patterns for that vault
+pub const IGNORE_FILE_NAME: &str = ".datoxidizeignore";
+
+/// Ignore rules for a single vault, using gitignore semantics
+/// Patterns are built from the server defaults first then the vault's .datoxidizeignore, so a vault
+/// can re-include something ignored by default with a negated pattern eg: `!.DS_Store`
+/// A pattern ending in / only matches directories, and anything under an ignored directory is ignored
+/// All paths are matched relative to the vault root
+#[derive(Clone, Debug)]
+pub struct IgnoreRules {
+    matcher: Gitignore,
+}
+
+impl IgnoreRules {
+    /// Rules that ignore nothing
+    pub fn empty() -> Self {
+        IgnoreRules {
+            matcher: Gitignore::empty(),
+        }
+    }
+
+    /// Builds rules from a list of patterns in gitignore format
+    pub fn from_patterns(patterns: &[String]) -> Self {
+        let mut builder = GitignoreBuilder::new("");
+        add_patterns(&mut builder, patterns, None);
+        build(builder)
+    }
+
+    /// Builds the rules for the vault at vault_root from the default patterns and
+    /// the .datoxidizeignore in the vault root, if there is one
+    pub fn for_vault(vault_root: &Path, default_patterns: &[String]) -> Self {
+        let mut builder = GitignoreBuilder::new("");
+        add_patterns(&mut builder, default_patterns, None);
+
+        let ignore_file = vault_root.join(IGNORE_FILE_NAME);
+        if let Ok(contents) = fs::read_to_string(&ignore_file) {
+            let lines = contents.lines().map(String::from).collect::<Vec<String>>();
+            add_patterns(&mut builder, &lines, Some(ignore_file));
+        }
+        build(builder)
+    }
+
+    /// Returns true if a path relative to the vault root, or any of its parent directories is ignored
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if relative_path.has_root() || relative_path.as_os_str().is_empty() {
+            return false;
+        }
+        self.matcher
+            .matched_path_or_any_parents(relative_path, is_dir)
+            .is_ignore()
+    }
+
+    /// Same as `is_ignored` but for a full path, returns false for paths outside of vault_root
+    pub fn is_ignored_in_vault(&self, path: &Path, vault_root: &Path, is_dir: bool) -> bool {
+        match path.strip_prefix(vault_root) {
+            Ok(relative) => self.is_ignored(relative, is_dir),
+            Err(_) => false,
+        }
+    }
+}
+
+fn add_patterns(builder: &mut GitignoreBuilder, patterns: &[String], from: Option<PathBuf>) {
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(from.clone(), pattern) {
+            println!("Skipping invalid ignore pattern {:?}: {e}", pattern);
+        }
+    }
+}
+
+fn build(builder: GitignoreBuilder) -> IgnoreRules {
+    let matcher = builder.build().unwrap_or_else(|e| {
+        println!("Error building ignore rules, nothing will be ignored: {e}");
+        Gitignore::empty()
+    });
+    IgnoreRules { matcher }
+}
+
+/// Reads a file of default patterns in gitignore format, blank lines and comments are skipped
+/// Returns an empty list if the file can't be read
+pub fn read_default_patterns(path: &Path) -> Vec<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents
+            .lines()
+            .map(|line| line.trim_end().to_string())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect(),
+        Err(e) => {
+            println!("No default ignore patterns read from {:?}: {e}", path);
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_glob_and_negation() {
+        let rules = IgnoreRules::from_patterns(&patterns(&["*.tmp", "!keep.tmp", ".DS_Store"]));
+
+        assert!(rules.is_ignored(Path::new("scratch.tmp"), false));
+        assert!(rules.is_ignored(Path::new("nested/dir/scratch.tmp"), false));
+        assert!(rules.is_ignored(Path::new("nested/.DS_Store"), false));
+        assert!(!rules.is_ignored(Path::new("keep.tmp"), false));
+        assert!(!rules.is_ignored(Path::new("data.csv"), false));
+    }
+
+    #[test]
+    fn test_directory_rules_ignore_children() {
+        let rules = IgnoreRules::from_patterns(&patterns(&["build/", "/logs"]));
+
+        assert!(rules.is_ignored(Path::new("build"), true));
+        assert!(rules.is_ignored(Path::new("build/output/app.o"), false));
+        assert!(rules.is_ignored(Path::new("nested/build/app.o"), false));
+        // a trailing slash only matches directories
+        assert!(!rules.is_ignored(Path::new("build"), false));
+        // a leading slash anchors the pattern to the vault root
+        assert!(rules.is_ignored(Path::new("logs/today.log"), false));
+        assert!(!rules.is_ignored(Path::new("nested/logs/today.log"), false));
+    }
+
+    #[test]
+    fn test_vault_file_overrides_defaults() {
+        let vault = tempfile::tempdir().unwrap();
+        fs::write(vault.path().join(IGNORE_FILE_NAME), "# vault rules\n!.DS_Store\n*.bak\n").unwrap();
+
+        let rules = IgnoreRules::for_vault(vault.path(), &patterns(&[".DS_Store", "*.swp"]));
+
+        assert!(!rules.is_ignored(Path::new(".DS_Store"), false));
+        assert!(rules.is_ignored(Path::new("notes.swp"), false));
+        assert!(rules.is_ignored(Path::new("notes.bak"), false));
+        assert!(!rules.is_ignored(Path::new(IGNORE_FILE_NAME), false));
+    }
+
+    #[test]
+    fn test_is_ignored_in_vault() {
+        let rules = IgnoreRules::from_patterns(&patterns(&["*.tmp"]));
+        let root = Path::new("/home/vault0");
+
+        assert!(rules.is_ignored_in_vault(Path::new("/home/vault0/a/b.tmp"), root, false));
+        assert!(!rules.is_ignored_in_vault(Path::new("/elsewhere/b.tmp"), root, false));
+    }
+}
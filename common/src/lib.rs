@@ -2,6 +2,9 @@ pub mod file_utils;
 pub mod router_utils;
 pub mod config_utils;
 pub mod common_db_utils;
+pub mod ignore_utils;
+pub mod version_vector;
+pub mod merge_utils;
 
 
 
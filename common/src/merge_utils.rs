@@ -0,0 +1,213 @@
+use crate::version_vector::VersionVector;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Files larger than this are never merged or kept as ancestors
+pub const MAX_MERGE_BYTES: usize = 1024 * 1024;
+
+/// Changed regions longer than this many lines on both sides are not diffed, the merge gives up instead
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Sent by a client whose copy of a file changed concurrently with the server's
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MergeRequest {
+    pub vault_id: i32,
+    /// Relative to the vault root
+    pub relative_path: PathBuf,
+    /// The version of the client's copy
+    pub version: VersionVector,
+    pub contents: Vec<u8>,
+}
+
+/// The merged file the server saved, the client writes it over its own copy
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MergedFile {
+    pub contents: Vec<u8>,
+    pub modified_time: i64,
+    pub modified_nanos: u32,
+    pub version: VersionVector,
+}
+
+/// Only small UTF-8 files without NUL bytes are treated as text and merged
+pub fn is_mergeable(contents: &[u8]) -> bool {
+    contents.len() <= MAX_MERGE_BYTES && !contents.contains(&0) && std::str::from_utf8(contents).is_ok()
+}
+
+/// Merges the changes made to base in ours and in theirs, None if they overlap or aren't text
+/// Rows of a .csv file that both sides added at the same place are all kept, ours first
+pub fn merge_file(path: &Path, base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+    if !(is_mergeable(base) && is_mergeable(ours) && is_mergeable(theirs)) {
+        return None;
+    }
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    merge_lines(
+        std::str::from_utf8(base).ok()?,
+        std::str::from_utf8(ours).ok()?,
+        std::str::from_utf8(theirs).ok()?,
+        is_csv,
+    )
+    .map(String::into_bytes)
+}
+
+/// A line based three way merge, each region changed on only one side takes that side's lines and
+/// a region changed the same way on both sides is taken once
+/// With keep_both_inserts, lines both sides inserted at the same place without changing any
+/// existing line are all kept instead of overlapping
+pub fn merge_lines(base: &str, ours: &str, theirs: &str, keep_both_inserts: bool) -> Option<String> {
+    let base = base.split_inclusive('\n').collect::<Vec<&str>>();
+    let ours = ours.split_inclusive('\n').collect::<Vec<&str>>();
+    let theirs = theirs.split_inclusive('\n').collect::<Vec<&str>>();
+    let in_ours = match_lines(&base, &ours)?;
+    let in_theirs = match_lines(&base, &theirs)?;
+
+    let mut merged = String::new();
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // lines kept unchanged by both sides are copied as they are
+        while b < base.len() && in_ours[b] == Some(o) && in_theirs[b] == Some(t) {
+            merged.push_str(base[b]);
+            b += 1;
+            o += 1;
+            t += 1;
+        }
+
+        // the changed region runs up to the next line both sides kept, or to the end
+        let next = (b..base.len()).find(|line| in_ours[*line].is_some() && in_theirs[*line].is_some());
+        let (base_end, ours_end, theirs_end) = match next {
+            Some(line) => (line, in_ours[line].unwrap(), in_theirs[line].unwrap()),
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+        let base_region = &base[b..base_end];
+        let ours_region = &ours[o..ours_end];
+        let theirs_region = &theirs[t..theirs_end];
+
+        if ours_region == base_region || ours_region == theirs_region {
+            theirs_region.iter().for_each(|line| merged.push_str(line));
+        } else if theirs_region == base_region {
+            ours_region.iter().for_each(|line| merged.push_str(line));
+        } else if keep_both_inserts && base_region.is_empty() {
+            ours_region.iter().chain(theirs_region.iter()).for_each(|line| merged.push_str(line));
+        } else {
+            return None;
+        }
+
+        if next.is_none() {
+            return Some(merged);
+        }
+        b = base_end;
+        o = ours_end;
+        t = theirs_end;
+    }
+}
+
+/// For each line of base, the line of other it is kept as in the longest common subsequence
+/// None if the changed part of the two is too large to diff
+fn match_lines(base: &[&str], other: &[&str]) -> Option<Vec<Option<usize>>> {
+    let mut matches = vec![None; base.len()];
+    let prefix = base.iter().zip(other.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    for (line, matched) in matches.iter_mut().enumerate().take(prefix) {
+        *matched = Some(line);
+    }
+    for line in 0..suffix {
+        matches[base.len() - 1 - line] = Some(other.len() - 1 - line);
+    }
+
+    let base_middle = &base[prefix..base.len() - suffix];
+    let other_middle = &other[prefix..other.len() - suffix];
+    if base_middle.is_empty() || other_middle.is_empty() {
+        return Some(matches);
+    }
+    if base_middle.len() * other_middle.len() > MAX_DIFF_CELLS {
+        return None;
+    }
+
+    // lengths[i][j] is the longest common subsequence of base_middle[i..] and other_middle[j..]
+    let width = other_middle.len() + 1;
+    let mut lengths = vec![0u32; (base_middle.len() + 1) * width];
+    for i in (0..base_middle.len()).rev() {
+        for j in (0..other_middle.len()).rev() {
+            lengths[i * width + j] = if base_middle[i] == other_middle[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < base_middle.len() && j < other_middle.len() {
+        if base_middle[i] == other_middle[j] {
+            matches[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    #[test]
+    fn test_separate_edits_merge() {
+        let ours = "one\nTWO\nthree\nfour\nfive\n";
+        let theirs = "one\ntwo\nthree\nfour\nFIVE\nsix\n";
+
+        assert_eq!(
+            merge_lines(BASE, ours, theirs, false).unwrap(),
+            "one\nTWO\nthree\nfour\nFIVE\nsix\n"
+        );
+    }
+
+    #[test]
+    fn test_deletes_and_identical_edits_merge() {
+        let ours = "one\nthree\nfour\nFIVE\n";
+        let theirs = "one\ntwo\nthree\nfour\nFIVE\n";
+
+        assert_eq!(merge_lines(BASE, ours, theirs, false).unwrap(), "one\nthree\nfour\nFIVE\n");
+    }
+
+    #[test]
+    fn test_overlapping_edits_dont_merge() {
+        let ours = "one\nTWO\nthree\nfour\nfive\n";
+        let theirs = "one\n2\nthree\nfour\nfive\n";
+
+        assert_eq!(merge_lines(BASE, ours, theirs, false), None);
+    }
+
+    #[test]
+    fn test_csv_keeps_rows_added_on_both_sides() {
+        let base = b"id,name\n1,ash\n";
+        let ours = b"id,name\n1,ash\n2,birch\n";
+        let theirs = b"id,name\n1,ash\n3,cedar\n";
+
+        assert_eq!(merge_lines("a\n", "a\nb\n", "a\nc\n", false), None);
+        assert_eq!(
+            merge_file(Path::new("trees.csv"), base, ours, theirs).unwrap(),
+            b"id,name\n1,ash\n2,birch\n3,cedar\n".to_vec()
+        );
+        assert_eq!(merge_file(Path::new("trees.txt"), base, ours, theirs), None);
+    }
+
+    #[test]
+    fn test_binary_files_dont_merge() {
+        assert!(is_mergeable(BASE.as_bytes()));
+        assert!(!is_mergeable(&[0x89, b'P', b'N', b'G', 0, 1]));
+        assert_eq!(merge_file(Path::new("image.png"), b"a", b"b\0", b"a"), None);
+    }
+}
@@ -1,6 +1,10 @@
 use std::path::PathBuf;
 use crate::file_utils;
 
+/// Header the server sends its clock in with the metadata blob, in milliseconds since unix epoch
+/// The client measures the clock skew between them from it
+pub const SERVER_TIME_HEADER: &str = "x-server-time";
+
 pub async fn show_files() -> String {
     let files = file_utils::get_all_files_from_path(&PathBuf::from("./backend/storage")).unwrap();
     let mut files_as_string = String::new();
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// Device id the server counts its own changes under, eg: files uploaded in the browser or over WebDAV
+pub const SERVER_DEVICE: &str = "server";
+
+/// A counter per device that changed a file, each change bumps the changing device's counter
+/// A copy whose counters are all at least another's has seen every change the other has
+/// An empty vector means the history of the copy is unknown
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+/// How one copy of a file relates to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    /// Both copies have seen the same changes
+    Equal,
+    /// The other copy is based on this one and has changes this one hasn't seen
+    Ancestor,
+    /// This copy is based on the other one and has changes the other hasn't seen
+    Descendant,
+    /// Each copy has changes the other hasn't seen
+    Concurrent,
+}
+
+impl VersionVector {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Counts a change made by device
+    pub fn increment(&mut self, device: &str) {
+        *self.0.entry(device.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn incremented(&self, device: &str) -> VersionVector {
+        let mut version = self.clone();
+        version.increment(device);
+        version
+    }
+
+    /// Takes the highest counter of each device, the result has seen every change of both
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, counter) in other.0.iter() {
+            let current = self.0.entry(device.clone()).or_insert(0);
+            *current = (*current).max(*counter);
+        }
+    }
+
+    /// The changes both copies have seen, the lowest counter of each device
+    /// For copies changed concurrently since a sync this is the version they were synced at
+    pub fn common_ancestor(&self, other: &VersionVector) -> VersionVector {
+        VersionVector(
+            self.0
+                .iter()
+                .map(|(device, counter)| {
+                    let theirs = other.0.get(device).copied().unwrap_or(0);
+                    (device.clone(), (*counter).min(theirs))
+                })
+                .filter(|(_, counter)| *counter > 0)
+                .collect(),
+        )
+    }
+
+    /// How this copy relates to other
+    pub fn compare(&self, other: &VersionVector) -> Causality {
+        let mut behind = false;
+        let mut ahead = false;
+        for device in self.0.keys().chain(other.0.keys()) {
+            let mine = self.0.get(device).copied().unwrap_or(0);
+            let theirs = other.0.get(device).copied().unwrap_or(0);
+            match mine.cmp(&theirs) {
+                Ordering::Less => behind = true,
+                Ordering::Greater => ahead = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (ahead, behind) {
+            (false, false) => Causality::Equal,
+            (false, true) => Causality::Ancestor,
+            (true, false) => Causality::Descendant,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+
+    /// The JSON stored in the version column of file_metadata
+    pub fn to_db(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Reads the version column of file_metadata, a missing or unreadable version is unknown
+    pub fn from_db(raw: Option<String>) -> VersionVector {
+        raw.and_then(|raw| serde_json::from_str(&raw).ok()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(counters: &[(&str, u64)]) -> VersionVector {
+        VersionVector(counters.iter().map(|(device, counter)| (device.to_string(), *counter)).collect())
+    }
+
+    #[test]
+    fn test_compare() {
+        let base = version(&[("laptop", 1), ("server", 1)]);
+
+        assert_eq!(base.compare(&base.clone()), Causality::Equal);
+        assert_eq!(base.compare(&base.incremented("desktop")), Causality::Ancestor);
+        assert_eq!(base.incremented("laptop").compare(&base), Causality::Descendant);
+        assert_eq!(
+            base.incremented("laptop").compare(&base.incremented("desktop")),
+            Causality::Concurrent
+        );
+    }
+
+    #[test]
+    fn test_merge_descends_from_both() {
+        let mut merged = version(&[("laptop", 2)]);
+        let other = version(&[("laptop", 1), ("desktop", 3)]);
+        merged.merge(&other);
+
+        assert_eq!(merged, version(&[("laptop", 2), ("desktop", 3)]));
+        assert_eq!(merged.compare(&other), Causality::Descendant);
+    }
+
+    #[test]
+    fn test_common_ancestor_is_the_synced_version() {
+        let synced = version(&[("laptop", 1), ("server", 2)]);
+        let laptop = synced.incremented("laptop");
+        let server = synced.incremented("desktop").incremented(SERVER_DEVICE);
+
+        assert_eq!(laptop.common_ancestor(&server), synced);
+        assert_eq!(server.common_ancestor(&laptop), synced);
+    }
+
+    #[test]
+    fn test_db_round_trip() {
+        let version = version(&[("server", 4)]);
+
+        assert_eq!(VersionVector::from_db(Some(version.to_db())), version);
+        assert!(VersionVector::from_db(None).is_empty());
+        assert!(VersionVector::from_db(Some("not json".to_string())).is_empty());
+    }
+}